@@ -0,0 +1,192 @@
+//! Wall time of every backend on a few compute heavy scripts.
+//!
+//! Run with `cargo bench`, adding `--features nan-boxing` to compare the two
+//! representations of VM values, `--features threaded-dispatch` to compare
+//! the two dispatch loops, or `--features jit` to compile hot VM functions to
+//! machine code.
+//!
+//! # Slot-indexed locals
+//!
+//! The tree-walking interpreter used to keep every scope in a map, looking
+//! variables up by name through the chain of enclosing scopes. It now finds
+//! locals at the slot the resolver assigned them. The name-based version is
+//! gone, so here's how the two compared, the median of five runs of release
+//! builds on the same machine, running the benches of the same name:
+//!
+//! | bench              | by name | by slot |
+//! |--------------------|--------:|--------:|
+//! | fib(30)            | 1672 ms | 1070 ms |
+//! | global loop        |  567 ms |  318 ms |
+//! | nested local loops |  946 ms |  364 ms |
+//!
+//! The global loop gains too, as its counter is a local of the loop.
+
+use lox0::closure_compiler::ClosureCompiler;
+use lox0::compiler::Compiler;
+use lox0::interpreter::Interpreter;
+use lox0::parser::Parser;
+use lox0::register;
+use lox0::resolver::Resolver;
+use lox0::scanner::Scanner;
+use lox0::value::Value;
+use lox0::vm::Vm;
+use std::io;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+const BENCHES: &[(&str, &str)] = &[
+    (
+        "fib(30)",
+        "
+        fun fib(n) {
+          if (n < 2) return n;
+          return fib(n - 1) + fib(n - 2);
+        }
+        fib(30);
+        ",
+    ),
+    (
+        "global loop",
+        "
+        var sum = 0;
+        for (var i = 0; i < 1000000; i = i + 1) {
+          sum = sum + i;
+        }
+        ",
+    ),
+    (
+        "nested local loops",
+        "
+        fun loops() {
+          var sum = 0;
+          for (var i = 0; i < 1000; i = i + 1) {
+            for (var j = 0; j < 1000; j = j + 1) {
+              var k = i * j;
+              sum = sum + k;
+            }
+          }
+          return sum;
+        }
+        loops();
+        ",
+    ),
+    (
+        "method calls",
+        "
+        class Counter {
+          init() { this.count = 0; }
+          increment() {
+            this.count = this.count + 1;
+            return this;
+          }
+          get() { return this.count; }
+        }
+        var counter = Counter();
+        for (var i = 0; i < 300000; i = i + 1) {
+          counter.increment().increment();
+          counter.get();
+        }
+        ",
+    ),
+    (
+        "bound methods",
+        "
+        class Point {
+          init(x, y) {
+            this.x = x;
+            this.y = y;
+          }
+          add(other) { return Point(this.x + other.x, this.y + other.y); }
+        }
+        var sum = Point(0, 0);
+        var one = Point(1, 1);
+        for (var i = 0; i < 200000; i = i + 1) {
+          var add = sum.add;
+          sum = add(one);
+        }
+        ",
+    ),
+    (
+        "string equality",
+        "
+        var name = \"some\" + \"thing\";
+        var same = 0;
+        for (var i = 0; i < 300000; i = i + 1) {
+          if (name == \"something\") same = same + 1;
+          if (name != \"something else\") same = same + 1;
+        }
+        ",
+    ),
+];
+
+fn run(backend: &str, source: &str) -> Duration {
+    let tokens = Scanner::new(source).scan_tokens().unwrap();
+    let statements = Parser::new(&tokens).parse().unwrap();
+    let locals = Resolver::new().resolve(&statements).unwrap();
+
+    let start = Instant::now();
+    match backend {
+        "interpreter" => {
+            let mut interpreter = Interpreter::with_output(Box::new(io::sink()));
+            interpreter.resolve(locals);
+            interpreter.interpret(&statements).unwrap();
+        }
+        "closure" => {
+            let mut compiler = ClosureCompiler::with_output(Box::new(io::sink()));
+            compiler.resolve(locals);
+            compiler.interpret(&statements).unwrap();
+        }
+        "vm" => {
+            let script = Compiler::new().compile(&statements).unwrap();
+            Vm::with_output(Box::new(io::sink()))
+                .interpret(script)
+                .unwrap();
+        }
+        "register" => {
+            let script = register::compiler::Compiler::new()
+                .compile(&statements)
+                .unwrap();
+            register::vm::Vm::with_output(Box::new(io::sink()))
+                .interpret(script)
+                .unwrap();
+        }
+        _ => unreachable!(),
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let representation = if cfg!(feature = "nan-boxing") {
+        "NaN-boxed"
+    } else {
+        "enum"
+    };
+    let dispatch = if cfg!(feature = "threaded-dispatch") {
+        "threaded"
+    } else {
+        "match"
+    };
+    println!(
+        "VM values: {representation}, {} bytes",
+        std::mem::size_of::<Value>()
+    );
+    println!("VM dispatch: {dispatch}");
+    println!(
+        "VM JIT: {}\n",
+        if cfg!(feature = "jit") { "on" } else { "off" }
+    );
+
+    for (name, source) in BENCHES {
+        for backend in ["interpreter", "closure", "vm", "register"] {
+            let mut times: Vec<Duration> = (0..RUNS).map(|_| run(backend, source)).collect();
+            times.sort();
+
+            println!(
+                "{name:<20} {backend:<12} min {:>8.2?}  median {:>8.2?}",
+                times[0],
+                times[RUNS / 2]
+            );
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use crate::token::{Token, TokenType};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Identifies a single variable use (`a`, `a = 1`, `this`, `super.m`) so that
+/// passes running after the parser can attach information to it, like the
+/// resolver does with scope distances.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ExprId(usize);
+
+impl ExprId {
+    /// Ids are unique per process, not per parse, so that the REPL can keep
+    /// resolving new lines against functions declared in earlier ones.
+    pub fn next() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Assign {
+        id: ExprId,
+        name: Token,
+        value: Box<Expr>,
+    },
+    Binary {
+        lhs: Box<Expr>,
+        op: Token,
+        rhs: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        args: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Token,
+    },
+    Grouping(Box<Expr>),
+    Literal {
+        value: TokenType,
+        line: usize,
+    },
+    Logical {
+        lhs: Box<Expr>,
+        op: Token,
+        rhs: Box<Expr>,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+    },
+    Super {
+        id: ExprId,
+        keyword: Token,
+        method: Token,
+    },
+    This {
+        id: ExprId,
+        keyword: Token,
+    },
+    Unary {
+        op: Token,
+        rhs: Box<Expr>,
+    },
+    Variable {
+        id: ExprId,
+        name: Token,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt {
+    Block(Vec<Stmt>),
+    Class {
+        name: Token,
+        /// Always an `Expr::Variable`, kept as an expression so it gets
+        /// resolved like any other variable use.
+        superclass: Option<Expr>,
+        methods: Vec<Rc<Function>>,
+    },
+    Expression(Expr),
+    Function(Rc<Function>),
+    If {
+        line: usize,
+        cond: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    Print {
+        line: usize,
+        value: Expr,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    Var {
+        name: Token,
+        init: Option<Expr>,
+    },
+    While {
+        line: usize,
+        cond: Expr,
+        body: Box<Stmt>,
+    },
+}
+
+/// Shared behind an `Rc` because every runtime function value created from a
+/// declaration points back to it.
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
+}
@@ -0,0 +1,435 @@
+//! The classic Lox benchmark programs, under `benches/lox`, and what the
+//! `bench` subcommand measures running them: wall time and allocations.
+//!
+//! The programs are the ones of Crafting Interpreters, unchanged, under the
+//! license in `benches/lox/LICENSE`, but for `zoo_batch.lox`, which runs for
+//! as long as its clock allows rather than a fixed amount of work. At their
+//! full size they take seconds each, so the subcommand runs them once unless
+//! given more runs. What they print, timings included, goes nowhere.
+//!
+//! Allocations are counted by [`CountingAllocator`], which only knows about
+//! them once a binary installs it as its global allocator, the CLI does, and
+//! only counts them after [`count_allocations`], so other runs don't pay for it.
+//! Measurements save to and load from JSON, to compare against a baseline.
+
+use crate::closure_compiler::ClosureCompiler;
+use crate::compiler::Compiler;
+use crate::interpreter::Interpreter;
+use crate::optimizer;
+use crate::parser::Parser;
+use crate::register;
+use crate::resolver::Resolver;
+use crate::scanner::Scanner;
+use crate::vm::Vm;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+macro_rules! programs {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_str!(concat!("../benches/lox/", $name, ".lox")))),*]
+    };
+}
+
+/// Every benchmark program, by name.
+pub const PROGRAMS: &[(&str, &str)] = programs![
+    "binary_trees",
+    "equality",
+    "fib",
+    "instantiation",
+    "invocation",
+    "method_call",
+    "properties",
+    "string_equality",
+    "trees",
+    "zoo",
+];
+
+pub const BACKENDS: &[&str] = &["interpreter", "closure", "vm", "register"];
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting how many times it's asked for memory once
+/// [`count_allocations`] was called.
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn count(&self) {
+        if COUNTING.load(Ordering::Relaxed) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Makes [`CountingAllocator`] start counting.
+pub fn count_allocations() {
+    COUNTING.store(true, Ordering::Relaxed);
+}
+
+/// How many allocations [`CountingAllocator`] has counted so far.
+pub fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// Runs `source` once on `backend`, optimized the way the CLI does unless
+/// told not to, timing and counting the allocations of what comes after the
+/// optimizer, which is the same for every backend.
+pub fn run(backend: &str, source: &str, optimize: bool) -> Result<(Duration, usize), String> {
+    let tokens = Scanner::new(source).scan_tokens().map_err(describe)?;
+    let mut statements = Parser::new(&tokens).parse().map_err(describe)?;
+    let locals = Resolver::new().resolve(&statements).map_err(describe)?;
+    if optimize {
+        statements = optimizer::optimize(&statements);
+    }
+
+    let allocations = allocations();
+    let start = Instant::now();
+    match backend {
+        "interpreter" => {
+            let mut interpreter = Interpreter::with_output(Box::new(io::sink()));
+            interpreter.resolve(locals);
+            interpreter
+                .interpret(&statements)
+                .map_err(|err| err.to_string())?;
+        }
+        "closure" => {
+            let mut compiler = ClosureCompiler::with_output(Box::new(io::sink()));
+            compiler.resolve(locals);
+            compiler
+                .interpret(&statements)
+                .map_err(|err| err.to_string())?;
+        }
+        "vm" => {
+            let script = Compiler::new().compile(&statements).map_err(describe)?;
+            Vm::with_output(Box::new(io::sink()))
+                .interpret(script)
+                .map_err(|err| err.to_string())?;
+        }
+        "register" => {
+            let script = register::compiler::Compiler::new()
+                .compile(&statements)
+                .map_err(describe)?;
+            register::vm::Vm::with_output(Box::new(io::sink()))
+                .interpret(script)
+                .map_err(|err| err.to_string())?;
+        }
+        _ => return Err(format!("unknown backend '{backend}'")),
+    }
+    Ok((start.elapsed(), self::allocations() - allocations))
+}
+
+fn describe(errors: Vec<impl ToString>) -> String {
+    let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+    errors.join("\n")
+}
+
+/// What running a program a few times on a backend took.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    pub min_ms: f64,
+    pub median_ms: f64,
+    /// The sample standard deviation, 0 for a single run.
+    pub stddev_ms: f64,
+    /// The median over runs, they only differ by what runs before.
+    pub allocations: usize,
+}
+
+impl Stats {
+    pub fn new(times: &[Duration], allocations: &[usize]) -> Self {
+        let mut times: Vec<f64> = times.iter().map(|t| t.as_secs_f64() * 1e3).collect();
+        times.sort_by(f64::total_cmp);
+        let mut allocations = allocations.to_vec();
+        allocations.sort();
+
+        let n = times.len() as f64;
+        let mean = times.iter().sum::<f64>() / n;
+        let variance = match times.len() {
+            1 => 0.0,
+            _ => times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (n - 1.0),
+        };
+        let mid = times.len() / 2;
+        let (median_ms, allocations) = match times.len() % 2 {
+            1 => (times[mid], allocations[mid]),
+            _ => (
+                (times[mid - 1] + times[mid]) / 2.0,
+                (allocations[mid - 1] + allocations[mid]) / 2,
+            ),
+        };
+        Self {
+            min_ms: times[0],
+            median_ms,
+            stddev_ms: variance.sqrt(),
+            allocations,
+        }
+    }
+}
+
+/// Runs `source` `runs` times on `backend`.
+pub fn measure(backend: &str, source: &str, runs: usize, optimize: bool) -> Result<Stats, String> {
+    let samples = (0..runs.max(1))
+        .map(|_| run(backend, source, optimize))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (times, allocations): (Vec<_>, Vec<_>) = samples.into_iter().unzip();
+    Ok(Stats::new(&times, &allocations))
+}
+
+/// The stats of a program on a backend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub program: String,
+    pub backend: String,
+    pub stats: Stats,
+}
+
+/// Writes measurements as a JSON object of programs, holding objects of
+/// backends, with times to the microsecond.
+pub fn to_json(measurements: &[Measurement]) -> String {
+    let mut json = String::from("{");
+    for (i, group) in measurements
+        .chunk_by(|a, b| a.program == b.program)
+        .enumerate()
+    {
+        if i > 0 {
+            json.push(',');
+        }
+        write!(json, "\n  {:?}: {{", group[0].program).unwrap();
+        for (i, Measurement { backend, stats, .. }) in group.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "\n    {backend:?}: {{\"min_ms\": {:.3}, \"median_ms\": {:.3}, \"stddev_ms\": {:.3}, \"allocations\": {}}}",
+                stats.min_ms, stats.median_ms, stats.stddev_ms, stats.allocations
+            )
+            .unwrap();
+        }
+        json.push_str("\n  }");
+    }
+    json.push_str("\n}\n");
+    json
+}
+
+/// Reads measurements [`to_json`] wrote.
+pub fn from_json(json: &str) -> Result<Vec<Measurement>, String> {
+    let mut reader = JsonReader { json, at: 0 };
+    let Json::Object(programs) = reader.value()? else {
+        return Err("expected an object of programs".into());
+    };
+    reader.skip_whitespace();
+    if reader.at < json.len() {
+        return Err(format!("unexpected text at byte {}", reader.at));
+    }
+
+    let mut measurements = vec![];
+    for (program, backends) in programs {
+        let Json::Object(backends) = backends else {
+            return Err(format!("expected an object of backends for '{program}'"));
+        };
+        for (backend, stats) in backends {
+            let field = |name: &str| match &stats {
+                Json::Object(fields) => fields
+                    .iter()
+                    .find_map(|(key, value)| match value {
+                        Json::Number(n) if key == name => Some(*n),
+                        _ => None,
+                    })
+                    .ok_or_else(|| format!("no '{name}' for '{program}' on '{backend}'")),
+                _ => Err(format!("expected an object for '{program}' on '{backend}'")),
+            };
+            let stats = Stats {
+                min_ms: field("min_ms")?,
+                median_ms: field("median_ms")?,
+                stddev_ms: field("stddev_ms")?,
+                allocations: field("allocations")? as usize,
+            };
+            measurements.push(Measurement {
+                program: program.clone(),
+                backend,
+                stats,
+            });
+        }
+    }
+    Ok(measurements)
+}
+
+/// The part of JSON measurements are written with, strings are only keys.
+enum Json {
+    Number(f64),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonReader<'a> {
+    json: &'a str,
+    at: usize,
+}
+
+impl JsonReader<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.json[self.at..];
+        self.at += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.json[self.at..].chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(format!("expected '{c}' at byte {}", self.at));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some('{') => self.object(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(format!("expected a value at byte {}", self.at)),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = vec![];
+        if self.peek() == Some('}') {
+            self.at += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            match self.peek() {
+                Some(',') => self.at += 1,
+                _ => break,
+            }
+        }
+        self.expect('}')?;
+        Ok(Json::Object(fields))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        let mut chars = self.json[self.at..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.at += i + 1;
+                    return Ok(string);
+                }
+                '\\' => match chars.next() {
+                    Some((_, c @ ('"' | '\\' | '/'))) => string.push(c),
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    _ => return Err(format!("unsupported escape at byte {}", self.at + i)),
+                },
+                c => string.push(c),
+            }
+        }
+        Err("unterminated string".into())
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let rest = &self.json[self.at..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+            .unwrap_or(rest.len());
+        let number = rest[..len]
+            .parse()
+            .map_err(|_| format!("bad number at byte {}", self.at))?;
+        self.at += len;
+        Ok(Json::Number(number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let ms = Duration::from_millis;
+        let stats = Stats::new(&[ms(4), ms(2), ms(6)], &[10, 12, 10]);
+        assert_eq!(stats.min_ms, 2.0);
+        assert_eq!(stats.median_ms, 4.0);
+        assert_eq!(stats.stddev_ms, 2.0);
+        assert_eq!(stats.allocations, 10);
+
+        assert_eq!(Stats::new(&[ms(3)], &[1]).stddev_ms, 0.0);
+
+        // Even run counts average the middle samples.
+        let stats = Stats::new(&[ms(8), ms(2), ms(6), ms(3)], &[10, 14, 12, 20]);
+        assert_eq!(stats.median_ms, 4.5);
+        assert_eq!(stats.allocations, 13);
+    }
+
+    #[test]
+    fn test_json() {
+        let stats = |median_ms| Stats {
+            min_ms: 1.5,
+            median_ms,
+            stddev_ms: 0.25,
+            allocations: 1234,
+        };
+        let measurements = vec![
+            Measurement {
+                program: "fib".into(),
+                backend: "vm".into(),
+                stats: stats(2.0),
+            },
+            Measurement {
+                program: "fib".into(),
+                backend: "register".into(),
+                stats: stats(3.0),
+            },
+            Measurement {
+                program: "zoo".into(),
+                backend: "vm".into(),
+                stats: stats(0.125),
+            },
+        ];
+
+        assert_eq!(from_json(&to_json(&measurements)), Ok(measurements));
+        assert_eq!(from_json("{}"), Ok(vec![]));
+        assert!(from_json("{\"fib\": {\"vm\": {\"min_ms\": 1}}}").is_err());
+        assert!(from_json("[]").is_err());
+    }
+
+    #[test]
+    fn test_programs_parse() {
+        for (name, source) in PROGRAMS {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            assert!(Resolver::new().resolve(&statements).is_ok(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// Every instruction is one opcode byte followed by its operands, also
+/// bytes. Jump offsets are the only two bytes wide (big endian) operands.
+///
+/// `CLOSURE` is the only instruction without a fixed length: it's followed
+/// by a pair of bytes for every upvalue of its function.
+///
+/// The compiler never emits the superinstructions at the end, the
+/// [`peephole`] optimizer fuses common sequences into them.
+///
+/// [`peephole`]: crate::peephole
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OpCode {
+    /// `CONSTANT index`: pushes a constant from the pool.
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    /// `GET_LOCAL slot`
+    GetLocal,
+    /// `SET_LOCAL slot`
+    SetLocal,
+    /// `GET_GLOBAL name`, where name is the index of a string constant.
+    GetGlobal,
+    /// `DEFINE_GLOBAL name`
+    DefineGlobal,
+    /// `SET_GLOBAL name`
+    SetGlobal,
+    /// `GET_UPVALUE index`
+    GetUpvalue,
+    /// `SET_UPVALUE index`
+    SetUpvalue,
+    /// `GET_PROPERTY name`
+    GetProperty,
+    /// `SET_PROPERTY name`
+    SetProperty,
+    /// `GET_SUPER name`: binds a method of the superclass on top of the
+    /// stack to the receiver below it.
+    GetSuper,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    /// `JUMP offset`: moves forward.
+    Jump,
+    /// `JUMP_IF_FALSE offset`: moves forward, leaving the condition on the
+    /// stack.
+    JumpIfFalse,
+    /// `LOOP offset`: moves backwards.
+    Loop,
+    /// `CALL argc`
+    Call,
+    /// `TAIL_CALL argc`: a call whose result the running function returns,
+    /// reusing its frame for the callee.
+    TailCall,
+    /// `INVOKE name argc`: a method call, without creating a bound method.
+    Invoke,
+    /// `SUPER_INVOKE name argc`: calls a method of the superclass on top of
+    /// the stack.
+    SuperInvoke,
+    /// `CLOSURE function (is_local index)*`: wraps a function constant in a
+    /// closure, capturing either a local of the enclosing function or one of
+    /// its upvalues for every upvalue of the function.
+    Closure,
+    /// Moves the local on top of the stack to the heap, for the closures
+    /// capturing it, and pops it.
+    CloseUpvalue,
+    Return,
+    /// `CLASS name`
+    Class,
+    Inherit,
+    /// `METHOD name`
+    Method,
+    /// `ADD_LOCAL_CONSTANT slot index`: `GET_LOCAL slot`, `CONSTANT index`
+    /// and `ADD` in one.
+    AddLocalConstant,
+    /// `SUBTRACT_LOCAL_CONSTANT slot index`
+    SubtractLocalConstant,
+    /// `LESS_LOCAL_CONSTANT slot index`
+    LessLocalConstant,
+}
+
+impl OpCode {
+    const ALL: [OpCode; 43] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::TailCall,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::AddLocalConstant,
+        OpCode::SubtractLocalConstant,
+        OpCode::LessLocalConstant,
+    ];
+
+    /// How many bytes of operands follow the opcode, not counting the
+    /// upvalues of `CLOSURE`.
+    pub fn operand_bytes(self) -> usize {
+        match self {
+            Self::Constant
+            | Self::GetLocal
+            | Self::SetLocal
+            | Self::GetGlobal
+            | Self::DefineGlobal
+            | Self::SetGlobal
+            | Self::GetUpvalue
+            | Self::SetUpvalue
+            | Self::GetProperty
+            | Self::SetProperty
+            | Self::GetSuper
+            | Self::Call
+            | Self::TailCall
+            | Self::Closure
+            | Self::Class
+            | Self::Method => 1,
+            Self::Jump
+            | Self::JumpIfFalse
+            | Self::Loop
+            | Self::Invoke
+            | Self::SuperInvoke
+            | Self::AddLocalConstant
+            | Self::SubtractLocalConstant
+            | Self::LessLocalConstant => 2,
+            Self::Nil
+            | Self::True
+            | Self::False
+            | Self::Pop
+            | Self::Equal
+            | Self::Greater
+            | Self::GreaterEqual
+            | Self::Less
+            | Self::LessEqual
+            | Self::Add
+            | Self::Subtract
+            | Self::Multiply
+            | Self::Divide
+            | Self::Not
+            | Self::Negate
+            | Self::Print
+            | Self::CloseUpvalue
+            | Self::Return
+            | Self::Inherit => 0,
+        }
+    }
+}
+
+impl fmt::Display for OpCode {
+    /// The name in `OP_SCREAMING_CASE`, as listings show it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let mut name = String::from("OP");
+        for c in format!("{self:?}").chars() {
+            if c.is_ascii_uppercase() {
+                name.push('_');
+            }
+            name.push(c.to_ascii_uppercase());
+        }
+        f.pad(&name)
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Self::ALL.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+/// Values known at compile time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{s}"),
+            Self::Function(function) => write!(f, "{function}"),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    /// Source line of every byte in `code`.
+    pub lines: Vec<usize>,
+}
+
+impl Chunk {
+    pub fn write(&mut self, byte: u8, line: usize) {
+        self.code.push(byte);
+        self.lines.push(line);
+    }
+
+    pub fn write_op(&mut self, op: OpCode, line: usize) {
+        self.write(op as u8, line);
+    }
+
+    /// Reuses the slot of an equal constant, if there's one.
+    pub fn add_constant(&mut self, constant: Constant) -> usize {
+        let existing = self.constants.iter().position(|c| match (c, &constant) {
+            (Constant::Number(l), Constant::Number(r)) => l.to_bits() == r.to_bits(),
+            (Constant::String(l), Constant::String(r)) => l == r,
+            _ => false,
+        });
+
+        existing.unwrap_or_else(|| {
+            self.constants.push(constant);
+            self.constants.len() - 1
+        })
+    }
+}
+
+/// A compiled function, the top level code of a script being one without a
+/// name.
+#[derive(Debug, Default, PartialEq)]
+pub struct Function {
+    pub name: Option<Rc<str>>,
+    pub arity: usize,
+    /// How many variables of enclosing functions it captures.
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, Constant, OpCode};
+
+    #[test]
+    fn test_opcode_round_trip() {
+        for (byte, op) in OpCode::ALL.iter().enumerate() {
+            assert_eq!(*op as u8, byte as u8);
+            assert_eq!(OpCode::try_from(byte as u8), Ok(*op));
+        }
+        assert_eq!(
+            OpCode::try_from(OpCode::ALL.len() as u8),
+            Err(OpCode::ALL.len() as u8)
+        );
+    }
+
+    #[test]
+    fn test_constants_are_deduplicated() {
+        let mut chunk = Chunk::default();
+        assert_eq!(chunk.add_constant(Constant::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Constant::String("a".into())), 1);
+        assert_eq!(chunk.add_constant(Constant::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Constant::String("a".into())), 1);
+        assert_eq!(chunk.add_constant(Constant::Number(-0.0)), 2);
+    }
+}
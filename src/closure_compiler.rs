@@ -0,0 +1,726 @@
+//! Execution backend that compiles every AST node once into a nested Rust
+//! closure, so running the program no longer matches on node kinds.
+//!
+//! Semantics are the ones of the tree-walking interpreter, which in turn
+//! extend the `Binary`/`Unary`/`Literal`/`Grouping` evaluation of `expr.rs`.
+//! Values, and the collector freeing their cycles, are shared with it in
+//! [`runtime`].
+
+use crate::ast::{self, Expr, Stmt};
+use crate::interpreter::RuntimeError;
+use crate::resolver::{Locals, Slot};
+use crate::runtime::{self, Code, LoxInstance};
+use crate::token::{Token, TokenType};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+type EvalFn = Box<dyn Fn(&mut Frame) -> Result<Value, RuntimeError>>;
+type ExecFn = Box<dyn Fn(&mut Frame) -> Result<(), Unwind>>;
+
+pub type Value = runtime::Value<Function>;
+type Closure = runtime::LoxFunction<Function>;
+type Class = runtime::LoxClass<Function>;
+type Environment = runtime::Environment<Function>;
+type CycleCollector = runtime::CycleCollector<Function>;
+type Unwind = runtime::Unwind<Function>;
+
+/// A function declaration, compiled once no matter how many closures are
+/// created out of it.
+pub struct Function {
+    name: String,
+    arity: usize,
+    body: ExecFn,
+}
+
+impl Code for Function {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn arity(&self) -> usize {
+        self.arity
+    }
+}
+
+/// Runtime state the compiled closures operate on.
+pub struct Frame {
+    /// Globals are numbered while compiling, `None` marks the ones declared
+    /// but not defined yet.
+    globals: Vec<Option<Value>>,
+    /// `None` while executing top level code.
+    environment: Option<Rc<RefCell<Environment>>>,
+    collector: CycleCollector,
+    /// How many calls are running.
+    depth: usize,
+    out: Box<dyn Write>,
+}
+
+impl Frame {
+    /// Looks for garbage if it's time to, between statements, where nothing
+    /// is borrowed.
+    fn collect_garbage(&mut self) {
+        if self.collector.should_collect() {
+            self.collector.collect();
+        }
+    }
+
+    /// Runs statements one after the other.
+    fn execute(&mut self, statements: &[ExecFn]) -> Result<(), Unwind> {
+        statements.iter().try_for_each(|statement| {
+            self.collect_garbage();
+            statement(self)
+        })
+    }
+
+    /// Opens a new scope as the current one.
+    fn push_scope(&mut self) -> Option<Rc<RefCell<Environment>>> {
+        let environment = Environment::new(self.environment.clone());
+        let environment = self.collector.environment(environment);
+        self.environment.replace(environment)
+    }
+
+    fn environment(&self) -> &Rc<RefCell<Environment>> {
+        self.environment
+            .as_ref()
+            .expect("resolver bound a local variable at the top level")
+    }
+
+    fn define_local(&self, value: Value) {
+        self.environment().borrow_mut().values.push(value);
+    }
+}
+
+pub struct ClosureCompiler {
+    locals: Locals,
+    global_slots: HashMap<String, usize>,
+    /// How many local scopes enclose the code being compiled, declarations
+    /// outside of all of them are globals.
+    scope_depth: usize,
+    frame: Frame,
+}
+
+impl Default for ClosureCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClosureCompiler {
+    pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Where `print` statements write to.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        let mut compiler = Self {
+            locals: Locals::default(),
+            global_slots: HashMap::new(),
+            scope_depth: 0,
+            frame: Frame {
+                globals: vec![],
+                environment: None,
+                collector: CycleCollector::default(),
+                depth: 0,
+                out,
+            },
+        };
+
+        for native in runtime::natives() {
+            let slot = compiler.global_slot(native.name);
+            compiler.frame.globals[slot] = Some(Value::Native(native));
+        }
+
+        compiler
+    }
+
+    /// Records the slots computed by the resolver, variables without one are
+    /// globals.
+    pub fn resolve(&mut self, locals: Locals) {
+        self.locals.extend(locals);
+    }
+
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        let code: Vec<ExecFn> = statements
+            .iter()
+            .map(|statement| self.compile_stmt(statement))
+            .collect();
+
+        match self.frame.execute(&code) {
+            Ok(()) => Ok(()),
+            Err(Unwind::Error(err)) => Err(err),
+            Err(Unwind::Return(_)) => unreachable!("resolver rejects top level returns"),
+        }
+    }
+
+    fn global_slot(&mut self, name: &str) -> usize {
+        if let Some(&slot) = self.global_slots.get(name) {
+            return slot;
+        }
+
+        let slot = self.frame.globals.len();
+        self.frame.globals.push(None);
+        self.global_slots.insert(name.to_string(), slot);
+        slot
+    }
+
+    /// Compiles the definition of a variable, leaving its value to `value`.
+    fn compile_define(&mut self, name: &Token, value: EvalFn) -> ExecFn {
+        if self.scope_depth > 0 {
+            return Box::new(move |frame| {
+                let value = value(frame)?;
+                frame.define_local(value);
+                Ok(())
+            });
+        }
+
+        let slot = self.global_slot(&name.lexeme);
+        Box::new(move |frame| {
+            frame.globals[slot] = Some(value(frame)?);
+            Ok(())
+        })
+    }
+
+    fn compile_block(&mut self, statements: &[Stmt]) -> Vec<ExecFn> {
+        self.scope_depth += 1;
+        let code = statements
+            .iter()
+            .map(|statement| self.compile_stmt(statement))
+            .collect();
+        self.scope_depth -= 1;
+        code
+    }
+
+    fn compile_stmt(&mut self, statement: &Stmt) -> ExecFn {
+        match statement {
+            Stmt::Block(statements) => {
+                let code = self.compile_block(statements);
+                Box::new(move |frame| {
+                    let previous = frame.push_scope();
+                    let result = frame.execute(&code);
+                    frame.environment = previous;
+                    result
+                })
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.compile_class(name, superclass.as_ref(), methods),
+            Stmt::Expression(expr) => {
+                let expr = self.compile_expr(expr);
+                Box::new(move |frame| {
+                    expr(frame)?;
+                    Ok(())
+                })
+            }
+            Stmt::Function(declaration) => {
+                let function = self.compile_function(declaration);
+                self.compile_define(
+                    &declaration.name,
+                    Box::new(move |frame| {
+                        let closure = Closure {
+                            code: function.clone(),
+                            closure: frame.environment.clone(),
+                            is_initializer: false,
+                        };
+                        Ok(Value::Function(frame.collector.function(closure)))
+                    }),
+                )
+            }
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let cond = self.compile_expr(cond);
+                let then_branch = self.compile_stmt(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        let else_branch = self.compile_stmt(else_branch);
+                        Box::new(move |frame| {
+                            if cond(frame)?.is_truthy() {
+                                then_branch(frame)
+                            } else {
+                                else_branch(frame)
+                            }
+                        })
+                    }
+                    None => Box::new(move |frame| {
+                        if cond(frame)?.is_truthy() {
+                            then_branch(frame)?;
+                        }
+                        Ok(())
+                    }),
+                }
+            }
+            Stmt::Print { value, .. } => {
+                let expr = self.compile_expr(value);
+                Box::new(move |frame| {
+                    let value = expr(frame)?;
+                    writeln!(frame.out, "{value}").expect("failed to write to output");
+                    Ok(())
+                })
+            }
+            Stmt::Return { value, .. } => match value {
+                Some(value) => {
+                    let value = self.compile_expr(value);
+                    Box::new(move |frame| Err(Unwind::Return(value(frame)?)))
+                }
+                None => Box::new(|_| Err(Unwind::Return(Value::Nil))),
+            },
+            Stmt::Var { name, init } => {
+                let init = match init {
+                    Some(init) => self.compile_expr(init),
+                    None => Box::new(|_: &mut Frame| Ok(Value::Nil)),
+                };
+                self.compile_define(name, init)
+            }
+            Stmt::While { cond, body, .. } => {
+                let cond = self.compile_expr(cond);
+                let body = self.compile_stmt(body);
+                Box::new(move |frame| {
+                    while cond(frame)?.is_truthy() {
+                        frame.collect_garbage();
+                        body(frame)?;
+                    }
+                    Ok(())
+                })
+            }
+        }
+    }
+
+    fn compile_function(&mut self, declaration: &ast::Function) -> Rc<Function> {
+        // The parameters and the body share a single scope.
+        let body = self.compile_block(&declaration.body);
+
+        Rc::new(Function {
+            name: declaration.name.lexeme.clone(),
+            arity: declaration.params.len(),
+            body: Box::new(move |frame| frame.execute(&body)),
+        })
+    }
+
+    fn compile_class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &[Rc<ast::Function>],
+    ) -> ExecFn {
+        let superclass = superclass.map(|expr| {
+            let token = match expr {
+                Expr::Variable { name, .. } => name.clone(),
+                _ => name.clone(),
+            };
+            (self.compile_expr(expr), token)
+        });
+
+        // Methods close over a scope holding "super" when there's a superclass,
+        // and get bound to a scope holding "this" later on.
+        let scopes = if superclass.is_some() { 2 } else { 1 };
+        self.scope_depth += scopes;
+        let methods: Vec<(String, Rc<Function>)> = methods
+            .iter()
+            .map(|method| (method.name.lexeme.clone(), self.compile_function(method)))
+            .collect();
+        self.scope_depth -= scopes;
+
+        let class_name = name.lexeme.clone();
+        let global = (self.scope_depth == 0).then(|| self.global_slot(&name.lexeme));
+
+        Box::new(move |frame| {
+            let superclass = match &superclass {
+                Some((expr, token)) => match expr(frame)? {
+                    Value::Class(class) => Some(class),
+                    _ => return Err(RuntimeError::new(token, "Superclass must be a class.").into()),
+                },
+                None => None,
+            };
+
+            let mut environment = frame.environment.clone();
+            if let Some(superclass) = &superclass {
+                let mut scope = Environment::new(environment);
+                scope.define(Value::Class(superclass.clone()));
+                environment = Some(frame.collector.environment(scope));
+            }
+
+            let methods = methods
+                .iter()
+                .map(|(name, function)| {
+                    let closure = Closure {
+                        code: function.clone(),
+                        closure: environment.clone(),
+                        is_initializer: name == "init",
+                    };
+                    (name.clone(), frame.collector.function(closure))
+                })
+                .collect();
+
+            let class = Value::Class(frame.collector.class(Class {
+                name: class_name.clone(),
+                superclass,
+                methods,
+            }));
+
+            match global {
+                Some(slot) => frame.globals[slot] = Some(class),
+                None => frame.define_local(class),
+            }
+
+            Ok(())
+        })
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> EvalFn {
+        match expr {
+            Expr::Assign { id, name, value } => {
+                let value = self.compile_expr(value);
+                match self.locals.get(id) {
+                    Some(&slot) => Box::new(move |frame| {
+                        let value = value(frame)?;
+                        Environment::assign_at(frame.environment(), slot, value.clone());
+                        Ok(value)
+                    }),
+                    None => {
+                        let slot = self.global_slot(&name.lexeme);
+                        let name = name.clone();
+                        Box::new(move |frame| {
+                            let value = value(frame)?;
+                            match &mut frame.globals[slot] {
+                                Some(global) => *global = value.clone(),
+                                None => return Err(runtime::undefined_variable(&name)),
+                            }
+                            Ok(value)
+                        })
+                    }
+                }
+            }
+            Expr::Binary { lhs, op, rhs } => self.compile_binary(lhs, op, rhs),
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                let callee = self.compile_expr(callee);
+                let args: Vec<EvalFn> = args.iter().map(|arg| self.compile_expr(arg)).collect();
+                let paren = paren.clone();
+                Box::new(move |frame| {
+                    let callee = callee(frame)?;
+                    let args = args
+                        .iter()
+                        .map(|arg| arg(frame))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    call(frame, callee, &paren, args)
+                })
+            }
+            Expr::Get { object, name } => {
+                let object = self.compile_expr(object);
+                let name = name.clone();
+                Box::new(move |frame| match object(frame)? {
+                    Value::Instance(instance) => {
+                        runtime::get_property(&instance, &name, &mut frame.collector)
+                    }
+                    _ => Err(RuntimeError::new(&name, "Only instances have properties.")),
+                })
+            }
+            // Groupings only matter to the parser.
+            Expr::Grouping(expr) => self.compile_expr(expr),
+            Expr::Literal { value, .. } => {
+                let value = Value::from(value);
+                Box::new(move |_| Ok(value.clone()))
+            }
+            Expr::Logical { lhs, op, rhs } => {
+                let lhs = self.compile_expr(lhs);
+                let rhs = self.compile_expr(rhs);
+                if op.ty == TokenType::Or {
+                    Box::new(move |frame| {
+                        let left = lhs(frame)?;
+                        if left.is_truthy() {
+                            Ok(left)
+                        } else {
+                            rhs(frame)
+                        }
+                    })
+                } else {
+                    Box::new(move |frame| {
+                        let left = lhs(frame)?;
+                        if !left.is_truthy() {
+                            Ok(left)
+                        } else {
+                            rhs(frame)
+                        }
+                    })
+                }
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                let object = self.compile_expr(object);
+                let value = self.compile_expr(value);
+                let name = name.clone();
+                Box::new(move |frame| match (object(frame)?, value(frame)?) {
+                    (Value::Instance(instance), value) => {
+                        instance
+                            .borrow_mut()
+                            .fields
+                            .insert(name.lexeme.clone(), value.clone());
+                        Ok(value)
+                    }
+                    _ => Err(RuntimeError::new(&name, "Only instances have fields.")),
+                })
+            }
+            Expr::Super { id, method, .. } => {
+                let slot = self.locals[id];
+                // "this" is always bound right inside the scope holding "super".
+                let this = Slot {
+                    depth: slot.depth - 1,
+                    index: 0,
+                };
+                let method = method.clone();
+                Box::new(move |frame| {
+                    let superclass = match Environment::get_at(frame.environment(), slot) {
+                        Value::Class(class) => class,
+                        _ => unreachable!("'super' is always bound to a class"),
+                    };
+                    let object = match Environment::get_at(frame.environment(), this) {
+                        Value::Instance(instance) => instance,
+                        _ => unreachable!("'this' is always bound to an instance"),
+                    };
+
+                    match superclass.find_method(&method.lexeme) {
+                        Some(function) => {
+                            Ok(Value::Function(function.bind(object, &mut frame.collector)))
+                        }
+                        None => Err(runtime::undefined_property(&method)),
+                    }
+                })
+            }
+            Expr::This { id, keyword } => self.compile_variable(*id, keyword),
+            Expr::Unary { op, rhs } => {
+                let rhs = self.compile_expr(rhs);
+                match op.ty {
+                    TokenType::Bang => {
+                        Box::new(move |frame| Ok(Value::Bool(!rhs(frame)?.is_truthy())))
+                    }
+                    TokenType::Minus => {
+                        let op = op.clone();
+                        Box::new(move |frame| match rhs(frame)? {
+                            Value::Number(n) => Ok(Value::Number(-n)),
+                            _ => Err(RuntimeError::new(&op, "Operand must be a number.")),
+                        })
+                    }
+                    _ => unreachable!("not an unary operator: {op:?}"),
+                }
+            }
+            Expr::Variable { id, name } => self.compile_variable(*id, name),
+        }
+    }
+
+    fn compile_variable(&mut self, id: ast::ExprId, name: &Token) -> EvalFn {
+        match self.locals.get(&id) {
+            Some(&slot) if slot.depth == 0 => {
+                Box::new(move |frame| Ok(frame.environment().borrow().values[slot.index].clone()))
+            }
+            Some(&slot) => {
+                Box::new(move |frame| Ok(Environment::get_at(frame.environment(), slot)))
+            }
+            None => {
+                let slot = self.global_slot(&name.lexeme);
+                let name = name.clone();
+                Box::new(move |frame| match &frame.globals[slot] {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(runtime::undefined_variable(&name)),
+                })
+            }
+        }
+    }
+
+    /// Every operator gets its own closure, so the operator itself is only
+    /// looked at once.
+    fn compile_binary(&mut self, lhs: &Expr, op: &Token, rhs: &Expr) -> EvalFn {
+        let lhs = self.compile_expr(lhs);
+        let rhs = self.compile_expr(rhs);
+        let op = op.clone();
+
+        macro_rules! numeric {
+            ($result:expr) => {
+                Box::new(move |frame| match (lhs(frame)?, rhs(frame)?) {
+                    (Value::Number(l), Value::Number(r)) => Ok($result(l, r)),
+                    _ => Err(RuntimeError::new(&op, "Operands must be numbers.")),
+                })
+            };
+        }
+
+        match op.ty {
+            // comparisons
+            TokenType::Greater => numeric!(|l, r| Value::Bool(l > r)),
+            TokenType::GreaterEqual => numeric!(|l, r| Value::Bool(l >= r)),
+            TokenType::Less => numeric!(|l, r| Value::Bool(l < r)),
+            TokenType::LessEqual => numeric!(|l, r| Value::Bool(l <= r)),
+            TokenType::BangEqual => Box::new(move |frame| {
+                let left = lhs(frame)?;
+                Ok(Value::Bool(!left.is_equal(&rhs(frame)?)))
+            }),
+            TokenType::EqualEqual => Box::new(move |frame| {
+                let left = lhs(frame)?;
+                Ok(Value::Bool(left.is_equal(&rhs(frame)?)))
+            }),
+
+            // arithmetic
+            TokenType::Minus => numeric!(|l, r| Value::Number(l - r)),
+            TokenType::Slash => numeric!(|l, r| Value::Number(l / r)),
+            TokenType::Star => numeric!(|l, r| Value::Number(l * r)),
+            // and concatenation
+            TokenType::Plus => Box::new(move |frame| match (lhs(frame)?, rhs(frame)?) {
+                (Value::Number(l), Value::Number(r)) => Ok(Value::Number(l + r)),
+                (Value::String(s), Value::String(u)) => Ok(Value::String(format!("{s}{u}").into())),
+                _ => Err(RuntimeError::new(
+                    &op,
+                    "Operands must be two numbers or two strings.",
+                )),
+            }),
+            _ => unreachable!("not a binary operator: {op:?}"),
+        }
+    }
+}
+
+fn call(
+    frame: &mut Frame,
+    callee: Value,
+    paren: &Token,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    runtime::check_call(&callee, paren, args.len())?;
+
+    match callee {
+        Value::Function(closure) => call_closure(frame, &closure, paren, args),
+        Value::Native(native) => Ok((native.function)(&args)),
+        Value::Class(class) => {
+            let instance = frame.collector.instance(LoxInstance {
+                class: class.clone(),
+                fields: HashMap::new(),
+            });
+
+            if let Some(init) = class.find_method("init") {
+                let init = init.bind(instance.clone(), &mut frame.collector);
+                call_closure(frame, &init, paren, args)?;
+            }
+
+            Ok(Value::Instance(instance))
+        }
+        _ => unreachable!("check_call rejects other callees"),
+    }
+}
+
+fn call_closure(
+    frame: &mut Frame,
+    closure: &Closure,
+    paren: &Token,
+    args: Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    runtime::check_depth(frame.depth, paren)?;
+
+    let environment = Environment {
+        values: args,
+        enclosing: closure.closure.clone(),
+    };
+    let environment = frame.collector.environment(environment);
+    let previous = frame.environment.replace(environment);
+
+    frame.depth += 1;
+    let result = (closure.code.body)(frame);
+    frame.depth -= 1;
+
+    frame.environment = previous;
+
+    match result {
+        Ok(()) => Ok(closure.result(Value::Nil)),
+        Err(Unwind::Return(value)) => Ok(closure.result(value)),
+        Err(Unwind::Error(err)) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::closure_compiler::{ClosureCompiler, Value};
+    use crate::parser::Parser;
+    use crate::programs::{self, Output};
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    fn run(compiler: &mut ClosureCompiler, source: &str) {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let locals = Resolver::new().resolve(&statements).unwrap();
+        compiler.resolve(locals);
+        compiler.interpret(&statements).unwrap();
+    }
+
+    #[test]
+    fn test_programs() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let locals = Resolver::new().resolve(&statements).unwrap();
+
+            let mut compiler = ClosureCompiler::with_output(Box::new(output.clone()));
+            compiler.resolve(locals);
+            if let Err(err) = compiler.interpret(&statements) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let source = "
+        fun count(n) { if (n == 0) return \"done\"; return count(n - 1); }
+        print count(1022);
+        print count(1023);
+        ";
+        let (output, err) = programs::with_stack(|| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let locals = Resolver::new().resolve(&statements).unwrap();
+
+            let output = Output::default();
+            let mut compiler = ClosureCompiler::with_output(Box::new(output.clone()));
+            compiler.resolve(locals);
+            let err = compiler.interpret(&statements).unwrap_err();
+            (output.contents(), err.to_string())
+        });
+        assert_eq!(output, "done\n");
+        assert_eq!(err, "Stack overflow.\n[line 2]");
+    }
+
+    #[test]
+    fn test_collects_cycles() {
+        let mut compiler = ClosureCompiler::with_output(Box::new(std::io::sink()));
+        run(
+            &mut compiler,
+            "
+            class Node {
+              init() { this.self = this; }
+            }
+            var node = Node();
+            ",
+        );
+        let slot = compiler.global_slots["node"];
+        let Some(Value::Instance(node)) = &compiler.frame.globals[slot] else {
+            panic!("node is an instance");
+        };
+        let node = Rc::downgrade(node);
+
+        // The instance references itself, so dropping the global isn't enough.
+        run(&mut compiler, "node = nil;");
+        assert!(node.upgrade().is_some());
+        compiler.frame.collector.collect();
+        assert!(node.upgrade().is_none());
+    }
+}
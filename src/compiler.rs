@@ -0,0 +1,806 @@
+use crate::ast::{self, Expr, Stmt};
+use crate::chunk::{Chunk, Constant, Function, OpCode};
+use crate::peephole;
+use crate::token::{Token, TokenType};
+use std::fmt;
+use std::rc::Rc;
+
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+#[derive(Debug, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    /// The token the error is at, like ` at 'x'`, empty for the errors of
+    /// code too large to jump over.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "[line {}] Error{}: {}",
+            self.line, self.location, self.message
+        )
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    /// Whether a closure captures it, so it has to be moved off the stack
+    /// when its scope ends.
+    is_captured: bool,
+}
+
+/// Where a closure gets a captured variable from when it's created.
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    /// Slot of the local of the enclosing function if `is_local`, otherwise
+    /// index of one of its upvalues.
+    index: u8,
+    is_local: bool,
+}
+
+/// Compilation state of one function, they nest like the declarations do.
+struct FunctionState {
+    function: Function,
+    ty: FunctionType,
+    /// Mirrors what the stack of the function will look like at runtime, the
+    /// position of a local being its slot.
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(ty: FunctionType, name: Option<Rc<str>>) -> Self {
+        // Slot zero holds the function being called, or the receiver in
+        // methods, where it can be reached as `this`.
+        let reserved = match ty {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Script | FunctionType::Function => "",
+        };
+
+        Self {
+            function: Function {
+                name,
+                ..Function::default()
+            },
+            ty,
+            locals: vec![Local {
+                name: reserved.into(),
+                depth: 0,
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Single pass from the resolved AST to bytecode for the [`Vm`].
+///
+/// The resolver is expected to have accepted the program already, so the
+/// only errors left are about limits of the bytecode format.
+///
+/// [`Vm`]: crate::vm::Vm
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    /// Line of the last token seen, for the instructions that have none.
+    line: usize,
+    errors: Vec<CompileError>,
+    optimize: bool,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            line: 1,
+            errors: vec![],
+            optimize: true,
+        }
+    }
+
+    /// Whether to run the [`peephole`] optimizer over the compiled code, which
+    /// it does by default. Listings of unoptimized code map more directly to
+    /// the source.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn compile(mut self, statements: &[Stmt]) -> Result<Rc<Function>, Vec<CompileError>> {
+        for statement in statements {
+            self.statement(statement);
+        }
+        let (function, _) = self.end_function();
+
+        if !self.errors.is_empty() {
+            Err(self.errors)
+        } else if self.optimize {
+            Ok(Rc::new(peephole::optimize(&function)))
+        } else {
+            Ok(Rc::new(function))
+        }
+    }
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("no function being compiled")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state().function.chunk
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.end_scope();
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            Stmt::Expression(expr) => {
+                self.expression(expr);
+                self.emit_op(OpCode::Pop);
+            }
+            Stmt::Function(function) => {
+                self.line = function.name.line;
+                // Initialized right away, so the function can call itself.
+                self.declare_variable(&function.name);
+                self.mark_initialized();
+                self.function(function, FunctionType::Function);
+                self.define_variable(&function.name);
+            }
+            Stmt::If {
+                line,
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(cond);
+                self.line = *line;
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(then_branch);
+
+                self.line = *line;
+                let else_jump = self.emit_jump(OpCode::Jump);
+                self.patch_jump(then_jump);
+                self.emit_op(OpCode::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::Print { line, value } => {
+                self.expression(value);
+                self.line = *line;
+                self.emit_op(OpCode::Print);
+            }
+            Stmt::Return { keyword, value } => {
+                self.line = keyword.line;
+                match value {
+                    Some(Expr::Call {
+                        callee,
+                        paren,
+                        args,
+                    }) if !matches!(callee.as_ref(), Expr::Get { .. } | Expr::Super { .. }) => {
+                        self.expression(callee);
+                        for arg in args {
+                            self.expression(arg);
+                        }
+
+                        self.line = paren.line;
+                        self.emit_bytes(OpCode::TailCall, args.len() as u8);
+                    }
+                    Some(value) => {
+                        self.expression(value);
+                        self.emit_op(OpCode::Return);
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Stmt::Var { name, init } => {
+                self.line = name.line;
+                self.declare_variable(name);
+                match init {
+                    Some(init) => self.expression(init),
+                    None => self.emit_op(OpCode::Nil),
+                }
+                self.define_variable(name);
+            }
+            Stmt::While { line, cond, body } => {
+                let loop_start = self.chunk().code.len();
+                self.expression(cond);
+                self.line = *line;
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_op(OpCode::Pop);
+                self.statement(body);
+                self.line = *line;
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    fn class(&mut self, name: &Token, superclass: Option<&Expr>, methods: &[Rc<ast::Function>]) {
+        self.line = name.line;
+        let name_constant = self.identifier_constant(name);
+        self.declare_variable(name);
+        self.emit_bytes(OpCode::Class, name_constant);
+        self.define_variable(name);
+
+        if let Some(superclass) = superclass {
+            self.expression(superclass);
+            // The superclass stays on the stack as a local of its own, for
+            // the methods to capture as `super`.
+            self.begin_scope();
+            self.add_local(&Token::new(TokenType::Super, "super".into(), name.line));
+            self.mark_initialized();
+
+            self.named_variable(name, false);
+            self.emit_op(OpCode::Inherit);
+        }
+
+        // The class stays on the stack while its methods get attached.
+        self.named_variable(name, false);
+        for method in methods {
+            let ty = if method.name.lexeme == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.function(method, ty);
+
+            let name_constant = self.identifier_constant(&method.name);
+            self.emit_bytes(OpCode::Method, name_constant);
+        }
+        self.emit_op(OpCode::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    /// Compiles the function on its own chunk and leaves a closure over it on
+    /// the stack.
+    fn function(&mut self, function: &ast::Function, ty: FunctionType) {
+        let name = Some(function.name.lexeme.as_str().into());
+        self.states.push(FunctionState::new(ty, name));
+        self.begin_scope();
+
+        self.state().function.arity = function.params.len();
+        for param in &function.params {
+            self.declare_variable(param);
+            self.mark_initialized();
+        }
+        for statement in &function.body {
+            self.statement(statement);
+        }
+
+        // No need to end the scope, the return discards the whole frame.
+        let (compiled, upvalues) = self.end_function();
+        self.line = function.name.line;
+        let compiled = Constant::Function(Rc::new(compiled));
+        let index = self.make_constant(compiled, &function.name.lexeme);
+        self.emit_bytes(OpCode::Closure, index);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_byte(upvalue.index);
+        }
+    }
+
+    fn end_function(&mut self) -> (Function, Vec<Upvalue>) {
+        self.emit_return();
+        let mut state = self.states.pop().expect("no function being compiled");
+        state.function.upvalue_count = state.upvalues.len();
+        (state.function, state.upvalues)
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                self.expression(value);
+                self.named_variable(name, true);
+            }
+            Expr::Binary { lhs, op, rhs } => {
+                self.expression(lhs);
+                self.expression(rhs);
+
+                self.line = op.line;
+                match op.ty {
+                    TokenType::BangEqual => {
+                        self.emit_op(OpCode::Equal);
+                        self.emit_op(OpCode::Not);
+                    }
+                    TokenType::EqualEqual => self.emit_op(OpCode::Equal),
+                    TokenType::Greater => self.emit_op(OpCode::Greater),
+                    TokenType::GreaterEqual => self.emit_op(OpCode::GreaterEqual),
+                    TokenType::Less => self.emit_op(OpCode::Less),
+                    TokenType::LessEqual => self.emit_op(OpCode::LessEqual),
+                    TokenType::Plus => self.emit_op(OpCode::Add),
+                    TokenType::Minus => self.emit_op(OpCode::Subtract),
+                    TokenType::Star => self.emit_op(OpCode::Multiply),
+                    TokenType::Slash => self.emit_op(OpCode::Divide),
+                    _ => unreachable!("not a binary operator: {:?}", op.ty),
+                }
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                if let Expr::Get { object, name } = callee.as_ref() {
+                    self.expression(object);
+                    for arg in args {
+                        self.expression(arg);
+                    }
+
+                    self.line = paren.line;
+                    let name_constant = self.identifier_constant(name);
+                    self.emit_bytes(OpCode::Invoke, name_constant);
+                    self.emit_byte(args.len() as u8);
+                } else if let Expr::Super {
+                    keyword, method, ..
+                } = callee.as_ref()
+                {
+                    self.named_variable(&this(keyword), false);
+                    for arg in args {
+                        self.expression(arg);
+                    }
+                    self.named_variable(keyword, false);
+
+                    self.line = paren.line;
+                    let name_constant = self.identifier_constant(method);
+                    self.emit_bytes(OpCode::SuperInvoke, name_constant);
+                    self.emit_byte(args.len() as u8);
+                } else {
+                    self.expression(callee);
+                    for arg in args {
+                        self.expression(arg);
+                    }
+
+                    self.line = paren.line;
+                    self.emit_bytes(OpCode::Call, args.len() as u8);
+                }
+            }
+            Expr::Get { object, name } => {
+                self.expression(object);
+                self.line = name.line;
+                let name_constant = self.identifier_constant(name);
+                self.emit_bytes(OpCode::GetProperty, name_constant);
+            }
+            Expr::Grouping(expr) => self.expression(expr),
+            Expr::Literal { value, line } => {
+                self.line = *line;
+                match value {
+                    // Spelled the way they're written, as only their value
+                    // is left.
+                    TokenType::Number(n) => {
+                        self.emit_constant(Constant::Number(*n), &n.to_string())
+                    }
+                    TokenType::String(s) => {
+                        self.emit_constant(Constant::String(s.as_str().into()), &format!("\"{s}\""))
+                    }
+                    TokenType::Bool(true) => self.emit_op(OpCode::True),
+                    TokenType::Bool(false) => self.emit_op(OpCode::False),
+                    TokenType::Nil => self.emit_op(OpCode::Nil),
+                    _ => unreachable!("not a literal: {value:?}"),
+                }
+            }
+            Expr::Logical { lhs, op, rhs } => {
+                self.expression(lhs);
+                self.line = op.line;
+                if op.ty == TokenType::And {
+                    let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit_op(OpCode::Pop);
+                    self.expression(rhs);
+                    self.patch_jump(end_jump);
+                } else {
+                    let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    let end_jump = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(else_jump);
+                    self.emit_op(OpCode::Pop);
+                    self.expression(rhs);
+                    self.patch_jump(end_jump);
+                }
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expression(object);
+                self.expression(value);
+                self.line = name.line;
+                let name_constant = self.identifier_constant(name);
+                self.emit_bytes(OpCode::SetProperty, name_constant);
+            }
+            Expr::Super {
+                keyword, method, ..
+            } => {
+                self.named_variable(&this(keyword), false);
+                self.named_variable(keyword, false);
+                self.line = method.line;
+                let name_constant = self.identifier_constant(method);
+                self.emit_bytes(OpCode::GetSuper, name_constant);
+            }
+            Expr::This { keyword, .. } => self.named_variable(keyword, false),
+            Expr::Unary { op, rhs } => {
+                self.expression(rhs);
+                self.line = op.line;
+                match op.ty {
+                    TokenType::Bang => self.emit_op(OpCode::Not),
+                    TokenType::Minus => self.emit_op(OpCode::Negate),
+                    _ => unreachable!("not a unary operator: {:?}", op.ty),
+                }
+            }
+            Expr::Variable { name, .. } => self.named_variable(name, false),
+        }
+    }
+
+    fn named_variable(&mut self, name: &Token, assign: bool) {
+        self.line = name.line;
+
+        let current = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            let op = if assign {
+                OpCode::SetLocal
+            } else {
+                OpCode::GetLocal
+            };
+            self.emit_bytes(op, slot);
+            return;
+        }
+
+        if let Some(index) = self.resolve_upvalue(current, name) {
+            let op = if assign {
+                OpCode::SetUpvalue
+            } else {
+                OpCode::GetUpvalue
+            };
+            self.emit_bytes(op, index);
+            return;
+        }
+
+        let op = if assign {
+            OpCode::SetGlobal
+        } else {
+            OpCode::GetGlobal
+        };
+        let name_constant = self.identifier_constant(name);
+        self.emit_bytes(op, name_constant);
+    }
+
+    fn resolve_local(&self, state: usize, name: &Token) -> Option<u8> {
+        self.states[state]
+            .locals
+            .iter()
+            .rposition(|local| local.name == name.lexeme)
+            .map(|slot| slot as u8)
+    }
+
+    /// Finds a variable of the functions enclosing `state`, adding it to the
+    /// upvalues of every function in between.
+    fn resolve_upvalue(&mut self, state: usize, name: &Token) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+
+        if let Some(slot) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(state, slot, true, name));
+        }
+
+        let index = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, index, false, name))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool, name: &Token) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &mut self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == MAX_UPVALUES {
+            self.error_at(name, "Too many closure variables in function.");
+            return 0;
+        }
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn declare_variable(&mut self, name: &Token) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: &Token) {
+        let state = self.state();
+        if state.locals.len() == MAX_LOCALS {
+            self.error_at(name, "Too many local variables in function.");
+            return;
+        }
+
+        // Uninitialized until its initializer has been compiled, which the
+        // resolver already guarantees doesn't read it.
+        state.locals.push(Local {
+            name: name.lexeme.clone(),
+            depth: usize::MAX,
+            is_captured: false,
+        });
+    }
+
+    fn define_variable(&mut self, name: &Token) {
+        if self.state().scope_depth > 0 {
+            // The value is already in the stack slot of the local.
+            self.mark_initialized();
+            return;
+        }
+
+        let name_constant = self.identifier_constant(name);
+        self.emit_bytes(OpCode::DefineGlobal, name_constant);
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = state.scope_depth;
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+
+        let depth = state.scope_depth;
+        while let Some(local) = self.state().locals.pop_if(|local| local.depth > depth) {
+            if local.is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u8 {
+        self.make_constant(Constant::String(name.lexeme.as_str().into()), &name.lexeme)
+    }
+
+    /// Adds `constant` to the chunk, reporting an overflow at `lexeme`,
+    /// which the constant comes from.
+    fn make_constant(&mut self, constant: Constant, lexeme: &str) -> u8 {
+        let index = self.chunk().add_constant(constant);
+        u8::try_from(index).unwrap_or_else(|_| {
+            self.errors.push(CompileError {
+                line: self.line,
+                location: format!(" at '{lexeme}'"),
+                message: "Too many constants in one chunk.".into(),
+            });
+            0
+        })
+    }
+
+    fn emit_constant(&mut self, constant: Constant, lexeme: &str) {
+        let index = self.make_constant(constant, lexeme);
+        self.emit_bytes(OpCode::Constant, index);
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.line;
+        self.chunk().write(byte, line);
+    }
+
+    fn emit_op(&mut self, op: OpCode) {
+        self.emit_byte(op as u8);
+    }
+
+    fn emit_bytes(&mut self, op: OpCode, operand: u8) {
+        self.emit_op(op);
+        self.emit_byte(operand);
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().ty == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal, 0);
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_op(OpCode::Return);
+    }
+
+    /// Emits a jump with a placeholder offset, returning where to patch it.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        self.emit_op(op);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to skip over the offset itself.
+        let jump = self.chunk().code.len() - offset - 2;
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error("Too much code to jump over.");
+            return;
+        };
+
+        let [hi, lo] = jump.to_be_bytes();
+        self.chunk().code[offset] = hi;
+        self.chunk().code[offset + 1] = lo;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(OpCode::Loop);
+
+        // +2 to also jump back over the offset.
+        let offset = self.chunk().code.len() - loop_start + 2;
+        let [hi, lo] = u16::try_from(offset)
+            .unwrap_or_else(|_| {
+                self.error("Loop body too large.");
+                0
+            })
+            .to_be_bytes();
+        self.emit_byte(hi);
+        self.emit_byte(lo);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push(CompileError {
+            line: self.line,
+            location: String::new(),
+            message: message.into(),
+        });
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        self.errors.push(CompileError {
+            line: token.line,
+            location: format!(" at '{}'", token.lexeme),
+            message: message.into(),
+        });
+    }
+}
+
+/// The `this` a `super` expression binds methods to.
+fn this(keyword: &Token) -> Token {
+    Token::new(TokenType::This, "this".into(), keyword.line)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Constant, OpCode};
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn compile_errors(source: &str) -> Vec<String> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+
+        match Compiler::new().compile(&statements) {
+            Ok(_) => vec![],
+            Err(errors) => errors.iter().map(|err| err.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_compile_locals() {
+        let tokens = Scanner::new("{ var a = 1; print a; }")
+            .scan_tokens()
+            .unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let chunk = &script.chunk;
+        assert_eq!(
+            chunk.code,
+            vec![
+                OpCode::Constant as u8,
+                0,
+                OpCode::GetLocal as u8,
+                1,
+                OpCode::Print as u8,
+                OpCode::Pop as u8,
+                OpCode::Nil as u8,
+                OpCode::Return as u8,
+            ]
+        );
+        assert_eq!(chunk.constants, vec![Constant::Number(1.0)]);
+    }
+
+    #[test]
+    fn test_lines() {
+        let tokens = Scanner::new("var a = 1;\nprint\n  2.5;\nprint\n  \"s\";")
+            .scan_tokens()
+            .unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        // CONSTANT, DEFINE_GLOBAL, then CONSTANT and PRINT twice.
+        assert_eq!(script.chunk.lines, vec![1, 1, 1, 1, 3, 3, 2, 5, 5, 4, 4, 4]);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let constants: String = (0..300).map(|i| format!("print v{i};\n")).collect();
+        let expected: Vec<_> = (257..=300)
+            .map(|line| {
+                let name = line - 1;
+                format!("[line {line}] Error at 'v{name}': Too many constants in one chunk.")
+            })
+            .collect();
+        assert_eq!(compile_errors(&constants), expected);
+        // Literals report their own line too, at how they're spelled.
+        let numbers: String = (0..300).map(|i| format!("{i};\n")).collect();
+        let expected: Vec<_> = (258..=301)
+            .map(|line| {
+                let number = line - 2;
+                format!("[line {line}] Error at '{number}': Too many constants in one chunk.")
+            })
+            .collect();
+        assert_eq!(
+            compile_errors(&format!("fun f() {{\n{numbers}}}")),
+            expected
+        );
+
+        let locals: String = (0..256).map(|i| format!("var v{i};")).collect();
+        assert_eq!(
+            compile_errors(&format!("fun f() {{ {locals} }}")),
+            vec!["[line 1] Error at 'v255': Too many local variables in function."]
+        );
+
+        // Split between two functions, as one can't have that many locals.
+        let outer: String = (0..200).map(|i| format!("var a{i};")).collect();
+        let middle: String = (0..57).map(|i| format!("var b{i};")).collect();
+        let captures: String = (0..200)
+            .map(|i| format!("a{i};"))
+            .chain((0..57).map(|i| format!("b{i};")))
+            .collect();
+        let source = format!(
+            "fun outer() {{ {outer} fun middle() {{ {middle} fun inner() {{ {captures} }} }} }}"
+        );
+        assert_eq!(
+            compile_errors(&source),
+            vec!["[line 1] Error at 'b56': Too many closure variables in function."]
+        );
+    }
+}
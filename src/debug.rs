@@ -0,0 +1,196 @@
+//! Human readable listings of compiled bytecode.
+
+use crate::chunk::{Chunk, Constant, OpCode};
+use std::fmt::Write;
+
+/// Lists every instruction of `chunk` with its offset, source line and
+/// operands, followed by the listings of the functions it defines.
+pub fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
+    let mut listing = format!("== {name} ==\n");
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (instruction, next) = disassemble_instruction(chunk, offset);
+        writeln!(listing, "{instruction}").unwrap();
+        offset = next;
+    }
+
+    for constant in &chunk.constants {
+        if let Constant::Function(function) = constant {
+            listing.push('\n');
+            listing.push_str(&disassemble_chunk(&function.chunk, &function.to_string()));
+        }
+    }
+
+    listing
+}
+
+/// Formats the instruction at `offset`, returning it along with the offset of
+/// the next instruction.
+///
+/// The upvalues `CLOSURE` captures are listed on lines of their own.
+pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let mut text = format!("{offset:04} ");
+    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+        text.push_str("   | ");
+    } else {
+        write!(text, "{:4} ", chunk.lines[offset]).unwrap();
+    }
+
+    let op = match OpCode::try_from(chunk.code[offset]) {
+        Ok(op) => op,
+        Err(byte) => {
+            write!(text, "Unknown opcode {byte}").unwrap();
+            return (text, offset + 1);
+        }
+    };
+
+    let operand = |i: usize| chunk.code.get(offset + i).copied().unwrap_or(0);
+    let constant = |index: u8| match chunk.constants.get(index as usize) {
+        Some(constant) => format!("'{constant}'"),
+        None => "<out of range>".into(),
+    };
+
+    let next = match op {
+        OpCode::Constant
+        | OpCode::GetGlobal
+        | OpCode::DefineGlobal
+        | OpCode::SetGlobal
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper
+        | OpCode::Class
+        | OpCode::Method => {
+            let index = operand(1);
+            write!(text, "{op:<16} {index:4} {}", constant(index)).unwrap();
+            offset + 2
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::TailCall => {
+            write!(text, "{op:<16} {:4}", operand(1)).unwrap();
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let jump = u16::from_be_bytes([operand(1), operand(2)]) as isize;
+            let sign = if op == OpCode::Loop { -1 } else { 1 };
+            let target = offset as isize + 3 + sign * jump;
+            write!(text, "{op:<16} {offset:4} -> {target}").unwrap();
+            offset + 3
+        }
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            let index = operand(1);
+            write!(
+                text,
+                "{op:<16} ({} args) {index:4} {}",
+                operand(2),
+                constant(index)
+            )
+            .unwrap();
+            offset + 3
+        }
+        OpCode::AddLocalConstant | OpCode::SubtractLocalConstant | OpCode::LessLocalConstant => {
+            let index = operand(2);
+            write!(
+                text,
+                "{op:<16} {:4} {index:4} {}",
+                operand(1),
+                constant(index)
+            )
+            .unwrap();
+            offset + 3
+        }
+        OpCode::Closure => {
+            let index = operand(1);
+            write!(text, "{op:<16} {index:4} {}", constant(index)).unwrap();
+
+            let upvalues = match chunk.constants.get(index as usize) {
+                Some(Constant::Function(function)) => function.upvalue_count,
+                _ => 0,
+            };
+            let mut next = offset + 2;
+            for _ in 0..upvalues {
+                let kind = if operand(next - offset) != 0 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                let index = operand(next - offset + 1);
+                write!(
+                    text,
+                    "\n{next:04}      |                     {kind} {index}"
+                )
+                .unwrap();
+                next += 2;
+            }
+            next
+        }
+        OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Pop
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::Print
+        | OpCode::CloseUpvalue
+        | OpCode::Return
+        | OpCode::Inherit => {
+            write!(text, "{op}").unwrap();
+            offset + 1
+        }
+    };
+
+    (text, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::debug::disassemble_chunk;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    #[test]
+    fn test_disassemble_chunk() {
+        let source = "fun f(a) {\n  if (a) return a;\n}\nprint f(1);";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let expected = "\
+== <script> ==
+0000    1 OP_CLOSURE          0 '<fn f>'
+0002    | OP_DEFINE_GLOBAL    1 'f'
+0004    4 OP_GET_GLOBAL       1 'f'
+0006    | OP_CONSTANT         2 '1'
+0008    | OP_CALL             1
+0010    | OP_PRINT
+0011    | OP_NIL
+0012    | OP_RETURN
+
+== <fn f> ==
+0000    2 OP_GET_LOCAL        1
+0002    | OP_JUMP_IF_FALSE    2 -> 12
+0005    | OP_POP
+0006    | OP_GET_LOCAL        1
+0008    | OP_RETURN
+0009    | OP_JUMP             9 -> 13
+0012    | OP_POP
+0013    | OP_NIL
+0014    | OP_RETURN
+";
+        assert_eq!(disassemble_chunk(&script.chunk, "<script>"), expected);
+    }
+}
@@ -0,0 +1,346 @@
+//! Differential testing of the execution backends: programs run through
+//! every one of them, which have to agree on what gets printed, the errors
+//! reported and the exit status the CLI would end with. Disagreements get
+//! shrunk to a small program still showing them.
+//!
+//! The expression evaluator of [`expr`] takes part for programs made only of
+//! `print` statements it can evaluate. It doesn't tell why an expression
+//! fails, so only its output and status are compared.
+//!
+//! [`expr`]: crate::expr
+
+use crate::ast::{Expr, Stmt};
+use crate::closure_compiler::ClosureCompiler;
+use crate::compiler::{CompileError, Compiler};
+use crate::expr::{Binary, BoxExpr, Grouping, Literal, Unary};
+use crate::interpreter::Interpreter;
+use crate::optimizer;
+use crate::parser::Parser;
+use crate::programs::{Output, PROGRAMS};
+use crate::register;
+use crate::resolver::{Locals, Resolver};
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenType};
+use crate::vm::Vm;
+use std::fmt::Write as _;
+
+/// How a program ended on a backend.
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub stdout: String,
+    /// The errors reported, unless the backend doesn't tell.
+    pub stderr: Option<String>,
+    /// The exit status of the CLI: 65 for compile errors, 70 for runtime
+    /// errors.
+    pub status: i32,
+}
+
+impl Outcome {
+    fn agrees(&self, other: &Self) -> bool {
+        let stderr = match (&self.stderr, &other.stderr) {
+            (Some(stderr), Some(other)) => stderr == other,
+            _ => true,
+        };
+        self.stdout == other.stdout && stderr && self.status == other.status
+    }
+}
+
+enum Error {
+    Compile(String),
+    Runtime(String),
+}
+
+type Backend = fn(&[Stmt], Locals, Output) -> Result<(), Error>;
+
+const BACKENDS: &[(&str, Backend)] = &[
+    ("interpreter", interpreter),
+    ("interpreter optimized", interpreter_optimized),
+    ("closure", closure),
+    ("vm", vm),
+    ("vm unoptimized", vm_unoptimized),
+    ("register", register_vm),
+];
+
+fn interpreter(statements: &[Stmt], locals: Locals, output: Output) -> Result<(), Error> {
+    let mut interpreter = Interpreter::with_output(Box::new(output));
+    interpreter.resolve(locals);
+    interpreter
+        .interpret(statements)
+        .map_err(|err| Error::Runtime(err.to_string()))
+}
+
+fn interpreter_optimized(statements: &[Stmt], locals: Locals, output: Output) -> Result<(), Error> {
+    interpreter(&optimizer::optimize(statements), locals, output)
+}
+
+fn closure(statements: &[Stmt], locals: Locals, output: Output) -> Result<(), Error> {
+    let mut compiler = ClosureCompiler::with_output(Box::new(output));
+    compiler.resolve(locals);
+    compiler
+        .interpret(statements)
+        .map_err(|err| Error::Runtime(err.to_string()))
+}
+
+/// Optimized like the CLI does by default, from the AST to the bytecode.
+fn vm(statements: &[Stmt], _: Locals, output: Output) -> Result<(), Error> {
+    run_vm(&optimizer::optimize(statements), output, true)
+}
+
+fn vm_unoptimized(statements: &[Stmt], _: Locals, output: Output) -> Result<(), Error> {
+    run_vm(statements, output, false)
+}
+
+fn run_vm(statements: &[Stmt], output: Output, optimize: bool) -> Result<(), Error> {
+    let mut compiler = Compiler::new();
+    compiler.set_optimize(optimize);
+    let script = compiler.compile(statements).map_err(compile_errors)?;
+    Vm::with_output(Box::new(output))
+        .interpret(script)
+        .map_err(|err| Error::Runtime(err.to_string()))
+}
+
+fn register_vm(statements: &[Stmt], _: Locals, output: Output) -> Result<(), Error> {
+    let script = register::compiler::Compiler::new()
+        .compile(statements)
+        .map_err(compile_errors)?;
+    register::vm::Vm::with_output(Box::new(output))
+        .interpret(script)
+        .map_err(|err| Error::Runtime(err.to_string()))
+}
+
+fn compile_errors(errors: Vec<CompileError>) -> Error {
+    Error::Compile(errors.iter().map(|err| format!("{err}\n")).collect())
+}
+
+fn parse(source: &str) -> Option<Vec<Stmt>> {
+    let tokens = Scanner::new(source).scan_tokens().ok()?;
+    Parser::new(&tokens).parse().ok()
+}
+
+fn run(backend: Backend, statements: &[Stmt]) -> Outcome {
+    let output = Output::default();
+    let result = match Resolver::new().resolve(statements) {
+        Ok(locals) => backend(statements, locals, output.clone()),
+        Err(errors) => Err(Error::Compile(
+            errors.iter().map(|err| format!("{err}\n")).collect(),
+        )),
+    };
+
+    let (stderr, status) = match result {
+        Ok(()) => (String::new(), 0),
+        Err(Error::Compile(stderr)) => (stderr, 65),
+        Err(Error::Runtime(message)) => (format!("{message}\n"), 70),
+    };
+    Outcome {
+        stdout: output.contents(),
+        stderr: Some(stderr),
+        status,
+    }
+}
+
+/// Evaluates the expression of every `print` statement, if all of them are
+/// made of what the evaluator supports.
+fn evaluate(statements: &[Stmt]) -> Option<Outcome> {
+    let exprs = statements
+        .iter()
+        .map(|statement| match statement {
+            Stmt::Print { value, .. } => evaluator_expr(value),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut stdout = String::new();
+    for expr in exprs {
+        let value = match expr.visit() {
+            Some(TokenType::Number(n)) => n.to_string(),
+            Some(TokenType::String(s)) => s,
+            Some(TokenType::Bool(b)) => b.to_string(),
+            Some(TokenType::Nil) => "nil".into(),
+            Some(ty) => unreachable!("not a value: {ty:?}"),
+            None => {
+                return Some(Outcome {
+                    stdout,
+                    stderr: None,
+                    status: 70,
+                })
+            }
+        };
+        writeln!(stdout, "{value}").unwrap();
+    }
+
+    Some(Outcome {
+        stdout,
+        stderr: None,
+        status: 0,
+    })
+}
+
+fn evaluator_expr(expr: &Expr) -> Option<BoxExpr> {
+    Some(match expr {
+        Expr::Literal { value, .. } => Literal::boxed(value.clone()),
+        Expr::Grouping(expr) => Grouping::boxed(evaluator_expr(expr)?),
+        Expr::Unary { op, rhs } => Unary::boxed(op.ty.clone(), evaluator_expr(rhs)?),
+        Expr::Binary { lhs, op, rhs } => {
+            Binary::boxed(evaluator_expr(lhs)?, op.ty.clone(), evaluator_expr(rhs)?)
+        }
+        _ => return None,
+    })
+}
+
+/// Runs `source` on every backend, unless it doesn't parse.
+pub fn run_all(source: &str) -> Option<Vec<(&'static str, Outcome)>> {
+    let statements = parse(source)?;
+    let mut outcomes: Vec<_> = BACKENDS
+        .iter()
+        .map(|&(name, backend)| (name, run(backend, &statements)))
+        .collect();
+    if let Some(outcome) = evaluate(&statements) {
+        outcomes.push(("expression evaluator", outcome));
+    }
+    Some(outcomes)
+}
+
+/// Whether the backends disagree on `source`.
+pub fn diverges(source: &str) -> bool {
+    run_all(source).is_some_and(|outcomes| {
+        let (_, reference) = &outcomes[0];
+        outcomes
+            .iter()
+            .any(|(_, outcome)| !outcome.agrees(reference))
+    })
+}
+
+/// Describes what every backend did with `source`, shrunk first, if they
+/// disagree on it.
+pub fn compare(source: &str) -> Result<(), String> {
+    if !diverges(source) {
+        return Ok(());
+    }
+
+    let source = shrink(source, diverges);
+    let mut report = format!("the backends disagree on:\n{source}\n");
+    for (name, outcome) in run_all(&source).expect("shrunk programs parse") {
+        writeln!(report, "  {name}: {outcome:?}").unwrap();
+    }
+    Err(report)
+}
+
+/// Shrinks `source`, as long as `fails` holds, first dropping whole lines,
+/// then single tokens. The program is rebuilt from its tokens, keeping the
+/// ones of a line together.
+pub fn shrink(source: &str, fails: impl Fn(&str) -> bool) -> String {
+    let Ok(tokens) = Scanner::new(source).scan_tokens() else {
+        return source.into();
+    };
+    let mut tokens: Vec<(usize, String)> = tokens
+        .into_iter()
+        .filter(|token| token.ty != TokenType::EOF)
+        .map(|Token { lexeme, line, .. }| (line, lexeme))
+        .collect();
+
+    let render = |tokens: &[(usize, String)]| {
+        let mut source = String::new();
+        for (i, (line, lexeme)) in tokens.iter().enumerate() {
+            if i > 0 {
+                source.push(if tokens[i - 1].0 == *line { ' ' } else { '\n' });
+            }
+            source.push_str(lexeme);
+        }
+        source
+    };
+    if !fails(&render(&tokens)) {
+        return source.into();
+    }
+
+    let mut lines = tokens
+        .chunk_by(|a, b| a.0 == b.0)
+        .map(<[_]>::to_vec)
+        .collect();
+    remove_chunks(&mut lines, |lines| fails(&render(&lines.concat())));
+    tokens = lines.concat();
+    remove_chunks(&mut tokens, |tokens| fails(&render(tokens)));
+    render(&tokens)
+}
+
+/// Drops chunks of `items` as long as `fails` holds without them, from
+/// halves down to single items, starting anywhere.
+fn remove_chunks<T: Clone>(items: &mut Vec<T>, fails: impl Fn(&[T]) -> bool) {
+    let mut size = items.len().div_ceil(2);
+    while !items.is_empty() {
+        let mut start = 0;
+        while start < items.len() {
+            let end = (start + size).min(items.len());
+            let candidate = [&items[..start], &items[end..]].concat();
+            if fails(&candidate) {
+                *items = candidate;
+            } else {
+                start += 1;
+            }
+        }
+        if size == 1 {
+            break;
+        }
+        size = size.div_ceil(2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compares the backends on every shared test program.
+    #[test]
+    fn test_programs() {
+        let failures: Vec<_> = PROGRAMS
+            .iter()
+            .filter_map(|program| {
+                let report = compare(program.source).err()?;
+                Some(format!("{}: {report}", program.name))
+            })
+            .collect();
+
+        assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn test_expression_evaluator() {
+        let sources = [
+            "print 1 + 2 * 3; print (1 + 2) * 3;",
+            "print \"con\" + \"cat\"; print \"a\" == \"a\";",
+            "print !nil; print !!0; print nil == false; print 1 == \"1\";",
+            "print 1 < 2; print 2 <= 1; print 0 / 0 == 0 / 0; print -(1 / 0);",
+            "print 1; print -\"x\"; print 2;",
+            "print 1 + nil;",
+        ];
+
+        for source in sources {
+            let outcomes = run_all(source).unwrap();
+            assert!(outcomes
+                .iter()
+                .any(|(name, _)| *name == "expression evaluator"));
+            assert_eq!(compare(source), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_shrink() {
+        let source = "
+        var a = 1;
+        var b = 0;
+        print \"start\";
+        fun f() { return a / b; }
+        print f() + 1;
+        print \"end\";
+        ";
+        // Stands for a backend getting infinities wrong.
+        let fails = |source: &str| {
+            parse(source)
+                .is_some_and(|statements| run(interpreter, &statements).stdout.contains("inf"))
+        };
+
+        assert_eq!(
+            shrink(source, fails),
+            "var a = 1 ;\nvar b = 0 ;\nfun f ( ) { return a / b ; }\nprint f ( ) ;"
+        );
+    }
+}
@@ -1,9 +1,24 @@
-pub fn error(line: usize, message: &str) -> ! {
-    report(line, "", message)
+use std::fmt;
+
+/// An error of the scanner or the parser, which stop the program before it
+/// runs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    /// The token the error is at, like ` at 'x'` or ` at end`, empty for
+    /// errors of the scanner.
+    pub location: String,
+    pub message: String,
 }
 
-pub fn report(line: usize, where_: &str, message: &str) -> ! {
-    eprintln!("[line {line}] Error {where_}: {message}");
-    // had_error = true;
-    panic!("bad (tmp)")
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "[line {}] Error{}: {}",
+            self.line, self.location, self.message
+        )
+    }
 }
+
+impl std::error::Error for SyntaxError {}
@@ -0,0 +1,468 @@
+//! Formats source code, keeping its comments and its tokens, only ever
+//! changing the whitespace between them.
+//!
+//! Statements go on lines of their own, blocks indented two spaces a level,
+//! and lines that would be wider than asked for are wrapped, four spaces
+//! further in, where parentheses nest the least. A statement keeps a single
+//! blank line before it, if it had any, and so do comments, which stay at
+//! the end of the line they were on, or on a line of their own.
+//!
+//! Only the tokens and which lines they were on are looked at, so formatting
+//! again gives the same back, and programs that don't parse are formatted
+//! all the same.
+
+use crate::error::SyntaxError;
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenType};
+use std::cmp::Reverse;
+
+/// Spaces a level of blocks indents by.
+const INDENT: usize = 2;
+
+/// Levels a line continuing a statement indents by.
+const CONTINUATION: usize = 2;
+
+/// Formats `source` to lines no wider than `width`, where there's a place to
+/// break them, or reports what doesn't scan.
+pub fn format(source: &str, width: usize) -> Result<String, Vec<SyntaxError>> {
+    let mut scanner = Scanner::new(source);
+    scanner.set_comments(true);
+    let tokens = scanner.scan_tokens()?;
+
+    let mut splitter = Splitter::default();
+    let mut previous: Option<&Token> = None;
+    for token in tokens.iter().take_while(|token| token.ty != TokenType::EOF) {
+        // Tokens are on the line they end on, strings can span several.
+        let line = token.line - token.lexeme.matches('\n').count();
+        let gap = previous.map_or(0, |previous| line - previous.line);
+        splitter.token(token, gap > 0, gap > 1);
+        previous = Some(token);
+    }
+    splitter.end_line();
+
+    let mut out = String::new();
+    for line in &splitter.lines {
+        match line {
+            Line::Blank => {}
+            Line::Tokens { indent, tokens } => wrap(&mut out, tokens, *indent, width),
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+enum Line<'a> {
+    Blank,
+    /// A statement, or a part of one, indented by so many levels.
+    Tokens {
+        indent: usize,
+        tokens: Vec<&'a Token>,
+    },
+}
+
+/// Splits tokens into lines.
+#[derive(Default)]
+struct Splitter<'a> {
+    lines: Vec<Line<'a>>,
+    line: Vec<&'a Token>,
+    indent: usize,
+    /// Blocks and parentheses the tokens so far are in.
+    blocks: usize,
+    parens: usize,
+    /// Whether the last statement isn't over, so lines continue it.
+    open: bool,
+}
+
+impl<'a> Splitter<'a> {
+    /// Adds a token, after a line break in the source, or a blank line.
+    fn token(&mut self, token: &'a Token, newline: bool, blank: bool) {
+        use TokenType::*;
+
+        if token.ty == Comment {
+            if !newline && self.line.is_empty() {
+                // After a statement, which ended its line already.
+                if let Some(Line::Tokens { tokens, .. }) = self.lines.last_mut() {
+                    tokens.push(token);
+                    return;
+                }
+            }
+            if newline || self.line.is_empty() {
+                self.end_line();
+                self.start_line(blank);
+            }
+            self.line.push(token);
+            self.end_line();
+            return;
+        }
+
+        // Braces end their lines, but for an `else` after a block, or the
+        // end of an empty one.
+        match (self.line.last().map(|last| &last.ty), &token.ty) {
+            (Some(LeftBrace), RightBrace) => {
+                self.blocks = self.blocks.saturating_sub(1);
+                self.line.push(token);
+                self.open = false;
+                return;
+            }
+            (Some(RightBrace), Else) => {}
+            (Some(LeftBrace | RightBrace), _) => self.end_line(),
+            _ => {}
+        }
+
+        if token.ty == RightBrace {
+            self.end_line();
+            self.blocks = self.blocks.saturating_sub(1);
+        }
+        if self.line.is_empty() {
+            self.start_line(blank && token.ty != RightBrace);
+        }
+        self.line.push(token);
+
+        match token.ty {
+            LeftParen => self.parens += 1,
+            RightParen => self.parens = self.parens.saturating_sub(1),
+            LeftBrace => self.blocks += 1,
+            Semicolon if self.parens == 0 => self.end_line(),
+            _ => {}
+        }
+        self.open = match token.ty {
+            LeftBrace | RightBrace => false,
+            Semicolon => self.parens > 0,
+            _ => true,
+        };
+    }
+
+    /// Starts a line, after a blank one if there was one in the source and
+    /// it's between statements.
+    fn start_line(&mut self, blank: bool) {
+        let after_brace = match self.lines.last() {
+            Some(Line::Tokens { tokens, .. }) => tokens.last().unwrap().ty == TokenType::LeftBrace,
+            _ => true,
+        };
+        if blank && !self.open && !after_brace {
+            self.lines.push(Line::Blank);
+        }
+
+        self.indent = self.blocks;
+        if self.open {
+            self.indent += CONTINUATION;
+        }
+    }
+
+    fn end_line(&mut self) {
+        if !self.line.is_empty() {
+            let tokens = std::mem::take(&mut self.line);
+            self.lines.push(Line::Tokens {
+                indent: self.indent,
+                tokens,
+            });
+        }
+    }
+}
+
+/// Writes the tokens of a line, breaking it where it gets wider than
+/// `width`, at the place before that where parentheses nest the least, the
+/// last of those.
+fn wrap(out: &mut String, tokens: &[&Token], indent: usize, width: usize) {
+    // Tokens without spaces between them have to scan the same together.
+    let mut spaced = vec![false; tokens.len()];
+    let mut run = 0;
+    for i in 1..tokens.len() {
+        spaced[i] = space(&tokens[..i], tokens[i]) || !glues(&tokens[run..=i]);
+        if spaced[i] {
+            run = i;
+        }
+    }
+    let breakable: Vec<bool> = (0..tokens.len())
+        .map(|i| i > 0 && breaks(&tokens[..i], tokens[i]))
+        .collect();
+    let mut depths = Vec::with_capacity(tokens.len());
+    let mut depth = 0isize;
+    for token in tokens {
+        match token.ty {
+            TokenType::LeftParen => depth += 1,
+            TokenType::RightParen => depth -= 1,
+            _ => {}
+        }
+        depths.push(depth);
+    }
+
+    let first = indent * INDENT;
+    let rest = (indent + CONTINUATION) * INDENT;
+    // The column after token `i`, at `column` before it, starting a line or
+    // not.
+    let advance = |column: usize, i: usize, starts: bool| {
+        let lexeme = &tokens[i].lexeme;
+        match lexeme.rsplit_once('\n') {
+            Some((_, last)) => last.chars().count(),
+            None => column + usize::from(!starts && spaced[i]) + lexeme.chars().count(),
+        }
+    };
+
+    let mut line_breaks = vec![];
+    let mut start = 0;
+    let mut column = first;
+    for i in 0..tokens.len() {
+        let first_line = tokens[i].lexeme.split('\n').next().unwrap();
+        let wide = column + usize::from(i > start && spaced[i]) + first_line.chars().count();
+        if wide > width {
+            let at = (start + 1..=i)
+                .filter(|&at| breakable[at])
+                .min_by_key(|&at| (depths[at - 1], Reverse(at)));
+            if let Some(at) = at {
+                line_breaks.push(at);
+                start = at;
+                column = (at..i).fold(rest, |column, j| advance(column, j, j == at));
+            }
+        }
+        column = advance(column, i, i == start);
+    }
+
+    out.push_str(&" ".repeat(first));
+    for (i, token) in tokens.iter().enumerate() {
+        if line_breaks.contains(&i) {
+            out.push('\n');
+            out.push_str(&" ".repeat(rest));
+        } else if spaced[i] {
+            out.push(' ');
+        }
+        out.push_str(token.lexeme.trim_end());
+    }
+}
+
+/// Whether the token ends an operand, so that a `-` after it subtracts and a
+/// `(` after it calls.
+fn ends_operand(ty: &TokenType) -> bool {
+    matches!(
+        ty,
+        TokenType::Identifier(_)
+            | TokenType::String(_)
+            | TokenType::Number(_)
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::This
+            | TokenType::RightParen
+    )
+}
+
+/// Whether `next` is a binary operator, after `before`.
+fn is_binary(before: &[&Token], next: &Token) -> bool {
+    use TokenType::*;
+
+    match next.ty {
+        Minus => before.last().is_some_and(|t| ends_operand(&t.ty)),
+        Plus | Star | Slash | BangEqual | EqualEqual | Greater | GreaterEqual | Less
+        | LessEqual | And | Or => true,
+        _ => false,
+    }
+}
+
+/// Whether there's a space between the tokens `before` and `next`.
+fn space(before: &[&Token], next: &Token) -> bool {
+    use TokenType::*;
+
+    let (prev, before) = before.split_last().unwrap();
+    match (&prev.ty, &next.ty) {
+        (_, Comment) => true,
+        (LeftParen | Dot | Bang, _) => false,
+        (Minus, _) if !is_binary(before, prev) => false,
+        (_, RightParen | Comma | Semicolon | Dot) => false,
+        (LeftBrace, RightBrace) => false,
+        (prev, LeftParen) => !ends_operand(prev),
+        _ => true,
+    }
+}
+
+/// Whether the tokens scan the same without spaces between them, which `1`
+/// `.` `5` or `!` `=` don't.
+fn glues(tokens: &[&Token]) -> bool {
+    let source: String = tokens.iter().map(|token| token.lexeme.as_str()).collect();
+    match Scanner::new(&source).scan_tokens() {
+        Ok(scanned) => {
+            scanned.len() == tokens.len() + 1
+                && scanned
+                    .iter()
+                    .zip(tokens)
+                    .all(|(a, b)| a.lexeme == b.lexeme)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether a line can break before `next`: after commas, assignments and
+/// the clauses of a `for`, and before binary operators.
+fn breaks(before: &[&Token], next: &Token) -> bool {
+    use TokenType::*;
+
+    match before.last().unwrap().ty {
+        _ if next.ty == Comment => false,
+        Comma | Equal | Semicolon => true,
+        _ => is_binary(before, next),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench;
+    use crate::fuzz;
+    use crate::programs::PROGRAMS;
+    use std::path::Path;
+
+    fn tokens(source: &str) -> Vec<(TokenType, String)> {
+        let mut scanner = Scanner::new(source);
+        scanner.set_comments(true);
+        let tokens = scanner.scan_tokens().unwrap();
+        tokens
+            .into_iter()
+            .map(|token| (token.ty, token.lexeme.trim_end().to_string()))
+            .collect()
+    }
+
+    fn assert_formats(source: &str, width: usize) {
+        let formatted = format(source, width).unwrap();
+        assert_eq!(
+            tokens(source),
+            tokens(&formatted),
+            "changed tokens:\n{formatted}"
+        );
+        assert_eq!(
+            format(&formatted, width).unwrap(),
+            formatted,
+            "formatted differently again:\n{formatted}"
+        );
+        assert!(
+            formatted.lines().all(|line| line == line.trim_end()),
+            "left trailing whitespace:\n{formatted}"
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let source = "
+class  Breakfast<Meal{
+    init( eggs ,bacon ){ this.eggs=eggs ; this.bacon = bacon;}
+
+
+    serve(){if(!this.eggs)return nil;else{print -this.eggs*-2;} while(true){}}
+}
+for(var i=0;i<2;i=i+1)print i  ;
+";
+
+        assert_eq!(
+            format(source, 80).unwrap(),
+            "\
+class Breakfast < Meal {
+  init(eggs, bacon) {
+    this.eggs = eggs;
+    this.bacon = bacon;
+  }
+
+  serve() {
+    if (!this.eggs) return nil;
+    else {
+      print -this.eggs * -2;
+    }
+    while (true) {}
+  }
+}
+for (var i = 0; i < 2; i = i + 1) print i;
+"
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        let source = "
+// The header.
+
+var a = 1;   // One.
+{ // A block.
+    // Inside.
+
+}
+if (a) {
+} // Done.
+else print a; print f(1, // One.
+  2);
+";
+
+        assert_eq!(
+            format(source, 80).unwrap(),
+            "\
+// The header.
+
+var a = 1; // One.
+{ // A block.
+  // Inside.
+}
+if (a) {} // Done.
+else print a;
+print f(1, // One.
+    2);
+"
+        );
+    }
+
+    #[test]
+    fn test_wrapping() {
+        let source = "var total = first(a, b) + second(c, d) * third(e, f, g) - fourth;";
+
+        assert_eq!(
+            format(source, 40).unwrap(),
+            "\
+var total = first(a, b) + second(c, d)
+    * third(e, f, g) - fourth;
+"
+        );
+        assert_eq!(
+            format(source, 20).unwrap(),
+            "\
+var total =
+    first(a, b)
+    + second(c, d)
+    * third(e, f, g)
+    - fourth;
+"
+        );
+        assert_eq!(
+            format("print \"a long string\";", 10).unwrap(),
+            "print \"a long string\";\n"
+        );
+        assert_eq!(format("1 . 5 ! = 2;", 80).unwrap(), "1. 5 ! = 2;\n");
+    }
+
+    /// Formats every program around, and random ones, which needn't parse,
+    /// at a few widths, checking the tokens are the same and formatting again
+    /// changes nothing.
+    #[test]
+    fn test_formatting_is_stable() {
+        let mut sources: Vec<String> = PROGRAMS.iter().map(|p| p.source.into()).collect();
+        sources.extend(bench::PROGRAMS.iter().map(|(_, source)| source.to_string()));
+        let mut dirs = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox")];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Ok(source) = std::fs::read_to_string(&path) {
+                    sources.push(source);
+                }
+            }
+        }
+        for seed in 0..100 {
+            sources.push(fuzz::generate(seed, 30));
+            sources.push(fuzz::mutate(seed, 30));
+        }
+
+        for source in sources {
+            if Scanner::new(&source).scan_tokens().is_err() {
+                continue;
+            }
+            for width in [20, 40, 80] {
+                assert_formats(&source, width);
+            }
+        }
+    }
+}
@@ -0,0 +1,846 @@
+//! Random programs for the scanner, the parser and every backend.
+//!
+//! The generator writes programs that are valid all the way to the resolver,
+//! from a seed and within a size. It keeps track of the type of variables so
+//! most of the code runs without type errors, with the odd expression mixing
+//! types to exercise runtime errors too. Programs never recurse and loops
+//! are bounded, so they all end quickly. They run on every backend, which
+//! have to agree, as checked by the [`differential`] harness.
+//!
+//! Mutation mode garbles generated programs instead, deleting, duplicating
+//! and inserting bits of source, for the errors of the scanner and the
+//! parser. Those only go as far as the resolver, as they could well recurse
+//! forever.
+//!
+//! Programs that make anything panic, or that backends disagree on, are
+//! saved to `fuzz/regressions`, whose programs get checked again on every
+//! run. `LOX_FUZZ_SEED` and `LOX_FUZZ_ITERATIONS` pick the seeds
+//! the tests go through, for longer runs.
+//!
+//! [`differential`]: crate::differential
+
+use crate::differential;
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::scanner::Scanner;
+use std::fmt::Write as _;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// SplitMix64, which is plenty for picking productions.
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn percent(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Number,
+    String,
+    Bool,
+    /// Functions return numbers, or closures taking one for makers.
+    Function {
+        arity: usize,
+        maker: bool,
+    },
+    /// An instance of the class at this index.
+    Instance(usize),
+}
+
+struct Variable {
+    name: String,
+    ty: Type,
+    /// Loop counters and functions are never assigned, so loops end and
+    /// functions keep their type.
+    assignable: bool,
+}
+
+struct Class {
+    name: String,
+    init_arity: usize,
+    /// Its fields and those it inherits, all set by initializers.
+    fields: Vec<(String, Type)>,
+    /// The arity of its methods and those it inherits, which all return
+    /// numbers.
+    methods: Vec<(String, usize)>,
+}
+
+/// What the code being generated is in.
+#[derive(Clone, Copy, PartialEq)]
+enum Context {
+    Script,
+    Function,
+    /// A method of the class at this index, which can use `this`.
+    Method(usize),
+}
+
+/// Writes a random program.
+struct Generator {
+    rng: Rng,
+    out: String,
+    indent: usize,
+    scopes: Vec<Vec<Variable>>,
+    classes: Vec<Class>,
+    /// Names are all different, so there's no shadowing to keep track of.
+    names: usize,
+    /// How many more statements to write.
+    budget: usize,
+    context: Context,
+    /// How many loops the code is in, or functions, which bounds how many
+    /// times it runs.
+    nesting: usize,
+}
+
+const MAX_DEPTH: usize = 3;
+const MAX_NESTING: usize = 2;
+
+const STRINGS: &[&str] = &["", "a", "lox", "hello world", "0", "nil"];
+
+impl Generator {
+    fn new(seed: u64, size: usize) -> Self {
+        Self {
+            rng: Rng(seed),
+            out: String::new(),
+            indent: 0,
+            scopes: vec![vec![]],
+            classes: vec![],
+            names: 0,
+            budget: size,
+            context: Context::Script,
+            nesting: 0,
+        }
+    }
+
+    fn program(mut self) -> String {
+        while self.budget > 0 {
+            self.declaration();
+        }
+        self.out
+    }
+
+    fn name(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{prefix}{}", self.names)
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn declare(&mut self, name: &str, ty: Type, assignable: bool) {
+        let scope = self.scopes.last_mut().expect("there's always a scope");
+        scope.push(Variable {
+            name: name.into(),
+            ty,
+            assignable,
+        });
+    }
+
+    fn variables(&self, matching: impl Fn(&Variable) -> bool) -> Vec<(String, Type)> {
+        self.scopes
+            .iter()
+            .flatten()
+            .filter(|variable| matching(variable))
+            .map(|variable| (variable.name.clone(), variable.ty))
+            .collect()
+    }
+
+    /// Opens a block, writing statements into it until `body` is done.
+    fn block(&mut self, header: &str, body: impl FnOnce(&mut Self)) {
+        self.line(&format!("{header}{{"));
+        self.indent += 1;
+        self.scopes.push(vec![]);
+        body(self);
+        self.scopes.pop();
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// A few statements, for the body of a block.
+    fn statements(&mut self) {
+        for _ in 0..1 + self.rng.below(4) {
+            if self.budget == 0 {
+                break;
+            }
+            self.declaration();
+        }
+    }
+
+    fn declaration(&mut self) {
+        self.budget = self.budget.saturating_sub(1);
+        let nested = self.nesting < MAX_NESTING;
+        match self.rng.below(20) {
+            0..=3 => self.var_declaration(),
+            4 | 5 if nested && self.context == Context::Script => self.function(),
+            // Classes aren't scoped like variables are, so they stay global.
+            6 if self.scopes.len() == 1 => self.class(),
+            7 | 8 if nested => self.for_loop(),
+            9 if nested => self.while_loop(),
+            10 | 11 => self.if_statement(),
+            12 => self.block("", Self::statements),
+            13 | 14 => self.assignment(),
+            15 => self.call_statement(),
+            _ => {
+                let ty = self.any_type();
+                let expr = self.expr(ty, 0);
+                self.line(&format!("print {expr};"));
+            }
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let name = self.name("v");
+        let ty = self.any_type();
+        let init = self.expr(ty, 0);
+        self.line(&format!("var {name} = {init};"));
+        self.declare(&name, ty, true);
+    }
+
+    /// A function returning a number, or a maker of closures.
+    fn function(&mut self) {
+        let name = self.name("f");
+        if self.rng.percent(25) {
+            self.maker(&name);
+            return;
+        }
+
+        let arity = self.rng.below(4);
+        let params: Vec<_> = (0..arity).map(|_| self.name("p")).collect();
+        let context = std::mem::replace(&mut self.context, Context::Function);
+        self.nesting += 1;
+        self.block(&format!("fun {name}({}) ", params.join(", ")), |g| {
+            for param in &params {
+                g.declare(param, Type::Number, true);
+            }
+            g.statements();
+            g.returns();
+        });
+        self.nesting -= 1;
+        self.context = context;
+
+        self.declare(
+            &name,
+            Type::Function {
+                arity,
+                maker: false,
+            },
+            false,
+        );
+    }
+
+    /// A function returning a closure over a local of its own.
+    fn maker(&mut self, name: &str) {
+        let param = self.name("p");
+        let local = self.name("c");
+        let closure = self.name("g");
+        let arg = self.name("p");
+        let op = *self.rng.pick(&["+", "-", "*"]);
+
+        self.block(&format!("fun {name}({param}) "), |g| {
+            g.line(&format!("var {local} = {param};"));
+            g.block(&format!("fun {closure}({arg}) "), |g| {
+                g.line(&format!("{local} = {local} {op} {arg};"));
+                g.line(&format!("return {local};"));
+            });
+            g.line(&format!("return {closure};"));
+        });
+
+        self.declare(
+            name,
+            Type::Function {
+                arity: 1,
+                maker: true,
+            },
+            false,
+        );
+    }
+
+    /// The return at the end of a function, maybe after an early one.
+    fn returns(&mut self) {
+        if self.rng.percent(30) {
+            let cond = self.expr(Type::Bool, 1);
+            let value = self.expr(Type::Number, 1);
+            self.line(&format!("if ({cond}) return {value};"));
+        }
+        let value = self.expr(Type::Number, 0);
+        self.line(&format!("return {value};"));
+    }
+
+    fn class(&mut self) {
+        let name = self.name("C");
+        let superclass = match self.classes.len() {
+            0 => None,
+            n if self.rng.percent(50) => Some(self.rng.below(n)),
+            _ => None,
+        };
+        let index = self.classes.len();
+
+        let (mut fields, mut methods, super_arity) = match superclass {
+            Some(superclass) => {
+                let class = &self.classes[superclass];
+                (
+                    class.fields.clone(),
+                    class.methods.clone(),
+                    Some(class.init_arity),
+                )
+            }
+            None => (vec![], vec![], None),
+        };
+        let inherited = methods.clone();
+        let init_arity = self.rng.below(3);
+        let params: Vec<_> = (0..init_arity).map(|_| self.name("p")).collect();
+        let new_fields: Vec<_> = (0..1 + self.rng.below(2))
+            .map(|_| {
+                let ty = self.scalar_type();
+                (self.name("x"), ty)
+            })
+            .collect();
+
+        let header = match superclass {
+            Some(superclass) => format!("class {name} < {} ", self.classes[superclass].name),
+            None => format!("class {name} "),
+        };
+        self.line(&format!("{header}{{"));
+        self.indent += 1;
+
+        // Initializers leave `this` alone until they've set all the fields.
+        let context = std::mem::replace(&mut self.context, Context::Function);
+        self.nesting += 1;
+        self.block(&format!("init({}) ", params.join(", ")), |g| {
+            for param in &params {
+                g.declare(param, Type::Number, true);
+            }
+            if let Some(arity) = super_arity {
+                let args = g.args(arity);
+                g.line(&format!("super.init({args});"));
+            }
+            for (field, ty) in &new_fields {
+                let value = g.expr(*ty, 1);
+                g.line(&format!("this.{field} = {value};"));
+            }
+        });
+        fields.extend(new_fields);
+        // Methods see the fields, but not the class, until it's declared.
+        self.classes.push(Class {
+            name: name.clone(),
+            init_arity,
+            fields,
+            methods: vec![],
+        });
+        self.context = Context::Method(index);
+
+        for _ in 0..self.rng.below(3) {
+            let overridden = match inherited.len() {
+                0 => None,
+                n if self.rng.percent(40) => Some(inherited[self.rng.below(n)].clone()),
+                _ => None,
+            };
+            let (method, arity) = match &overridden {
+                Some(method) => method.clone(),
+                None => (self.name("m"), self.rng.below(3)),
+            };
+            let params: Vec<_> = (0..arity).map(|_| self.name("p")).collect();
+            self.block(&format!("{method}({}) ", params.join(", ")), |g| {
+                for param in &params {
+                    g.declare(param, Type::Number, true);
+                }
+                g.statements();
+                // Overrides only call up, through `super`, as methods calling
+                // each other through `this` could end up recursing.
+                if overridden.is_some() {
+                    let args = g.args(arity);
+                    g.line(&format!("return super.{method}({args});"));
+                } else {
+                    g.returns();
+                }
+            });
+            methods.retain(|(name, _)| *name != method);
+            methods.push((method, arity));
+        }
+        self.nesting -= 1;
+        self.context = context;
+
+        self.indent -= 1;
+        self.line("}");
+        self.classes[index].methods = methods;
+    }
+
+    fn for_loop(&mut self) {
+        let counter = self.name("i");
+        let bound = self.rng.below(4);
+        self.nesting += 1;
+        self.block(
+            &format!("for (var {counter} = 0; {counter} < {bound}; {counter} = {counter} + 1) "),
+            |g| {
+                g.declare(&counter, Type::Number, false);
+                g.statements();
+            },
+        );
+        self.nesting -= 1;
+    }
+
+    fn while_loop(&mut self) {
+        let counter = self.name("w");
+        let bound = self.rng.below(4);
+        self.nesting += 1;
+        self.block("", |g| {
+            g.line(&format!("var {counter} = 0;"));
+            g.declare(&counter, Type::Number, false);
+            g.block(&format!("while ({counter} < {bound}) "), |g| {
+                g.statements();
+                g.line(&format!("{counter} = {counter} + 1;"));
+            });
+        });
+        self.nesting -= 1;
+    }
+
+    fn if_statement(&mut self) {
+        let cond = self.expr(Type::Bool, 0);
+        self.block(&format!("if ({cond}) "), Self::statements);
+        if self.rng.percent(40) {
+            self.block("else ", Self::statements);
+        }
+    }
+
+    fn assignment(&mut self) {
+        // Strings are only assigned literals, as they could double in size
+        // every time a loop runs otherwise.
+        let targets = self.variables(|v| v.assignable);
+        if targets.is_empty() {
+            return self.var_declaration();
+        }
+        let (name, ty) = self.rng.pick(&targets).clone();
+        let value = match ty {
+            Type::String => self.string_literal(),
+            _ => self.expr(ty, 0),
+        };
+        self.line(&format!("{name} = {value};"));
+
+        if let Some(field) = self.field_target() {
+            let value = self.expr(field.1, 1);
+            self.line(&format!("{} = {value};", field.0));
+        }
+    }
+
+    /// A field of an instance in scope, maybe. Strings fields are left out,
+    /// staying the literals initializers set.
+    fn field_target(&mut self) -> Option<(String, Type)> {
+        let mut fields = vec![];
+        for (name, ty) in self.variables(|v| matches!(v.ty, Type::Instance(_))) {
+            let Type::Instance(class) = ty else {
+                unreachable!()
+            };
+            for (field, ty) in &self.classes[class].fields {
+                if *ty != Type::String {
+                    fields.push((format!("{name}.{field}"), *ty));
+                }
+            }
+        }
+        if fields.is_empty() || self.rng.percent(50) {
+            return None;
+        }
+        Some(self.rng.pick(&fields).clone())
+    }
+
+    fn call_statement(&mut self) {
+        let expr = self.expr(Type::Number, 0);
+        self.line(&format!("{expr};"));
+    }
+
+    fn any_type(&mut self) -> Type {
+        match self.rng.below(10) {
+            0..=4 => Type::Number,
+            5 | 6 => Type::String,
+            7 => Type::Bool,
+            8 if !self.classes.is_empty() => Type::Instance(self.rng.below(self.classes.len())),
+            _ => {
+                let makers = self.variables(|v| {
+                    matches!(
+                        v.ty,
+                        Type::Function {
+                            arity: 1,
+                            maker: true
+                        }
+                    )
+                });
+                if makers.is_empty() {
+                    Type::Number
+                } else {
+                    Type::Function {
+                        arity: 1,
+                        maker: false,
+                    }
+                }
+            }
+        }
+    }
+
+    fn scalar_type(&mut self) -> Type {
+        *self.rng.pick(&[Type::Number, Type::String, Type::Bool])
+    }
+
+    fn args(&mut self, arity: usize) -> String {
+        let args: Vec<_> = (0..arity).map(|_| self.expr(Type::Number, 2)).collect();
+        args.join(", ")
+    }
+
+    fn string_literal(&mut self) -> String {
+        format!("\"{}\"", self.rng.pick(STRINGS))
+    }
+
+    /// An expression of type `ty`, or now and then one of anything.
+    fn expr(&mut self, ty: Type, depth: usize) -> String {
+        if depth < MAX_DEPTH && self.rng.below(1000) < 3 {
+            let lhs = self.scalar_type();
+            let rhs = self.scalar_type();
+            let op = *self.rng.pick(&["+", "-", "<", "*"]);
+            let lhs = self.expr(lhs, depth + 1);
+            let rhs = self.expr(rhs, depth + 1);
+            return format!("({lhs} {op} {rhs})");
+        }
+
+        let leaf = depth >= MAX_DEPTH || self.rng.percent(30);
+        let variables = self.variables(|v| v.ty == ty);
+        if !variables.is_empty() && (leaf || self.rng.percent(30)) {
+            return self.rng.pick(&variables).0.clone();
+        }
+
+        match ty {
+            Type::Number => self.number(depth, leaf),
+            Type::String => self.string(depth, leaf),
+            Type::Bool => self.bool(depth, leaf),
+            Type::Function { .. } => {
+                let makers = self.variables(|v| matches!(v.ty, Type::Function { maker: true, .. }));
+                let maker = self.rng.pick(&makers).0.clone();
+                let arg = self.expr(Type::Number, depth + 1);
+                format!("{maker}({arg})")
+            }
+            Type::Instance(class) => {
+                let arity = self.classes[class].init_arity;
+                let name = self.classes[class].name.clone();
+                let args = self.args(arity);
+                format!("{name}({args})")
+            }
+        }
+    }
+
+    fn number(&mut self, depth: usize, leaf: bool) -> String {
+        if leaf {
+            return match self.rng.below(10) {
+                0 => format!("{}.5", self.rng.below(10)),
+                1 => "0".into(),
+                _ => self.rng.below(20).to_string(),
+            };
+        }
+
+        let depth = depth + 1;
+        match self.rng.below(12) {
+            0 => format!("-{}", self.expr(Type::Number, depth)),
+            1 => format!("({})", self.expr(Type::Number, depth)),
+            2 => {
+                let targets = self.variables(|v| v.assignable && v.ty == Type::Number);
+                if targets.is_empty() {
+                    return self.number(depth, true);
+                }
+                let target = self.rng.pick(&targets).0.clone();
+                format!("({target} = {})", self.expr(Type::Number, depth))
+            }
+            3 | 4 => self.call(depth),
+            5 => self.field(Type::Number, depth),
+            _ => {
+                let op = *self.rng.pick(&["+", "-", "*", "/"]);
+                let lhs = self.expr(Type::Number, depth);
+                let rhs = self.expr(Type::Number, depth);
+                format!("{lhs} {op} {rhs}")
+            }
+        }
+    }
+
+    /// A call of a function, closure or method, or of the method being
+    /// overridden.
+    fn call(&mut self, depth: usize) -> String {
+        let mut calls = vec![];
+        for (name, ty) in self.variables(|v| matches!(v.ty, Type::Function { maker: false, .. })) {
+            if let Type::Function { arity, .. } = ty {
+                calls.push((name, arity));
+            }
+        }
+        for (name, ty) in self.variables(|v| matches!(v.ty, Type::Instance(_))) {
+            let Type::Instance(class) = ty else {
+                unreachable!()
+            };
+            for (method, arity) in &self.classes[class].methods {
+                calls.push((format!("{name}.{method}"), *arity));
+            }
+        }
+        if calls.is_empty() {
+            return self.number(depth, true);
+        }
+        let (callee, arity) = self.rng.pick(&calls).clone();
+        let args: Vec<_> = (0..arity).map(|_| self.expr(Type::Number, depth)).collect();
+        format!("{callee}({})", args.join(", "))
+    }
+
+    /// A field of `this` or of an instance in scope.
+    fn field(&mut self, ty: Type, depth: usize) -> String {
+        let mut fields = vec![];
+        if let Context::Method(class) = self.context {
+            for (field, field_ty) in &self.classes[class].fields {
+                if *field_ty == ty {
+                    fields.push(format!("this.{field}"));
+                }
+            }
+        }
+        for (name, var_ty) in self.variables(|v| matches!(v.ty, Type::Instance(_))) {
+            let Type::Instance(class) = var_ty else {
+                unreachable!()
+            };
+            for (field, field_ty) in &self.classes[class].fields {
+                if *field_ty == ty {
+                    fields.push(format!("{name}.{field}"));
+                }
+            }
+        }
+
+        if fields.is_empty() {
+            return self.expr(ty, MAX_DEPTH.max(depth));
+        }
+        self.rng.pick(&fields).clone()
+    }
+
+    fn string(&mut self, depth: usize, leaf: bool) -> String {
+        if leaf {
+            return self.string_literal();
+        }
+        let depth = depth + 1;
+        match self.rng.below(4) {
+            0 => self.field(Type::String, depth),
+            _ => {
+                let lhs = self.expr(Type::String, depth);
+                let rhs = self.expr(Type::String, depth);
+                format!("{lhs} + {rhs}")
+            }
+        }
+    }
+
+    fn bool(&mut self, depth: usize, leaf: bool) -> String {
+        if leaf {
+            return self.rng.pick(&["true", "false"]).to_string();
+        }
+        let depth = depth + 1;
+        match self.rng.below(10) {
+            0 | 1 => {
+                let ty = self.any_type();
+                format!("!{}", self.expr(ty, depth))
+            }
+            2..=4 => {
+                let op = *self.rng.pick(&["<", "<=", ">", ">="]);
+                let lhs = self.expr(Type::Number, depth);
+                let rhs = self.expr(Type::Number, depth);
+                format!("{lhs} {op} {rhs}")
+            }
+            5 | 6 => {
+                let op = *self.rng.pick(&["==", "!="]);
+                let lhs = self.any_type();
+                let rhs = if self.rng.percent(70) {
+                    lhs
+                } else {
+                    self.any_type()
+                };
+                let lhs = self.expr(lhs, depth);
+                let rhs = self.expr(rhs, depth);
+                format!("({lhs} {op} {rhs})")
+            }
+            7 | 8 => {
+                let op = *self.rng.pick(&["and", "or"]);
+                let lhs = self.expr(Type::Bool, depth);
+                let rhs = self.expr(Type::Bool, depth);
+                format!("({lhs} {op} {rhs})")
+            }
+            _ => self.field(Type::Bool, depth),
+        }
+    }
+}
+
+/// A program of about `size` statements.
+pub fn generate(seed: u64, size: usize) -> String {
+    Generator::new(seed, size).program()
+}
+
+/// Snippets mutations insert, breaking programs in all sorts of ways.
+const SNIPPETS: &[&str] = &[
+    "(", ")", "{", "}", ";", ",", ".", "=", "!", "<=", "\"", "/", "//", "\n", "var", "fun",
+    "class", "return", "this", "super", "and", "or", "else", "1.", ".5", "é", "@", "#", "~",
+];
+
+/// Garbles a generated program with a few random edits.
+pub fn mutate(seed: u64, size: usize) -> String {
+    let mut rng = Rng(seed);
+    let mut chars: Vec<char> = generate(rng.next(), size).chars().collect();
+
+    for _ in 0..1 + rng.below(4) {
+        let at = rng.below(chars.len() + 1);
+        let len = (1 + rng.below(8)).min(chars.len() - at);
+        match rng.below(3) {
+            0 => {
+                chars.drain(at..at + len);
+            }
+            1 => {
+                let copy = chars[at..at + len].to_vec();
+                chars.splice(at..at, copy);
+            }
+            _ => {
+                let snippet = rng.pick(SNIPPETS);
+                chars.splice(at..at, snippet.chars());
+            }
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// Runs a generated program on every backend, which have to agree.
+fn check_generated(source: &str) -> Result<(), String> {
+    let tokens = Scanner::new(source).scan_tokens().map_err(describe)?;
+    let statements = Parser::new(&tokens).parse().map_err(describe)?;
+    Resolver::new().resolve(&statements).map_err(describe)?;
+    differential::compare(source)
+}
+
+/// Goes through the front end with a mutated program, which can fail but
+/// mustn't panic.
+fn check_mutated(source: &str) {
+    let Ok(tokens) = Scanner::new(source).scan_tokens() else {
+        return;
+    };
+    let Ok(statements) = Parser::new(&tokens).parse() else {
+        return;
+    };
+    let _ = Resolver::new().resolve(&statements);
+}
+
+fn describe(errors: Vec<impl ToString>) -> String {
+    let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+    format!("generated an invalid program:\n{}", errors.join("\n"))
+}
+
+/// Checks a program, turning panics into failures.
+fn check(mutated: bool, source: &str) -> Result<(), String> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        if mutated {
+            check_mutated(source);
+            Ok(())
+        } else {
+            check_generated(source)
+        }
+    }));
+    result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(ToString::to_string)
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(format!("panicked: {message}"))
+    })
+}
+
+fn regressions() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions")
+}
+
+/// Where the seeds of a fuzzing run start, and how many there are.
+fn seeds() -> std::ops::Range<u64> {
+    let var = |name: &str, default: u64| {
+        std::env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    let start = var("LOX_FUZZ_SEED", 0);
+    start..start + var("LOX_FUZZ_ITERATIONS", 200)
+}
+
+/// Checks the programs of every seed, saving those failing as regressions.
+fn fuzz(mutated: bool, program: fn(u64, usize) -> String) {
+    let mode = if mutated { "mutated" } else { "generated" };
+    let mut failures = String::new();
+
+    for seed in seeds() {
+        let source = program(seed, 30);
+        let Err(report) = check(mutated, &source) else {
+            continue;
+        };
+
+        // The report has disagreements shrunk, the file keeps the program
+        // as it was generated, which the seed gives back anyway.
+        let path = regressions().join(format!("{mode}-{seed}.lox"));
+        std::fs::create_dir_all(regressions()).unwrap();
+        std::fs::write(&path, &source).unwrap();
+        writeln!(failures, "{}: {report}", path.display()).unwrap();
+    }
+
+    assert!(failures.is_empty(), "\n{failures}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_programs() {
+        fuzz(false, generate);
+    }
+
+    #[test]
+    fn test_mutated_programs() {
+        fuzz(true, mutate);
+    }
+
+    #[test]
+    fn test_regressions() {
+        let Ok(entries) = std::fs::read_dir(regressions()) else {
+            return;
+        };
+
+        let mut failures = String::new();
+        for entry in entries {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            let source = std::fs::read_to_string(&path).unwrap();
+            if let Err(report) = check(name.starts_with("mutated-"), &source) {
+                writeln!(failures, "{}: {report}", path.display()).unwrap();
+            }
+        }
+
+        assert!(failures.is_empty(), "\n{failures}");
+    }
+
+    #[test]
+    fn test_generate_is_deterministic() {
+        assert_eq!(generate(7, 30), generate(7, 30));
+        assert_ne!(generate(7, 30), generate(8, 30));
+        assert_eq!(mutate(7, 30), mutate(7, 30));
+    }
+}
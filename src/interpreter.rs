@@ -0,0 +1,574 @@
+use crate::ast::{self, Expr, Stmt};
+use crate::resolver::{Locals, Slot};
+use crate::runtime::{self, Code};
+use crate::token::{Token, TokenType};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+pub type Value = runtime::Value<ast::Function>;
+type LoxFunction = runtime::LoxFunction<ast::Function>;
+type LoxClass = runtime::LoxClass<ast::Function>;
+type LoxInstance = runtime::LoxInstance<ast::Function>;
+type Environment = runtime::Environment<ast::Function>;
+type CycleCollector = runtime::CycleCollector<ast::Function>;
+type Unwind = runtime::Unwind<ast::Function>;
+
+impl Code for ast::Function {
+    fn name(&self) -> &str {
+        &self.name.lexeme
+    }
+
+    fn arity(&self) -> usize {
+        self.params.len()
+    }
+}
+
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub token: Token,
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub(crate) fn new(token: &Token, message: impl Into<String>) -> Self {
+        Self {
+            token: token.clone(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}\n[line {}]", self.message, self.token.line)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+pub struct Interpreter {
+    globals: HashMap<String, Value>,
+    /// `None` while executing top level code.
+    environment: Option<Rc<RefCell<Environment>>>,
+    locals: Locals,
+    collector: CycleCollector,
+    /// How many calls are running.
+    depth: usize,
+    out: Box<dyn Write>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Where `print` statements write to.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        let globals = runtime::natives()
+            .into_iter()
+            .map(|native| (native.name.to_string(), Value::Native(native)))
+            .collect();
+
+        Self {
+            globals,
+            environment: None,
+            locals: Locals::default(),
+            collector: CycleCollector::default(),
+            depth: 0,
+            out,
+        }
+    }
+
+    /// Records the slots computed by the resolver, variables without one are
+    /// looked up in the globals.
+    pub fn resolve(&mut self, locals: Locals) {
+        self.locals.extend(locals);
+    }
+
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<(), RuntimeError> {
+        for statement in statements {
+            match self.execute(statement) {
+                Ok(()) => {}
+                Err(Unwind::Error(err)) => return Err(err),
+                Err(Unwind::Return(_)) => unreachable!("resolver rejects top level returns"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute(&mut self, statement: &Stmt) -> Result<(), Unwind> {
+        // Nothing is borrowed between statements, so it's a safe point to
+        // look for garbage.
+        if self.collector.should_collect() {
+            self.collector.collect();
+        }
+
+        match statement {
+            Stmt::Block(statements) => {
+                let environment = Environment::new(self.environment.clone());
+                let environment = self.collector.environment(environment);
+                self.execute_block(statements, environment)?;
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class_declaration(name, superclass.as_ref(), methods)?,
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+            }
+            Stmt::Function(declaration) => {
+                let function = self.collector.function(LoxFunction {
+                    code: declaration.clone(),
+                    closure: self.environment.clone(),
+                    is_initializer: false,
+                });
+                self.define(&declaration.name, Value::Function(function));
+            }
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                if self.evaluate(cond)?.is_truthy() {
+                    self.execute(then_branch)?;
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)?;
+                }
+            }
+            Stmt::Print { value, .. } => {
+                let value = self.evaluate(value)?;
+                writeln!(self.out, "{value}").expect("failed to write to output");
+            }
+            Stmt::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Nil,
+                };
+                return Err(Unwind::Return(value));
+            }
+            Stmt::Var { name, init } => {
+                let value = match init {
+                    Some(init) => self.evaluate(init)?,
+                    None => Value::Nil,
+                };
+                self.define(name, value);
+            }
+            Stmt::While { cond, body, .. } => {
+                while self.evaluate(cond)?.is_truthy() {
+                    self.execute(body)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Rc<RefCell<Environment>>,
+    ) -> Result<(), Unwind> {
+        let previous = self.environment.replace(environment);
+
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement));
+
+        self.environment = previous;
+
+        result
+    }
+
+    fn class_declaration(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &[Rc<ast::Function>],
+    ) -> Result<(), RuntimeError> {
+        let superclass = match superclass {
+            Some(expr) => match self.evaluate(expr)? {
+                Value::Class(class) => Some(class),
+                _ => {
+                    let token = match expr {
+                        Expr::Variable { name, .. } => name,
+                        _ => name,
+                    };
+                    return Err(RuntimeError::new(token, "Superclass must be a class."));
+                }
+            },
+            None => None,
+        };
+
+        let slot = self
+            .environment
+            .as_ref()
+            .map(|environment| environment.borrow().values.len());
+        self.define(name, Value::Nil);
+
+        let enclosing = self.environment.clone();
+        if let Some(superclass) = &superclass {
+            let mut environment = Environment::new(self.environment.clone());
+            environment.define(Value::Class(superclass.clone()));
+            self.environment = Some(self.collector.environment(environment));
+        }
+
+        let methods = methods
+            .iter()
+            .map(|method| {
+                let function = self.collector.function(LoxFunction {
+                    code: method.clone(),
+                    closure: self.environment.clone(),
+                    is_initializer: method.name.lexeme == "init",
+                });
+                (method.name.lexeme.clone(), function)
+            })
+            .collect();
+
+        self.environment = enclosing;
+
+        let class = LoxClass {
+            name: name.lexeme.clone(),
+            superclass,
+            methods,
+        };
+        let class = Value::Class(self.collector.class(class));
+        match (&self.environment, slot) {
+            (Some(environment), Some(index)) => environment.borrow_mut().values[index] = class,
+            _ => self.define(name, class),
+        }
+
+        Ok(())
+    }
+
+    fn define(&mut self, name: &Token, value: Value) {
+        match &self.environment {
+            Some(environment) => environment.borrow_mut().define(value),
+            None => {
+                self.globals.insert(name.lexeme.clone(), value);
+            }
+        }
+    }
+
+    fn current_environment(&self) -> &Rc<RefCell<Environment>> {
+        self.environment
+            .as_ref()
+            .expect("resolver bound a local variable at the top level")
+    }
+
+    fn evaluate(&mut self, expr: &Expr) -> Result<Value, RuntimeError> {
+        match expr {
+            Expr::Assign { id, name, value } => {
+                let value = self.evaluate(value)?;
+                match self.locals.get(id) {
+                    Some(&slot) => {
+                        Environment::assign_at(self.current_environment(), slot, value.clone())
+                    }
+                    None => match self.globals.get_mut(&name.lexeme) {
+                        Some(slot) => *slot = value.clone(),
+                        None => return Err(runtime::undefined_variable(name)),
+                    },
+                }
+                Ok(value)
+            }
+            Expr::Binary { lhs, op, rhs } => {
+                let left = self.evaluate(lhs)?;
+                let right = self.evaluate(rhs)?;
+                binary(left, op, right)
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                let callee = self.evaluate(callee)?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.evaluate(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call(callee, paren, args)
+            }
+            Expr::Get { object, name } => match self.evaluate(object)? {
+                Value::Instance(instance) => {
+                    runtime::get_property(&instance, name, &mut self.collector)
+                }
+                _ => Err(RuntimeError::new(name, "Only instances have properties.")),
+            },
+            Expr::Grouping(expr) => self.evaluate(expr),
+            Expr::Literal { value, .. } => Ok(Value::from(value)),
+            Expr::Logical { lhs, op, rhs } => {
+                let left = self.evaluate(lhs)?;
+
+                let short_circuits = if op.ty == TokenType::Or {
+                    left.is_truthy()
+                } else {
+                    !left.is_truthy()
+                };
+
+                if short_circuits {
+                    Ok(left)
+                } else {
+                    self.evaluate(rhs)
+                }
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => match (self.evaluate(object)?, self.evaluate(value)?) {
+                (Value::Instance(instance), value) => {
+                    instance
+                        .borrow_mut()
+                        .fields
+                        .insert(name.lexeme.clone(), value.clone());
+                    Ok(value)
+                }
+                _ => Err(RuntimeError::new(name, "Only instances have fields.")),
+            },
+            Expr::Super { id, method, .. } => {
+                let slot = self.locals[id];
+                let environment = self.current_environment().clone();
+                let superclass = match Environment::get_at(&environment, slot) {
+                    Value::Class(class) => class,
+                    _ => unreachable!("'super' is always bound to a class"),
+                };
+                // "this" is always bound right inside the scope holding "super".
+                let this = Slot {
+                    depth: slot.depth - 1,
+                    index: 0,
+                };
+                let object = match Environment::get_at(&environment, this) {
+                    Value::Instance(instance) => instance,
+                    _ => unreachable!("'this' is always bound to an instance"),
+                };
+
+                match superclass.find_method(&method.lexeme) {
+                    Some(function) => {
+                        Ok(Value::Function(function.bind(object, &mut self.collector)))
+                    }
+                    None => Err(runtime::undefined_property(method)),
+                }
+            }
+            Expr::This { id, keyword } => self.look_up_variable(*id, keyword),
+            Expr::Unary { op, rhs } => {
+                let right = self.evaluate(rhs)?;
+
+                match (&op.ty, right) {
+                    (TokenType::Bang, right) => Ok(Value::Bool(!right.is_truthy())),
+                    (TokenType::Minus, Value::Number(n)) => Ok(Value::Number(-n)),
+                    (TokenType::Minus, _) => {
+                        Err(RuntimeError::new(op, "Operand must be a number."))
+                    }
+                    _ => unreachable!("not an unary operator: {op:?}"),
+                }
+            }
+            Expr::Variable { id, name } => self.look_up_variable(*id, name),
+        }
+    }
+
+    fn look_up_variable(&self, id: ast::ExprId, name: &Token) -> Result<Value, RuntimeError> {
+        match self.locals.get(&id) {
+            Some(&slot) => Ok(Environment::get_at(self.current_environment(), slot)),
+            None => self
+                .globals
+                .get(&name.lexeme)
+                .cloned()
+                .ok_or_else(|| runtime::undefined_variable(name)),
+        }
+    }
+
+    fn call(
+        &mut self,
+        callee: Value,
+        paren: &Token,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        runtime::check_call(&callee, paren, args.len())?;
+
+        match callee {
+            Value::Function(function) => self.call_function(&function, paren, args),
+            Value::Native(native) => Ok((native.function)(&args)),
+            Value::Class(class) => {
+                let instance = self.collector.instance(LoxInstance {
+                    class: class.clone(),
+                    fields: HashMap::new(),
+                });
+
+                if let Some(init) = class.find_method("init") {
+                    let init = init.bind(instance.clone(), &mut self.collector);
+                    self.call_function(&init, paren, args)?;
+                }
+
+                Ok(Value::Instance(instance))
+            }
+            _ => unreachable!("check_call rejects other callees"),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &LoxFunction,
+        paren: &Token,
+        args: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        runtime::check_depth(self.depth, paren)?;
+
+        let mut environment = Environment::new(function.closure.clone());
+        for arg in args {
+            environment.define(arg);
+        }
+
+        let environment = self.collector.environment(environment);
+        self.depth += 1;
+        let result = self.execute_block(&function.code.body, environment);
+        self.depth -= 1;
+
+        match result {
+            Ok(()) => Ok(function.result(Value::Nil)),
+            Err(Unwind::Return(value)) => Ok(function.result(value)),
+            Err(Unwind::Error(err)) => Err(err),
+        }
+    }
+}
+
+fn binary(left: Value, op: &Token, right: Value) -> Result<Value, RuntimeError> {
+    match (left, &op.ty, right) {
+        // comparisons
+        (Value::Number(l), TokenType::Greater, Value::Number(r)) => Ok(Value::Bool(l > r)),
+        (Value::Number(l), TokenType::GreaterEqual, Value::Number(r)) => Ok(Value::Bool(l >= r)),
+        (Value::Number(l), TokenType::Less, Value::Number(r)) => Ok(Value::Bool(l < r)),
+        (Value::Number(l), TokenType::LessEqual, Value::Number(r)) => Ok(Value::Bool(l <= r)),
+        (l, TokenType::BangEqual, r) => Ok(Value::Bool(!l.is_equal(&r))),
+        (l, TokenType::EqualEqual, r) => Ok(Value::Bool(l.is_equal(&r))),
+
+        // arithmetic
+        (Value::Number(l), TokenType::Minus, Value::Number(r)) => Ok(Value::Number(l - r)),
+        (Value::Number(l), TokenType::Slash, Value::Number(r)) => Ok(Value::Number(l / r)),
+        (Value::Number(l), TokenType::Star, Value::Number(r)) => Ok(Value::Number(l * r)),
+        (Value::Number(l), TokenType::Plus, Value::Number(r)) => Ok(Value::Number(l + r)),
+
+        // concatenation
+        (Value::String(s), TokenType::Plus, Value::String(u)) => {
+            Ok(Value::String(format!("{s}{u}").into()))
+        }
+
+        (_, TokenType::Plus, _) => Err(RuntimeError::new(
+            op,
+            "Operands must be two numbers or two strings.",
+        )),
+        _ => Err(RuntimeError::new(op, "Operands must be numbers.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{Interpreter, Value};
+    use crate::parser::Parser;
+    use crate::programs::{self, Output};
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    fn run(interpreter: &mut Interpreter, source: &str) {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let locals = Resolver::new().resolve(&statements).unwrap();
+        interpreter.resolve(locals);
+        interpreter.interpret(&statements).unwrap();
+    }
+
+    #[test]
+    fn test_programs() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let locals = Resolver::new().resolve(&statements).unwrap();
+
+            let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+            interpreter.resolve(locals);
+            if let Err(err) = interpreter.interpret(&statements) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // Calls nest as deep as the frames of the VM go, one more fails
+        // instead of overflowing the Rust stack.
+        let source = "
+        fun count(n) { if (n == 0) return \"done\"; return count(n - 1); }
+        print count(1022);
+        print count(1023);
+        ";
+        let (output, err) = programs::with_stack(|| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let locals = Resolver::new().resolve(&statements).unwrap();
+
+            let output = Output::default();
+            let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+            interpreter.resolve(locals);
+            let err = interpreter.interpret(&statements).unwrap_err();
+            (output.contents(), err.to_string())
+        });
+        assert_eq!(output, "done\n");
+        assert_eq!(err, "Stack overflow.\n[line 2]");
+    }
+
+    #[test]
+    fn test_collects_cycles() {
+        let mut interpreter = Interpreter::with_output(Box::new(std::io::sink()));
+        run(
+            &mut interpreter,
+            "
+            class Node {
+              init() { this.self = this; }
+            }
+            fun make() {
+              fun recurse() { return recurse; }
+              return recurse;
+            }
+            var node = Node();
+            var function = make();
+            ",
+        );
+        let Some(Value::Instance(node)) = interpreter.globals.get("node") else {
+            panic!("node is an instance");
+        };
+        let Some(Value::Function(function)) = interpreter.globals.get("function") else {
+            panic!("function is a function");
+        };
+        let node = Rc::downgrade(node);
+        let function = Rc::downgrade(function);
+
+        // The instance references itself, and the function the environment
+        // it's stored in, so dropping the globals isn't enough.
+        run(&mut interpreter, "node = nil; function = nil;");
+        assert!(node.upgrade().is_some());
+        assert!(function.upgrade().is_some());
+
+        // Making more cycles gets them collected along the way, the tracked
+        // objects staying bounded.
+        run(
+            &mut interpreter,
+            "for (var i = 0; i < 100000; i = i + 1) { Node(); make(); }",
+        );
+        assert!(node.upgrade().is_none());
+        assert!(function.upgrade().is_none());
+        let tracked = interpreter.collector.objects.len();
+        assert!(tracked < 100_000, "{tracked} objects tracked");
+    }
+}
@@ -1,7 +1,33 @@
+pub mod ast;
+pub mod bench;
+pub mod chunk;
+pub mod closure_compiler;
+pub mod compiler;
+pub mod debug;
+#[cfg(test)]
+mod differential;
 pub mod error;
 pub mod expr;
+pub mod formatter;
+#[cfg(test)]
+mod fuzz;
 pub mod interpreter;
+pub mod loxc;
+pub mod memory;
+pub mod object;
+pub mod optimizer;
 pub mod parser;
+pub mod peephole;
+pub mod pretty;
 pub mod print;
+#[cfg(test)]
+mod programs;
+pub mod register;
+pub mod resolver;
+pub mod runtime;
 pub mod scanner;
+pub mod shape;
 pub mod token;
+pub mod value;
+pub mod verifier;
+pub mod vm;
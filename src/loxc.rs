@@ -0,0 +1,308 @@
+//! The `.loxc` file format, compiled scripts that can run without their
+//! source.
+//!
+//! Everything is little endian:
+//!
+//! ```text
+//! file     → "LOXC" version:u16 function
+//! function → name:option<string> arity:u32 upvalues:u32 code:bytes lines constants
+//! lines    → count:u32 (line:u32 run:u32)*
+//! constant → 0:u8 number:f64 | 1:u8 string | 2:u8 function
+//! bytes    → length:u32 byte*
+//! string   → bytes, valid UTF-8
+//! option   → 0:u8 | 1:u8 value
+//! ```
+//!
+//! Lines are run-length encoded, as consecutive instructions usually share
+//! theirs.
+
+use crate::chunk::{Chunk, Constant, Function};
+use std::fmt;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped on every change to the format or to the meaning of the opcodes.
+pub const VERSION: u16 = 4;
+
+/// Deeper nesting than any sane script has, keeping malicious files from
+/// overflowing the stack of the loader.
+const MAX_NESTING: usize = 256;
+
+const NUMBER: u8 = 0;
+const STRING: u8 = 1;
+const FUNCTION: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    Truncated,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::NotBytecode => write!(f, "Not a compiled Lox file."),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Compiled with format version {version}, but only version {VERSION} is supported."
+            ),
+            Self::Truncated => write!(f, "Compiled file is truncated."),
+            Self::Malformed(reason) => write!(f, "Compiled file is malformed: {reason}."),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Serializes a compiled script.
+pub fn write(script: &Function) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    write_function(&mut out, script);
+    out
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function) {
+    match &function.name {
+        Some(name) => {
+            out.push(1);
+            write_bytes(out, name.as_bytes());
+        }
+        None => out.push(0),
+    }
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_bytes(out, &chunk.code);
+
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for &line in &chunk.lines {
+        match runs.last_mut() {
+            Some((last, run)) if *last == line => *run += 1,
+            _ => runs.push((line, 1)),
+        }
+    }
+    write_u32(out, runs.len());
+    for (line, run) in runs {
+        write_u32(out, line);
+        write_u32(out, run);
+    }
+
+    write_u32(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant {
+            Constant::Number(n) => {
+                out.push(NUMBER);
+                out.extend(n.to_le_bytes());
+            }
+            Constant::String(s) => {
+                out.push(STRING);
+                write_bytes(out, s.as_bytes());
+            }
+            Constant::Function(function) => {
+                out.push(FUNCTION);
+                write_function(out, function);
+            }
+        }
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("too big for the compiled format");
+    out.extend(n.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len());
+    out.extend(bytes);
+}
+
+/// Deserializes a compiled script.
+///
+/// Only the structure of the file is checked here, the bytecode itself can
+/// still be nonsense.
+pub fn read(bytes: &[u8]) -> Result<Rc<Function>, LoadError> {
+    let mut reader = Reader { bytes, position: 0 };
+
+    if !bytes.starts_with(MAGIC) {
+        return Err(if MAGIC.starts_with(bytes) {
+            LoadError::Truncated
+        } else {
+            LoadError::NotBytecode
+        });
+    }
+    reader.position = MAGIC.len();
+
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let script = reader.function(0)?;
+    if reader.position != bytes.len() {
+        return Err(LoadError::Malformed("trailing bytes after the script"));
+    }
+    Ok(Rc::new(script))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.position.checked_add(n).ok_or(LoadError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(LoadError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], LoadError> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<Rc<str>, LoadError> {
+        let bytes = self.bytes()?;
+        let string =
+            std::str::from_utf8(bytes).map_err(|_| LoadError::Malformed("string is not UTF-8"))?;
+        Ok(string.into())
+    }
+
+    fn function(&mut self, depth: usize) -> Result<Function, LoadError> {
+        if depth > MAX_NESTING {
+            return Err(LoadError::Malformed("functions are nested too deeply"));
+        }
+
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(LoadError::Malformed("invalid function name")),
+        };
+        let arity = self.u32()?;
+        let upvalue_count = self.u32()?;
+        let code = self.bytes()?.to_vec();
+
+        let runs = self.u32()?;
+        let mut lines = vec![];
+        for _ in 0..runs {
+            let line = self.u32()?;
+            let run = self.u32()?;
+            if lines.len() + run > code.len() {
+                return Err(LoadError::Malformed("more lines than instructions"));
+            }
+            lines.extend(std::iter::repeat_n(line, run));
+        }
+        if lines.len() != code.len() {
+            return Err(LoadError::Malformed("fewer lines than instructions"));
+        }
+
+        let count = self.u32()?;
+        // Not preallocating from an untrusted count, a bogus one just runs out
+        // of bytes to read.
+        let mut constants = vec![];
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                NUMBER => Constant::Number(f64::from_le_bytes(self.array()?)),
+                STRING => Constant::String(self.string()?),
+                FUNCTION => Constant::Function(Rc::new(self.function(depth + 1)?)),
+                _ => return Err(LoadError::Malformed("unknown constant type")),
+            };
+            constants.push(constant);
+        }
+
+        Ok(Function {
+            name,
+            arity,
+            upvalue_count,
+            chunk: Chunk {
+                code,
+                constants,
+                lines,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::loxc::{self, LoadError, VERSION};
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn compiled() -> Vec<u8> {
+        let source = "
+        fun greet(name) {
+          print \"hello \" + name;
+        }
+        class A { method() { return 1.5; } }
+        greet(\"world\");
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+        loxc::write(&script)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = compiled();
+        let script = loxc::read(&bytes).unwrap();
+        assert_eq!(loxc::write(&script), bytes);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let bytes = compiled();
+        for len in 0..bytes.len() {
+            assert_eq!(
+                loxc::read(&bytes[..len]),
+                Err(LoadError::Truncated),
+                "length {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejected() {
+        assert_eq!(loxc::read(b"print 1;"), Err(LoadError::NotBytecode));
+
+        let mut bytes = compiled();
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            loxc::read(&bytes).unwrap_err().to_string(),
+            format!(
+                "Compiled with format version {}, but only version {VERSION} is supported.",
+                VERSION + 1
+            )
+        );
+
+        let mut bytes = compiled();
+        bytes.push(0);
+        assert_eq!(
+            loxc::read(&bytes),
+            Err(LoadError::Malformed("trailing bytes after the script"))
+        );
+    }
+}
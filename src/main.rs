@@ -1,30 +1,428 @@
+use lox0::ast::Stmt;
+use lox0::bench::{self, CountingAllocator, Measurement};
+use lox0::chunk::Function;
+use lox0::closure_compiler::ClosureCompiler;
+use lox0::compiler::Compiler;
+use lox0::debug::disassemble_chunk;
+use lox0::formatter;
+use lox0::interpreter::Interpreter;
+use lox0::loxc;
+use lox0::optimizer;
 use lox0::parser::Parser;
+use lox0::register;
+use lox0::resolver::{Locals, Resolver};
 use lox0::scanner::Scanner;
+use lox0::verifier;
+use lox0::vm::Vm;
+use std::fmt::Display;
 use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+const USAGE: &str = "\
+Usage: rlox [--backend interpreter|closure|vm|register] [--disassemble]
+            [--trace] [--gc-stress] [--gc-stats] [--no-optimize] [script]
+       rlox compile <script> [output]
+       rlox bench [--runs n] [--backend name]... [--baseline file]
+                  [--save file] [--threshold percent] [--no-optimize]
+                  [program]...
+       rlox fmt [--check] [--width n] <script>...";
+
+/// Counts allocations once `bench` asks, it's otherwise the system allocator.
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+enum RunError {
+    Compile,
+    Runtime,
+}
+
+/// The execution engines programs can run on, they only differ in speed.
+enum Backend {
+    Interpreter(Interpreter),
+    Closure(ClosureCompiler),
+    Vm {
+        vm: Box<Vm>,
+        /// Whether to list the bytecode of every script before running it.
+        disassemble: bool,
+        /// Whether to report what the collector did after every script.
+        gc_stats: bool,
+        /// Whether to run the peephole optimizer over the bytecode.
+        optimize: bool,
+    },
+    Register(Box<register::vm::Vm>),
+}
+
+impl Backend {
+    fn new(name: &str) -> Option<Self> {
+        match name {
+            "interpreter" => Some(Self::Interpreter(Interpreter::new())),
+            "closure" => Some(Self::Closure(ClosureCompiler::new())),
+            "vm" => Some(Self::Vm {
+                vm: Box::new(Vm::new()),
+                disassemble: false,
+                gc_stats: false,
+                optimize: true,
+            }),
+            "register" => Some(Self::Register(Box::new(register::vm::Vm::new()))),
+            _ => None,
+        }
+    }
+
+    fn resolve(&mut self, locals: Locals) {
+        match self {
+            Self::Interpreter(interpreter) => interpreter.resolve(locals),
+            Self::Closure(compiler) => compiler.resolve(locals),
+            // The bytecode compilers track locals on their own.
+            Self::Vm { .. } | Self::Register(_) => {}
+        }
+    }
+
+    fn interpret(&mut self, statements: &[Stmt]) -> Result<(), RunError> {
+        let result = match self {
+            Self::Interpreter(interpreter) => interpreter
+                .interpret(statements)
+                .map_err(|err| err.to_string()),
+            Self::Closure(compiler) => compiler
+                .interpret(statements)
+                .map_err(|err| err.to_string()),
+            Self::Vm { optimize, .. } => {
+                let script = compile(statements, *optimize)?;
+                return self.run_compiled(script);
+            }
+            Self::Register(vm) => {
+                let script = register::compiler::Compiler::new()
+                    .compile(statements)
+                    .map_err(report_errors)?;
+                vm.interpret(script).map_err(|err| err.to_string())
+            }
+        };
+
+        result.map_err(|err| {
+            eprintln!("{err}");
+            RunError::Runtime
+        })
+    }
+
+    /// Runs bytecode, which only the VM can do.
+    fn run_compiled(&mut self, script: Rc<Function>) -> Result<(), RunError> {
+        let Self::Vm {
+            vm,
+            disassemble,
+            gc_stats,
+            ..
+        } = self
+        else {
+            unreachable!("only the VM runs bytecode");
+        };
+
+        if *disassemble {
+            print!("{}", disassemble_chunk(&script.chunk, &script.to_string()));
+        }
+        let result = vm.interpret(script).map_err(|err| {
+            eprintln!("{err}");
+            RunError::Runtime
+        });
+
+        if *gc_stats {
+            let stats = vm.gc_stats();
+            eprintln!(
+                "gc: {} collections, {} bytes allocated, {} bytes freed",
+                stats.collections, stats.bytes_allocated, stats.bytes_freed
+            );
+        }
+        result
+    }
+}
+
+/// The tree-walking backends recurse on the Rust stack, and calls nesting as
+/// deep as the VM allows take more of it than the main thread has in debug
+/// builds.
+const STACK_SIZE: usize = 64 << 20;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().skip(1).collect();
+    let cli = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(cli)?;
+    match cli.join() {
+        Ok(result) => Ok(result?),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+fn cli() -> io::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().is_some_and(|arg| arg == "compile") {
+        args.next();
+        let script = args.next().unwrap_or_else(|| usage());
+        let output = args.next();
+        if args.next().is_some() {
+            usage();
+        }
+        compile_file(&script, output.as_deref())?;
+        return Ok(());
+    }
+    if args.peek().is_some_and(|arg| arg == "bench") {
+        args.next();
+        bench::count_allocations();
+        run_benches(args)?;
+        return Ok(());
+    }
+    if args.peek().is_some_and(|arg| arg == "fmt") {
+        args.next();
+        format_files(args)?;
+        return Ok(());
+    }
+
+    let mut backend = None;
+    let mut disassemble = false;
+    let mut trace = false;
+    let mut gc_stress = false;
+    let mut gc_stats = false;
+    let mut optimize = true;
+    let mut script = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--backend" => backend = Some(args.next().unwrap_or_else(|| usage())),
+            "--disassemble" => disassemble = true,
+            "--trace" => trace = true,
+            "--gc-stress" => gc_stress = true,
+            "--gc-stats" => gc_stats = true,
+            "--no-optimize" => optimize = false,
+            _ if arg.starts_with("--") => usage(),
+            _ if script.is_none() => script = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    // Listings, traces and compiled files are all bytecode, so they go with
+    // the VM.
+    let compiled = script.as_ref().is_some_and(|s| s.ends_with(".loxc"));
+    let bytecode = disassemble || trace || gc_stress || gc_stats || compiled;
+    let default = if bytecode { "vm" } else { "interpreter" };
+    let backend = backend.unwrap_or_else(|| default.into());
+    let mut backend = Backend::new(&backend).unwrap_or_else(|| usage());
+    match &mut backend {
+        Backend::Vm {
+            vm,
+            disassemble: d,
+            gc_stats: s,
+            optimize: o,
+        } => {
+            vm.set_trace(trace);
+            vm.set_gc_stress(gc_stress);
+            *d = disassemble;
+            *s = gc_stats;
+            *o = optimize;
+        }
+        _ if bytecode => usage(),
+        _ => {}
+    }
+
+    match script {
+        Some(script) => run_file(backend, &script, optimize)?,
+        None => run_prompt(backend, optimize)?,
+    }
+
+    Ok(())
+}
+
+fn usage() -> ! {
+    println!("{USAGE}");
+    std::process::exit(64);
+}
+
+fn exit(err: RunError) -> ! {
+    match err {
+        RunError::Compile => std::process::exit(65),
+        RunError::Runtime => std::process::exit(70),
+    }
+}
+
+fn run_file(mut backend: Backend, file_path: &str, optimize: bool) -> io::Result<()> {
+    let result = if file_path.ends_with(".loxc") {
+        let script = loxc::read(&std::fs::read(file_path)?).map_err(|err| err.to_string());
+        let verified = script.and_then(|script| {
+            verifier::verify(&script).map_err(|err| err.to_string())?;
+            Ok(script)
+        });
 
-    if args.len() > 1 {
-        println!("Usage: rlox [script]");
-        std::process::exit(64);
-    } else if args.len() == 1 {
-        run_file(&args[0])?;
+        match verified {
+            Ok(script) => backend.run_compiled(script),
+            Err(err) => {
+                eprintln!("{file_path}: {err}");
+                Err(RunError::Compile)
+            }
+        }
     } else {
-        run_prompt()?;
+        run(&mut backend, &std::fs::read_to_string(file_path)?, optimize)
+    };
+
+    result.or_else(|err| exit(err))
+}
+
+/// Writes the bytecode of a script next to it, or to `output`.
+fn compile_file(file_path: &str, output: Option<&str>) -> io::Result<()> {
+    let source = std::fs::read_to_string(file_path)?;
+    let (statements, _) = parse(&source, true).unwrap_or_else(|err| exit(err));
+    let script = compile(&statements, true).unwrap_or_else(|err| exit(err));
+
+    let output = match output {
+        Some(output) => output.into(),
+        None => Path::new(file_path).with_extension("loxc"),
+    };
+    std::fs::write(output, loxc::write(&script))
+}
+
+/// Runs the benchmark programs on every backend, or those picked, and
+/// reports how they did, compared to a baseline saved by an earlier run if
+/// there's one. Exits with 1 if any got slower, or allocated more, than the
+/// threshold allows.
+fn run_benches(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut runs = 1;
+    let mut optimize = true;
+    let mut backends = vec![];
+    let mut baseline = None;
+    let mut save = None;
+    let mut threshold = 10.0;
+    let mut programs = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--runs" => runs = value().parse().unwrap_or_else(|_| usage()),
+            "--backend" => backends.push(value()),
+            "--baseline" => baseline = Some(value()),
+            "--save" => save = Some(value()),
+            "--threshold" => threshold = value().parse().unwrap_or_else(|_| usage()),
+            "--no-optimize" => optimize = false,
+            _ if arg.starts_with("--") => usage(),
+            _ => programs.push(arg),
+        }
+    }
+    if backends
+        .iter()
+        .any(|b| !bench::BACKENDS.contains(&b.as_str()))
+        || programs
+            .iter()
+            .any(|p| !bench::PROGRAMS.iter().any(|(name, _)| name == p))
+    {
+        usage();
+    }
+
+    let baseline = match baseline {
+        Some(path) => bench::from_json(&std::fs::read_to_string(&path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {err}")))?,
+        None => vec![],
+    };
+
+    println!(
+        "{:<16} {:<12} {:>10} {:>10} {:>10} {:>12}",
+        "program", "backend", "min", "median", "stddev", "allocations"
+    );
+    let mut measurements = vec![];
+    let mut regressions = 0;
+    for (program, source) in bench::PROGRAMS {
+        if !programs.is_empty() && !programs.iter().any(|p| p == program) {
+            continue;
+        }
+        for backend in bench::BACKENDS {
+            if !backends.is_empty() && !backends.iter().any(|b| b == backend) {
+                continue;
+            }
+            let stats = bench::measure(backend, source, runs, optimize).unwrap_or_else(|err| {
+                eprintln!("{program} on {backend}: {err}");
+                exit(RunError::Runtime)
+            });
+
+            print!(
+                "{program:<16} {backend:<12} {:>8.2}ms {:>8.2}ms {:>8.2}ms {:>12}",
+                stats.min_ms, stats.median_ms, stats.stddev_ms, stats.allocations
+            );
+            let base = baseline
+                .iter()
+                .find(|m| m.program == *program && m.backend == *backend);
+            if let Some(Measurement { stats: base, .. }) = base {
+                let change = |now: f64, then: f64| (now - then) / then * 100.0;
+                let time = change(stats.median_ms, base.median_ms);
+                let allocations = change(stats.allocations as f64, base.allocations.max(1) as f64);
+                print!("  median {time:+.1}%, allocations {allocations:+.1}%");
+                if time > threshold || allocations > threshold {
+                    print!("  REGRESSION");
+                    regressions += 1;
+                }
+            }
+            println!();
+
+            measurements.push(Measurement {
+                program: program.to_string(),
+                backend: backend.to_string(),
+                stats,
+            });
+        }
     }
 
+    if let Some(path) = save {
+        std::fs::write(path, bench::to_json(&measurements))?;
+    }
+    if regressions > 0 {
+        println!("\n{regressions} regressions over {threshold}%");
+        std::process::exit(1);
+    }
     Ok(())
 }
 
-fn run_file(file_path: &str) -> io::Result<()> {
-    run(&std::fs::read_to_string(file_path)?);
+/// Formats scripts in place, or only lists those that aren't formatted with
+/// `--check`, exiting with 1 if there are any.
+fn format_files(mut args: impl Iterator<Item = String>) -> io::Result<()> {
+    let mut check = false;
+    let mut width = 80;
+    let mut files = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--width" => {
+                width = args
+                    .next()
+                    .and_then(|width| width.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            _ if arg.starts_with("--") => usage(),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        usage();
+    }
+
+    let mut unformatted = 0;
+    for file in &files {
+        let source = std::fs::read_to_string(file)?;
+        let formatted = formatter::format(&source, width).unwrap_or_else(|errors| {
+            let errors = errors.iter().map(|err| format!("{file}: {err}"));
+            exit(report_errors(errors.collect()))
+        });
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{file}");
+            unformatted += 1;
+        } else {
+            std::fs::write(file, formatted)?;
+        }
+    }
+
+    if unformatted > 0 {
+        std::process::exit(1);
+    }
     Ok(())
 }
 
-fn run_prompt() -> io::Result<()> {
+fn run_prompt(mut backend: Backend, optimize: bool) -> io::Result<()> {
     let stdin = io::stdin();
-    // let mut had_error = false;
 
     loop {
         print!("> ");
@@ -38,20 +436,41 @@ fn run_prompt() -> io::Result<()> {
             break Ok(());
         }
 
-        print!("{line}");
-        run(&line);
-        // had_error = false;
+        // errors were already reported, the session goes on
+        let _ = run(&mut backend, &line, optimize);
     }
 }
 
-fn run(source: &str) {
+fn run(backend: &mut Backend, source: &str, optimize: bool) -> Result<(), RunError> {
+    let (statements, locals) = parse(source, optimize)?;
+    backend.resolve(locals);
+    backend.interpret(&statements)
+}
+
+/// Scans, parses and resolves a script, optimizing it unless told not to.
+fn parse(source: &str, optimize: bool) -> Result<(Vec<Stmt>, Locals), RunError> {
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
-    let token_types = tokens.into_iter().map(|token| token.ty).collect();
-    let _parser = Parser::new(&token_types);
-    // println!("{:?}", tokens);
-    //
-    // for token in tokens {
-    //     println!("{}", token);
-    // }
+    let tokens = scanner.scan_tokens().map_err(report_errors)?;
+    let statements = Parser::new(&tokens).parse().map_err(report_errors)?;
+    let locals = Resolver::new()
+        .resolve(&statements)
+        .map_err(report_errors)?;
+
+    if optimize {
+        return Ok((optimizer::optimize(&statements), locals));
+    }
+    Ok((statements, locals))
+}
+
+fn compile(statements: &[Stmt], optimize: bool) -> Result<Rc<Function>, RunError> {
+    let mut compiler = Compiler::new();
+    compiler.set_optimize(optimize);
+    compiler.compile(statements).map_err(report_errors)
+}
+
+fn report_errors(errors: Vec<impl Display>) -> RunError {
+    for err in errors {
+        eprintln!("{err}");
+    }
+    RunError::Compile
 }
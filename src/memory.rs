@@ -0,0 +1,491 @@
+use crate::object::{
+    Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjRef,
+    ObjRegisterFunction, ObjUpvalue,
+};
+use crate::shape::InlineCache;
+use crate::value::{Value, ValueKind};
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+/// The heap doesn't bother collecting before it holds this much.
+const MIN_NEXT_GC: usize = 1024 * 1024;
+
+/// How much the heap can grow after a collection before the next one, as a
+/// multiple of what survived.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcStats {
+    /// Over the whole life of the heap.
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub collections: usize,
+}
+
+/// Where the objects of the VM live, reclaimed by a mark and sweep
+/// collector.
+///
+/// The heap doesn't know the roots, so the VM drives collections: it asks
+/// [`Heap::should_collect`] before allocating, marks everything it holds
+/// and then calls [`Heap::collect`].
+pub struct Heap {
+    /// Freed slots are `None`, waiting in `free` to be reused.
+    objects: Vec<Option<Obj>>,
+    marks: Vec<bool>,
+    /// Size of every object when it was last measured, at allocation or by
+    /// [`Heap::resized`], which is what gets accounted for when it's freed.
+    sizes: Vec<usize>,
+    free: Vec<u32>,
+    /// Every string in the heap, there's never two with the same contents.
+    /// Strings don't stay alive just for being in here.
+    strings: HashMap<Rc<str>, ObjRef>,
+    /// Marked objects whose references haven't been marked yet.
+    gray: Vec<ObjRef>,
+    bytes_in_use: usize,
+    next_gc: usize,
+    /// Collects before every allocation, to flush out objects the VM forgets
+    /// to mark.
+    stress: bool,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: vec![],
+            marks: vec![],
+            sizes: vec![],
+            free: vec![],
+            strings: HashMap::new(),
+            gray: vec![],
+            bytes_in_use: 0,
+            next_gc: MIN_NEXT_GC,
+            stress: false,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl Heap {
+    /// Moves an object into the heap. Strings must have been looked up with
+    /// [`Heap::find_string`] first, so they stay unique.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_in_use += size;
+        self.stats.bytes_allocated += size;
+
+        let string = match &obj {
+            Obj::String(s) => Some(s.clone()),
+            _ => None,
+        };
+        let reference = match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = Some(obj);
+                self.sizes[index as usize] = size;
+                ObjRef(index)
+            }
+            None => {
+                let index = u32::try_from(self.objects.len()).expect("VM heap is full");
+                self.objects.push(Some(obj));
+                self.marks.push(false);
+                self.sizes.push(size);
+                ObjRef(index)
+            }
+        };
+
+        if let Some(string) = string {
+            debug_assert!(!self.strings.contains_key(&string), "string not interned");
+            self.strings.insert(string, reference);
+        }
+        reference
+    }
+
+    /// Measures an object again after it grew in place, like an instance
+    /// getting a field or a class a method, accounting for the difference.
+    pub fn resized(&mut self, obj: ObjRef) {
+        let size = self.get(obj).size();
+        let old = mem::replace(&mut self.sizes[obj.0 as usize], size);
+        if size >= old {
+            self.bytes_in_use += size - old;
+            self.stats.bytes_allocated += size - old;
+        } else {
+            self.bytes_in_use -= old - size;
+            self.stats.bytes_freed += old - size;
+        }
+    }
+
+    /// The string object with these contents, if there's one.
+    pub fn find_string(&self, s: &str) -> Option<ObjRef> {
+        self.strings.get(s).copied()
+    }
+
+    /// Every object in the heap.
+    pub fn objects(&self) -> impl Iterator<Item = &Obj> {
+        self.objects.iter().flatten()
+    }
+
+    pub fn objects_mut(&mut self) -> impl Iterator<Item = &mut Obj> {
+        self.objects.iter_mut().flatten()
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        self.objects[obj.0 as usize]
+            .as_ref()
+            .expect("use of a freed object")
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        self.objects[obj.0 as usize]
+            .as_mut()
+            .expect("use of a freed object")
+    }
+
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_in_use > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let ValueKind::Obj(obj) = value.kind() {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        mark(&mut self.marks, &mut self.gray, obj);
+    }
+
+    /// Marks what an object that isn't in the heap yet refers to, so it's
+    /// all still there once the object is.
+    pub fn mark_references(&mut self, obj: &Obj) {
+        obj.references(|reference| mark(&mut self.marks, &mut self.gray, reference));
+    }
+
+    /// Frees everything not reachable from the objects marked since the last
+    /// collection.
+    pub fn collect(&mut self) {
+        while let Some(obj) = self.gray.pop() {
+            let Self {
+                objects,
+                marks,
+                gray,
+                ..
+            } = self;
+            let obj = objects[obj.0 as usize]
+                .as_ref()
+                .expect("marked a freed object");
+            obj.references(|reference| mark(marks, gray, reference));
+        }
+
+        for (index, obj) in self.objects.iter_mut().enumerate() {
+            if mem::take(&mut self.marks[index]) || obj.is_none() {
+                continue;
+            }
+
+            if let Some(Obj::String(s)) = obj.take() {
+                self.strings.remove(&s);
+            }
+            self.free.push(index as u32);
+            self.bytes_in_use -= self.sizes[index];
+            self.stats.bytes_freed += self.sizes[index];
+        }
+
+        self.stats.collections += 1;
+        self.next_gc = (self.bytes_in_use * GC_HEAP_GROW_FACTOR).max(MIN_NEXT_GC);
+    }
+
+    pub fn string(&self, obj: ObjRef) -> &Rc<str> {
+        match self.get(obj) {
+            Obj::String(s) => s,
+            _ => unreachable!("not a string"),
+        }
+    }
+
+    pub fn function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            _ => unreachable!("not a function"),
+        }
+    }
+
+    pub fn function_mut(&mut self, obj: ObjRef) -> &mut ObjFunction {
+        match self.get_mut(obj) {
+            Obj::Function(function) => function,
+            _ => unreachable!("not a function"),
+        }
+    }
+
+    pub fn register_function(&self, obj: ObjRef) -> &ObjRegisterFunction {
+        match self.get(obj) {
+            Obj::RegisterFunction(function) => function,
+            _ => unreachable!("not a register function"),
+        }
+    }
+
+    pub fn register_function_mut(&mut self, obj: ObjRef) -> &mut ObjRegisterFunction {
+        match self.get_mut(obj) {
+            Obj::RegisterFunction(function) => function,
+            _ => unreachable!("not a register function"),
+        }
+    }
+
+    pub fn closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            _ => unreachable!("not a closure"),
+        }
+    }
+
+    pub fn closure_mut(&mut self, obj: ObjRef) -> &mut ObjClosure {
+        match self.get_mut(obj) {
+            Obj::Closure(closure) => closure,
+            _ => unreachable!("not a closure"),
+        }
+    }
+
+    pub fn upvalue(&self, obj: ObjRef) -> &ObjUpvalue {
+        match self.get(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => unreachable!("not an upvalue"),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            _ => unreachable!("not an upvalue"),
+        }
+    }
+
+    pub fn class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            _ => unreachable!("not a class"),
+        }
+    }
+
+    pub fn class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Obj::Class(class) => class,
+            _ => unreachable!("not a class"),
+        }
+    }
+
+    pub fn instance(&self, obj: ObjRef) -> &ObjInstance {
+        match self.get(obj) {
+            Obj::Instance(instance) => instance,
+            _ => unreachable!("not an instance"),
+        }
+    }
+
+    pub fn instance_mut(&mut self, obj: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(obj) {
+            Obj::Instance(instance) => instance,
+            _ => unreachable!("not an instance"),
+        }
+    }
+
+    pub fn bound_method(&self, obj: ObjRef) -> &ObjBoundMethod {
+        match self.get(obj) {
+            Obj::BoundMethod(bound) => bound,
+            _ => unreachable!("not a bound method"),
+        }
+    }
+
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
+        match (a.kind(), b.kind()) {
+            (ValueKind::Nil, ValueKind::Nil) => true,
+            (ValueKind::Bool(l), ValueKind::Bool(r)) => l == r,
+            (ValueKind::Number(l), ValueKind::Number(r)) => l == r,
+            // Strings are interned, so equal ones are the same object.
+            (ValueKind::Obj(l), ValueKind::Obj(r)) => l == r,
+            _ => false,
+        }
+    }
+
+    /// Formats a value the way `print` shows it.
+    pub fn display(&self, value: Value) -> impl fmt::Display + '_ {
+        DisplayValue { heap: self, value }
+    }
+}
+
+fn mark(marks: &mut [bool], gray: &mut Vec<ObjRef>, obj: ObjRef) {
+    let marked = &mut marks[obj.0 as usize];
+    if !*marked {
+        *marked = true;
+        gray.push(obj);
+    }
+}
+
+impl Obj {
+    /// A rough count of the bytes the object takes.
+    fn size(&self) -> usize {
+        let entry = mem::size_of::<(ObjRef, ObjRef)>();
+        let payload = match self {
+            Obj::String(s) => s.len(),
+            Obj::Function(function) => {
+                #[cfg(feature = "threaded-dispatch")]
+                let threaded =
+                    function.threaded.len() * mem::size_of::<crate::vm::threaded::Threaded>();
+                #[cfg(not(feature = "threaded-dispatch"))]
+                let threaded = 0;
+
+                function.constants.len() * mem::size_of::<Value>()
+                    + function.caches.len() * mem::size_of::<InlineCache>()
+                    + threaded
+            }
+            Obj::RegisterFunction(function) => {
+                function.constants.len() * mem::size_of::<Value>()
+                    + function.caches.len() * mem::size_of::<InlineCache>()
+            }
+            Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+            Obj::Class(class) => class.methods.capacity() * entry,
+            Obj::Instance(instance) => instance.fields.capacity() * mem::size_of::<Value>(),
+            Obj::Upvalue(_) | Obj::Native(_) | Obj::BoundMethod(_) => 0,
+        };
+        mem::size_of::<Obj>() + payload
+    }
+
+    fn references(&self, mut mark: impl FnMut(ObjRef)) {
+        let mut mark_value = |value: Value| {
+            if let ValueKind::Obj(obj) = value.kind() {
+                mark(obj);
+            }
+        };
+
+        match self {
+            Obj::String(_) | Obj::Native(_) => {}
+            Obj::Function(function) => function.constants.iter().copied().for_each(mark_value),
+            Obj::RegisterFunction(function) => {
+                function.constants.iter().copied().for_each(mark_value)
+            }
+            Obj::Closure(closure) => {
+                mark_value(Value::obj(closure.function));
+                closure
+                    .upvalues
+                    .iter()
+                    .for_each(|upvalue| mark_value(Value::obj(*upvalue)));
+            }
+            // Open upvalues point into the stack, which is a root anyway.
+            Obj::Upvalue(ObjUpvalue::Open(_)) => {}
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => mark_value(*value),
+            Obj::Class(class) => {
+                for (name, method) in &class.methods {
+                    mark_value(Value::obj(*name));
+                    mark_value(Value::obj(*method));
+                }
+            }
+            Obj::Instance(instance) => {
+                mark_value(Value::obj(instance.class));
+                instance.fields.iter().copied().for_each(mark_value);
+            }
+            Obj::BoundMethod(bound) => {
+                mark_value(bound.receiver);
+                mark_value(Value::obj(bound.method));
+            }
+        }
+    }
+}
+
+struct DisplayValue<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+impl fmt::Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let obj = match self.value.kind() {
+            ValueKind::Nil => return write!(f, "nil"),
+            ValueKind::Bool(b) => return write!(f, "{b}"),
+            ValueKind::Number(n) => return write!(f, "{n}"),
+            ValueKind::Obj(obj) => obj,
+        };
+
+        match self.heap.get(obj) {
+            Obj::String(s) => write!(f, "{s}"),
+            Obj::Function(function) => write!(f, "{}", function.function),
+            Obj::RegisterFunction(function) => write!(f, "{}", function.function),
+            Obj::Closure(closure) => self.function(f, closure.function),
+            Obj::Upvalue(_) => write!(f, "upvalue"),
+            Obj::Native(_) => write!(f, "<native fn>"),
+            Obj::Class(class) => write!(f, "{}", class.name),
+            Obj::Instance(instance) => {
+                write!(f, "{} instance", self.heap.class(instance.class).name)
+            }
+            Obj::BoundMethod(bound) => {
+                let closure = self.heap.closure(bound.method);
+                self.function(f, closure.function)
+            }
+        }
+    }
+}
+
+impl DisplayValue<'_> {
+    /// Shows the function of a closure, compiled for either VM.
+    fn function(&self, f: &mut fmt::Formatter<'_>, function: ObjRef) -> Result<(), fmt::Error> {
+        match self.heap.get(function) {
+            Obj::Function(function) => write!(f, "{}", function.function),
+            Obj::RegisterFunction(function) => write!(f, "{}", function.function),
+            _ => unreachable!("closures are made of functions"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Heap;
+    use crate::object::{Obj, ObjInstance};
+    use crate::shape::Shapes;
+    use crate::value::Value;
+
+    #[test]
+    fn test_interned_strings_are_weak() {
+        let mut heap = Heap::default();
+        let kept = heap.alloc(Obj::String("kept".into()));
+        let dropped = heap.alloc(Obj::String("dropped".into()));
+        assert_eq!(heap.find_string("kept"), Some(kept));
+        assert_eq!(heap.find_string("dropped"), Some(dropped));
+
+        heap.mark_object(kept);
+        heap.collect();
+        assert_eq!(heap.find_string("kept"), Some(kept));
+        assert_eq!(heap.find_string("dropped"), None);
+
+        // The slot gets reused, under the new contents only.
+        let again = heap.alloc(Obj::String("again".into()));
+        assert_eq!(again, dropped);
+        assert_eq!(heap.find_string("again"), Some(again));
+        assert_eq!(heap.find_string("dropped"), None);
+    }
+
+    #[test]
+    fn test_growth_is_accounted() {
+        let mut heap = Heap::default();
+        let class = heap.alloc(Obj::String("class".into()));
+        let instance = heap.alloc(Obj::Instance(ObjInstance {
+            class,
+            shape: Shapes::default().root(),
+            fields: vec![],
+        }));
+        let allocated = heap.stats().bytes_allocated;
+
+        heap.instance_mut(instance).fields.extend([Value::NIL; 100]);
+        heap.resized(instance);
+        let grown = heap.stats().bytes_allocated - allocated;
+        assert!(grown >= 100 * std::mem::size_of::<Value>(), "{grown}");
+
+        // All of it is freed along with the instance.
+        heap.collect();
+        let stats = heap.stats();
+        assert_eq!(stats.bytes_freed, stats.bytes_allocated);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::chunk::Function;
+use crate::register;
+use crate::shape::{InlineCache, ShapeId};
+use crate::value::Value;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Handle to an object in the VM heap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub(crate) u32);
+
+pub enum Obj {
+    String(Rc<str>),
+    Function(ObjFunction),
+    RegisterFunction(ObjRegisterFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Native(NativeFunction),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+}
+
+/// A compiled function loaded into the VM, with its constant pool turned into
+/// runtime values.
+pub struct ObjFunction {
+    pub function: Rc<Function>,
+    pub constants: Vec<Value>,
+    /// The inline caches of the property accesses, at the offset of their
+    /// instruction.
+    pub caches: Vec<InlineCache>,
+    /// The code decoded for threaded dispatch.
+    #[cfg(feature = "threaded-dispatch")]
+    pub threaded: Rc<[crate::vm::threaded::Threaded]>,
+    /// How many times it was called or looped, until it's compiled.
+    #[cfg(feature = "jit")]
+    pub hotness: u32,
+    /// Its machine code, once it got hot.
+    #[cfg(feature = "jit")]
+    pub jit: Option<Rc<crate::vm::jit::Code>>,
+}
+
+/// A function compiled for the register VM, loaded like an [`ObjFunction`].
+pub struct ObjRegisterFunction {
+    pub function: Rc<register::Function>,
+    pub constants: Vec<Value>,
+    /// The inline caches of the property accesses, at the index of their
+    /// instruction.
+    pub caches: Vec<InlineCache>,
+}
+
+/// A function along with the variables it captured, what functions are at
+/// runtime.
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A captured variable. It stays on the stack while its scope runs, shared by
+/// the closures capturing it, and moves into the upvalue when the scope ends.
+pub enum ObjUpvalue {
+    /// Index of the variable in the stack.
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Clone, Copy)]
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: fn(&[Value]) -> Value,
+}
+
+/// Methods are keyed by their interned name, so looking them up never
+/// compares strings.
+pub struct ObjClass {
+    pub name: Rc<str>,
+    pub methods: HashMap<ObjRef, ObjRef>,
+    /// What new instances start with.
+    pub shape: ShapeId,
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    /// Where `fields` has the value of each field.
+    pub shape: ShapeId,
+    pub fields: Vec<Value>,
+}
+
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
@@ -0,0 +1,478 @@
+//! An optimization pass over the AST, running after the resolver, so code it
+//! removes still gets its errors reported, and before any backend.
+//!
+//! It folds constant subexpressions, with the evaluator of [`expr`], and
+//! replaces reads of locals initialized with a constant and never assigned
+//! with the constant. Conditions that fold drop the branch never taken, and
+//! loops that never run go away. Only the truthiness of conditions matters,
+//! so `!!x` is just `x` there.
+//!
+//! None of it changes what programs do: expressions that would fail at
+//! runtime are left alone, to fail there, with their operands folded. Every
+//! variable use that stays keeps its id, so the resolver's locals still
+//! apply.
+//!
+//! [`expr`]: crate::expr
+
+use crate::ast::{Expr, Function, Stmt};
+use crate::expr::{self, Binary, Literal, Unary};
+use crate::token::{Token, TokenType};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// Optimizes resolved statements.
+pub fn optimize(statements: &[Stmt]) -> Vec<Stmt> {
+    Optimizer { scopes: vec![] }.statements(statements)
+}
+
+/// A block, or the body of a function.
+struct Scope {
+    /// The constant value of every local declared so far, if it has one.
+    locals: HashMap<String, Option<TokenType>>,
+    /// Every name assigned anywhere in the scope, a local with one of those
+    /// names might not stay the same.
+    assigned: HashSet<String>,
+}
+
+struct Optimizer {
+    /// Globals aren't tracked, they can be assigned before they're declared
+    /// and declared again.
+    scopes: Vec<Scope>,
+}
+
+impl Optimizer {
+    fn statements(&mut self, statements: &[Stmt]) -> Vec<Stmt> {
+        statements
+            .iter()
+            .filter_map(|statement| self.stmt(statement))
+            .collect()
+    }
+
+    fn scoped<T>(
+        &mut self,
+        params: &[Token],
+        body: &[Stmt],
+        optimize: impl FnOnce(&mut Self) -> T,
+    ) -> T {
+        let mut assigned = HashSet::new();
+        for statement in body {
+            assignments_in_stmt(statement, &mut assigned);
+        }
+        let locals = params
+            .iter()
+            .map(|param| (param.lexeme.clone(), None))
+            .collect();
+        self.scopes.push(Scope { locals, assigned });
+        let optimized = optimize(self);
+        self.scopes.pop();
+        optimized
+    }
+
+    fn declare(&mut self, name: &Token, value: Option<TokenType>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            let value = value.filter(|_| !scope.assigned.contains(&name.lexeme));
+            scope.locals.insert(name.lexeme.clone(), value);
+        }
+    }
+
+    /// The statement optimized, unless it does nothing.
+    fn stmt(&mut self, stmt: &Stmt) -> Option<Stmt> {
+        Some(match stmt {
+            Stmt::Block(statements) => {
+                Stmt::Block(self.scoped(&[], statements, |o| o.statements(statements)))
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                self.declare(name, None);
+                Stmt::Class {
+                    name: name.clone(),
+                    superclass: superclass.clone(),
+                    methods: methods.iter().map(|method| self.function(method)).collect(),
+                }
+            }
+            Stmt::Expression(expr) => match self.expr(expr) {
+                Expr::Literal { .. } => return None,
+                expr => Stmt::Expression(expr),
+            },
+            Stmt::Function(function) => {
+                self.declare(&function.name, None);
+                Stmt::Function(self.function(function))
+            }
+            Stmt::If {
+                line,
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.condition(cond);
+                if let Expr::Literal { value, .. } = cond {
+                    return match (value.is_truthy(), else_branch) {
+                        (true, _) => self.stmt(then_branch),
+                        (false, Some(else_branch)) => self.stmt(else_branch),
+                        (false, None) => None,
+                    };
+                }
+                Stmt::If {
+                    line: *line,
+                    cond,
+                    then_branch: Box::new(self.body(then_branch)),
+                    else_branch: else_branch
+                        .as_ref()
+                        .and_then(|else_branch| self.stmt(else_branch))
+                        .map(Box::new),
+                }
+            }
+            Stmt::Print { line, value } => Stmt::Print {
+                line: *line,
+                value: self.expr(value),
+            },
+            Stmt::Return { keyword, value } => Stmt::Return {
+                keyword: keyword.clone(),
+                value: value.as_ref().map(|value| self.expr(value)),
+            },
+            Stmt::Var { name, init } => {
+                let init = init.as_ref().map(|init| self.expr(init));
+                let value = match &init {
+                    Some(Expr::Literal { value, .. }) => Some(value.clone()),
+                    _ => None,
+                };
+                self.declare(name, value);
+                Stmt::Var {
+                    name: name.clone(),
+                    init,
+                }
+            }
+            Stmt::While { line, cond, body } => {
+                let cond = self.condition(cond);
+                if matches!(&cond, Expr::Literal { value, .. } if !value.is_truthy()) {
+                    return None;
+                }
+                Stmt::While {
+                    line: *line,
+                    cond,
+                    body: Box::new(self.body(body)),
+                }
+            }
+        })
+    }
+
+    /// The body of an `if` or a loop, which has to stay, if only empty.
+    fn body(&mut self, stmt: &Stmt) -> Stmt {
+        self.stmt(stmt).unwrap_or(Stmt::Block(vec![]))
+    }
+
+    fn function(&mut self, function: &Function) -> Rc<Function> {
+        let body = self.scoped(&function.params, &function.body, |o| {
+            o.statements(&function.body)
+        });
+        Rc::new(Function {
+            name: function.name.clone(),
+            params: function.params.clone(),
+            body,
+        })
+    }
+
+    /// An expression only its truthiness matters of, so double negations go.
+    fn condition(&mut self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Unary { op, rhs } if op.ty == TokenType::Bang => match ungrouped(rhs) {
+                Expr::Unary { op, rhs } if op.ty == TokenType::Bang => self.condition(rhs),
+                _ => self.expr(expr),
+            },
+            Expr::Grouping(inner) => match self.condition(inner) {
+                literal @ Expr::Literal { .. } => literal,
+                inner => Expr::Grouping(Box::new(inner)),
+            },
+            Expr::Logical { lhs, op, rhs } => {
+                let lhs = self.condition(lhs);
+                let rhs = self.condition(rhs);
+                logical(lhs, op, rhs)
+            }
+            _ => self.expr(expr),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Assign { id, name, value } => Expr::Assign {
+                id: *id,
+                name: name.clone(),
+                value: Box::new(self.expr(value)),
+            },
+            Expr::Binary { lhs, op, rhs } => {
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                if let (Expr::Literal { value: l, .. }, Expr::Literal { value: r, .. }) =
+                    (&lhs, &rhs)
+                {
+                    let folded = Binary::new(
+                        Literal::boxed(l.clone()),
+                        op.ty.clone(),
+                        Literal::boxed(r.clone()),
+                    );
+                    if let Some(value) = expr::Expr::visit(&folded) {
+                        let line = op.line;
+                        return Expr::Literal { value, line };
+                    }
+                }
+                Expr::Binary {
+                    lhs: Box::new(lhs),
+                    op: op.clone(),
+                    rhs: Box::new(rhs),
+                }
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => Expr::Call {
+                callee: Box::new(self.expr(callee)),
+                paren: paren.clone(),
+                args: args.iter().map(|arg| self.expr(arg)).collect(),
+            },
+            Expr::Get { object, name } => Expr::Get {
+                object: Box::new(self.expr(object)),
+                name: name.clone(),
+            },
+            Expr::Grouping(inner) => match self.expr(inner) {
+                literal @ Expr::Literal { .. } => literal,
+                inner => Expr::Grouping(Box::new(inner)),
+            },
+            Expr::Literal { .. } | Expr::Super { .. } | Expr::This { .. } => expr.clone(),
+            Expr::Logical { lhs, op, rhs } => {
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                logical(lhs, op, rhs)
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => Expr::Set {
+                object: Box::new(self.expr(object)),
+                name: name.clone(),
+                value: Box::new(self.expr(value)),
+            },
+            Expr::Unary { op, rhs } => {
+                // `!` only looks at whether its operand is truthy.
+                let rhs = match op.ty {
+                    TokenType::Bang => self.condition(rhs),
+                    _ => self.expr(rhs),
+                };
+                if let Expr::Literal { value, .. } = &rhs {
+                    let folded = Unary::new(op.ty.clone(), Literal::boxed(value.clone()));
+                    if let Some(value) = expr::Expr::visit(&folded) {
+                        let line = op.line;
+                        return Expr::Literal { value, line };
+                    }
+                }
+                Expr::Unary {
+                    op: op.clone(),
+                    rhs: Box::new(rhs),
+                }
+            }
+            Expr::Variable { name, .. } => {
+                let local = self
+                    .scopes
+                    .iter()
+                    .rev()
+                    .find_map(|scope| scope.locals.get(&name.lexeme));
+                match local {
+                    Some(Some(value)) => Expr::Literal {
+                        value: value.clone(),
+                        line: name.line,
+                    },
+                    _ => expr.clone(),
+                }
+            }
+        }
+    }
+}
+
+/// `lhs and rhs` or `lhs or rhs`, short-circuited when `lhs` is a constant.
+fn logical(lhs: Expr, op: &Token, rhs: Expr) -> Expr {
+    match &lhs {
+        Expr::Literal { value, .. } if value.is_truthy() == (op.ty == TokenType::Or) => lhs,
+        Expr::Literal { .. } => rhs,
+        _ => Expr::Logical {
+            lhs: Box::new(lhs),
+            op: op.clone(),
+            rhs: Box::new(rhs),
+        },
+    }
+}
+
+fn ungrouped(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Grouping(inner) => ungrouped(inner),
+        _ => expr,
+    }
+}
+
+/// Adds the names assigned anywhere in `stmt` to `assigned`, functions
+/// declared in it included.
+fn assignments_in_stmt(stmt: &Stmt, assigned: &mut HashSet<String>) {
+    match stmt {
+        Stmt::Block(statements) => {
+            for statement in statements {
+                assignments_in_stmt(statement, assigned);
+            }
+        }
+        Stmt::Class { methods, .. } => {
+            for statement in methods.iter().flat_map(|method| &method.body) {
+                assignments_in_stmt(statement, assigned);
+            }
+        }
+        Stmt::Expression(value) | Stmt::Print { value, .. } => assignments_in_expr(value, assigned),
+        Stmt::Function(function) => {
+            for statement in &function.body {
+                assignments_in_stmt(statement, assigned);
+            }
+        }
+        Stmt::If {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            assignments_in_expr(cond, assigned);
+            assignments_in_stmt(then_branch, assigned);
+            if let Some(else_branch) = else_branch {
+                assignments_in_stmt(else_branch, assigned);
+            }
+        }
+        Stmt::Return { value, .. } | Stmt::Var { init: value, .. } => {
+            if let Some(value) = value {
+                assignments_in_expr(value, assigned);
+            }
+        }
+        Stmt::While { cond, body, .. } => {
+            assignments_in_expr(cond, assigned);
+            assignments_in_stmt(body, assigned);
+        }
+    }
+}
+
+fn assignments_in_expr(expr: &Expr, assigned: &mut HashSet<String>) {
+    match expr {
+        Expr::Assign { name, value, .. } => {
+            assigned.insert(name.lexeme.clone());
+            assignments_in_expr(value, assigned);
+        }
+        Expr::Binary { lhs, rhs, .. } | Expr::Logical { lhs, rhs, .. } => {
+            assignments_in_expr(lhs, assigned);
+            assignments_in_expr(rhs, assigned);
+        }
+        Expr::Call { callee, args, .. } => {
+            assignments_in_expr(callee, assigned);
+            for arg in args {
+                assignments_in_expr(arg, assigned);
+            }
+        }
+        Expr::Get { object, .. } => assignments_in_expr(object, assigned),
+        Expr::Grouping(expr) | Expr::Unary { rhs: expr, .. } => assignments_in_expr(expr, assigned),
+        Expr::Set { object, value, .. } => {
+            assignments_in_expr(object, assigned);
+            assignments_in_expr(value, assigned);
+        }
+        Expr::Literal { .. } | Expr::Super { .. } | Expr::This { .. } | Expr::Variable { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn optimized(source: &str) -> String {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let optimized: Vec<_> = optimize(&statements)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        optimized.join(" ")
+    }
+
+    #[test]
+    fn test_folding() {
+        assert_eq!(optimized("print 1 + 2 * 3;"), "(print 7)");
+        assert_eq!(optimized("print (1 + 2) * 3;"), "(print 9)");
+        assert_eq!(optimized("print \"a\" + \"b\";"), "(print ab)");
+        assert_eq!(optimized("print -(1 - 3) < 3 == !nil;"), "(print true)");
+        assert_eq!(optimized("print nil or 1 and 2;"), "(print 2)");
+        assert_eq!(optimized("print 1 / 0 > 1;"), "(print true)");
+        assert_eq!(optimized("1 + 2;"), "");
+    }
+
+    #[test]
+    fn test_failing_expressions_stay() {
+        assert_eq!(optimized("print -\"a\";"), "(print (- a))");
+        assert_eq!(optimized("print (1 + 2) + nil;"), "(print (+ 3 nil))");
+        assert_eq!(
+            optimized("print 1 < \"2\" or true;"),
+            "(print (or (< 1 2) true))"
+        );
+        assert_eq!(optimized("print 1(2 + 3);"), "(print (call 1 5))");
+    }
+
+    #[test]
+    fn test_propagation() {
+        assert_eq!(
+            optimized("{ var a = 1 + 1; print a * 3; }"),
+            "(block (var a 2) (print 6))"
+        );
+        // Globals can change before they're read.
+        assert_eq!(optimized("var a = 1; print a;"), "(var a 1) (print a)");
+        // So can locals assigned anywhere, closures included.
+        assert_eq!(
+            optimized("{ var a = 1; fun f() { a = 2; } print a; }"),
+            "(block (var a 1) (fun f () (; (= a 2))) (print a))"
+        );
+        assert_eq!(
+            optimized("{ var a = 1; { var a = nil; print a; } print a; }"),
+            "(block (var a 1) (block (var a nil) (print nil)) (print 1))"
+        );
+        assert_eq!(
+            optimized("{ var a = 1; fun f(a) { print a; } class a {} print a; }"),
+            "(block (var a 1) (fun f (a) (print a)) (class a) (print a))"
+        );
+    }
+
+    #[test]
+    fn test_dead_branches() {
+        assert_eq!(optimized("if (1 > 2) print 1; else print 2;"), "(print 2)");
+        assert_eq!(optimized("if (nil) print 1;"), "");
+        assert_eq!(optimized("if (\"\") { print 1; }"), "(block (print 1))");
+        assert_eq!(optimized("while (false) print 1;"), "");
+        assert_eq!(
+            optimized("for (var i = 0; false; i = i + 1) print i;"),
+            "(block (var i 0))"
+        );
+        assert_eq!(
+            optimized("fun f(x) { if (x) if (false) print 1; }"),
+            "(fun f (x) (if x (block)))"
+        );
+    }
+
+    #[test]
+    fn test_double_negation() {
+        assert_eq!(
+            optimized("fun f(x) { if (!!x) print 1; }"),
+            "(fun f (x) (if x (print 1)))"
+        );
+        assert_eq!(
+            optimized("fun f(x, y) { while (!(!x) and !!!y) return; }"),
+            "(fun f (x y) (while (and x (! y)) (return)))"
+        );
+        // Where the value matters, it's a bool, not `x`.
+        assert_eq!(
+            optimized("fun f(x) { print !!x; }"),
+            "(fun f (x) (print (! (! x))))"
+        );
+    }
+}
@@ -1,6 +1,12 @@
 #![allow(dead_code)]
-use crate::expr::{Binary, BoxExpr, Grouping, Literal, Unary};
-use crate::token::TokenType;
+use crate::ast::{Expr, ExprId, Function, Stmt};
+use crate::error::SyntaxError;
+use crate::token::{Token, TokenType};
+use std::rc::Rc;
+
+/// Function declarations and calls are capped like in the reference
+/// implementation, so programs stay portable to the bytecode backends.
+const MAX_ARGS: usize = 255;
 
 /// Simplified grammar:
 ///
@@ -18,45 +24,418 @@ use crate::token::TokenType;
 ///
 /// "Strict"/complete grammar:
 ///
-/// expression     → equality ;
+/// program        → declaration* EOF ;
+///
+/// declaration    → classDecl
+///                | funDecl
+///                | varDecl
+///                | statement ;
+/// classDecl      → "class" IDENTIFIER ( "<" IDENTIFIER )?
+///                  "{" function* "}" ;
+/// funDecl        → "fun" function ;
+/// function       → IDENTIFIER "(" parameters? ")" block ;
+/// parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
+/// varDecl        → "var" IDENTIFIER ( "=" expression )? ";" ;
+///
+/// statement      → exprStmt
+///                | forStmt
+///                | ifStmt
+///                | printStmt
+///                | returnStmt
+///                | whileStmt
+///                | block ;
+/// exprStmt       → expression ";" ;
+/// forStmt        → "for" "(" ( varDecl | exprStmt | ";" )
+///                  expression? ";"
+///                  expression? ")" statement ;
+/// ifStmt         → "if" "(" expression ")" statement
+///                  ( "else" statement )? ;
+/// printStmt      → "print" expression ";" ;
+/// returnStmt     → "return" expression? ";" ;
+/// whileStmt      → "while" "(" expression ")" statement ;
+/// block          → "{" declaration* "}" ;
+///
+/// expression     → assignment ;
+/// assignment     → ( call "." )? IDENTIFIER "=" assignment
+///                | logic_or ;
+/// logic_or       → logic_and ( "or" logic_and )* ;
+/// logic_and      → equality ( "and" equality )* ;
 /// equality       → comparison ( ( "!=" | "==" ) comparison )* ;
 /// comparison     → term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
 /// term           → factor ( ( "-" | "+" ) factor )* ;
 /// factor         → unary ( ( "/" | "*" ) unary )* ;
 /// unary          → ( "!" | "-" ) unary
-///                | primary ;
+///                | call ;
+/// call           → primary ( "(" arguments? ")" | "." IDENTIFIER )* ;
+/// arguments      → expression ( "," expression )* ;
 /// primary        → "(" expression ")"
+///                | "this" | "super" "." IDENTIFIER
+///                | IDENTIFIER
 ///                | literal ;
 /// literal        → NUMBER | STRING | "true" | "false" | "nil" ;
 ///
 pub struct Parser<'a> {
-    tokens: &'a Vec<TokenType>,
+    tokens: &'a [Token],
     current: usize,
+    errors: Vec<SyntaxError>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(tokens: &'a Vec<TokenType>) -> Self {
-        Self { tokens, current: 0 }
+    pub fn new(tokens: &'a [Token]) -> Self {
+        Self {
+            tokens,
+            current: 0,
+            errors: vec![],
+        }
+    }
+
+    /// Parses the whole program, or reports every syntax error in it, going
+    /// on with the next statement after each one.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<SyntaxError>> {
+        let mut statements = vec![];
+
+        while !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    /// A declaration, or `None` if it has an error, which is recorded.
+    fn declaration(&mut self) -> Option<Stmt> {
+        let declaration = if self.match_(&[TokenType::Class]) {
+            self.class_declaration()
+        } else if self.match_(&[TokenType::Fun]) {
+            self.function("function").map(Stmt::Function)
+        } else if self.match_(&[TokenType::Var]) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+
+        declaration
+            .map_err(|err| {
+                self.errors.push(err);
+                self.synchronize();
+            })
+            .ok()
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, SyntaxError> {
+        let name = self.consume_identifier("Expect class name.")?;
+
+        let superclass = if self.match_(&[TokenType::Less]) {
+            let name = self.consume_identifier("Expect superclass name.")?;
+            Some(Expr::Variable {
+                id: ExprId::next(),
+                name,
+            })
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+
+        let mut methods = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    fn function(&mut self, kind: &str) -> Result<Rc<Function>, SyntaxError> {
+        let name = self.consume_identifier(&format!("Expect {kind} name."))?;
+        self.consume(
+            TokenType::LeftParen,
+            &format!("Expect '(' after {kind} name."),
+        )?;
+
+        let mut params = vec![];
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGS {
+                    let err = self.error(self.peek(), "Can't have more than 255 parameters.");
+                    self.errors.push(err);
+                }
+                params.push(self.consume_identifier("Expect parameter name.")?);
+
+                if !self.match_(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {kind} body."),
+        )?;
+        let body = self.block()?;
+
+        Ok(Rc::new(Function { name, params, body }))
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, SyntaxError> {
+        let name = self.consume_identifier("Expect variable name.")?;
+
+        let init = if self.match_(&[TokenType::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+
+        Ok(Stmt::Var { name, init })
+    }
+
+    fn statement(&mut self) -> Result<Stmt, SyntaxError> {
+        if self.match_(&[TokenType::For]) {
+            return self.for_statement();
+        }
+        if self.match_(&[TokenType::If]) {
+            return self.if_statement();
+        }
+        if self.match_(&[TokenType::Print]) {
+            return self.print_statement();
+        }
+        if self.match_(&[TokenType::Return]) {
+            return self.return_statement();
+        }
+        if self.match_(&[TokenType::While]) {
+            return self.while_statement();
+        }
+        if self.match_(&[TokenType::LeftBrace]) {
+            return Ok(Stmt::Block(self.block()?));
+        }
+
+        self.expression_statement()
+    }
+
+    /// There's no `for` node, the loop is desugared into a `while`.
+    fn for_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+
+        let initializer = if self.match_(&[TokenType::Semicolon]) {
+            None
+        } else if self.match_(&[TokenType::Var]) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+
+        let cond = if !self.check(&TokenType::Semicolon) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+
+        let increment = if !self.check(&TokenType::RightParen) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let mut body = self.statement()?;
+
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
+        }
+
+        body = Stmt::While {
+            cond: cond.unwrap_or(Expr::Literal {
+                value: TokenType::Bool(true),
+                line: keyword.line,
+            }),
+            line: keyword.line,
+            body: Box::new(body),
+        };
+
+        if let Some(initializer) = initializer {
+            body = Stmt::Block(vec![initializer, body]);
+        }
+
+        Ok(body)
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let cond = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
+
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.match_(&[TokenType::Else]) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+
+        Ok(Stmt::If {
+            line: keyword.line,
+            cond,
+            then_branch,
+            else_branch,
+        })
     }
 
-    pub fn expression(&mut self) -> BoxExpr {
-        self.equality()
+    fn print_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let keyword = self.previous().clone();
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
+        Ok(Stmt::Print {
+            line: keyword.line,
+            value,
+        })
     }
 
-    fn equality(&mut self) -> BoxExpr {
-        let mut expr = self.comparison();
+    fn return_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let keyword = self.previous().clone();
+
+        let value = if !self.check(&TokenType::Semicolon) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+
+        Ok(Stmt::Return { keyword, value })
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let keyword = self.previous().clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let cond = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let body = Box::new(self.statement()?);
+
+        Ok(Stmt::While {
+            line: keyword.line,
+            cond,
+            body,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, SyntaxError> {
+        let mut statements = vec![];
+
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            if let Some(statement) = self.declaration() {
+                statements.push(statement);
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+
+        Ok(statements)
+    }
+
+    fn expression_statement(&mut self) -> Result<Stmt, SyntaxError> {
+        let expr = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        Ok(Stmt::Expression(expr))
+    }
+
+    pub fn expression(&mut self) -> Result<Expr, SyntaxError> {
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> Result<Expr, SyntaxError> {
+        let expr = self.or()?;
+
+        if self.match_(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
+            let value = Box::new(self.assignment()?);
+
+            return Ok(match expr {
+                Expr::Variable { name, .. } => Expr::Assign {
+                    id: ExprId::next(),
+                    name,
+                    value,
+                },
+                Expr::Get { object, name } => Expr::Set {
+                    object,
+                    name,
+                    value,
+                },
+                // Reported without synchronizing, the parser isn't confused.
+                _ => {
+                    let err = self.error(&equals, "Invalid assignment target.");
+                    self.errors.push(err);
+                    expr
+                }
+            });
+        }
+
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.and()?;
+
+        while self.match_(&[TokenType::Or]) {
+            let op = self.previous().clone();
+            let rhs = self.and()?;
+            expr = Expr::Logical {
+                lhs: Box::new(expr),
+                op,
+                rhs: Box::new(rhs),
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.equality()?;
+
+        while self.match_(&[TokenType::And]) {
+            let op = self.previous().clone();
+            let rhs = self.equality()?;
+            expr = Expr::Logical {
+                lhs: Box::new(expr),
+                op,
+                rhs: Box::new(rhs),
+            };
+        }
+
+        Ok(expr)
+    }
+
+    fn equality(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.comparison()?;
 
         while self.match_(&[TokenType::BangEqual, TokenType::EqualEqual]) {
-            let operator = self.previous().unwrap().clone();
-            let right = self.comparison();
-            expr = Binary::boxed(expr, operator, right);
+            let operator = self.previous().clone();
+            let right = self.comparison()?;
+            expr = binary(expr, operator, right);
         }
 
-        expr
+        Ok(expr)
     }
 
-    fn comparison(&mut self) -> BoxExpr {
-        let mut expr = self.term();
+    fn comparison(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.term()?;
 
         while self.match_(&[
             TokenType::Greater,
@@ -64,88 +443,164 @@ impl<'a> Parser<'a> {
             TokenType::Less,
             TokenType::LessEqual,
         ]) {
-            let operator = self.previous().unwrap().clone();
-            let right = self.term();
-            expr = Binary::boxed(expr, operator, right);
+            let operator = self.previous().clone();
+            let right = self.term()?;
+            expr = binary(expr, operator, right);
         }
 
-        expr
+        Ok(expr)
     }
 
-    fn term(&mut self) -> BoxExpr {
-        let mut expr = self.factor();
+    fn term(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.factor()?;
 
         while self.match_(&[TokenType::Minus, TokenType::Plus]) {
-            let operator = self.previous().unwrap().clone();
-            let right = self.factor();
-            expr = Binary::boxed(expr, operator, right);
+            let operator = self.previous().clone();
+            let right = self.factor()?;
+            expr = binary(expr, operator, right);
         }
 
-        expr
+        Ok(expr)
     }
 
-    fn factor(&mut self) -> BoxExpr {
-        let mut expr = self.unary();
+    fn factor(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.unary()?;
 
         while self.match_(&[TokenType::Slash, TokenType::Star]) {
-            let operator = self.previous().unwrap().clone();
-            let right = self.unary();
-            expr = Binary::boxed(expr, operator, right);
+            let operator = self.previous().clone();
+            let right = self.unary()?;
+            expr = binary(expr, operator, right);
         }
 
-        expr
+        Ok(expr)
     }
 
-    fn unary(&mut self) -> BoxExpr {
+    fn unary(&mut self) -> Result<Expr, SyntaxError> {
         if self.match_(&[TokenType::Bang, TokenType::Minus]) {
-            let operator = self.previous().unwrap().clone();
-            let right = self.unary();
-            return Unary::boxed(operator, right);
+            let op = self.previous().clone();
+            let rhs = Box::new(self.unary()?);
+            return Ok(Expr::Unary { op, rhs });
+        }
+
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Expr, SyntaxError> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.match_(&[TokenType::LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_(&[TokenType::Dot]) {
+                let name = self.consume_identifier("Expect property name after '.'.")?;
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    name,
+                };
+            } else {
+                break;
+            }
         }
 
-        self.primary()
+        Ok(expr)
     }
 
-    fn primary(&mut self) -> BoxExpr {
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, SyntaxError> {
+        let mut args = vec![];
+
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if args.len() >= MAX_ARGS {
+                    let err = self.error(self.peek(), "Can't have more than 255 arguments.");
+                    self.errors.push(err);
+                }
+                args.push(self.expression()?);
+
+                if !self.match_(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+
+        let paren = self
+            .consume(TokenType::RightParen, "Expect ')' after arguments.")?
+            .clone();
+
+        Ok(Expr::Call {
+            callee: Box::new(callee),
+            paren,
+            args,
+        })
+    }
+
+    fn primary(&mut self) -> Result<Expr, SyntaxError> {
+        let literal = |value, token: &Token| Expr::Literal {
+            value,
+            line: token.line,
+        };
         if self.match_(&[TokenType::False]) {
-            return Literal::boxed(TokenType::Bool(false));
+            return Ok(literal(TokenType::Bool(false), self.previous()));
         }
         if self.match_(&[TokenType::True]) {
-            return Literal::boxed(TokenType::Bool(true));
+            return Ok(literal(TokenType::Bool(true), self.previous()));
         }
         if self.match_(&[TokenType::Nil]) {
-            return Literal::boxed(TokenType::Nil);
+            return Ok(literal(TokenType::Nil, self.previous()));
         }
         if self.is_literal() {
-            return Literal::boxed(self.previous().map(|t| t.clone()).unwrap());
+            let token = self.previous();
+            return Ok(literal(token.ty.clone(), token));
+        }
+
+        if self.match_(&[TokenType::Super]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+            let method = self.consume_identifier("Expect superclass method name.")?;
+            return Ok(Expr::Super {
+                id: ExprId::next(),
+                keyword,
+                method,
+            });
+        }
+        if self.match_(&[TokenType::This]) {
+            return Ok(Expr::This {
+                id: ExprId::next(),
+                keyword: self.previous().clone(),
+            });
+        }
+        if self.is_identifier() {
+            return Ok(Expr::Variable {
+                id: ExprId::next(),
+                name: self.previous().clone(),
+            });
         }
 
         if self.match_(&[TokenType::LeftParen]) {
-            let expr = self.expression();
-            self.consume(TokenType::RightParen, "Expect ')' after expression.");
-            return Grouping::boxed(expr);
+            let expr = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+            return Ok(Expr::Grouping(Box::new(expr)));
         }
 
-        unreachable!("maybe? or just compiler/parser error")
+        Err(self.error(self.peek(), "Expect expression."))
     }
 
     fn synchronize(&mut self) {
         self.advance();
 
         while !self.is_at_end() {
-            if self.previous() == Some(&TokenType::Semicolon) {
+            if self.previous().ty == TokenType::Semicolon {
                 return;
             }
 
-            match self.peek() {
-                Some(&TokenType::Class)
-                | Some(&TokenType::Fun)
-                | Some(&TokenType::Var)
-                | Some(&TokenType::For)
-                | Some(&TokenType::If)
-                | Some(&TokenType::While)
-                | Some(&TokenType::Print)
-                | Some(&TokenType::Return) => return,
+            match self.peek().ty {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
                 _ => {}
             }
 
@@ -157,7 +612,17 @@ impl<'a> Parser<'a> {
     fn is_literal(&mut self) -> bool {
         if self.is_at_end() {
             false
-        } else if self.peek().map(|token| token.is_literal()).unwrap_or(false) {
+        } else if self.peek().ty.is_literal() {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    // same as is_literal, for Identifier
+    fn is_identifier(&mut self) -> bool {
+        if matches!(self.peek().ty, TokenType::Identifier(_)) {
             self.advance();
             true
         } else {
@@ -180,11 +645,11 @@ impl<'a> Parser<'a> {
         if self.is_at_end() {
             false
         } else {
-            self.peek().map(|token| token == ty).unwrap_or(false)
+            self.peek().ty == *ty
         }
     }
 
-    fn advance(&mut self) -> Option<&TokenType> {
+    fn advance(&mut self) -> &Token {
         if !self.is_at_end() {
             self.current += 1;
         }
@@ -192,35 +657,55 @@ impl<'a> Parser<'a> {
         self.previous()
     }
 
-    fn consume(&mut self, ty: TokenType, msg: &str) -> Option<&TokenType> {
+    fn consume(&mut self, ty: TokenType, msg: &str) -> Result<&Token, SyntaxError> {
         if self.check(&ty) {
-            return self.advance();
+            return Ok(self.advance());
+        }
+
+        Err(self.error(self.peek(), msg))
+    }
+
+    fn consume_identifier(&mut self, msg: &str) -> Result<Token, SyntaxError> {
+        if self.is_identifier() {
+            return Ok(self.previous().clone());
         }
 
-        self.error(self.peek(), msg);
+        Err(self.error(self.peek(), msg))
     }
 
     fn is_at_end(&self) -> bool {
-        self.peek()
-            .map(|token| *token == TokenType::EOF)
-            .unwrap_or(false)
+        self.peek().ty == TokenType::EOF
     }
 
-    fn peek(&self) -> Option<&TokenType> {
-        self.tokens.get(self.current)
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
     }
 
-    fn previous(&self) -> Option<&TokenType> {
-        self.tokens.get(self.current - 1)
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
     }
 
-    fn error(&self, at_token: Option<&TokenType>, msg: &str) -> ! {
-        panic!("parser error at {at_token:?} {msg}")
+    fn error(&self, at_token: &Token, msg: &str) -> SyntaxError {
+        let location = if at_token.ty == TokenType::EOF {
+            " at end".into()
+        } else {
+            format!(" at '{}'", at_token.lexeme)
+        };
+        SyntaxError {
+            line: at_token.line,
+            location,
+            message: msg.into(),
+        }
     }
 }
 
-#[allow(unused)]
-struct ParseError;
+fn binary(lhs: Expr, op: Token, rhs: Expr) -> Expr {
+    Expr::Binary {
+        lhs: Box::new(lhs),
+        op,
+        rhs: Box::new(rhs),
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -231,11 +716,43 @@ mod tests {
     fn test_parse_expr() {
         let source_code = "1 - (2 * 3) < 4 == false";
         let scanner = Scanner::new(source_code);
-        let tokens = scanner.scan_tokens();
-        let token_types = tokens.into_iter().map(|token| token.ty).collect();
+        let tokens = scanner.scan_tokens().unwrap();
 
-        let mut parser = Parser::new(&token_types);
-        let expr = parser.expression();
+        let mut parser = Parser::new(&tokens);
+        let expr = parser.expression().unwrap();
         assert_eq!(expr.to_string(), "(== (< (- 1 (group (* 2 3))) 4) false)");
     }
+
+    #[test]
+    fn test_parse_for_desugars_to_while() {
+        let source_code = "for (var i = 0; i < 3; i = i + 1) print i;";
+        let scanner = Scanner::new(source_code);
+        let tokens = scanner.scan_tokens().unwrap();
+
+        let mut parser = Parser::new(&tokens);
+        let statements = parser.parse().unwrap();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(
+            statements[0].to_string(),
+            "(block (var i 0) (while (< i 3) (block (print i) (; (= i (+ i 1))))))"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let source_code = "print 1 +;\nvar = 2;\n1 = 2;\nprint 3;\nfun f(";
+        let tokens = Scanner::new(source_code).scan_tokens().unwrap();
+
+        let errors = Parser::new(&tokens).parse().unwrap_err();
+        let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "[line 1] Error at ';': Expect expression.",
+                "[line 2] Error at '=': Expect variable name.",
+                "[line 3] Error at '=': Invalid assignment target.",
+                "[line 5] Error at end: Expect parameter name.",
+            ]
+        );
+    }
 }
@@ -0,0 +1,422 @@
+//! Rewrites of short instruction sequences in compiled bytecode.
+//!
+//! The code is decoded into a list of instructions, jumps pointing at the
+//! index of their target instead of an offset, which keeps them right as
+//! instructions get removed or fused. Sequences are only rewritten when
+//! nothing jumps into their middle. Every byte keeps the line of the
+//! instruction it came from, with the last byte of a superinstruction
+//! holding the line of the part that can fail, where the VM looks for it.
+
+use crate::chunk::{Chunk, Constant, Function, OpCode};
+use std::rc::Rc;
+
+#[derive(Clone)]
+struct Instruction {
+    /// `LOOP`s are `JUMP`s going back, which direction is only picked when
+    /// encoding.
+    op: OpCode,
+    /// The operand bytes, other than the offset of jumps.
+    operands: Vec<u8>,
+    /// Source line of every byte of the instruction.
+    lines: Vec<usize>,
+    /// Index of the instruction a jump goes to.
+    target: Option<usize>,
+}
+
+/// A value known while compiling.
+#[derive(PartialEq)]
+enum Literal<'a> {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(&'a str),
+}
+
+/// Optimizes a function compiled by the [`Compiler`], along with the
+/// functions it defines.
+///
+/// [`Compiler`]: crate::compiler::Compiler
+pub fn optimize(function: &Function) -> Function {
+    let constants: Vec<_> = function
+        .chunk
+        .constants
+        .iter()
+        .map(|constant| match constant {
+            Constant::Function(function) => Constant::Function(Rc::new(optimize(function))),
+            constant => constant.clone(),
+        })
+        .collect();
+
+    let mut instructions = decode(&function.chunk);
+    while thread_jumps(&mut instructions) | rewrite(&mut instructions, &constants) {}
+
+    Function {
+        name: function.name.clone(),
+        arity: function.arity,
+        upvalue_count: function.upvalue_count,
+        chunk: encode(&instructions, constants),
+    }
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    // The instruction starting at every offset, which jumps land on.
+    let mut starting = vec![usize::MAX; chunk.code.len() + 1];
+
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let op = OpCode::try_from(chunk.code[offset]).expect("compiled code decodes");
+        let mut len = 1 + op.operand_bytes();
+        if op == OpCode::Closure {
+            let Constant::Function(function) = &chunk.constants[chunk.code[offset + 1] as usize]
+            else {
+                unreachable!("closures are made of functions");
+            };
+            len += 2 * function.upvalue_count;
+        }
+
+        let operands = &chunk.code[offset + 1..offset + len];
+        let instruction = match op {
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = u16::from_be_bytes([operands[0], operands[1]]) as usize;
+                let target = if op == OpCode::Loop {
+                    offset + 3 - jump
+                } else {
+                    offset + 3 + jump
+                };
+                Instruction {
+                    op: if op == OpCode::Loop { OpCode::Jump } else { op },
+                    operands: vec![],
+                    lines: chunk.lines[offset..offset + len].to_vec(),
+                    // An offset until all instructions are known.
+                    target: Some(target),
+                }
+            }
+            _ => Instruction {
+                op,
+                operands: operands.to_vec(),
+                lines: chunk.lines[offset..offset + len].to_vec(),
+                target: None,
+            },
+        };
+
+        starting[offset] = instructions.len();
+        instructions.push(instruction);
+        offset += len;
+    }
+    starting[offset] = instructions.len();
+
+    for target in instructions.iter_mut().filter_map(|i| i.target.as_mut()) {
+        *target = starting[*target];
+    }
+    instructions
+}
+
+/// The offset every instruction starts at, followed by the length of the
+/// code.
+fn offsets(instructions: &[Instruction]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += instruction.lines.len();
+    }
+    offsets.push(offset);
+    offsets
+}
+
+fn encode(instructions: &[Instruction], constants: Vec<Constant>) -> Chunk {
+    let offsets = offsets(instructions);
+    let mut chunk = Chunk {
+        constants,
+        ..Chunk::default()
+    };
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        let mut op = instruction.op;
+        let mut operands = instruction.operands.clone();
+        if let Some(target) = instruction.target {
+            let after = offsets[i] + 3;
+            let jump = if target <= i {
+                debug_assert_eq!(op, OpCode::Jump, "only unconditional jumps go back");
+                op = OpCode::Loop;
+                after - offsets[target]
+            } else {
+                offsets[target] - after
+            };
+            let jump = u16::try_from(jump).expect("jumps are threaded only if they fit");
+            operands = jump.to_be_bytes().to_vec();
+        }
+
+        chunk.code.push(op as u8);
+        chunk.code.extend(operands);
+        chunk.lines.extend(&instruction.lines);
+    }
+
+    chunk
+}
+
+/// Points jumps landing on another jump to where that one goes, returning
+/// whether there were any.
+///
+/// A `JUMP_IF_FALSE` landing on another one leaves the same value on the
+/// stack for it, so it can skip it too.
+fn thread_jumps(instructions: &mut [Instruction]) -> bool {
+    // Code only shrinks from here, so jumps fitting now always will.
+    let offsets = offsets(instructions);
+    let fits =
+        |from: usize, to: usize| offsets[to].abs_diff(offsets[from] + 3) <= u16::MAX as usize;
+
+    let mut threaded = false;
+    for i in 0..instructions.len() {
+        let Some(mut target) = instructions[i].target else {
+            continue;
+        };
+        let op = instructions[i].op;
+
+        // Jumps can go around in circles.
+        let mut seen = vec![i];
+        while let Some(next) = instructions.get(target) {
+            let followed = match (op, next.op) {
+                (_, OpCode::Jump) | (OpCode::JumpIfFalse, OpCode::JumpIfFalse) => next.target,
+                _ => None,
+            };
+            match followed {
+                Some(followed)
+                    if !seen.contains(&followed)
+                        && fits(i, followed)
+                        && (op == OpCode::Jump || followed > i) =>
+                {
+                    seen.push(target);
+                    target = followed;
+                }
+                _ => break,
+            }
+        }
+
+        if instructions[i].target != Some(target) {
+            instructions[i].target = Some(target);
+            threaded = true;
+        }
+    }
+
+    threaded
+}
+
+/// Makes one pass of [`peephole`] over the code, returning whether it changed
+/// anything.
+fn rewrite(instructions: &mut Vec<Instruction>, constants: &[Constant]) -> bool {
+    let mut targeted = vec![false; instructions.len() + 1];
+    for target in instructions.iter().filter_map(|i| i.target) {
+        targeted[target] = true;
+    }
+
+    let mut rewritten = Vec::with_capacity(instructions.len());
+    // Where every instruction went, the ones removed leaving their jumps to
+    // what came after them.
+    let mut moved = Vec::with_capacity(instructions.len() + 1);
+    let mut changed = false;
+
+    let mut i = 0;
+    while i < instructions.len() {
+        let len = 1
+            + (i + 1..instructions.len().min(i + 3))
+                .take_while(|&next| !targeted[next])
+                .count();
+
+        let (consumed, replacement) = match peephole(&instructions[i..i + len], i, constants) {
+            Some(rewrite) => {
+                changed = true;
+                rewrite
+            }
+            None => (1, vec![instructions[i].clone()]),
+        };
+        moved.extend(std::iter::repeat_n(rewritten.len(), consumed));
+        rewritten.extend(replacement);
+        i += consumed;
+    }
+    moved.push(rewritten.len());
+
+    for target in rewritten.iter_mut().filter_map(|i| i.target.as_mut()) {
+        *target = moved[*target];
+    }
+    *instructions = rewritten;
+    changed
+}
+
+/// Rewrites the start of `window`, the instructions from `index` on that
+/// nothing jumps into, returning how many instructions it replaced and with
+/// what.
+fn peephole(
+    window: &[Instruction],
+    index: usize,
+    constants: &[Constant],
+) -> Option<(usize, Vec<Instruction>)> {
+    let literal = |i: usize| literal(&window[i], constants);
+    let ops: Vec<_> = window.iter().map(|instruction| instruction.op).collect();
+
+    match ops[..] {
+        // Jumps to the next instruction, taken or not.
+        [OpCode::Jump | OpCode::JumpIfFalse, ..] if window[0].target == Some(index + 1) => {
+            Some((1, vec![]))
+        }
+        // Conditions known to hold, the `while (true)` of `for (;;)` above all.
+        [OpCode::True, OpCode::JumpIfFalse, OpCode::Pop] => Some((3, vec![])),
+        [OpCode::False, OpCode::JumpIfFalse, ..] => {
+            let jump = Instruction {
+                op: OpCode::Jump,
+                ..window[1].clone()
+            };
+            Some((2, vec![window[0].clone(), jump]))
+        }
+        // Values pushed only to be popped, like expression statements
+        // without side effects, or locals never read.
+        [OpCode::Constant
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetLocal
+        | OpCode::GetUpvalue, OpCode::Pop, ..] => Some((2, vec![])),
+        [OpCode::Not, OpCode::Pop, ..] => Some((2, vec![window[1].clone()])),
+        [OpCode::GetLocal, OpCode::Constant, op @ (OpCode::Add | OpCode::Subtract | OpCode::Less)] =>
+        {
+            let op = match op {
+                OpCode::Add => OpCode::AddLocalConstant,
+                OpCode::Subtract => OpCode::SubtractLocalConstant,
+                _ => OpCode::LessLocalConstant,
+            };
+            let superinstruction = Instruction {
+                op,
+                operands: vec![window[0].operands[0], window[1].operands[0]],
+                lines: window
+                    .iter()
+                    .map(|instruction| instruction.lines[0])
+                    .collect(),
+                target: None,
+            };
+            Some((3, vec![superinstruction]))
+        }
+        [_, OpCode::Not, ..] => {
+            let value = literal(0)?;
+            let falsey = matches!(value, Literal::Nil | Literal::Bool(false));
+            Some((2, vec![boolean(falsey, &window[1])]))
+        }
+        [_, _, op @ (OpCode::Equal
+        | OpCode::Greater
+        | OpCode::GreaterEqual
+        | OpCode::Less
+        | OpCode::LessEqual), ..] => {
+            let (a, b) = (literal(0)?, literal(1)?);
+            let result = match (op, a, b) {
+                (OpCode::Equal, a, b) => a == b,
+                (op, Literal::Number(a), Literal::Number(b)) => match op {
+                    OpCode::Greater => a > b,
+                    OpCode::GreaterEqual => a >= b,
+                    OpCode::Less => a < b,
+                    _ => a <= b,
+                },
+                // A runtime error, which has to stay one.
+                _ => return None,
+            };
+            Some((3, vec![boolean(result, &window[2])]))
+        }
+        _ => None,
+    }
+}
+
+fn literal<'a>(instruction: &Instruction, constants: &'a [Constant]) -> Option<Literal<'a>> {
+    match instruction.op {
+        OpCode::Nil => Some(Literal::Nil),
+        OpCode::True => Some(Literal::Bool(true)),
+        OpCode::False => Some(Literal::Bool(false)),
+        OpCode::Constant => match &constants[instruction.operands[0] as usize] {
+            Constant::Number(n) => Some(Literal::Number(*n)),
+            Constant::String(s) => Some(Literal::String(s)),
+            Constant::Function(_) => None,
+        },
+        _ => None,
+    }
+}
+
+/// `TRUE` or `FALSE`, on the line of the instruction it replaces.
+fn boolean(value: bool, replaced: &Instruction) -> Instruction {
+    Instruction {
+        op: if value { OpCode::True } else { OpCode::False },
+        operands: vec![],
+        lines: vec![replaced.lines[0]],
+        target: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::debug::disassemble_chunk;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use crate::vm::Vm;
+
+    fn compile(source: &str, optimize: bool) -> std::rc::Rc<crate::chunk::Function> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let mut compiler = Compiler::new();
+        compiler.set_optimize(optimize);
+        compiler.compile(&statements).unwrap()
+    }
+
+    #[test]
+    fn test_optimized_listing() {
+        let source = "\
+fun f(n) {
+  while (true) {
+    if (n < 2 == !nil) return n;
+    n = n - 1;
+  }
+}";
+        let script = compile(source, true);
+
+        let expected = "\
+== <script> ==
+0000    1 OP_CLOSURE          0 '<fn f>'
+0002    | OP_DEFINE_GLOBAL    1 'f'
+0004    | OP_NIL
+0005    | OP_RETURN
+
+== <fn f> ==
+0000    3 OP_LESS_LOCAL_CONSTANT    1    0 '2'
+0003    | OP_TRUE
+0004    | OP_EQUAL
+0005    | OP_JUMP_IF_FALSE    5 -> 15
+0008    | OP_POP
+0009    | OP_GET_LOCAL        1
+0011    | OP_RETURN
+0012    | OP_JUMP            12 -> 16
+0015    | OP_POP
+0016    4 OP_SUBTRACT_LOCAL_CONSTANT    1    1 '1'
+0019    | OP_SET_LOCAL        1
+0021    | OP_POP
+0022    2 OP_LOOP            22 -> 0
+0025    | OP_POP
+0026    | OP_NIL
+0027    | OP_RETURN
+";
+        assert_eq!(disassemble_chunk(&script.chunk, "<script>"), expected);
+    }
+
+    #[test]
+    fn test_runtime_errors_keep_their_line() {
+        let source = "\
+fun f(n) {
+  return n
+    - 1;
+}
+f(\"one\");";
+
+        for optimize in [false, true] {
+            let err = Vm::with_output(Box::new(std::io::sink()))
+                .interpret(compile(source, optimize))
+                .unwrap_err();
+            assert_eq!(err.to_string(), "Operands must be numbers.\n[line 3]");
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Prints the AST back as Lox source, indented two spaces a level, unlike the
+//! S-expressions of `print.rs`, which don't parse.
+//!
+//! Parentheses are groupings, which the AST keeps, plus whatever the
+//! precedence of operators needs for trees that don't say, like the ones
+//! the optimizer rewrites. Parsing what gets printed gives the same tree
+//! back, but for `for` loops, which the parser already turned into `while`
+//! loops, and literals only expressions make, like negative numbers.
+
+use crate::ast::{Expr, Function, Stmt};
+use crate::token::TokenType;
+use std::fmt::Write as _;
+
+/// How tightly an expression binds, loosest first, following the grammar.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Precedence {
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
+}
+
+impl Precedence {
+    fn of(expr: &Expr) -> Self {
+        match expr {
+            Expr::Assign { .. } | Expr::Set { .. } => Self::Assignment,
+            Expr::Binary { op, .. } => Self::of_binary(&op.ty),
+            Expr::Call { .. } | Expr::Get { .. } => Self::Call,
+            Expr::Literal {
+                value: TokenType::Number(n),
+                ..
+            } if !n.is_finite() => Self::Primary,
+            Expr::Literal {
+                value: TokenType::Number(n),
+                ..
+            } if n.is_sign_negative() => Self::Unary,
+            Expr::Logical { op, .. } if op.ty == TokenType::Or => Self::Or,
+            Expr::Logical { .. } => Self::And,
+            Expr::Unary { .. } => Self::Unary,
+            Expr::Grouping(_)
+            | Expr::Literal { .. }
+            | Expr::Super { .. }
+            | Expr::This { .. }
+            | Expr::Variable { .. } => Self::Primary,
+        }
+    }
+
+    fn of_binary(op: &TokenType) -> Self {
+        match op {
+            TokenType::BangEqual | TokenType::EqualEqual => Self::Equality,
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => Self::Comparison,
+            TokenType::Minus | TokenType::Plus => Self::Term,
+            _ => Self::Factor,
+        }
+    }
+
+    /// The one after, which the right operand of a left associative operator
+    /// needs.
+    fn next(self) -> Self {
+        match self {
+            Self::Assignment => Self::Or,
+            Self::Or => Self::And,
+            Self::And => Self::Equality,
+            Self::Equality => Self::Comparison,
+            Self::Comparison => Self::Term,
+            Self::Term => Self::Factor,
+            Self::Factor => Self::Unary,
+            Self::Unary => Self::Call,
+            Self::Call | Self::Primary => Self::Primary,
+        }
+    }
+}
+
+/// The source of a whole program.
+pub fn program(statements: &[Stmt]) -> String {
+    let mut printer = Printer::default();
+    printer.statements(statements);
+    printer.out
+}
+
+/// The source of a single expression.
+pub fn expression(expr: &Expr) -> String {
+    let mut printer = Printer::default();
+    printer.expr(expr, Precedence::Assignment);
+    printer.out
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    indent: usize,
+}
+
+impl Printer {
+    fn newline(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+    }
+
+    /// Statements on lines of their own, at the current indentation.
+    fn statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            for _ in 0..self.indent {
+                self.out.push_str("  ");
+            }
+            self.stmt(statement);
+            self.out.push('\n');
+        }
+    }
+
+    /// Statements between braces, the closing one at the current
+    /// indentation.
+    fn block(&mut self, statements: &[Stmt]) {
+        if statements.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push_str("{\n");
+        self.indent += 1;
+        self.statements(statements);
+        self.indent -= 1;
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push('}');
+    }
+
+    /// A statement from where the line is at, without the line break.
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                write!(self.out, "class {}", name.lexeme).unwrap();
+                if let Some(superclass) = superclass {
+                    self.out.push_str(" < ");
+                    self.expr(superclass, Precedence::Primary);
+                }
+                if methods.is_empty() {
+                    self.out.push_str(" {}");
+                    return;
+                }
+                self.out.push_str(" {");
+                self.indent += 1;
+                for (i, method) in methods.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push('\n');
+                    }
+                    self.newline();
+                    self.function(method);
+                }
+                self.indent -= 1;
+                self.newline();
+                self.out.push('}');
+            }
+            Stmt::Expression(expr) => {
+                self.expr(expr, Precedence::Assignment);
+                self.out.push(';');
+            }
+            Stmt::Function(function) => {
+                self.out.push_str("fun ");
+                self.function(function);
+            }
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.out.push_str("if (");
+                self.expr(cond, Precedence::Assignment);
+                self.out.push_str(") ");
+                match else_branch {
+                    // An `else` after an `if` without one would go to that
+                    // `if` instead.
+                    Some(_) if dangles(then_branch) => {
+                        self.block(std::slice::from_ref(then_branch))
+                    }
+                    _ => self.stmt(then_branch),
+                }
+                if let Some(else_branch) = else_branch {
+                    self.out.push_str(" else ");
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::Print { value, .. } => {
+                self.out.push_str("print ");
+                self.expr(value, Precedence::Assignment);
+                self.out.push(';');
+            }
+            Stmt::Return { value, .. } => {
+                self.out.push_str("return");
+                if let Some(value) = value {
+                    self.out.push(' ');
+                    self.expr(value, Precedence::Assignment);
+                }
+                self.out.push(';');
+            }
+            Stmt::Var { name, init } => {
+                write!(self.out, "var {}", name.lexeme).unwrap();
+                if let Some(init) = init {
+                    self.out.push_str(" = ");
+                    self.expr(init, Precedence::Assignment);
+                }
+                self.out.push(';');
+            }
+            Stmt::While { cond, body, .. } => {
+                self.out.push_str("while (");
+                self.expr(cond, Precedence::Assignment);
+                self.out.push_str(") ");
+                self.stmt(body);
+            }
+        }
+    }
+
+    /// A function or method, from its name on.
+    fn function(&mut self, function: &Function) {
+        let params: Vec<_> = function.params.iter().map(|p| p.lexeme.as_str()).collect();
+        write!(self.out, "{}({}) ", function.name.lexeme, params.join(", ")).unwrap();
+        self.block(&function.body);
+    }
+
+    /// An expression, in parentheses if it binds looser than `min`.
+    fn expr(&mut self, expr: &Expr, min: Precedence) {
+        let precedence = Precedence::of(expr);
+        if precedence < min {
+            self.out.push('(');
+            self.expr(expr, Precedence::Assignment);
+            self.out.push(')');
+            return;
+        }
+
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                write!(self.out, "{} = ", name.lexeme).unwrap();
+                self.expr(value, Precedence::Assignment);
+            }
+            Expr::Binary { lhs, op, rhs } | Expr::Logical { lhs, op, rhs } => {
+                self.expr(lhs, precedence);
+                write!(self.out, " {} ", op.lexeme).unwrap();
+                self.expr(rhs, precedence.next());
+            }
+            Expr::Call { callee, args, .. } => {
+                self.expr(callee, Precedence::Call);
+                self.out.push('(');
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expr(arg, Precedence::Assignment);
+                }
+                self.out.push(')');
+            }
+            Expr::Get { object, name } => {
+                self.expr(object, Precedence::Call);
+                write!(self.out, ".{}", name.lexeme).unwrap();
+            }
+            Expr::Grouping(inner) => {
+                self.out.push('(');
+                self.expr(inner, Precedence::Assignment);
+                self.out.push(')');
+            }
+            Expr::Literal { value, .. } => self.literal(value),
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expr(object, Precedence::Call);
+                write!(self.out, ".{} = ", name.lexeme).unwrap();
+                self.expr(value, Precedence::Assignment);
+            }
+            Expr::Super { method, .. } => write!(self.out, "super.{}", method.lexeme).unwrap(),
+            Expr::This { .. } => self.out.push_str("this"),
+            Expr::Unary { op, rhs } => {
+                self.out.push_str(&op.lexeme);
+                self.expr(rhs, Precedence::Unary);
+            }
+            Expr::Variable { name, .. } => self.out.push_str(&name.lexeme),
+        }
+    }
+
+    fn literal(&mut self, value: &TokenType) {
+        match value {
+            TokenType::Number(n) if n.is_nan() => self.out.push_str("(0 / 0)"),
+            TokenType::Number(n) if n.is_infinite() && *n > 0.0 => self.out.push_str("(1 / 0)"),
+            TokenType::Number(n) if n.is_infinite() => self.out.push_str("(-1 / 0)"),
+            // Literals are never negative, so those are negations, `-0`
+            // included.
+            TokenType::Number(n) if n.is_sign_negative() => write!(self.out, "-{}", -n).unwrap(),
+            TokenType::Number(n) => write!(self.out, "{n}").unwrap(),
+            TokenType::String(s) => write!(self.out, "\"{s}\"").unwrap(),
+            TokenType::Bool(b) => write!(self.out, "{b}").unwrap(),
+            TokenType::Nil => self.out.push_str("nil"),
+            ty => unreachable!("not a literal: {ty:?}"),
+        }
+    }
+}
+
+/// Whether an `else` after `stmt` would bind to an `if` inside it.
+fn dangles(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::If {
+            else_branch: None, ..
+        } => true,
+        Stmt::If {
+            else_branch: Some(stmt),
+            ..
+        }
+        | Stmt::While { body: stmt, .. } => dangles(stmt),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench;
+    use crate::fuzz;
+    use crate::optimizer;
+    use crate::parser::Parser;
+    use crate::programs::PROGRAMS;
+    use crate::scanner::Scanner;
+    use crate::token::Token;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        Parser::new(&tokens).parse().unwrap()
+    }
+
+    /// The AST without what depends on where it was parsed from: ids and
+    /// lines.
+    fn shape(statements: &[Stmt]) -> String {
+        let debug = format!("{statements:?}");
+        let mut shape = String::new();
+        let mut rest = debug.as_str();
+        while let Some(at) = ["ExprId(", "line: "]
+            .iter()
+            .filter_map(|marker| Some(rest.find(marker)? + marker.len()))
+            .min()
+        {
+            shape.push_str(&rest[..at]);
+            rest = rest[at..].trim_start_matches(|c: char| c.is_ascii_digit());
+        }
+        shape.push_str(rest);
+        shape
+    }
+
+    fn assert_round_trips(source: &str) {
+        let statements = parse(source);
+        let printed = program(&statements);
+        let tokens = Scanner::new(&printed).scan_tokens();
+        let reparsed = tokens.and_then(|tokens| Parser::new(&tokens).parse());
+        let Ok(reparsed) = reparsed else {
+            panic!("printed what doesn't parse:\n{printed}");
+        };
+        assert_eq!(
+            shape(&statements),
+            shape(&reparsed),
+            "printed a different program:\n{printed}"
+        );
+    }
+
+    #[test]
+    fn test_program() {
+        let source = "
+        class B < A { init(x) { super.init(x); this.x = x; } get() { return this.x; } }
+        fun f(a, b) { if (a) print b; else { while (!b) b = b + 1; } return; }
+        for (var i = 0; i < 2; i = i + 1) print -i * (i + 2);
+        var e;
+        {}
+        ";
+
+        assert_eq!(
+            program(&parse(source)),
+            "\
+class B < A {
+  init(x) {
+    super.init(x);
+    this.x = x;
+  }
+
+  get() {
+    return this.x;
+  }
+}
+fun f(a, b) {
+  if (a) print b; else {
+    while (!b) b = b + 1;
+  }
+  return;
+}
+{
+  var i = 0;
+  while (i < 2) {
+    print -i * (i + 2);
+    i = i + 1;
+  }
+}
+var e;
+{}
+"
+        );
+    }
+
+    #[test]
+    fn test_minimal_parentheses() {
+        let token = |ty: TokenType, lexeme: &str| Token::new(ty, lexeme.into(), 1);
+        let var = |name: &str| Expr::Variable {
+            id: crate::ast::ExprId::next(),
+            name: token(TokenType::Identifier(name.into()), name),
+        };
+        let number = |n| Expr::Literal {
+            value: TokenType::Number(n),
+            line: 1,
+        };
+        let binary = |lhs, op: TokenType, lexeme: &str, rhs| Expr::Binary {
+            lhs: Box::new(lhs),
+            op: token(op, lexeme),
+            rhs: Box::new(rhs),
+        };
+
+        let sum = || binary(var("a"), TokenType::Plus, "+", var("b"));
+        let product = binary(sum(), TokenType::Star, "*", var("c"));
+        assert_eq!(expression(&product), "(a + b) * c");
+        let difference = binary(var("c"), TokenType::Minus, "-", sum());
+        assert_eq!(expression(&difference), "c - (a + b)");
+        let sum = binary(sum(), TokenType::Plus, "+", var("c"));
+        assert_eq!(expression(&sum), "a + b + c");
+
+        let negated = Expr::Unary {
+            op: token(TokenType::Minus, "-"),
+            rhs: Box::new(number(-2.0)),
+        };
+        assert_eq!(expression(&negated), "--2");
+        let call = Expr::Call {
+            callee: Box::new(number(f64::NAN)),
+            paren: token(TokenType::RightParen, ")"),
+            args: vec![product, number(-0.0)],
+        };
+        assert_eq!(expression(&call), "(0 / 0)((a + b) * c, -0)");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sources = [
+            "a = b = c; a.b.c = d = e; (a = 1); print (a);",
+            "print -(-1); print !(!x); print !!x; print ((1 + 2)) * 3 - (4 - 5);",
+            "print a or b and c or (d or e); print (a and b) == (c != d) <= e;",
+            "f()(1)(a, b.c(d)); (f)(); print (x).y; print \"multi\nline\";",
+            "if (a) if (b) print 1; else print 2;",
+            "if (a) { if (b) print 1; } else print 2;",
+            "if (a) print 1; else if (b) print 2; else { print 3; }",
+            "while (true) while (x) if (y) return; else return 1;",
+            "for (;;) {} for (var i = 0; i < 3;) print i; for (i = 0; i; i = i + 1) {}",
+            "class A {} class B < A { m() { return super.m; } n() { return this; } }",
+            "fun f() {} fun g(a, b, c) { fun h() { return; } return h; }",
+            "{ var a; var b = 1; { { } } }",
+        ];
+        for source in sources {
+            assert_round_trips(source);
+        }
+
+        for program in PROGRAMS {
+            assert_round_trips(program.source);
+        }
+        for (_, source) in bench::PROGRAMS {
+            assert_round_trips(source);
+        }
+    }
+
+    /// Round trips random programs, which take every form of statement and
+    /// most forms of expressions, and prints them optimized, without the
+    /// groupings folding took.
+    #[test]
+    fn test_round_trip_generated() {
+        for seed in 0..200 {
+            let source = fuzz::generate(seed, 30);
+            assert_round_trips(&source);
+
+            let printed = program(&optimizer::optimize(&parse(&source)));
+            let tokens = Scanner::new(&printed).scan_tokens();
+            let reparsed = tokens.and_then(|tokens| Parser::new(&tokens).parse());
+            assert!(reparsed.is_ok(), "printed what doesn't parse:\n{printed}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::ast::{self, Stmt};
 use crate::expr::{Binary, Grouping, Literal, Unary};
 use crate::token::{Token, TokenType};
 use std::fmt;
@@ -31,7 +32,7 @@ impl fmt::Display for TokenType {
             // Literals.
             Self::Identifier(s) => write!(f, "{}", s),
             Self::String(s) => write!(f, "{}", s),
-            Self::Number(n) => write!(f, "{}", n.to_string()),
+            Self::Number(n) => write!(f, "{}", n),
             Self::Bool(b) => write!(f, "{}", b),
             Self::Nil => write!(f, "nil"),
 
@@ -52,6 +53,8 @@ impl fmt::Display for TokenType {
             Self::For => write!(f, "for"),
             Self::Print => write!(f, "print"),
 
+            Self::Comment => write!(f, "//"),
+
             Self::EOF => write!(f, "EOF"),
         }
     }
@@ -87,6 +90,101 @@ impl fmt::Display for Unary {
     }
 }
 
+impl fmt::Display for ast::Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Assign { name, value, .. } => write!(f, "(= {name} {value})"),
+            Self::Binary { lhs, op, rhs } => write!(f, "({op} {lhs} {rhs})"),
+            Self::Call { callee, args, .. } => {
+                write!(f, "(call {callee}")?;
+                for arg in args {
+                    write!(f, " {arg}")?;
+                }
+                write!(f, ")")
+            }
+            Self::Get { object, name } => write!(f, "(. {object} {name})"),
+            Self::Grouping(expr) => write!(f, "(group {expr})"),
+            Self::Literal { value, .. } => write!(f, "{value}"),
+            Self::Logical { lhs, op, rhs } => write!(f, "({op} {lhs} {rhs})"),
+            Self::Set {
+                object,
+                name,
+                value,
+            } => write!(f, "(= (. {object} {name}) {value})"),
+            Self::Super { method, .. } => write!(f, "(super {method})"),
+            Self::This { .. } => write!(f, "this"),
+            Self::Unary { op, rhs } => write!(f, "({op} {rhs})"),
+            Self::Variable { name, .. } => write!(f, "{name}"),
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Block(statements) => {
+                write!(f, "(block")?;
+                for statement in statements {
+                    write!(f, " {statement}")?;
+                }
+                write!(f, ")")
+            }
+            Self::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                write!(f, "(class {name}")?;
+                if let Some(superclass) = superclass {
+                    write!(f, " < {superclass}")?;
+                }
+                for method in methods {
+                    write!(f, " {method}")?;
+                }
+                write!(f, ")")
+            }
+            Self::Expression(expr) => write!(f, "(; {expr})"),
+            Self::Function(function) => write!(f, "{function}"),
+            Self::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => match else_branch {
+                Some(else_branch) => write!(f, "(if {cond} {then_branch} {else_branch})"),
+                None => write!(f, "(if {cond} {then_branch})"),
+            },
+            Self::Print { value, .. } => write!(f, "(print {value})"),
+            Self::Return { value, .. } => match value {
+                Some(value) => write!(f, "(return {value})"),
+                None => write!(f, "(return)"),
+            },
+            Self::Var { name, init } => match init {
+                Some(init) => write!(f, "(var {name} {init})"),
+                None => write!(f, "(var {name})"),
+            },
+            Self::While { cond, body, .. } => write!(f, "(while {cond} {body})"),
+        }
+    }
+}
+
+impl fmt::Display for ast::Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "(fun {} (", self.name)?;
+        for (i, param) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{param}")?;
+        }
+        write!(f, ")")?;
+        for statement in &self.body {
+            write!(f, " {statement}")?;
+        }
+        write!(f, ")")
+    }
+}
+
 #[test]
 fn test_print() {
     let expr = Binary::new(
@@ -0,0 +1,659 @@
+//! Lox programs with their expected output, shared by the tests of every
+//! execution backend so they're all held to the same semantics.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+pub struct Program {
+    pub name: &'static str,
+    pub source: &'static str,
+    /// What `print` writes, followed by the runtime error if there's one.
+    pub expected: &'static str,
+}
+
+/// An in-memory `print` destination.
+#[derive(Clone, Default)]
+pub struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `f` on a thread with as much stack as the CLI runs programs with,
+/// for tests nesting calls as deep as the backends allow, which takes more
+/// than test threads get.
+pub fn with_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn_scoped(scope, f)
+            .unwrap()
+            .join()
+            .unwrap()
+    })
+}
+
+/// Runs every program through `run`, which is expected to execute the source
+/// writing to the given output, and reports all the mismatches at once.
+pub fn check(run: impl Fn(&str, Output)) {
+    check_except(&[], run);
+}
+
+/// Like [`check`], leaving out the programs named in `skipped`, for backends
+/// that don't support everything yet.
+pub fn check_except(skipped: &[&str], run: impl Fn(&str, Output)) {
+    let mut failures = vec![];
+
+    for program in PROGRAMS.iter().filter(|p| !skipped.contains(&p.name)) {
+        let output = Output::default();
+        run(program.source, output.clone());
+
+        let actual = output.contents();
+        if actual != program.expected {
+            failures.push(format!(
+                "{}:\n  expected: {:?}\n  actual:   {:?}",
+                program.name, program.expected, actual
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+pub const PROGRAMS: &[Program] = &[
+    Program {
+        name: "arithmetic",
+        source: "
+        print 1 + 2 * 3;
+        print (1 + 2) * 3;
+        print 10 / 4;
+        print 7 - 10;
+        print -(3);
+        print 1 / 0;
+        print 0.1 + 0.2;
+        ",
+        expected: "7\n9\n2.5\n-3\n-3\ninf\n0.30000000000000004\n",
+    },
+    Program {
+        name: "strings",
+        source: "
+        print \"con\" + \"cat\";
+        print \"\";
+        var s = \"a\";
+        s = s + s;
+        print s + s;
+        ",
+        expected: "concat\n\naaaa\n",
+    },
+    Program {
+        name: "comparison and equality",
+        source: "
+        print 1 < 2;
+        print 2 <= 2;
+        print 1 > 2;
+        print 3 >= 4;
+        print 1 == 1;
+        print \"a\" == \"a\";
+        print \"a\" != \"b\";
+        print nil == nil;
+        print nil == false;
+        print 1 == \"1\";
+        print true == true;
+        ",
+        expected: "true\ntrue\nfalse\nfalse\ntrue\ntrue\ntrue\ntrue\nfalse\nfalse\ntrue\n",
+    },
+    Program {
+        name: "truthiness and logic",
+        source: "
+        print !nil;
+        print !0;
+        print !!\"\";
+        print nil or \"default\";
+        print \"first\" or \"second\";
+        print false and 1;
+        print 1 and 2;
+        var called = false;
+        fun touch() { called = true; return true; }
+        false and touch();
+        print called;
+        true or touch();
+        print called;
+        ",
+        expected: "true\nfalse\ntrue\ndefault\nfirst\nfalse\n2\nfalse\nfalse\n",
+    },
+    Program {
+        name: "scopes",
+        source: "
+        var a = \"global a\";
+        var b = \"global b\";
+        var c = \"global c\";
+        {
+          var a = \"outer a\";
+          var b = \"outer b\";
+          {
+            var a = \"inner a\";
+            print a;
+            print b;
+            print c;
+          }
+          print a;
+          print b;
+          print c;
+        }
+        print a;
+        print b;
+        print c;
+        ",
+        expected: "inner a\nouter b\nglobal c\nouter a\nouter b\nglobal c\nglobal a\nglobal b\nglobal c\n",
+    },
+    Program {
+        name: "uninitialized and reassigned variables",
+        source: "
+        var a;
+        print a;
+        var b;
+        a = b = 2;
+        print a + b;
+        {
+          var c;
+          c = \"local\";
+          print c;
+        }
+        ",
+        expected: "nil\n4\nlocal\n",
+    },
+    Program {
+        name: "control flow",
+        source: "
+        if (1 < 2) print \"then\"; else print \"else\";
+        if (nil) print \"then\"; else print \"else\";
+        if (false) print \"dangling\";
+        var i = 0;
+        while (i < 3) {
+          print i;
+          i = i + 1;
+        }
+        for (var j = 10; j > 7; j = j - 1) print j;
+        var k = 0;
+        for (; k < 2;) k = k + 1;
+        print k;
+        ",
+        expected: "then\nelse\n0\n1\n2\n10\n9\n8\n2\n",
+    },
+    Program {
+        name: "functions",
+        source: "
+        fun add(a, b) {
+          return a + b;
+        }
+        fun nothing() {}
+        fun early(n) {
+          if (n > 0) return \"positive\";
+          return \"not positive\";
+        }
+        print add(1, 2);
+        print nothing();
+        print early(1);
+        print early(-1);
+        print add;
+        print clock;
+        fun fib(n) {
+          if (n < 2) return n;
+          return fib(n - 1) + fib(n - 2);
+        }
+        print fib(15);
+        ",
+        expected: "3\nnil\npositive\nnot positive\n<fn add>\n<native fn>\n610\n",
+    },
+    Program {
+        name: "closures",
+        source: "
+        fun makeCounter() {
+          var i = 0;
+          fun count() {
+            i = i + 1;
+            print i;
+          }
+
+          return count;
+        }
+
+        var counter = makeCounter();
+        counter();
+        counter();
+
+        var other = makeCounter();
+        other();
+        counter();
+
+        fun returnFunction() {
+          var outside = \"outside\";
+
+          fun inner() {
+            print outside;
+          }
+
+          return inner;
+        }
+
+        var fn = returnFunction();
+        fn();
+        ",
+        expected: "1\n2\n1\n3\noutside\n",
+    },
+    Program {
+        name: "closures share captured variables",
+        source: "
+        var get;
+        var set;
+        {
+          var shared = \"initial\";
+          fun getter() { return shared; }
+          fun setter(value) { shared = value; }
+          get = getter;
+          set = setter;
+        }
+        print get();
+        set(\"updated\");
+        print get();
+        ",
+        expected: "initial\nupdated\n",
+    },
+    Program {
+        name: "closure binds at declaration",
+        source: "
+        var a = \"global\";
+        {
+          fun showA() {
+            print a;
+          }
+
+          showA();
+          var a = \"block\";
+          showA();
+        }
+        ",
+        expected: "global\nglobal\n",
+    },
+    Program {
+        name: "closures capture loop variables",
+        source: "
+        var first;
+        var second;
+        for (var i = 1; i <= 2; i = i + 1) {
+          var j = i;
+          fun show() {
+            print j;
+            print i;
+          }
+          if (first == nil) first = show; else second = show;
+        }
+        first();
+        second();
+        ",
+        // Every iteration gets its own `j`, but there's only one `i`.
+        expected: "1\n3\n2\n3\n",
+    },
+    Program {
+        name: "closures capture through nested functions",
+        source: "
+        fun outer() {
+          var x = \"outer\";
+          fun middle() {
+            fun inner() {
+              print x;
+              x = \"assigned\";
+            }
+            return inner;
+          }
+          return middle;
+        }
+        var inner = outer()();
+        inner();
+        inner();
+        ",
+        expected: "outer\nassigned\n",
+    },
+    Program {
+        name: "closures from the scanner tests",
+        source: "
+        fun addPair(a, b) {
+          return a + b;
+        }
+
+        fun identity(a) {
+          return a;
+        }
+
+        print identity(addPair)(1, 2); // Prints \"3\".
+
+        fun outerFunction() {
+          fun localFunction() {
+            print \"I'm local!\";
+          }
+
+          localFunction();
+        }
+        outerFunction();
+
+        fun returnFunction() {
+          var outside = \"outside\";
+
+          fun inner() {
+            print outside;
+          }
+
+          return inner;
+        }
+
+        var fn = returnFunction();
+        fn();
+        ",
+        expected: "3\nI'm local!\noutside\n",
+    },
+    Program {
+        name: "tail calls",
+        source: "
+        fun zero() { return 0; }
+        fun capture(n, previous) {
+          var local = n;
+          fun get() { return local + previous(); }
+          if (n == 0) return get;
+          return capture(n - 1, get);
+        }
+        print capture(3, zero)();
+
+        class Point {
+          init(x) { this.x = x; }
+          get() { return this.x; }
+        }
+        fun make(x) { return Point(x); }
+        fun bound(point) {
+          var get = point.get;
+          return get();
+        }
+        fun native() { return clock(); }
+        print make(1).x;
+        print bound(Point(2));
+        print native() > 0;
+        ",
+        expected: "6\n1\n2\ntrue\n",
+    },
+    Program {
+        name: "classes",
+        source: "
+        class Breakfast {
+          init(meat, bread) {
+            this.meat = meat;
+            this.bread = bread;
+          }
+
+          serve(who) {
+            print \"Enjoy your \" + this.meat + \" and \" +
+                this.bread + \", \" + who + \".\";
+          }
+        }
+
+        var baconAndToast = Breakfast(\"bacon\", \"toast\");
+        baconAndToast.serve(\"Dear Reader\");
+        print Breakfast;
+        print baconAndToast;
+
+        baconAndToast.meat = \"sausage\";
+        var serve = baconAndToast.serve;
+        serve(\"bound method\");
+        print baconAndToast.init(\"eggs\", \"bread\") == baconAndToast;
+        ",
+        expected: "Enjoy your bacon and toast, Dear Reader.\nBreakfast\nBreakfast instance\nEnjoy your sausage and toast, bound method.\ntrue\n",
+    },
+    Program {
+        name: "fields shadow methods",
+        source: "
+        class A {
+          method() { return \"method\"; }
+        }
+        var a = A();
+        print a.method();
+        fun field() { return \"field\"; }
+        a.method = field;
+        print a.method();
+        ",
+        expected: "method\nfield\n",
+    },
+    Program {
+        name: "property accesses seeing different layouts",
+        source: "
+        class A {
+          describe() { return \"A\"; }
+        }
+        class B {
+          describe() { return \"B\"; }
+        }
+        fun describe(object) { return object.describe(); }
+        fun x(object) { return object.x; }
+        fun setX(object, value) { object.x = value; }
+
+        var first = A();
+        first.x = 1;
+        var second = A();
+        second.y = 2;
+        second.x = 3;
+        var third = B();
+        third.x = 4;
+        var fourth = B();
+        fourth.describe = first.describe;
+        fourth.x = 5;
+        var fifth = A();
+        fifth.z = 6;
+        fifth.x = 7;
+
+        for (var i = 0; i < 2; i = i + 1) {
+          print describe(first) + describe(second) + describe(third) +
+            describe(fourth) + describe(fifth);
+          print x(first) + x(second) + x(third) + x(fourth) + x(fifth);
+          setX(first, 10);
+          setX(second, 10);
+          setX(third, 10);
+          setX(fourth, 10);
+          setX(fifth, 10);
+        }
+        print second.y;
+        print fifth.z;
+        ",
+        expected: "AABAA\n20\nAABAA\n50\n2\n6\n",
+    },
+    Program {
+        name: "early return from init",
+        source: "
+        class Early {
+          init(n) {
+            this.n = n;
+            if (n > 0) return;
+            this.n = \"never positive\";
+          }
+        }
+        print Early(1).n;
+        print Early(0).n;
+        ",
+        expected: "1\nnever positive\n",
+    },
+    Program {
+        name: "inheritance",
+        source: "
+        class Breakfast {
+          init(meat, bread) {
+            this.meat = meat;
+            this.bread = bread;
+          }
+
+          serve(who) {
+            print \"Enjoy your \" + this.meat + \" and \" +
+                this.bread + \", \" + who + \".\";
+          }
+        }
+
+        class Brunch < Breakfast {
+          init(meat, bread, drink) {
+            super.init(meat, bread);
+            this.drink = drink;
+          }
+
+          drink() {
+            print \"How about a \" + this.drink + \"?\";
+          }
+        }
+
+        var benedict = Brunch(\"ham\", \"English muffin\", \"Bloody Mary\");
+        benedict.serve(\"Noble Reader\");
+        print benedict.drink;
+        print benedict;
+        ",
+        expected: "Enjoy your ham and English muffin, Noble Reader.\nBloody Mary\nBrunch instance\n",
+    },
+    Program {
+        name: "super binds to the superclass of the method",
+        source: "
+        class A {
+          method() { print \"A method\"; }
+        }
+        class B < A {
+          method() { print \"B method\"; }
+          test() { super.method(); }
+        }
+        class C < B {}
+        C().test();
+        var test = C().test;
+        test();
+        ",
+        expected: "A method\nA method\n",
+    },
+    Program {
+        name: "local classes and functions",
+        source: "
+        {
+          class Local {
+            name() { return \"local\"; }
+          }
+          fun make() { return Local(); }
+          print make().name();
+        }
+        ",
+        expected: "local\n",
+    },
+    Program {
+        name: "operands read before later ones assign",
+        source: "
+        {
+          var a = 1;
+          print a + (a = 2);
+          fun set() {
+            a = 10;
+            return 1;
+          }
+          print a + set();
+          print a;
+
+          var b = true;
+          var c = false;
+          c = b and c;
+          print c;
+          c = c or a;
+          print c;
+
+          class Box {}
+          var box = Box();
+          box.value = a - (a = 3);
+          print box.value;
+        }
+        ",
+        expected: "3\n3\n10\nfalse\n10\n7\n",
+    },
+    Program {
+        name: "error: operand must be a number",
+        source: "print 1;\nprint -\"a\";\nprint 2;",
+        expected: "1\nOperand must be a number.\n[line 2]\n",
+    },
+    Program {
+        name: "error: operands must be numbers",
+        source: "print 1 < \"2\";",
+        expected: "Operands must be numbers.\n[line 1]\n",
+    },
+    Program {
+        name: "error: operands must be two numbers or two strings",
+        source: "print \"a\" + 1;",
+        expected: "Operands must be two numbers or two strings.\n[line 1]\n",
+    },
+    Program {
+        name: "error: undefined variable",
+        source: "print \"before\";\n\nprint missing;",
+        expected: "before\nUndefined variable 'missing'.\n[line 3]\n",
+    },
+    Program {
+        name: "error: assign to undefined variable",
+        source: "missing = 1;",
+        expected: "Undefined variable 'missing'.\n[line 1]\n",
+    },
+    Program {
+        name: "error: call a non callable",
+        source: "\"not a function\"();",
+        expected: "Can only call functions and classes.\n[line 1]\n",
+    },
+    Program {
+        name: "error: arity",
+        source: "fun f(a, b) {}\nf(1);",
+        expected: "Expected 2 arguments but got 1.\n[line 2]\n",
+    },
+    Program {
+        name: "error: class arity",
+        source: "class A { init(a) {} }\nA();",
+        expected: "Expected 1 arguments but got 0.\n[line 2]\n",
+    },
+    Program {
+        name: "error: undefined property",
+        source: "class A {}\nprint A().missing;",
+        expected: "Undefined property 'missing'.\n[line 2]\n",
+    },
+    Program {
+        name: "error: only instances have properties",
+        source: "var a = 1;\nprint a.field;",
+        expected: "Only instances have properties.\n[line 2]\n",
+    },
+    Program {
+        name: "error: only instances have fields",
+        source: "var a = \"str\";\na.field = 1;",
+        expected: "Only instances have fields.\n[line 2]\n",
+    },
+    Program {
+        name: "error: superclass must be a class",
+        source: "var NotClass = 1;\nclass A < NotClass {}",
+        expected: "Superclass must be a class.\n[line 2]\n",
+    },
+    Program {
+        name: "error: inside a function",
+        source: "
+        fun inner() {
+          return nil + 1;
+        }
+        fun outer() {
+          print \"calling\";
+          inner();
+          print \"unreachable\";
+        }
+        outer();
+        ",
+        expected: "calling\nOperands must be two numbers or two strings.\n[line 3]\n",
+    },
+];
@@ -0,0 +1,347 @@
+//! A register machine alternative to the stack based [`Vm`], as an
+//! experiment.
+//!
+//! Every function gets a window of registers in the stack. Locals live in
+//! registers of their own for their whole scope, temporaries take the
+//! registers above them while an expression is evaluated, and instructions
+//! name the registers they read and write, `ADD r1, r2, r3` adding the
+//! second and third into the first. Reading a local is free, where the stack
+//! VM pushes a copy of it, and statements leave nothing to pop.
+//!
+//! Calls put the callee and its arguments in consecutive registers, which
+//! become the first ones of the window of the callee, and the result comes
+//! back in the register of the callee.
+//!
+//! # Compared to the stack VM
+//!
+//! Wall time from `cargo bench`, the median of five runs on the same
+//! machine, and the instructions compiled for every bench, with the size of
+//! the code:
+//!
+//! | bench              | stack VM | register VM | stack VM code   | register VM code |
+//! |--------------------|---------:|------------:|----------------:|-----------------:|
+//! | fib(30)            |   221 ms |      196 ms | 25 in 45 bytes  | 23 in 184 bytes  |
+//! | global loop        |    81 ms |       54 ms | 19 in 34 bytes  | 14 in 112 bytes  |
+//! | nested local loops |    71 ms |       27 ms | 41 in 72 bytes  | 26 in 208 bytes  |
+//! | method calls       |    95 ms |       73 ms | 54 in 96 bytes  | 39 in 312 bytes  |
+//! | bound methods      |    84 ms |       70 ms | 62 in 112 bytes | 45 in 360 bytes  |
+//! | string equality    |    65 ms |       48 ms | 43 in 76 bytes  | 31 in 248 bytes  |
+//!
+//! Loops over locals gain the most, as arithmetic on them is a single
+//! instruction instead of three or four, even against the superinstructions
+//! of the peephole optimizer. Code going through globals, fields and calls
+//! still has to move values into registers first, much like pushing them,
+//! so it gains less. A quarter to a third fewer instructions get compiled,
+//! but they take about three times the space, eight bytes each against one
+//! to three bytes.
+//!
+//! [`Vm`]: crate::vm::Vm
+
+use std::fmt;
+use std::rc::Rc;
+
+pub mod compiler;
+pub mod vm;
+
+/// Index of a register in the window of the running function.
+pub type Register = u8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    LoadConstant {
+        dst: Register,
+        constant: u16,
+    },
+    LoadNil {
+        dst: Register,
+    },
+    LoadBool {
+        dst: Register,
+        value: bool,
+    },
+    Move {
+        dst: Register,
+        src: Register,
+    },
+    GetGlobal {
+        dst: Register,
+        name: u16,
+    },
+    DefineGlobal {
+        src: Register,
+        name: u16,
+    },
+    SetGlobal {
+        src: Register,
+        name: u16,
+    },
+    GetUpvalue {
+        dst: Register,
+        index: u8,
+    },
+    SetUpvalue {
+        src: Register,
+        index: u8,
+    },
+    GetProperty {
+        dst: Register,
+        object: Register,
+        name: u16,
+    },
+    SetProperty {
+        object: Register,
+        name: u16,
+        src: Register,
+    },
+    /// Binds the method `name` of the superclass in `class` to `receiver`.
+    GetSuper {
+        dst: Register,
+        receiver: Register,
+        class: Register,
+        name: u16,
+    },
+    Equal {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    NotEqual {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Greater {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    GreaterEqual {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Less {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    LessEqual {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Add {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Subtract {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Multiply {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Divide {
+        dst: Register,
+        lhs: Register,
+        rhs: Register,
+    },
+    Not {
+        dst: Register,
+        src: Register,
+    },
+    Negate {
+        dst: Register,
+        src: Register,
+    },
+    Print {
+        src: Register,
+    },
+    /// Jumps to the instruction at index `target`.
+    Jump {
+        target: u32,
+    },
+    JumpIfFalse {
+        cond: Register,
+        target: u32,
+    },
+    JumpIfTrue {
+        cond: Register,
+        target: u32,
+    },
+    /// Calls the callee in `base` with the `argc` arguments after it, the
+    /// result replacing the callee.
+    Call {
+        base: Register,
+        argc: u8,
+    },
+    /// Calls the method `name` of the receiver in `base`, like [`Call`].
+    ///
+    /// [`Call`]: Instruction::Call
+    Invoke {
+        base: Register,
+        name: u16,
+        argc: u8,
+    },
+    /// Calls the method `name` of the superclass in `class` on the receiver
+    /// in `base`, like [`Call`].
+    ///
+    /// [`Call`]: Instruction::Call
+    SuperInvoke {
+        base: Register,
+        class: Register,
+        name: u16,
+        argc: u8,
+    },
+    Closure {
+        dst: Register,
+        function: u16,
+    },
+    /// Moves the variables in `from` and the registers above out to the
+    /// closures capturing them, as their scope ends.
+    CloseUpvalues {
+        from: Register,
+    },
+    Return {
+        src: Register,
+    },
+    Class {
+        dst: Register,
+        name: u16,
+    },
+    Inherit {
+        class: Register,
+        superclass: Register,
+    },
+    Method {
+        class: Register,
+        name: u16,
+        method: Register,
+    },
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match *self {
+            Self::LoadConstant { dst, constant } => write!(f, "LOAD_CONSTANT r{dst}, k{constant}"),
+            Self::LoadNil { dst } => write!(f, "LOAD_NIL r{dst}"),
+            Self::LoadBool { dst, value } => write!(f, "LOAD_BOOL r{dst}, {value}"),
+            Self::Move { dst, src } => write!(f, "MOVE r{dst}, r{src}"),
+            Self::GetGlobal { dst, name } => write!(f, "GET_GLOBAL r{dst}, k{name}"),
+            Self::DefineGlobal { src, name } => write!(f, "DEFINE_GLOBAL k{name}, r{src}"),
+            Self::SetGlobal { src, name } => write!(f, "SET_GLOBAL k{name}, r{src}"),
+            Self::GetUpvalue { dst, index } => write!(f, "GET_UPVALUE r{dst}, u{index}"),
+            Self::SetUpvalue { src, index } => write!(f, "SET_UPVALUE u{index}, r{src}"),
+            Self::GetProperty { dst, object, name } => {
+                write!(f, "GET_PROPERTY r{dst}, r{object}, k{name}")
+            }
+            Self::SetProperty { object, name, src } => {
+                write!(f, "SET_PROPERTY r{object}, k{name}, r{src}")
+            }
+            Self::GetSuper {
+                dst,
+                receiver,
+                class,
+                name,
+            } => write!(f, "GET_SUPER r{dst}, r{receiver}, r{class}, k{name}"),
+            Self::Equal { dst, lhs, rhs } => write!(f, "EQUAL r{dst}, r{lhs}, r{rhs}"),
+            Self::NotEqual { dst, lhs, rhs } => write!(f, "NOT_EQUAL r{dst}, r{lhs}, r{rhs}"),
+            Self::Greater { dst, lhs, rhs } => write!(f, "GREATER r{dst}, r{lhs}, r{rhs}"),
+            Self::GreaterEqual { dst, lhs, rhs } => {
+                write!(f, "GREATER_EQUAL r{dst}, r{lhs}, r{rhs}")
+            }
+            Self::Less { dst, lhs, rhs } => write!(f, "LESS r{dst}, r{lhs}, r{rhs}"),
+            Self::LessEqual { dst, lhs, rhs } => write!(f, "LESS_EQUAL r{dst}, r{lhs}, r{rhs}"),
+            Self::Add { dst, lhs, rhs } => write!(f, "ADD r{dst}, r{lhs}, r{rhs}"),
+            Self::Subtract { dst, lhs, rhs } => write!(f, "SUBTRACT r{dst}, r{lhs}, r{rhs}"),
+            Self::Multiply { dst, lhs, rhs } => write!(f, "MULTIPLY r{dst}, r{lhs}, r{rhs}"),
+            Self::Divide { dst, lhs, rhs } => write!(f, "DIVIDE r{dst}, r{lhs}, r{rhs}"),
+            Self::Not { dst, src } => write!(f, "NOT r{dst}, r{src}"),
+            Self::Negate { dst, src } => write!(f, "NEGATE r{dst}, r{src}"),
+            Self::Print { src } => write!(f, "PRINT r{src}"),
+            Self::Jump { target } => write!(f, "JUMP {target}"),
+            Self::JumpIfFalse { cond, target } => write!(f, "JUMP_IF_FALSE r{cond}, {target}"),
+            Self::JumpIfTrue { cond, target } => write!(f, "JUMP_IF_TRUE r{cond}, {target}"),
+            Self::Call { base, argc } => write!(f, "CALL r{base}, {argc}"),
+            Self::Invoke { base, name, argc } => write!(f, "INVOKE r{base}, k{name}, {argc}"),
+            Self::SuperInvoke {
+                base,
+                class,
+                name,
+                argc,
+            } => write!(f, "SUPER_INVOKE r{base}, r{class}, k{name}, {argc}"),
+            Self::Closure { dst, function } => write!(f, "CLOSURE r{dst}, k{function}"),
+            Self::CloseUpvalues { from } => write!(f, "CLOSE_UPVALUES r{from}"),
+            Self::Return { src } => write!(f, "RETURN r{src}"),
+            Self::Class { dst, name } => write!(f, "CLASS r{dst}, k{name}"),
+            Self::Inherit { class, superclass } => write!(f, "INHERIT r{class}, r{superclass}"),
+            Self::Method {
+                class,
+                name,
+                method,
+            } => write!(f, "METHOD r{class}, k{name}, r{method}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+}
+
+/// Where a closure gets a captured variable from when it's created.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capture {
+    /// Register of the local of the enclosing function if `is_local`,
+    /// otherwise index of one of its upvalues.
+    pub index: u8,
+    pub is_local: bool,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Function {
+    pub name: Option<Rc<str>>,
+    pub arity: usize,
+    /// How many registers its window needs.
+    pub registers: usize,
+    pub code: Vec<Instruction>,
+    /// The source line of every instruction.
+    pub lines: Vec<usize>,
+    pub constants: Vec<Constant>,
+    pub captures: Vec<Capture>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+/// Lists the code of a function and of the functions in its constants.
+pub fn disassemble(function: &Function) -> String {
+    let mut out = format!("== {function} ({} registers) ==\n", function.registers);
+    for (index, instruction) in function.code.iter().enumerate() {
+        let line = function.lines[index];
+        if index > 0 && line == function.lines[index - 1] {
+            out.push_str(&format!("{index:04}    | {instruction}\n"));
+        } else {
+            out.push_str(&format!("{index:04} {line:>4} {instruction}\n"));
+        }
+    }
+
+    for constant in &function.constants {
+        if let Constant::Function(nested) = constant {
+            out.push_str(&disassemble(nested));
+        }
+    }
+    out
+}
@@ -0,0 +1,867 @@
+use super::{Capture, Constant, Function, Instruction, Register};
+use crate::ast::{self, Expr, Stmt};
+use crate::compiler::CompileError;
+use crate::token::{Token, TokenType};
+use std::rc::Rc;
+
+const MAX_REGISTERS: usize = Register::MAX as usize + 1;
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local {
+    name: String,
+    depth: usize,
+    register: Register,
+    /// Whether a closure captures it, so it has to be moved out of its
+    /// register when its scope ends.
+    is_captured: bool,
+}
+
+/// Compilation state of one function, they nest like the declarations do.
+struct FunctionState {
+    function: Function,
+    ty: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Capture>,
+    scope_depth: usize,
+    /// The first free register, the ones below it hold locals or
+    /// temporaries still in use.
+    top: usize,
+}
+
+impl FunctionState {
+    fn new(ty: FunctionType, name: Option<Rc<str>>) -> Self {
+        // Register zero holds the function being called, or the receiver in
+        // methods, where it can be reached as `this`.
+        let reserved = match ty {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Script | FunctionType::Function => "",
+        };
+
+        Self {
+            function: Function {
+                name,
+                registers: 1,
+                ..Function::default()
+            },
+            ty,
+            locals: vec![Local {
+                name: reserved.into(),
+                depth: 0,
+                register: 0,
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+            top: 1,
+        }
+    }
+}
+
+/// Where a variable lives, as far as the function being compiled sees.
+enum Variable {
+    Local(Register),
+    Upvalue(u8),
+    Global(u16),
+}
+
+/// Single pass from the resolved AST to code for the register [`Vm`].
+///
+/// Expressions are compiled into a destination register the caller picks,
+/// or into whatever register already holds their value when they're only
+/// read, which is what saves copying locals around.
+///
+/// [`Vm`]: super::vm::Vm
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    /// Line of the last token seen, for the instructions that have none.
+    line: usize,
+    errors: Vec<CompileError>,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self {
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            line: 1,
+            errors: vec![],
+        }
+    }
+
+    pub fn compile(mut self, statements: &[Stmt]) -> Result<Rc<Function>, Vec<CompileError>> {
+        for statement in statements {
+            self.statement(statement);
+        }
+        let function = self.end_function();
+
+        if self.errors.is_empty() {
+            Ok(Rc::new(function))
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn state(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("no function being compiled")
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        let top = self.state().top;
+
+        match statement {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.statement(statement);
+                }
+                self.end_scope();
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class(name, superclass.as_ref(), methods),
+            Stmt::Expression(expr) => self.discard(expr),
+            Stmt::Function(function) => {
+                self.line = function.name.line;
+                let dst = self.reserve();
+                // Declared right away, so the function can call itself.
+                self.declare_variable(&function.name, dst);
+                self.function(function, FunctionType::Function, dst);
+                self.define_variable(&function.name, dst);
+            }
+            Stmt::If {
+                line,
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.operand(cond);
+                self.line = *line;
+                let then_jump = self.emit_jump(|target| Instruction::JumpIfFalse { cond, target });
+                self.state().top = top;
+                self.statement(then_branch);
+
+                match else_branch {
+                    Some(else_branch) => {
+                        self.line = *line;
+                        let else_jump = self.emit_jump(|target| Instruction::Jump { target });
+                        self.patch_jump(then_jump);
+                        self.statement(else_branch);
+                        self.patch_jump(else_jump);
+                    }
+                    None => self.patch_jump(then_jump),
+                }
+            }
+            Stmt::Print { line, value } => {
+                let src = self.operand(value);
+                self.line = *line;
+                self.emit(Instruction::Print { src });
+            }
+            Stmt::Return { keyword, value } => {
+                self.line = keyword.line;
+                match value {
+                    Some(value) => {
+                        let src = self.operand(value);
+                        self.line = keyword.line;
+                        self.emit(Instruction::Return { src });
+                    }
+                    None => self.emit_return(),
+                }
+            }
+            Stmt::Var { name, init } => {
+                self.line = name.line;
+                let dst = self.reserve();
+                match init {
+                    Some(init) => self.expression(init, dst),
+                    None => self.emit(Instruction::LoadNil { dst }),
+                }
+                // Only declared now, the resolver already guarantees the
+                // initializer doesn't read it.
+                self.declare_variable(name, dst);
+                self.define_variable(name, dst);
+            }
+            Stmt::While { line, cond, body } => {
+                let loop_start = self.state().function.code.len() as u32;
+                let cond = self.operand(cond);
+                self.line = *line;
+                let exit_jump = self.emit_jump(|target| Instruction::JumpIfFalse { cond, target });
+                self.state().top = top;
+                self.statement(body);
+                self.line = *line;
+                self.emit(Instruction::Jump { target: loop_start });
+                self.patch_jump(exit_jump);
+            }
+        }
+
+        // Temporaries don't outlive the statement, the locals it declared do.
+        let locals = self.state().locals.iter().map(|l| l.register as usize + 1);
+        let top = locals.max().unwrap_or(0).max(top);
+        self.state().top = top;
+    }
+
+    fn class(&mut self, name: &Token, superclass: Option<&Expr>, methods: &[Rc<ast::Function>]) {
+        self.line = name.line;
+        let name_constant = self.identifier_constant(name);
+        let class = self.reserve();
+        self.emit(Instruction::Class {
+            dst: class,
+            name: name_constant,
+        });
+        self.declare_variable(name, class);
+        self.define_variable(name, class);
+
+        if let Some(superclass) = superclass {
+            // The superclass gets a local of its own, for the methods to
+            // capture as `super`.
+            self.begin_scope();
+            let register = self.reserve();
+            self.expression(superclass, register);
+            self.add_local("super", register);
+            self.mark_initialized();
+
+            self.line = name.line;
+            self.emit(Instruction::Inherit {
+                class,
+                superclass: register,
+            });
+        }
+
+        for method in methods {
+            let ty = if method.name.lexeme == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            let register = self.reserve();
+            self.function(method, ty, register);
+
+            let name_constant = self.identifier_constant(&method.name);
+            self.emit(Instruction::Method {
+                class,
+                name: name_constant,
+                method: register,
+            });
+            self.state().top -= 1;
+        }
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+    }
+
+    /// Compiles the function on its own and puts a closure over it in
+    /// `dst`.
+    fn function(&mut self, function: &ast::Function, ty: FunctionType, dst: Register) {
+        let name = Some(function.name.lexeme.as_str().into());
+        self.states.push(FunctionState::new(ty, name));
+        self.begin_scope();
+
+        self.state().function.arity = function.params.len();
+        for param in &function.params {
+            let register = self.reserve();
+            self.add_local(&param.lexeme, register);
+            self.mark_initialized();
+        }
+        for statement in &function.body {
+            self.statement(statement);
+        }
+
+        // No need to end the scope, the return discards the whole window.
+        let compiled = self.end_function();
+        self.line = function.name.line;
+        let index = self.make_constant(Constant::Function(Rc::new(compiled)));
+        self.emit(Instruction::Closure {
+            dst,
+            function: index,
+        });
+    }
+
+    fn end_function(&mut self) -> Function {
+        self.emit_return();
+        let mut state = self.states.pop().expect("no function being compiled");
+        state.function.captures = state.upvalues;
+        state.function
+    }
+
+    /// Compiles an expression only for its side effects.
+    fn discard(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                self.assign(name, value);
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.set_property(object, name, value);
+            }
+            Expr::Grouping(expr) => self.discard(expr),
+            _ => {
+                let dst = self.reserve();
+                self.expression(expr, dst);
+            }
+        }
+    }
+
+    /// Compiles an expression into a register that already holds its value
+    /// if there's one, like the register of a local, or else into a new
+    /// temporary.
+    fn operand(&mut self, expr: &Expr) -> Register {
+        match expr {
+            Expr::Grouping(expr) => self.operand(expr),
+            Expr::Variable { name, .. } | Expr::This { keyword: name, .. } => {
+                self.variable_operand(name)
+            }
+            Expr::Assign { name, value, .. } => self.assign(name, value),
+            _ => {
+                let dst = self.reserve();
+                self.expression(expr, dst);
+                dst
+            }
+        }
+    }
+
+    /// Like [`Compiler::operand`], but with the value copied into a new
+    /// temporary if evaluating `rest` could change it before it's used.
+    fn operand_before(&mut self, expr: &Expr, rest: &Expr) -> Register {
+        if assigns(rest) {
+            let dst = self.reserve();
+            self.expression(expr, dst);
+            dst
+        } else {
+            self.operand(expr)
+        }
+    }
+
+    /// Compiles an expression into `dst`.
+    fn expression(&mut self, expr: &Expr, dst: Register) {
+        let top = self.state().top;
+
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                let src = self.assign(name, value);
+                self.emit_move(dst, src);
+            }
+            Expr::Binary { lhs, op, rhs } => {
+                let lhs = self.operand_before(lhs, rhs);
+                let rhs = self.operand(rhs);
+
+                self.line = op.line;
+                self.emit(match op.ty {
+                    TokenType::BangEqual => Instruction::NotEqual { dst, lhs, rhs },
+                    TokenType::EqualEqual => Instruction::Equal { dst, lhs, rhs },
+                    TokenType::Greater => Instruction::Greater { dst, lhs, rhs },
+                    TokenType::GreaterEqual => Instruction::GreaterEqual { dst, lhs, rhs },
+                    TokenType::Less => Instruction::Less { dst, lhs, rhs },
+                    TokenType::LessEqual => Instruction::LessEqual { dst, lhs, rhs },
+                    TokenType::Plus => Instruction::Add { dst, lhs, rhs },
+                    TokenType::Minus => Instruction::Subtract { dst, lhs, rhs },
+                    TokenType::Star => Instruction::Multiply { dst, lhs, rhs },
+                    TokenType::Slash => Instruction::Divide { dst, lhs, rhs },
+                    _ => unreachable!("not a binary operator: {:?}", op.ty),
+                });
+            }
+            Expr::Call {
+                callee,
+                paren,
+                args,
+            } => {
+                // The callee and arguments go in the registers on top, which
+                // are the ones of `dst` already when it's the last
+                // temporary.
+                let base = if dst as usize + 1 == top && !self.is_local(dst) {
+                    dst
+                } else {
+                    self.reserve()
+                };
+
+                let instruction = match callee.as_ref() {
+                    Expr::Get { object, name } => {
+                        self.expression(object, base);
+                        self.arguments(args);
+                        Instruction::Invoke {
+                            base,
+                            name: self.identifier_constant(name),
+                            argc: args.len() as u8,
+                        }
+                    }
+                    Expr::Super {
+                        keyword, method, ..
+                    } => {
+                        self.load_variable(&this(keyword), base);
+                        self.arguments(args);
+                        let class = self.variable_operand(keyword);
+                        Instruction::SuperInvoke {
+                            base,
+                            class,
+                            name: self.identifier_constant(method),
+                            argc: args.len() as u8,
+                        }
+                    }
+                    _ => {
+                        self.expression(callee, base);
+                        self.arguments(args);
+                        Instruction::Call {
+                            base,
+                            argc: args.len() as u8,
+                        }
+                    }
+                };
+
+                self.line = paren.line;
+                self.emit(instruction);
+                self.emit_move(dst, base);
+            }
+            Expr::Get { object, name } => {
+                let object = self.operand(object);
+                self.line = name.line;
+                let name = self.identifier_constant(name);
+                self.emit(Instruction::GetProperty { dst, object, name });
+            }
+            Expr::Grouping(expr) => self.expression(expr, dst),
+            Expr::Literal { value, line } => {
+                self.line = *line;
+                let instruction = match value {
+                    TokenType::Number(n) => Instruction::LoadConstant {
+                        dst,
+                        constant: self.make_constant(Constant::Number(*n)),
+                    },
+                    TokenType::String(s) => Instruction::LoadConstant {
+                        dst,
+                        constant: self.make_constant(Constant::String(s.as_str().into())),
+                    },
+                    TokenType::Bool(value) => Instruction::LoadBool { dst, value: *value },
+                    TokenType::Nil => Instruction::LoadNil { dst },
+                    _ => unreachable!("not a literal: {value:?}"),
+                };
+                self.emit(instruction);
+            }
+            Expr::Logical { lhs, op, rhs } => {
+                // Both sides get written to the destination, so a local there
+                // would change before the right side reads it.
+                let result = if self.is_local(dst) {
+                    self.reserve()
+                } else {
+                    dst
+                };
+
+                self.expression(lhs, result);
+                self.line = op.line;
+                let end_jump = if op.ty == TokenType::And {
+                    self.emit_jump(|target| Instruction::JumpIfFalse {
+                        cond: result,
+                        target,
+                    })
+                } else {
+                    self.emit_jump(|target| Instruction::JumpIfTrue {
+                        cond: result,
+                        target,
+                    })
+                };
+                self.expression(rhs, result);
+                self.patch_jump(end_jump);
+                self.emit_move(dst, result);
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                let src = self.set_property(object, name, value);
+                self.emit_move(dst, src);
+            }
+            Expr::Super {
+                keyword, method, ..
+            } => {
+                let receiver = self.variable_operand(&this(keyword));
+                let class = self.variable_operand(keyword);
+                self.line = method.line;
+                let name = self.identifier_constant(method);
+                self.emit(Instruction::GetSuper {
+                    dst,
+                    receiver,
+                    class,
+                    name,
+                });
+            }
+            Expr::Unary { op, rhs } => {
+                let src = self.operand(rhs);
+                self.line = op.line;
+                match op.ty {
+                    TokenType::Bang => self.emit(Instruction::Not { dst, src }),
+                    TokenType::Minus => self.emit(Instruction::Negate { dst, src }),
+                    _ => unreachable!("not a unary operator: {:?}", op.ty),
+                }
+            }
+            Expr::Variable { name, .. } | Expr::This { keyword: name, .. } => {
+                self.load_variable(name, dst);
+            }
+        }
+
+        self.state().top = top;
+    }
+
+    /// Compiles the arguments of a call into the registers after the callee.
+    fn arguments(&mut self, args: &[Expr]) {
+        for arg in args {
+            let register = self.reserve();
+            self.expression(arg, register);
+        }
+    }
+
+    /// Assigns `value` to a variable, returning the register holding it.
+    fn assign(&mut self, name: &Token, value: &Expr) -> Register {
+        let variable = self.resolve(name);
+        let src = match variable {
+            Variable::Local(register) => {
+                self.expression(value, register);
+                return register;
+            }
+            Variable::Upvalue(_) | Variable::Global(_) => self.operand(value),
+        };
+
+        self.line = name.line;
+        match variable {
+            Variable::Local(_) => unreachable!("assigned above"),
+            Variable::Upvalue(index) => self.emit(Instruction::SetUpvalue { src, index }),
+            Variable::Global(name) => self.emit(Instruction::SetGlobal { src, name }),
+        }
+        src
+    }
+
+    /// Sets a field, returning the register holding the value.
+    fn set_property(&mut self, object: &Expr, name: &Token, value: &Expr) -> Register {
+        let object = self.operand_before(object, value);
+        let src = self.operand(value);
+        self.line = name.line;
+        let name = self.identifier_constant(name);
+        self.emit(Instruction::SetProperty { object, name, src });
+        src
+    }
+
+    fn load_variable(&mut self, name: &Token, dst: Register) {
+        match self.resolve(name) {
+            Variable::Local(src) => self.emit_move(dst, src),
+            Variable::Upvalue(index) => self.emit(Instruction::GetUpvalue { dst, index }),
+            Variable::Global(name) => self.emit(Instruction::GetGlobal { dst, name }),
+        }
+    }
+
+    /// The register holding a variable, which gets loaded into a temporary
+    /// if it isn't a local.
+    fn variable_operand(&mut self, name: &Token) -> Register {
+        if let Variable::Local(register) = self.resolve(name) {
+            return register;
+        }
+        let dst = self.reserve();
+        self.load_variable(name, dst);
+        dst
+    }
+
+    fn resolve(&mut self, name: &Token) -> Variable {
+        self.line = name.line;
+
+        let current = self.states.len() - 1;
+        if let Some(register) = self.resolve_local(current, name) {
+            return Variable::Local(register);
+        }
+        if let Some(index) = self.resolve_upvalue(current, name) {
+            return Variable::Upvalue(index);
+        }
+        Variable::Global(self.identifier_constant(name))
+    }
+
+    fn resolve_local(&self, state: usize, name: &Token) -> Option<Register> {
+        self.states[state]
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.name == name.lexeme)
+            .map(|local| local.register)
+    }
+
+    /// Finds a variable of the functions enclosing `state`, adding it to the
+    /// upvalues of every function in between.
+    fn resolve_upvalue(&mut self, state: usize, name: &Token) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+
+        if let Some(register) = self.resolve_local(state - 1, name) {
+            let local = self.states[state - 1]
+                .locals
+                .iter_mut()
+                .find(|local| local.register == register)
+                .expect("resolved to a local");
+            local.is_captured = true;
+            return Some(self.add_upvalue(state, register, true, name));
+        }
+
+        let index = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, index, false, name))
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool, name: &Token) -> u8 {
+        let upvalue = Capture { index, is_local };
+        let upvalues = &mut self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == MAX_UPVALUES {
+            self.error_at(name, "Too many closure variables in function.");
+            return 0;
+        }
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn is_local(&mut self, register: Register) -> bool {
+        self.state()
+            .locals
+            .iter()
+            .any(|local| local.register == register)
+    }
+
+    /// Takes the first free register.
+    fn reserve(&mut self) -> Register {
+        let state = self.state();
+        if state.top == MAX_REGISTERS {
+            self.error("Too many registers in function.");
+            return 0;
+        }
+
+        state.top += 1;
+        state.function.registers = state.function.registers.max(state.top);
+        (state.top - 1) as Register
+    }
+
+    /// Makes `register` the home of the variable `name`, unless it's a
+    /// global.
+    fn declare_variable(&mut self, name: &Token, register: Register) {
+        if self.state().scope_depth == 0 {
+            return;
+        }
+        self.add_local(&name.lexeme, register);
+    }
+
+    fn add_local(&mut self, name: &str, register: Register) {
+        // Uninitialized until it's defined.
+        self.state().locals.push(Local {
+            name: name.into(),
+            depth: usize::MAX,
+            register,
+            is_captured: false,
+        });
+    }
+
+    fn define_variable(&mut self, name: &Token, src: Register) {
+        if self.state().scope_depth > 0 {
+            // The value is already in the register of the local.
+            self.mark_initialized();
+            return;
+        }
+
+        let name = self.identifier_constant(name);
+        self.emit(Instruction::DefineGlobal { src, name });
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state();
+        if state.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = state.scope_depth;
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let state = self.state();
+        state.scope_depth -= 1;
+
+        let depth = state.scope_depth;
+        let mut first = None;
+        let mut captured = false;
+        while let Some(local) = self.state().locals.pop_if(|local| local.depth > depth) {
+            first = Some(local.register);
+            captured |= local.is_captured;
+        }
+
+        if let Some(first) = first {
+            if captured {
+                self.emit(Instruction::CloseUpvalues { from: first });
+            }
+            self.state().top = first as usize;
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> u16 {
+        self.make_constant(Constant::String(name.lexeme.as_str().into()))
+    }
+
+    fn make_constant(&mut self, constant: Constant) -> u16 {
+        let constants = &mut self.state().function.constants;
+        let existing = constants.iter().position(|c| match (c, &constant) {
+            // By their bits, so 0 and -0 stay apart.
+            (Constant::Number(a), Constant::Number(b)) => a.to_bits() == b.to_bits(),
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => false,
+        });
+        let index = existing.unwrap_or_else(|| {
+            constants.push(constant);
+            constants.len() - 1
+        });
+
+        u16::try_from(index).unwrap_or_else(|_| {
+            self.error("Too many constants in one function.");
+            0
+        })
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        let line = self.line;
+        let function = &mut self.state().function;
+        function.code.push(instruction);
+        function.lines.push(line);
+    }
+
+    fn emit_move(&mut self, dst: Register, src: Register) {
+        if dst != src {
+            self.emit(Instruction::Move { dst, src });
+        }
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().ty == FunctionType::Initializer {
+            self.emit(Instruction::Return { src: 0 });
+        } else {
+            let src = self.reserve();
+            self.emit(Instruction::LoadNil { dst: src });
+            self.emit(Instruction::Return { src });
+            self.state().top -= 1;
+        }
+    }
+
+    /// Emits a jump to be patched, returning where it is.
+    fn emit_jump(&mut self, jump: impl Fn(u32) -> Instruction) -> usize {
+        self.emit(jump(u32::MAX));
+        self.state().function.code.len() - 1
+    }
+
+    /// Points the jump at `index` to the next instruction.
+    fn patch_jump(&mut self, index: usize) {
+        let function = &mut self.state().function;
+        let next = function.code.len() as u32;
+        match &mut function.code[index] {
+            Instruction::Jump { target }
+            | Instruction::JumpIfFalse { target, .. }
+            | Instruction::JumpIfTrue { target, .. } => *target = next,
+            _ => unreachable!("not a jump"),
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        self.errors.push(CompileError {
+            line: self.line,
+            location: String::new(),
+            message: message.into(),
+        });
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        self.errors.push(CompileError {
+            line: token.line,
+            location: format!(" at '{}'", token.lexeme),
+            message: message.into(),
+        });
+    }
+}
+
+/// Whether evaluating the expression could assign to a local, directly or
+/// through a function it calls.
+fn assigns(expr: &Expr) -> bool {
+    match expr {
+        Expr::Assign { .. } | Expr::Call { .. } => true,
+        Expr::Binary { lhs, rhs, .. } | Expr::Logical { lhs, rhs, .. } => {
+            assigns(lhs) || assigns(rhs)
+        }
+        Expr::Get { object, .. } => assigns(object),
+        Expr::Grouping(expr) => assigns(expr),
+        Expr::Set { object, value, .. } => assigns(object) || assigns(value),
+        Expr::Unary { rhs, .. } => assigns(rhs),
+        Expr::Literal { .. } | Expr::Super { .. } | Expr::This { .. } | Expr::Variable { .. } => {
+            false
+        }
+    }
+}
+
+/// The `this` a `super` expression binds methods to.
+fn this(keyword: &Token) -> Token {
+    Token::new(TokenType::This, "this".into(), keyword.line)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+    use crate::register::compiler::Compiler;
+    use crate::register::{Constant, Instruction};
+    use crate::scanner::Scanner;
+
+    #[test]
+    fn test_compile_locals() {
+        let tokens = Scanner::new("{ var a = 1; var b = 2; print a + b; }")
+            .scan_tokens()
+            .unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        // The locals are read from their registers, only the sum takes a
+        // temporary.
+        assert_eq!(
+            script.code,
+            vec![
+                Instruction::LoadConstant {
+                    dst: 1,
+                    constant: 0
+                },
+                Instruction::LoadConstant {
+                    dst: 2,
+                    constant: 1
+                },
+                Instruction::Add {
+                    dst: 3,
+                    lhs: 1,
+                    rhs: 2
+                },
+                Instruction::Print { src: 3 },
+                Instruction::LoadNil { dst: 1 },
+                Instruction::Return { src: 1 },
+            ]
+        );
+        assert_eq!(
+            script.constants,
+            vec![Constant::Number(1.0), Constant::Number(2.0)]
+        );
+        assert_eq!(script.registers, 4);
+    }
+}
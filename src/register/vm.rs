@@ -0,0 +1,781 @@
+use super::{Constant, Function, Instruction, Register};
+use crate::memory::{GcStats, Heap};
+use crate::object::{
+    NativeFunction, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjRef,
+    ObjRegisterFunction, ObjUpvalue,
+};
+use crate::shape::{CacheTarget, InlineCache, Shapes};
+use crate::value::{Value, ValueKind};
+use crate::vm::{clock, RuntimeError, FRAMES_MAX};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+struct CallFrame {
+    closure: ObjRef,
+    /// The function of `closure`, and the compiled code behind it, kept
+    /// around to not go through the heap for every instruction.
+    function: ObjRef,
+    code: Rc<Function>,
+    ip: usize,
+    /// Where register zero of the function is in the stack.
+    base: usize,
+    /// How long the stack was before the call, which is what it gets back
+    /// to on return. The windows of callers may end past the one of their
+    /// callee.
+    stack_len: usize,
+}
+
+/// Register based virtual machine running the code of the register
+/// [`Compiler`].
+///
+/// It shares the heap, objects and inline caches of the stack based
+/// [`Vm`](crate::vm::Vm), only the code and how it runs differ.
+///
+/// [`Compiler`]: super::compiler::Compiler
+pub struct Vm {
+    heap: Heap,
+    /// The register windows of the running functions, each one starting at
+    /// the register its callee was in.
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<ObjRef, Value>,
+    /// The interned name of initializers.
+    init_string: ObjRef,
+    shapes: Shapes,
+    /// Upvalues still pointing into the stack, ordered by their slot, so
+    /// closures capturing the same variable share them.
+    open_upvalues: Vec<ObjRef>,
+    out: Box<dyn Write>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Sends what `print` writes to `out` instead of the standard output.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        let mut heap = Heap::default();
+        let init_string = heap.alloc(Obj::String("init".into()));
+
+        let mut vm = Self {
+            heap,
+            stack: vec![],
+            frames: vec![],
+            globals: HashMap::new(),
+            init_string,
+            shapes: Shapes::default(),
+            open_upvalues: vec![],
+            out,
+        };
+
+        vm.define_native(NativeFunction {
+            name: "clock",
+            arity: 0,
+            function: clock,
+        });
+
+        vm
+    }
+
+    /// Collects garbage before every allocation, which makes any object the
+    /// VM forgets to mark get freed right away.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    fn define_native(&mut self, native: NativeFunction) {
+        let name = self.intern(native.name);
+        self.stack.push(Value::obj(name));
+        let function = self.alloc(Obj::Native(native));
+        self.stack.pop();
+        self.globals.insert(name, Value::obj(function));
+    }
+
+    /// Runs a script, keeping its globals around for the next one.
+    pub fn interpret(&mut self, script: Rc<Function>) -> Result<(), RuntimeError> {
+        let function = self.load(script);
+        self.stack.push(Value::obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: vec![],
+        }));
+        self.stack.pop();
+        self.stack.push(Value::obj(closure));
+
+        let result = self
+            .call(closure, self.stack.len() - 1, 0)
+            .and_then(|()| self.run());
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    /// Moves a compiled function into the heap, along with the functions in
+    /// its constants.
+    fn load(&mut self, function: Rc<Function>) -> ObjRef {
+        // The constants wait on the stack, where the collector sees them.
+        let base = self.stack.len();
+        for constant in &function.constants {
+            let value = match constant {
+                Constant::Number(n) => Value::number(*n),
+                Constant::String(s) => Value::obj(self.intern(s)),
+                Constant::Function(f) => Value::obj(self.load(f.clone())),
+            };
+            self.stack.push(value);
+        }
+
+        let constants = self.stack.split_off(base);
+        let caches = vec![InlineCache::default(); function.code.len()];
+        self.alloc(Obj::RegisterFunction(ObjRegisterFunction {
+            function,
+            constants,
+            caches,
+        }))
+    }
+
+    /// Allocates an object, collecting garbage first if it's time to.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.heap.mark_references(&obj);
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    /// The string object with these contents, made if there's none yet.
+    fn intern(&mut self, s: &str) -> ObjRef {
+        match self.heap.find_string(s) {
+            Some(string) => string,
+            None => self.alloc(Obj::String(s.into())),
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        // Registers above the temporaries in use may hold stale values,
+        // which only keeps them alive a bit longer.
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+        for (name, value) in &self.globals {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+        self.heap.mark_object(self.init_string);
+        for name in self.shapes.names() {
+            self.heap.mark_object(name);
+        }
+
+        self.heap.collect();
+        self.shapes.collect(&mut self.heap);
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            let frame = self.frames.last_mut().expect("no frame running");
+            let instruction = frame.code.code[frame.ip];
+            frame.ip += 1;
+            let base = frame.base;
+            let reg = |r: Register| base + r as usize;
+
+            match instruction {
+                Instruction::LoadConstant { dst, constant } => {
+                    self.stack[reg(dst)] = self.constant(constant);
+                }
+                Instruction::LoadNil { dst } => self.stack[reg(dst)] = Value::NIL,
+                Instruction::LoadBool { dst, value } => self.stack[reg(dst)] = Value::bool(value),
+                Instruction::Move { dst, src } => self.stack[reg(dst)] = self.stack[reg(src)],
+                Instruction::GetGlobal { dst, name } => {
+                    let name = self.name(name);
+                    match self.globals.get(&name) {
+                        Some(value) => self.stack[reg(dst)] = *value,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::DefineGlobal { src, name } => {
+                    let name = self.name(name);
+                    self.globals.insert(name, self.stack[reg(src)]);
+                }
+                Instruction::SetGlobal { src, name } => {
+                    let name = self.name(name);
+                    let value = self.stack[reg(src)];
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                Instruction::GetUpvalue { dst, index } => {
+                    let upvalue = self.upvalue(index);
+                    self.stack[reg(dst)] = match self.heap.upvalue(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot],
+                        ObjUpvalue::Closed(value) => *value,
+                    };
+                }
+                Instruction::SetUpvalue { src, index } => {
+                    let upvalue = self.upvalue(index);
+                    let value = self.stack[reg(src)];
+                    match self.heap.upvalue_mut(upvalue) {
+                        ObjUpvalue::Open(slot) => self.stack[*slot] = value,
+                        ObjUpvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                Instruction::GetProperty { dst, object, name } => {
+                    let name = self.name(name);
+                    let value = self.stack[reg(object)];
+                    let Some(instance) = self.as_instance(value) else {
+                        return Err(self.error("Only instances have properties."));
+                    };
+
+                    self.stack[reg(dst)] = match self.find_property(instance, name)? {
+                        CacheTarget::Field(slot) => self.heap.instance(instance).fields[slot],
+                        CacheTarget::Method(method) => self.bind(value, method),
+                        CacheTarget::Transition(_) => unreachable!("only setters transition"),
+                    };
+                }
+                Instruction::SetProperty { object, name, src } => {
+                    let name = self.name(name);
+                    let Some(instance) = self.as_instance(self.stack[reg(object)]) else {
+                        return Err(self.error("Only instances have fields."));
+                    };
+                    self.set_property(instance, name, self.stack[reg(src)]);
+                }
+                Instruction::GetSuper {
+                    dst,
+                    receiver,
+                    class,
+                    name,
+                } => {
+                    let name = self.name(name);
+                    let class = self.superclass(self.stack[reg(class)])?;
+                    let Some(method) = self.heap.class(class).methods.get(&name).copied() else {
+                        return Err(self.undefined_property(name));
+                    };
+                    self.stack[reg(dst)] = self.bind(self.stack[reg(receiver)], method);
+                }
+                Instruction::Equal { dst, lhs, rhs } => {
+                    let equal = self
+                        .heap
+                        .values_equal(self.stack[reg(lhs)], self.stack[reg(rhs)]);
+                    self.stack[reg(dst)] = Value::bool(equal);
+                }
+                Instruction::NotEqual { dst, lhs, rhs } => {
+                    let equal = self
+                        .heap
+                        .values_equal(self.stack[reg(lhs)], self.stack[reg(rhs)]);
+                    self.stack[reg(dst)] = Value::bool(!equal);
+                }
+                Instruction::Greater { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::bool(a > b))?;
+                }
+                Instruction::GreaterEqual { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::bool(a >= b))?;
+                }
+                Instruction::Less { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::bool(a < b))?;
+                }
+                Instruction::LessEqual { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::bool(a <= b))?;
+                }
+                Instruction::Add { dst, lhs, rhs } => self.add(reg(dst), reg(lhs), reg(rhs))?,
+                Instruction::Subtract { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::number(a - b))?;
+                }
+                Instruction::Multiply { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::number(a * b))?;
+                }
+                Instruction::Divide { dst, lhs, rhs } => {
+                    self.binary_op(reg(dst), reg(lhs), reg(rhs), |a, b| Value::number(a / b))?;
+                }
+                Instruction::Not { dst, src } => {
+                    self.stack[reg(dst)] = Value::bool(self.stack[reg(src)].is_falsey());
+                }
+                Instruction::Negate { dst, src } => match self.stack[reg(src)].kind() {
+                    ValueKind::Number(n) => self.stack[reg(dst)] = Value::number(-n),
+                    _ => return Err(self.error("Operand must be a number.")),
+                },
+                Instruction::Print { src } => {
+                    let value = self.stack[reg(src)];
+                    writeln!(self.out, "{}", self.heap.display(value)).expect("failed to print");
+                }
+                Instruction::Jump { target } => self.frame_mut().ip = target as usize,
+                Instruction::JumpIfFalse { cond, target } => {
+                    if self.stack[reg(cond)].is_falsey() {
+                        self.frame_mut().ip = target as usize;
+                    }
+                }
+                Instruction::JumpIfTrue { cond, target } => {
+                    if !self.stack[reg(cond)].is_falsey() {
+                        self.frame_mut().ip = target as usize;
+                    }
+                }
+                Instruction::Call { base, argc } => self.call_value(reg(base), argc as usize)?,
+                Instruction::Invoke {
+                    base: receiver,
+                    name,
+                    argc,
+                } => {
+                    let name = self.name(name);
+                    self.invoke(reg(receiver), name, argc as usize)?;
+                }
+                Instruction::SuperInvoke {
+                    base: receiver,
+                    class,
+                    name,
+                    argc,
+                } => {
+                    let name = self.name(name);
+                    let class = self.superclass(self.stack[reg(class)])?;
+                    match self.heap.class(class).methods.get(&name).copied() {
+                        Some(method) => self.call(method, reg(receiver), argc as usize)?,
+                        None => return Err(self.undefined_property(name)),
+                    }
+                }
+                Instruction::Closure { dst, function } => {
+                    let ValueKind::Obj(function) = self.constant(function).kind() else {
+                        unreachable!("closures are made of function constants");
+                    };
+                    self.closure(function, reg(dst));
+                }
+                Instruction::CloseUpvalues { from } => self.close_upvalues(reg(from)),
+                Instruction::Return { src } => {
+                    let result = self.stack[reg(src)];
+                    let frame = self.frames.pop().expect("no frame to return from");
+                    self.close_upvalues(frame.base);
+
+                    if self.frames.is_empty() {
+                        self.stack.truncate(frame.base);
+                        return Ok(());
+                    }
+                    self.stack[frame.base] = result;
+                    self.stack.truncate(frame.stack_len);
+                }
+                Instruction::Class { dst, name } => {
+                    let name = self.name(name);
+                    let shape = self.shapes.root();
+                    let class = self.alloc(Obj::Class(ObjClass {
+                        name: self.heap.string(name).clone(),
+                        methods: HashMap::new(),
+                        shape,
+                    }));
+                    self.stack[reg(dst)] = Value::obj(class);
+                }
+                Instruction::Inherit { class, superclass } => {
+                    let Some(superclass) = self.as_class(self.stack[reg(superclass)]) else {
+                        return Err(self.error("Superclass must be a class."));
+                    };
+                    let Some(class) = self.as_class(self.stack[reg(class)]) else {
+                        return Err(self.error("Invalid code: only classes inherit."));
+                    };
+
+                    // Copied down, so method lookups never walk the chain.
+                    let methods = self.heap.class(superclass).methods.clone();
+                    self.heap.class_mut(class).methods.extend(methods);
+                    self.heap.resized(class);
+                }
+                Instruction::Method {
+                    class,
+                    name,
+                    method,
+                } => {
+                    let name = self.name(name);
+                    let (Some(class), Some(method)) = (
+                        self.as_class(self.stack[reg(class)]),
+                        self.as_closure(self.stack[reg(method)]),
+                    ) else {
+                        return Err(
+                            self.error("Invalid code: methods are functions defined on classes.")
+                        );
+                    };
+                    self.heap.class_mut(class).methods.insert(name, method);
+                    self.heap.resized(class);
+                }
+            }
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no frame running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no frame running")
+    }
+
+    fn constant(&self, index: u16) -> Value {
+        self.heap.register_function(self.frame().function).constants[index as usize]
+    }
+
+    /// Reads a name, which is an interned string.
+    fn name(&self, index: u16) -> ObjRef {
+        match self.constant(index).kind() {
+            ValueKind::Obj(obj) => obj,
+            _ => unreachable!("names are string constants"),
+        }
+    }
+
+    fn upvalue(&self, index: u8) -> ObjRef {
+        self.heap.closure(self.frame().closure).upvalues[index as usize]
+    }
+
+    fn add(&mut self, dst: usize, lhs: usize, rhs: usize) -> Result<(), RuntimeError> {
+        match (self.stack[lhs].kind(), self.stack[rhs].kind()) {
+            (ValueKind::Number(a), ValueKind::Number(b)) => {
+                self.stack[dst] = Value::number(a + b);
+            }
+            (ValueKind::Obj(a), ValueKind::Obj(b))
+                if matches!(self.heap.get(a), Obj::String(_))
+                    && matches!(self.heap.get(b), Obj::String(_)) =>
+            {
+                let concatenated = format!("{}{}", self.heap.string(a), self.heap.string(b));
+                let string = self.intern(&concatenated);
+                self.stack[dst] = Value::obj(string);
+            }
+            _ => return Err(self.error("Operands must be two numbers or two strings.")),
+        }
+        Ok(())
+    }
+
+    fn binary_op(
+        &mut self,
+        dst: usize,
+        lhs: usize,
+        rhs: usize,
+        op: impl Fn(f64, f64) -> Value,
+    ) -> Result<(), RuntimeError> {
+        match (self.stack[lhs].kind(), self.stack[rhs].kind()) {
+            (ValueKind::Number(a), ValueKind::Number(b)) => {
+                self.stack[dst] = op(a, b);
+                Ok(())
+            }
+            _ => Err(self.error("Operands must be numbers.")),
+        }
+    }
+
+    fn as_instance(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Instance(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn as_class(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Class(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn as_closure(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Closure(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// The class `super` refers to.
+    fn superclass(&self, value: Value) -> Result<ObjRef, RuntimeError> {
+        self.as_class(value)
+            .ok_or_else(|| self.error("Invalid code: 'super' is not a class."))
+    }
+
+    /// Wraps `function` in a closure, which goes in the stack at `dst`.
+    fn closure(&mut self, function: ObjRef, dst: usize) {
+        let code = self.heap.register_function(function).function.clone();
+
+        // In its register before capturing anything, as that can collect
+        // garbage.
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::with_capacity(code.captures.len()),
+        }));
+        self.stack[dst] = Value::obj(closure);
+
+        for capture in &code.captures {
+            let upvalue = if capture.is_local {
+                self.capture_upvalue(self.frame().base + capture.index as usize)
+            } else {
+                self.upvalue(capture.index)
+            };
+            self.heap.closure_mut(closure).upvalues.push(upvalue);
+        }
+    }
+
+    /// The upvalue for the variable at `slot` in the stack, shared with the
+    /// closures that already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self
+            .open_upvalues
+            .partition_point(|&upvalue| self.open_slot(upvalue) < slot);
+        if let Some(&upvalue) = self.open_upvalues.get(position) {
+            if self.open_slot(upvalue) == slot {
+                return upvalue;
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    fn open_slot(&self, upvalue: ObjRef) -> usize {
+        match self.heap.upvalue(upvalue) {
+            ObjUpvalue::Open(slot) => *slot,
+            ObjUpvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+        }
+    }
+
+    /// Moves the variables from `last` up in the stack into their upvalues,
+    /// as their registers are about to be reused.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = self.open_slot(upvalue);
+            if slot < last {
+                break;
+            }
+
+            *self.heap.upvalue_mut(upvalue) = ObjUpvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Calls the value in the stack at `callee`, with the `argc` arguments
+    /// after it.
+    fn call_value(&mut self, callee: usize, argc: usize) -> Result<(), RuntimeError> {
+        let ValueKind::Obj(obj) = self.stack[callee].kind() else {
+            return Err(self.error("Can only call functions and classes."));
+        };
+
+        match self.heap.get(obj) {
+            Obj::Closure(_) => self.call(obj, callee, argc),
+            Obj::BoundMethod(ObjBoundMethod { receiver, method }) => {
+                let method = *method;
+                self.stack[callee] = *receiver;
+                self.call(method, callee, argc)
+            }
+            Obj::Class(class) => {
+                let init = class.methods.get(&self.init_string).copied();
+                let instance = self.alloc(Obj::Instance(ObjInstance {
+                    class: obj,
+                    shape: class.shape,
+                    fields: vec![],
+                }));
+                self.stack[callee] = Value::obj(instance);
+
+                match init {
+                    Some(init) => self.call(init, callee, argc),
+                    None if argc != 0 => {
+                        Err(self.error(format!("Expected 0 arguments but got {argc}.")))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Obj::Native(native) => {
+                if argc != native.arity {
+                    let message = format!("Expected {} arguments but got {argc}.", native.arity);
+                    return Err(self.error(message));
+                }
+
+                let args = &self.stack[callee + 1..callee + 1 + argc];
+                self.stack[callee] = (native.function)(args);
+                Ok(())
+            }
+            _ => Err(self.error("Can only call functions and classes.")),
+        }
+    }
+
+    /// Starts running `closure` with its window at `base`, where the callee
+    /// is.
+    fn call(&mut self, closure: ObjRef, base: usize, argc: usize) -> Result<(), RuntimeError> {
+        let function = self.heap.closure(closure).function;
+        let code = self.heap.register_function(function).function.clone();
+        if argc != code.arity {
+            return Err(self.error(format!("Expected {} arguments but got {argc}.", code.arity)));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.error("Stack overflow."));
+        }
+
+        let stack_len = self.stack.len();
+        let end = base + code.registers;
+        if stack_len < end {
+            self.stack.resize(end, Value::NIL);
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            code,
+            ip: 0,
+            base,
+            stack_len,
+        });
+        Ok(())
+    }
+
+    /// Calls a method straight from the receiver at `receiver` in the stack,
+    /// unless it's shadowed by a field.
+    fn invoke(&mut self, receiver: usize, name: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        let Some(instance) = self.as_instance(self.stack[receiver]) else {
+            return Err(self.error("Only instances have properties."));
+        };
+
+        match self.find_property(instance, name)? {
+            CacheTarget::Field(slot) => {
+                self.stack[receiver] = self.heap.instance(instance).fields[slot];
+                self.call_value(receiver, argc)
+            }
+            CacheTarget::Method(method) => self.call(method, receiver, argc),
+            CacheTarget::Transition(_) => unreachable!("only setters transition"),
+        }
+    }
+
+    /// Finds the property `name` of `instance`, a field or else a method,
+    /// going through the inline cache of the running instruction.
+    fn find_property(
+        &mut self,
+        instance: ObjRef,
+        name: ObjRef,
+    ) -> Result<CacheTarget, RuntimeError> {
+        let ObjInstance { class, shape, .. } = *self.heap.instance(instance);
+        let CallFrame { function, ip, .. } = *self.frame();
+        let site = ip - 1;
+        if let Some(target) = self.heap.register_function(function).caches[site].lookup(shape) {
+            return Ok(target);
+        }
+
+        let target = match self.shapes.slot(shape, name) {
+            Some(slot) => CacheTarget::Field(slot),
+            None => match self.heap.class(class).methods.get(&name) {
+                Some(&method) => CacheTarget::Method(method),
+                None => return Err(self.undefined_property(name)),
+            },
+        };
+        self.heap.register_function_mut(function).caches[site].insert(shape, target);
+        Ok(target)
+    }
+
+    fn set_property(&mut self, instance: ObjRef, name: ObjRef, value: Value) {
+        let shape = self.heap.instance(instance).shape;
+        let CallFrame { function, ip, .. } = *self.frame();
+        let site = ip - 1;
+        let target = match self.heap.register_function(function).caches[site].lookup(shape) {
+            Some(target) => target,
+            None => {
+                let target = match self.shapes.slot(shape, name) {
+                    Some(slot) => CacheTarget::Field(slot),
+                    None => CacheTarget::Transition(self.shapes.transition(shape, name)),
+                };
+                self.heap.register_function_mut(function).caches[site].insert(shape, target);
+                target
+            }
+        };
+
+        match target {
+            CacheTarget::Field(slot) => self.heap.instance_mut(instance).fields[slot] = value,
+            CacheTarget::Transition(next) => {
+                let object = self.heap.instance_mut(instance);
+                object.fields.push(value);
+                object.shape = next;
+                // The fields may have outgrown what the instance was counted at.
+                self.heap.resized(instance);
+            }
+            CacheTarget::Method(_) => unreachable!("setters don't cache methods"),
+        }
+    }
+
+    /// `method` bound to `receiver`.
+    fn bind(&mut self, receiver: Value, method: ObjRef) -> Value {
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method }));
+        Value::obj(bound)
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> RuntimeError {
+        self.error(format!("Undefined variable '{}'.", self.heap.string(name)))
+    }
+
+    fn undefined_property(&self, name: ObjRef) -> RuntimeError {
+        self.error(format!("Undefined property '{}'.", self.heap.string(name)))
+    }
+
+    /// Builds an error pointing at the instruction being run.
+    fn error(&self, message: impl Into<String>) -> RuntimeError {
+        let frame = self.frame();
+        RuntimeError {
+            message: message.into(),
+            line: frame.code.lines[frame.ip - 1],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+    use crate::programs;
+    use crate::register::compiler::Compiler;
+    use crate::register::vm::Vm;
+    use crate::scanner::Scanner;
+    use std::io::Write;
+
+    #[test]
+    fn test_programs() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let script = Compiler::new().compile(&statements).unwrap();
+
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            if let Err(err) = vm.interpret(script) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_programs_under_gc_stress() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let script = Compiler::new().compile(&statements).unwrap();
+
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            vm.set_gc_stress(true);
+            if let Err(err) = vm.interpret(script) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let tokens = Scanner::new("fun f() { f(); }\nf();")
+            .scan_tokens()
+            .unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let err = Vm::with_output(Box::new(std::io::sink()))
+            .interpret(script)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Stack overflow.\n[line 1]");
+    }
+}
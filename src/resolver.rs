@@ -0,0 +1,439 @@
+use crate::ast::{Expr, ExprId, Function, Stmt};
+use crate::token::Token;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+/// Where the binding of a local variable lives: how many scopes out from its
+/// use, and at which position inside that scope.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
+/// Resolved variable uses. Uses missing from the map are globals.
+pub type Locals = HashMap<ExprId, Slot, BuildHasherDefault<IdHasher>>;
+
+/// `ExprId`s are already unique integers, so there's nothing to hash.
+#[derive(Default)]
+pub struct IdHasher(u64);
+
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | *byte as u64;
+        }
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.0 = i as u64;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Local {
+    /// Whether the initializer of the variable has finished.
+    defined: bool,
+    slot: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ResolveError {
+    pub token: Token,
+    pub message: String,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "[line {}] Error at '{}': {}",
+            self.token.line, self.token.lexeme, self.message
+        )
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Static pass that runs between the parser and the interpreter, binding
+/// every local variable use to its declaration and reporting the semantic
+/// errors that don't need the program to run.
+pub struct Resolver {
+    /// Slots are handed out in declaration order, which is also the order
+    /// the interpreter pushes values into an environment.
+    scopes: Vec<HashMap<String, Local>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+    locals: Locals,
+    errors: Vec<ResolveError>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![],
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+            locals: Locals::default(),
+            errors: vec![],
+        }
+    }
+
+    pub fn resolve(mut self, statements: &[Stmt]) -> Result<Locals, Vec<ResolveError>> {
+        self.resolve_statements(statements);
+
+        if self.errors.is_empty() {
+            Ok(self.locals)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn resolve_statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.resolve_statement(statement);
+        }
+    }
+
+    fn resolve_statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(statements) => {
+                self.begin_scope();
+                self.resolve_statements(statements);
+                self.end_scope();
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.resolve_class(name, superclass.as_ref(), methods),
+            Stmt::Expression(expr) => self.resolve_expr(expr),
+            Stmt::Function(function) => {
+                self.declare(&function.name);
+                self.define(&function.name);
+                self.resolve_function(function, FunctionType::Function);
+            }
+            Stmt::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.resolve_expr(cond);
+                self.resolve_statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_statement(else_branch);
+                }
+            }
+            Stmt::Print { value, .. } => self.resolve_expr(value),
+            Stmt::Return { keyword, value } => {
+                if self.current_function == FunctionType::None {
+                    self.error(keyword, "Can't return from top-level code.");
+                }
+
+                if let Some(value) = value {
+                    if self.current_function == FunctionType::Initializer {
+                        self.error(keyword, "Can't return a value from an initializer.");
+                    }
+                    self.resolve_expr(value);
+                }
+            }
+            Stmt::Var { name, init } => {
+                self.declare(name);
+                if let Some(init) = init {
+                    self.resolve_expr(init);
+                }
+                self.define(name);
+            }
+            Stmt::While { cond, body, .. } => {
+                self.resolve_expr(cond);
+                self.resolve_statement(body);
+            }
+        }
+    }
+
+    fn resolve_class(&mut self, name: &Token, superclass: Option<&Expr>, methods: &[Rc<Function>]) {
+        let enclosing_class = self.current_class;
+        self.current_class = ClassType::Class;
+
+        self.declare(name);
+        self.define(name);
+
+        if let Some(superclass) = superclass {
+            if let Expr::Variable {
+                name: superclass_name,
+                ..
+            } = superclass
+            {
+                if superclass_name.lexeme == name.lexeme {
+                    self.error(superclass_name, "A class can't inherit from itself.");
+                }
+            }
+
+            self.current_class = ClassType::Subclass;
+            self.resolve_expr(superclass);
+
+            self.begin_scope();
+            self.declare_implicit("super");
+        }
+
+        self.begin_scope();
+        self.declare_implicit("this");
+
+        for method in methods {
+            let declaration = if method.name.lexeme == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.resolve_function(method, declaration);
+        }
+
+        self.end_scope();
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+
+        self.current_class = enclosing_class;
+    }
+
+    fn resolve_function(&mut self, function: &Function, ty: FunctionType) {
+        let enclosing_function = self.current_function;
+        self.current_function = ty;
+
+        self.begin_scope();
+        for param in &function.params {
+            self.declare(param);
+            self.define(param);
+        }
+        self.resolve_statements(&function.body);
+        self.end_scope();
+
+        self.current_function = enclosing_function;
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { id, name, value } => {
+                self.resolve_expr(value);
+                self.resolve_local(*id, name);
+            }
+            Expr::Binary { lhs, rhs, .. } | Expr::Logical { lhs, rhs, .. } => {
+                self.resolve_expr(lhs);
+                self.resolve_expr(rhs);
+            }
+            Expr::Call { callee, args, .. } => {
+                self.resolve_expr(callee);
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            Expr::Get { object, .. } => self.resolve_expr(object),
+            Expr::Grouping(expr) => self.resolve_expr(expr),
+            Expr::Literal { .. } => {}
+            Expr::Set { object, value, .. } => {
+                self.resolve_expr(value);
+                self.resolve_expr(object);
+            }
+            Expr::Super { id, keyword, .. } => {
+                match self.current_class {
+                    ClassType::None => self.error(keyword, "Can't use 'super' outside of a class."),
+                    ClassType::Class => {
+                        self.error(keyword, "Can't use 'super' in a class with no superclass.")
+                    }
+                    ClassType::Subclass => {}
+                }
+                self.resolve_local(*id, keyword);
+            }
+            Expr::This { id, keyword } => {
+                if self.current_class == ClassType::None {
+                    self.error(keyword, "Can't use 'this' outside of a class.");
+                    return;
+                }
+                self.resolve_local(*id, keyword);
+            }
+            Expr::Unary { rhs, .. } => self.resolve_expr(rhs),
+            Expr::Variable { id, name } => {
+                let local = self.scopes.last().and_then(|scope| scope.get(&name.lexeme));
+                if local.is_some_and(|local| !local.defined) {
+                    self.error(name, "Can't read local variable in its own initializer.");
+                }
+                self.resolve_local(*id, name);
+            }
+        }
+    }
+
+    fn resolve_local(&mut self, id: ExprId, name: &Token) {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(local) = scope.get(&name.lexeme) {
+                let index = local.slot;
+                self.locals.insert(id, Slot { depth, index });
+                return;
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// `this` and `super` live alone in a scope wrapping the methods.
+    fn declare_implicit(&mut self, name: &str) {
+        let scope = self.scopes.last_mut().expect("no scope to declare into");
+        let local = Local {
+            defined: true,
+            slot: scope.len(),
+        };
+        scope.insert(name.into(), local);
+    }
+
+    fn declare(&mut self, name: &Token) {
+        let Some(scope) = self.scopes.last_mut() else {
+            return;
+        };
+
+        if scope.contains_key(&name.lexeme) {
+            self.error(name, "Already a variable with this name in this scope.");
+            return;
+        }
+
+        let local = Local {
+            defined: false,
+            slot: scope.len(),
+        };
+        scope.insert(name.lexeme.clone(), local);
+    }
+
+    fn define(&mut self, name: &Token) {
+        if let Some(local) = self
+            .scopes
+            .last_mut()
+            .and_then(|scope| scope.get_mut(&name.lexeme))
+        {
+            local.defined = true;
+        }
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.errors.push(ResolveError {
+            token: token.clone(),
+            message: message.into(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn resolve_errors(source: &str) -> Vec<String> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+
+        match Resolver::new().resolve(&statements) {
+            Ok(_) => vec![],
+            Err(errors) => errors.iter().map(|err| err.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_slots() {
+        let source = "
+        var global;
+        {
+          var a;
+          var b;
+          fun f(x) {
+            print x + b + global;
+          }
+        }
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let locals = Resolver::new().resolve(&statements).unwrap();
+
+        let mut slots: Vec<_> = locals
+            .values()
+            .map(|slot| (slot.depth, slot.index))
+            .collect();
+        slots.sort();
+        // `x` is the first parameter of `f`, `b` the second variable of the
+        // block and `global` isn't resolved at all.
+        assert_eq!(slots, vec![(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn test_resolve_valid_program() {
+        let source = "
+        var a = 1;
+        {
+          var b = a;
+          var a = b;
+        }
+        fun f(x) { return x; }
+        class A { init() { return; } method() { return this; } }
+        class B < A { method() { return super.method(); } }
+        ";
+        assert_eq!(resolve_errors(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let source = "
+        { var a = a; }
+        { var b; var b; }
+        return 1;
+        print this;
+        print super.x;
+        class A { init() { return 1; } m() { super.m(); } }
+        class B < B {}
+        ";
+        assert_eq!(
+            resolve_errors(source),
+            vec![
+                "[line 2] Error at 'a': Can't read local variable in its own initializer.",
+                "[line 3] Error at 'b': Already a variable with this name in this scope.",
+                "[line 4] Error at 'return': Can't return from top-level code.",
+                "[line 5] Error at 'this': Can't use 'this' outside of a class.",
+                "[line 6] Error at 'super': Can't use 'super' outside of a class.",
+                "[line 7] Error at 'return': Can't return a value from an initializer.",
+                "[line 7] Error at 'super': Can't use 'super' in a class with no superclass.",
+                "[line 8] Error at 'B': A class can't inherit from itself.",
+            ]
+        );
+    }
+}
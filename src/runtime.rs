@@ -0,0 +1,581 @@
+//! Values and memory management shared by the tree-walking interpreter and
+//! the closure compiler.
+//!
+//! The two backends only differ in what the code of a function is: the
+//! declaration the interpreter walks, or the closures the compiler turned it
+//! into. Everything here is generic over it, as long as it implements
+//! [`Code`].
+
+use crate::interpreter::RuntimeError;
+use crate::resolver::Slot;
+use crate::token::{Token, TokenType};
+use crate::vm::FRAMES_MAX;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a backend runs when a function is called.
+pub trait Code {
+    fn name(&self) -> &str;
+    fn arity(&self) -> usize;
+}
+
+pub enum Value<C> {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<LoxFunction<C>>),
+    Native(NativeFunction<C>),
+    Class(Rc<LoxClass<C>>),
+    Instance(Rc<RefCell<LoxInstance<C>>>),
+}
+
+// Derived, these would need `C: Clone`.
+impl<C> Clone for Value<C> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Nil => Self::Nil,
+            Self::Bool(b) => Self::Bool(*b),
+            Self::Number(n) => Self::Number(*n),
+            Self::String(s) => Self::String(s.clone()),
+            Self::Function(function) => Self::Function(function.clone()),
+            Self::Native(native) => Self::Native(*native),
+            Self::Class(class) => Self::Class(class.clone()),
+            Self::Instance(instance) => Self::Instance(instance.clone()),
+        }
+    }
+}
+
+impl<C> Value<C> {
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Self::Bool(b) => *b,
+            Self::Nil => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_equal(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Bool(l), Self::Bool(r)) => l == r,
+            (Self::Number(l), Self::Number(r)) => l == r,
+            (Self::String(l), Self::String(r)) => l == r,
+            (Self::Function(l), Self::Function(r)) => Rc::ptr_eq(l, r),
+            (Self::Native(l), Self::Native(r)) => l.name == r.name,
+            (Self::Class(l), Self::Class(r)) => Rc::ptr_eq(l, r),
+            (Self::Instance(l), Self::Instance(r)) => Rc::ptr_eq(l, r),
+            _ => false,
+        }
+    }
+}
+
+impl<C> From<&TokenType> for Value<C> {
+    fn from(literal: &TokenType) -> Self {
+        match literal {
+            TokenType::Number(n) => Self::Number(*n),
+            TokenType::String(s) => Self::String(s.as_str().into()),
+            TokenType::Bool(b) => Self::Bool(*b),
+            TokenType::Nil => Self::Nil,
+            _ => unreachable!("not a literal: {literal:?}"),
+        }
+    }
+}
+
+impl<C: Code> fmt::Display for Value<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Nil => write!(f, "nil"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{s}"),
+            Self::Function(function) => write!(f, "<fn {}>", function.code.name()),
+            Self::Native(_) => write!(f, "<native fn>"),
+            Self::Class(class) => write!(f, "{}", class.name),
+            Self::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+        }
+    }
+}
+
+pub struct NativeFunction<C> {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: fn(&[Value<C>]) -> Value<C>,
+}
+
+impl<C> Clone for NativeFunction<C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<C> Copy for NativeFunction<C> {}
+
+/// The functions every program starts with as globals.
+pub fn natives<C>() -> [NativeFunction<C>; 1] {
+    [NativeFunction {
+        name: "clock",
+        arity: 0,
+        function: clock,
+    }]
+}
+
+fn clock<C>(_args: &[Value<C>]) -> Value<C> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is set before the unix epoch");
+    Value::Number(now.as_secs_f64())
+}
+
+/// A function along with the scope it closes over.
+pub struct LoxFunction<C> {
+    pub code: Rc<C>,
+    pub closure: Option<Rc<RefCell<Environment<C>>>>,
+    pub is_initializer: bool,
+}
+
+impl<C> LoxFunction<C> {
+    /// The method bound to `instance`, in a scope of its own holding "this".
+    pub fn bind(
+        &self,
+        instance: Rc<RefCell<LoxInstance<C>>>,
+        collector: &mut CycleCollector<C>,
+    ) -> Rc<Self> {
+        let mut environment = Environment::new(self.closure.clone());
+        environment.define(Value::Instance(instance));
+
+        let closure = collector.environment(environment);
+        collector.function(Self {
+            code: self.code.clone(),
+            closure: Some(closure),
+            is_initializer: self.is_initializer,
+        })
+    }
+
+    /// What calling the function returns, given what its body returned.
+    /// Initializers always return "this".
+    pub fn result(&self, returned: Value<C>) -> Value<C> {
+        if !self.is_initializer {
+            return returned;
+        }
+
+        let closure = self
+            .closure
+            .as_ref()
+            .expect("initializers are always bound to an instance");
+        Environment::get_at(closure, Slot { depth: 0, index: 0 })
+    }
+}
+
+pub struct LoxClass<C> {
+    pub name: String,
+    pub superclass: Option<Rc<LoxClass<C>>>,
+    pub methods: HashMap<String, Rc<LoxFunction<C>>>,
+}
+
+impl<C: Code> LoxClass<C> {
+    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction<C>>> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => self
+                .superclass
+                .as_ref()
+                .and_then(|superclass| superclass.find_method(name)),
+        }
+    }
+
+    fn arity(&self) -> usize {
+        self.find_method("init")
+            .map(|init| init.code.arity())
+            .unwrap_or(0)
+    }
+}
+
+pub struct LoxInstance<C> {
+    pub class: Rc<LoxClass<C>>,
+    pub fields: HashMap<String, Value<C>>,
+}
+
+/// A local scope. Values sit at the slots the resolver assigned to them, so
+/// looking a variable up never touches its name. Globals are kept by the
+/// backends instead.
+pub struct Environment<C> {
+    pub values: Vec<Value<C>>,
+    pub enclosing: Option<Rc<RefCell<Environment<C>>>>,
+}
+
+impl<C> Environment<C> {
+    pub fn new(enclosing: Option<Rc<RefCell<Environment<C>>>>) -> Self {
+        Self {
+            values: vec![],
+            enclosing,
+        }
+    }
+
+    pub fn define(&mut self, value: Value<C>) {
+        self.values.push(value);
+    }
+
+    pub fn get_at(environment: &Rc<RefCell<Environment<C>>>, slot: Slot) -> Value<C> {
+        let environment = environment.borrow();
+        if slot.depth == 0 {
+            return environment.values[slot.index].clone();
+        }
+
+        let enclosing = environment
+            .enclosing
+            .as_ref()
+            .expect("resolver computed a depth past the outermost scope");
+        Self::get_at(
+            enclosing,
+            Slot {
+                depth: slot.depth - 1,
+                ..slot
+            },
+        )
+    }
+
+    pub fn assign_at(environment: &Rc<RefCell<Environment<C>>>, slot: Slot, value: Value<C>) {
+        let mut environment = environment.borrow_mut();
+        if slot.depth == 0 {
+            environment.values[slot.index] = value;
+            return;
+        }
+
+        let enclosing = environment
+            .enclosing
+            .as_ref()
+            .expect("resolver computed a depth past the outermost scope");
+        Self::assign_at(
+            enclosing,
+            Slot {
+                depth: slot.depth - 1,
+                ..slot
+            },
+            value,
+        )
+    }
+}
+
+/// Why the execution of a statement stopped early.
+pub enum Unwind<C> {
+    Return(Value<C>),
+    Error(RuntimeError),
+}
+
+impl<C> From<RuntimeError> for Unwind<C> {
+    fn from(err: RuntimeError) -> Self {
+        Self::Error(err)
+    }
+}
+
+/// Checks `callee` can be called with `argc` arguments.
+pub fn check_call<C: Code>(
+    callee: &Value<C>,
+    paren: &Token,
+    argc: usize,
+) -> Result<(), RuntimeError> {
+    let arity = match callee {
+        Value::Function(function) => function.code.arity(),
+        Value::Native(native) => native.arity,
+        Value::Class(class) => class.arity(),
+        _ => {
+            return Err(RuntimeError::new(
+                paren,
+                "Can only call functions and classes.",
+            ))
+        }
+    };
+
+    if argc != arity {
+        return Err(RuntimeError::new(
+            paren,
+            format!("Expected {arity} arguments but got {argc}."),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a call can nest in the `depth` calls running already, as many as
+/// the VM has frames for. These backends recurse on the Rust stack, which
+/// would overflow long before a Lox program runs out of memory.
+pub fn check_depth(depth: usize, paren: &Token) -> Result<(), RuntimeError> {
+    // The frames of the VM count the script's too.
+    if depth + 1 >= FRAMES_MAX {
+        return Err(RuntimeError::new(paren, "Stack overflow."));
+    }
+    Ok(())
+}
+
+/// A field of `instance`, or else one of its methods bound to it.
+pub fn get_property<C: Code>(
+    instance: &Rc<RefCell<LoxInstance<C>>>,
+    name: &Token,
+    collector: &mut CycleCollector<C>,
+) -> Result<Value<C>, RuntimeError> {
+    if let Some(value) = instance.borrow().fields.get(&name.lexeme) {
+        return Ok(value.clone());
+    }
+
+    let method = instance.borrow().class.find_method(&name.lexeme);
+    match method {
+        Some(method) => Ok(Value::Function(method.bind(instance.clone(), collector))),
+        None => Err(undefined_property(name)),
+    }
+}
+
+pub fn undefined_variable(name: &Token) -> RuntimeError {
+    RuntimeError::new(name, format!("Undefined variable '{}'.", name.lexeme))
+}
+
+pub fn undefined_property(name: &Token) -> RuntimeError {
+    RuntimeError::new(name, format!("Undefined property '{}'.", name.lexeme))
+}
+
+/// Once this many objects were made, the collector looks for cycles.
+const FIRST_COLLECTION: usize = 10_000;
+
+/// Reference counting frees most objects as soon as they become
+/// unreachable, but not the ones kept alive by a cycle: a closure stored in
+/// the environment it captures, or an instance referencing itself. The
+/// collector keeps track of every object that could be part of one, and
+/// every now and then frees those only referenced by other tracked objects.
+///
+/// That's done the way CPython does it. Each object starts with its
+/// reference count, minus the references it gets from other tracked
+/// objects. What is left over are references from the outside (globals, the
+/// current environment, values the backend is working with), and whatever
+/// can't be reached from objects that have any is garbage. As every cycle
+/// goes through an environment or an instance, emptying the garbage ones
+/// breaks the cycles, and reference counting frees the rest.
+pub struct CycleCollector<C> {
+    pub(crate) objects: Vec<Tracked<C>>,
+    next_collection: usize,
+}
+
+/// An object that can be part of a cycle. Functions and classes never change
+/// once made, but they hold on to environments that do.
+enum Object<C> {
+    Environment(Rc<RefCell<Environment<C>>>),
+    Function(Rc<LoxFunction<C>>),
+    Class(Rc<LoxClass<C>>),
+    Instance(Rc<RefCell<LoxInstance<C>>>),
+}
+
+/// Tracking an object doesn't keep it alive.
+pub(crate) enum Tracked<C> {
+    Environment(Weak<RefCell<Environment<C>>>),
+    Function(Weak<LoxFunction<C>>),
+    Class(Weak<LoxClass<C>>),
+    Instance(Weak<RefCell<LoxInstance<C>>>),
+}
+
+/// Objects are told apart by their address.
+type ObjectId = *const ();
+
+impl<C> Object<C> {
+    fn id(&self) -> ObjectId {
+        match self {
+            Self::Environment(environment) => Rc::as_ptr(environment) as ObjectId,
+            Self::Function(function) => Rc::as_ptr(function) as ObjectId,
+            Self::Class(class) => Rc::as_ptr(class) as ObjectId,
+            Self::Instance(instance) => Rc::as_ptr(instance) as ObjectId,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Self::Environment(environment) => Rc::strong_count(environment),
+            Self::Function(function) => Rc::strong_count(function),
+            Self::Class(class) => Rc::strong_count(class),
+            Self::Instance(instance) => Rc::strong_count(instance),
+        }
+    }
+
+    /// Calls `visit` with every object this one references. Returns `false`
+    /// without visiting anything if the object is borrowed at the moment.
+    fn references(&self, mut visit: impl FnMut(ObjectId)) -> bool {
+        match self {
+            Self::Environment(environment) => {
+                let Ok(environment) = environment.try_borrow() else {
+                    return false;
+                };
+                environment
+                    .values
+                    .iter()
+                    .filter_map(value_reference)
+                    .for_each(&mut visit);
+                if let Some(enclosing) = &environment.enclosing {
+                    visit(Rc::as_ptr(enclosing) as ObjectId);
+                }
+            }
+            Self::Function(function) => {
+                if let Some(closure) = &function.closure {
+                    visit(Rc::as_ptr(closure) as ObjectId);
+                }
+            }
+            Self::Class(class) => {
+                if let Some(superclass) = &class.superclass {
+                    visit(Rc::as_ptr(superclass) as ObjectId);
+                }
+                for method in class.methods.values() {
+                    visit(Rc::as_ptr(method) as ObjectId);
+                }
+            }
+            Self::Instance(instance) => {
+                let Ok(instance) = instance.try_borrow() else {
+                    return false;
+                };
+                visit(Rc::as_ptr(&instance.class) as ObjectId);
+                instance
+                    .fields
+                    .values()
+                    .filter_map(value_reference)
+                    .for_each(&mut visit);
+            }
+        }
+
+        true
+    }
+
+    /// Drops everything a garbage object references.
+    fn clear(&self) {
+        match self {
+            Self::Environment(environment) => {
+                let mut environment = environment.borrow_mut();
+                let values = std::mem::take(&mut environment.values);
+                let enclosing = environment.enclosing.take();
+                drop(environment);
+                drop((values, enclosing));
+            }
+            Self::Instance(instance) => {
+                let fields = std::mem::take(&mut instance.borrow_mut().fields);
+                drop(fields);
+            }
+            // Only reference older objects, so they can't close a cycle.
+            Self::Function(_) | Self::Class(_) => {}
+        }
+    }
+}
+
+fn value_reference<C>(value: &Value<C>) -> Option<ObjectId> {
+    match value {
+        Value::Function(function) => Some(Rc::as_ptr(function) as ObjectId),
+        Value::Class(class) => Some(Rc::as_ptr(class) as ObjectId),
+        Value::Instance(instance) => Some(Rc::as_ptr(instance) as ObjectId),
+        _ => None,
+    }
+}
+
+impl<C> Tracked<C> {
+    fn is_alive(&self) -> bool {
+        match self {
+            Self::Environment(environment) => environment.strong_count() > 0,
+            Self::Function(function) => function.strong_count() > 0,
+            Self::Class(class) => class.strong_count() > 0,
+            Self::Instance(instance) => instance.strong_count() > 0,
+        }
+    }
+
+    fn upgrade(&self) -> Option<Object<C>> {
+        match self {
+            Self::Environment(environment) => environment.upgrade().map(Object::Environment),
+            Self::Function(function) => function.upgrade().map(Object::Function),
+            Self::Class(class) => class.upgrade().map(Object::Class),
+            Self::Instance(instance) => instance.upgrade().map(Object::Instance),
+        }
+    }
+}
+
+impl<C> Default for CycleCollector<C> {
+    fn default() -> Self {
+        Self {
+            objects: vec![],
+            next_collection: FIRST_COLLECTION,
+        }
+    }
+}
+
+impl<C> CycleCollector<C> {
+    pub fn environment(&mut self, environment: Environment<C>) -> Rc<RefCell<Environment<C>>> {
+        let environment = Rc::new(RefCell::new(environment));
+        self.objects
+            .push(Tracked::Environment(Rc::downgrade(&environment)));
+        environment
+    }
+
+    pub fn function(&mut self, function: LoxFunction<C>) -> Rc<LoxFunction<C>> {
+        let function = Rc::new(function);
+        self.objects
+            .push(Tracked::Function(Rc::downgrade(&function)));
+        function
+    }
+
+    pub fn class(&mut self, class: LoxClass<C>) -> Rc<LoxClass<C>> {
+        let class = Rc::new(class);
+        self.objects.push(Tracked::Class(Rc::downgrade(&class)));
+        class
+    }
+
+    pub fn instance(&mut self, instance: LoxInstance<C>) -> Rc<RefCell<LoxInstance<C>>> {
+        let instance = Rc::new(RefCell::new(instance));
+        self.objects
+            .push(Tracked::Instance(Rc::downgrade(&instance)));
+        instance
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_collection
+    }
+
+    /// Frees the objects only kept alive by cycles.
+    pub fn collect(&mut self) {
+        let objects: Vec<Object<C>> = self.objects.iter().filter_map(Tracked::upgrade).collect();
+        let indices: HashMap<ObjectId, usize> = objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| (object.id(), index))
+            .collect();
+
+        // Not counting the reference just taken by upgrading.
+        let mut external: Vec<usize> = objects
+            .iter()
+            .map(|object| object.strong_count() - 1)
+            .collect();
+        for object in &objects {
+            // The references of a borrowed object stay external, keeping
+            // whatever it references alive.
+            object.references(|id| {
+                if let Some(&index) = indices.get(&id) {
+                    external[index] -= 1;
+                }
+            });
+        }
+
+        let mut reachable: Vec<bool> = external.iter().map(|&count| count > 0).collect();
+        let mut pending: Vec<usize> = (0..objects.len()).filter(|&i| reachable[i]).collect();
+        while let Some(index) = pending.pop() {
+            objects[index].references(|id| {
+                if let Some(&index) = indices.get(&id) {
+                    if !reachable[index] {
+                        reachable[index] = true;
+                        pending.push(index);
+                    }
+                }
+            });
+        }
+
+        for (object, &reachable) in objects.iter().zip(&reachable) {
+            if !reachable {
+                object.clear();
+            }
+        }
+
+        drop(objects);
+        self.objects.retain(Tracked::is_alive);
+        self.next_collection = (2 * self.objects.len()).max(FIRST_COLLECTION);
+    }
+}
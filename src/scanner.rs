@@ -1,4 +1,4 @@
-use crate::error;
+use crate::error::SyntaxError;
 use crate::token::{Token, TokenType};
 
 const KEYWORDS: &[(&str, TokenType)] = &[
@@ -18,14 +18,19 @@ const KEYWORDS: &[(&str, TokenType)] = &[
     ("while", TokenType::While),
     ("for", TokenType::For),
     ("print", TokenType::Print),
+    ("nil", TokenType::Nil),
 ];
 
 pub struct Scanner<'a> {
     source: &'a str,
     tokens: Vec<Token>,
+    errors: Vec<SyntaxError>,
+    /// Byte offsets of the start of the token and of the next character.
     start: usize,
     current: usize,
     line: usize,
+    /// Whether comments make tokens, for tools that keep them.
+    comments: bool,
 }
 
 impl<'a> Scanner<'a> {
@@ -33,22 +38,34 @@ impl<'a> Scanner<'a> {
         Self {
             source,
             tokens: vec![],
+            errors: vec![],
             start: 0,
             current: 0,
             line: 1,
+            comments: false,
         }
     }
 
-    pub fn scan_tokens(mut self) -> Vec<Token> {
+    pub fn set_comments(&mut self, comments: bool) {
+        self.comments = comments;
+    }
+
+    /// Scans the whole source, or reports every character it can't make a
+    /// token of.
+    pub fn scan_tokens(mut self) -> Result<Vec<Token>, Vec<SyntaxError>> {
         while !self.is_at_end() {
             self.start = self.current;
             self.scan_token();
         }
 
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+
         self.tokens
             .push(Token::new(TokenType::EOF, "".into(), self.line));
 
-        self.tokens
+        Ok(self.tokens)
     }
 
     fn scan_token(&mut self) {
@@ -97,7 +114,10 @@ impl<'a> Scanner<'a> {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
-                    return;
+                    if !self.comments {
+                        return;
+                    }
+                    TokenType::Comment
                 } else {
                     TokenType::Slash
                 }
@@ -110,14 +130,18 @@ impl<'a> Scanner<'a> {
                 self.line += 1;
                 return;
             }
-            '"' => TokenType::String(self.string()),
+            '"' => match self.string() {
+                Some(string) => TokenType::String(string),
+                None => return,
+            },
             c => {
                 if self.is_digit(c) {
                     TokenType::Number(self.number())
                 } else if self.is_alpha(c) {
                     self.identifier()
                 } else {
-                    self.error("Unexpected character.")
+                    self.error("Unexpected character.");
+                    return;
                 }
             }
         };
@@ -143,11 +167,11 @@ impl<'a> Scanner<'a> {
     }
 
     fn is_alpha(&self, c: char) -> bool {
-        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+        c.is_ascii_alphabetic() || c == '_'
     }
 
     fn is_digit(&self, c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
 
     fn number(&mut self) -> f64 {
@@ -168,14 +192,12 @@ impl<'a> Scanner<'a> {
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current + 1).unwrap()
-        }
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        chars.next().unwrap_or('\0')
     }
 
-    fn string(&mut self) -> String {
+    fn string(&mut self) -> Option<String> {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
                 self.line += 1;
@@ -186,19 +208,16 @@ impl<'a> Scanner<'a> {
 
         if self.is_at_end() {
             self.error("Unterminated string.");
+            return None;
         }
 
         self.advance();
 
-        (&self.source[self.start + 1..self.current - 1]).into()
+        Some(self.source[self.start + 1..self.current - 1].into())
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source.chars().nth(self.current).unwrap()
-        }
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn match_(&mut self, expected: char) -> bool {
@@ -206,11 +225,11 @@ impl<'a> Scanner<'a> {
             return false;
         }
 
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
 
         true
     }
@@ -220,8 +239,8 @@ impl<'a> Scanner<'a> {
     }
 
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.peek();
+        self.current += c.len_utf8();
         c
     }
 
@@ -230,8 +249,12 @@ impl<'a> Scanner<'a> {
         self.tokens.push(Token::new(ty, text.into(), self.line));
     }
 
-    fn error(&self, msg: &str) -> ! {
-        error::error(self.line, msg)
+    fn error(&mut self, msg: &str) {
+        self.errors.push(SyntaxError {
+            line: self.line,
+            location: String::new(),
+            message: msg.into(),
+        });
     }
 }
 
@@ -239,7 +262,7 @@ impl<'a> Scanner<'a> {
 fn test_print() {
     let source = "print \"Hello, world!\";";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -262,7 +285,7 @@ fn test_boolean() {
     false; // Not *not* false.
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -282,7 +305,7 @@ fn test_numbers() {
     12.34; // A decimal number.
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -303,7 +326,7 @@ fn test_strings() {
     \"123\"; // This is a string, not a number.
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -331,7 +354,7 @@ fn test_arithmetic() {
     divide / me;
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -370,7 +393,7 @@ fn test_negate() {
     -negateMe;
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -395,7 +418,7 @@ fn test_comparison() {
     greaterThan >= orEqual;
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -439,7 +462,7 @@ fn test_equality() {
     123 == \"123\"; // false.
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -477,7 +500,7 @@ fn test_logical_operators() {
     true or false;  // true.
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -514,7 +537,7 @@ fn test_precedence_and_grouping() {
     var average = (min + max) / 2;
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -542,7 +565,7 @@ fn test_block() {
       print \"Two statements.\";
     }";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -579,7 +602,7 @@ fn test_variables() {
     print breakfast; // \"beignets\".
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -649,7 +672,7 @@ fn test_if_else() {
     }
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -687,7 +710,7 @@ fn test_while() {
     }
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -726,7 +749,7 @@ fn test_for() {
     }
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -772,7 +795,7 @@ fn test_functions() {
       return a + b;
     }";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -873,7 +896,7 @@ fn test_closures() {
     fn();
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -1067,7 +1090,7 @@ fn test_class() {
     ";
 
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -1307,7 +1330,7 @@ fn test_inheritance() {
     }
     ";
     let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
+    let tokens = scanner.scan_tokens().unwrap();
     assert_eq!(
         tokens,
         vec![
@@ -1405,3 +1428,41 @@ fn test_inheritance() {
         ]
     );
 }
+
+#[test]
+fn test_scan_errors() {
+    let source = "print \"café\";\nprint 1 @ é;\n\"open";
+    let errors = Scanner::new(source).scan_tokens().unwrap_err();
+    let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        errors,
+        vec![
+            "[line 2] Error: Unexpected character.",
+            "[line 2] Error: Unexpected character.",
+            "[line 3] Error: Unterminated string.",
+        ]
+    );
+
+    let tokens = Scanner::new("print \"café\";").scan_tokens().unwrap();
+    assert_eq!(tokens[1].ty, TokenType::String("café".into()));
+}
+
+#[test]
+fn test_comments() {
+    let source = "// A comment.\n1 / 2; // Another.";
+    let mut scanner = Scanner::new(source);
+    scanner.set_comments(true);
+    let tokens = scanner.scan_tokens().unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::new(TokenType::Comment, "// A comment.".into(), 1),
+            Token::new(TokenType::Number(1.0), "1".into(), 2),
+            Token::new(TokenType::Slash, "/".into(), 2),
+            Token::new(TokenType::Number(2.0), "2".into(), 2),
+            Token::new(TokenType::Semicolon, ";".into(), 2),
+            Token::new(TokenType::Comment, "// Another.".into(), 2),
+            Token::new(TokenType::EOF, "".into(), 2),
+        ]
+    );
+}
@@ -0,0 +1,327 @@
+//! Hidden classes and inline caches, which make property accesses in the VM
+//! skip the hash lookups most of the time.
+//!
+//! Instances don't carry their field names, only the values, at the slots
+//! their [`Shape`] assigns to them. Instances that got the same fields in the
+//! same order share their shape, and every class has a root shape of its
+//! own, so a shape also tells the class of the instance. An instruction
+//! accessing properties keeps the shapes it saw along with where the
+//! property was for them, which holds for as long as the shape exists.
+//!
+//! Shapes are collected along with the heap: the ones no class or instance
+//! left uses, nor any shape descending from them, are freed after every
+//! collection, and the caches forget them before their index is reused.
+
+use crate::memory::Heap;
+use crate::object::{Obj, ObjRef};
+use std::collections::HashMap;
+
+/// Index of a shape in [`Shapes`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShapeId(u32);
+
+/// The layout of the fields of an instance.
+#[derive(Default)]
+struct Shape {
+    /// Slot of every field, keyed by its interned name.
+    slots: HashMap<ObjRef, usize>,
+    /// The shapes instances get by adding a field.
+    transitions: HashMap<ObjRef, ShapeId>,
+    /// The shape this one adds a field to, kept alive along with it so
+    /// instances growing the same fields keep sharing shapes.
+    parent: Option<ShapeId>,
+}
+
+#[derive(Default)]
+pub struct Shapes {
+    /// Freed shapes are `None`, waiting in `free` to be reused.
+    shapes: Vec<Option<Shape>>,
+    marks: Vec<bool>,
+    free: Vec<u32>,
+    /// The shapes made since the last collection, which survive it, as the
+    /// class or instance using them may not be in the heap yet.
+    young: Vec<ShapeId>,
+}
+
+impl Shapes {
+    /// A shape without fields, for the instances of a new class.
+    pub fn root(&mut self) -> ShapeId {
+        self.push(Shape::default())
+    }
+
+    pub fn slot(&self, shape: ShapeId, name: ObjRef) -> Option<usize> {
+        self.get(shape).slots.get(&name).copied()
+    }
+
+    /// The shape with the field `name` added after the ones of `shape`.
+    pub fn transition(&mut self, shape: ShapeId, name: ObjRef) -> ShapeId {
+        if let Some(&next) = self.get(shape).transitions.get(&name) {
+            return next;
+        }
+
+        let mut slots = self.get(shape).slots.clone();
+        slots.insert(name, slots.len());
+        let next = self.push(Shape {
+            slots,
+            transitions: HashMap::new(),
+            parent: Some(shape),
+        });
+        self.get_mut(shape).transitions.insert(name, next);
+        next
+    }
+
+    /// Every field name, which must outlive the shapes naming them.
+    pub fn names(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        self.shapes
+            .iter()
+            .flatten()
+            .flat_map(|shape| shape.slots.keys().copied())
+    }
+
+    /// How many shapes there are.
+    pub fn len(&self) -> usize {
+        self.shapes.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frees the shapes no class or instance in `heap` uses, which must
+    /// have just been collected, and clears them out of the caches.
+    pub fn collect(&mut self, heap: &mut Heap) {
+        for shape in std::mem::take(&mut self.young) {
+            self.mark(shape);
+        }
+        for obj in heap.objects() {
+            match obj {
+                Obj::Class(class) => self.mark(class.shape),
+                Obj::Instance(instance) => self.mark(instance.shape),
+                _ => {}
+            }
+        }
+        self.sweep();
+
+        for obj in heap.objects_mut() {
+            let caches = match obj {
+                Obj::Function(function) => &mut function.caches,
+                Obj::RegisterFunction(function) => &mut function.caches,
+                _ => continue,
+            };
+            for cache in caches {
+                cache.retain(|shape| self.shapes[shape.0 as usize].is_some());
+            }
+        }
+    }
+
+    /// Keeps `shape` and the shapes it grew from alive.
+    fn mark(&mut self, mut shape: ShapeId) {
+        while !self.marks[shape.0 as usize] {
+            self.marks[shape.0 as usize] = true;
+            match self.get(shape).parent {
+                Some(parent) => shape = parent,
+                None => break,
+            }
+        }
+    }
+
+    /// Frees the shapes not marked since the last sweep, along with the
+    /// transitions to them.
+    fn sweep(&mut self) {
+        for (index, shape) in self.shapes.iter_mut().enumerate() {
+            if !self.marks[index] && shape.take().is_some() {
+                self.free.push(index as u32);
+            }
+        }
+
+        let Self { shapes, marks, .. } = self;
+        for shape in shapes.iter_mut().flatten() {
+            shape.transitions.retain(|_, next| marks[next.0 as usize]);
+        }
+        marks.iter_mut().for_each(|mark| *mark = false);
+    }
+
+    fn get(&self, shape: ShapeId) -> &Shape {
+        self.shapes[shape.0 as usize]
+            .as_ref()
+            .expect("use of a freed shape")
+    }
+
+    fn get_mut(&mut self, shape: ShapeId) -> &mut Shape {
+        self.shapes[shape.0 as usize]
+            .as_mut()
+            .expect("use of a freed shape")
+    }
+
+    fn push(&mut self, shape: Shape) -> ShapeId {
+        let id = match self.free.pop() {
+            Some(index) => {
+                self.shapes[index as usize] = Some(shape);
+                ShapeId(index)
+            }
+            None => {
+                let id = u32::try_from(self.shapes.len()).expect("too many shapes");
+                self.shapes.push(Some(shape));
+                self.marks.push(false);
+                ShapeId(id)
+            }
+        };
+        self.young.push(id);
+        id
+    }
+}
+
+/// Where an instruction found a property for some shape.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheTarget {
+    Field(usize),
+    /// A method of the class, not shadowed by a field.
+    Method(ObjRef),
+    /// The property wasn't there, setting it moves the instance to this
+    /// shape, appending the value.
+    Transition(ShapeId),
+}
+
+/// How many shapes an instruction remembers before it stops caching.
+const POLYMORPHIC_ENTRIES: usize = 4;
+
+/// The cache of one instruction, monomorphic while it holds a single entry.
+#[derive(Clone, Copy, Default)]
+pub struct InlineCache {
+    entries: [Option<(ShapeId, CacheTarget)>; POLYMORPHIC_ENTRIES],
+}
+
+impl InlineCache {
+    pub fn lookup(&self, shape: ShapeId) -> Option<CacheTarget> {
+        for entry in &self.entries {
+            match entry {
+                Some((cached, target)) if *cached == shape => return Some(*target),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+        None
+    }
+
+    /// Forgets the entries for shapes, or transitioning to shapes, that
+    /// aren't `live`.
+    pub fn retain(&mut self, live: impl Fn(ShapeId) -> bool) {
+        let mut kept = 0;
+        for index in 0..POLYMORPHIC_ENTRIES {
+            let Some((shape, target)) = self.entries[index] else {
+                break;
+            };
+            let target_live = match target {
+                CacheTarget::Transition(next) => live(next),
+                CacheTarget::Field(_) | CacheTarget::Method(_) => true,
+            };
+            if live(shape) && target_live {
+                self.entries[kept] = Some((shape, target));
+                kept += 1;
+            }
+        }
+        self.entries[kept..].fill(None);
+    }
+
+    /// Remembers `target` for `shape`, unless the cache is full already.
+    pub fn insert(&mut self, shape: ShapeId, target: CacheTarget) {
+        if let Some(free) = self.entries.iter_mut().find(|entry| entry.is_none()) {
+            *free = Some((shape, target));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::Heap;
+    use crate::object::{Obj, ObjClass, ObjInstance, ObjRef};
+    use crate::shape::{CacheTarget, InlineCache, Shapes};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_transitions_are_shared() {
+        let mut shapes = Shapes::default();
+        let (x, y) = (ObjRef(0), ObjRef(1));
+
+        let root = shapes.root();
+        let xy = shapes.transition(root, x);
+        let xy = shapes.transition(xy, y);
+        let yx = shapes.transition(root, y);
+        let yx = shapes.transition(yx, x);
+
+        assert_ne!(xy, yx);
+        let x_again = shapes.transition(root, x);
+        assert_eq!(shapes.transition(x_again, y), xy);
+        assert_eq!((shapes.slot(xy, x), shapes.slot(xy, y)), (Some(0), Some(1)));
+        assert_eq!((shapes.slot(yx, x), shapes.slot(yx, y)), (Some(1), Some(0)));
+        assert_eq!(shapes.slot(root, x), None);
+
+        // Another class starts over.
+        let other = shapes.root();
+        assert_ne!(shapes.transition(other, x), x_again);
+    }
+
+    #[test]
+    fn test_inline_cache() {
+        let mut shapes = Shapes::default();
+        let roots: Vec<_> = (0..5).map(|_| shapes.root()).collect();
+
+        let mut cache = InlineCache::default();
+        assert_eq!(cache.lookup(roots[0]), None);
+        for (slot, &shape) in roots.iter().enumerate() {
+            cache.insert(shape, CacheTarget::Field(slot));
+        }
+
+        for (slot, &shape) in roots.iter().enumerate().take(4) {
+            assert_eq!(cache.lookup(shape), Some(CacheTarget::Field(slot)));
+        }
+        // Megamorphic from here on.
+        assert_eq!(cache.lookup(roots[4]), None);
+    }
+
+    #[test]
+    fn test_unused_shapes_are_freed() {
+        let mut shapes = Shapes::default();
+        let mut heap = Heap::default();
+        let x = heap.alloc(Obj::String("x".into()));
+        let y = heap.alloc(Obj::String("y".into()));
+
+        let root = shapes.root();
+        let xy = shapes.transition(root, x);
+        let xy = shapes.transition(xy, y);
+        let unused_root = shapes.root();
+        let unused = shapes.transition(root, y);
+        let class = heap.alloc(Obj::Class(ObjClass {
+            name: "A".into(),
+            methods: HashMap::new(),
+            shape: root,
+        }));
+        let instance = heap.alloc(Obj::Instance(ObjInstance {
+            class,
+            shape: xy,
+            fields: vec![],
+        }));
+
+        // Young shapes make it through their first collection.
+        shapes.collect(&mut heap);
+        assert_eq!(shapes.len(), 5);
+
+        let mut cache = InlineCache::default();
+        cache.insert(unused_root, CacheTarget::Field(0));
+        cache.insert(root, CacheTarget::Transition(unused));
+        cache.insert(xy, CacheTarget::Field(1));
+
+        // The instance keeps the shapes it grew from.
+        heap.mark_object(instance);
+        heap.collect();
+        shapes.collect(&mut heap);
+        assert_eq!(shapes.len(), 3);
+        assert_eq!(shapes.slot(xy, y), Some(1));
+        let x_again = shapes.transition(root, x);
+        assert_eq!(shapes.transition(x_again, y), xy);
+
+        cache.retain(|shape| shapes.shapes[shape.0 as usize].is_some());
+        assert_eq!(cache.lookup(xy), Some(CacheTarget::Field(1)));
+        assert_eq!(cache.lookup(root), None);
+    }
+}
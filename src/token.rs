@@ -47,6 +47,9 @@ pub enum TokenType {
     For,
     Print,
 
+    // Only scanned when asked for, the lexeme is the whole comment.
+    Comment,
+
     EOF,
 }
 
@@ -75,12 +78,11 @@ impl TokenType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub ty: TokenType,
     pub lexeme: String,
-    #[allow(dead_code)]
-    line: usize,
+    pub line: usize,
 }
 
 impl Token {
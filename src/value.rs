@@ -0,0 +1,217 @@
+//! Values of the bytecode VM. Everything bigger than a number lives in the
+//! heap and is referred to by handle.
+//!
+//! There are two representations to choose from at build time, with the
+//! same interface: a plain enum, and with the `nan-boxing` feature a single
+//! `u64` that is either a double or, hidden in the payload of a NaN, one of
+//! the other kinds of values. Either way, values are made with the
+//! constructors and looked at through [`Value::kind`].
+
+use crate::object::ObjRef;
+use std::fmt;
+
+/// What a value is, whatever its representation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueKind {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy)]
+pub struct Value(ValueKind);
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Self = Self(ValueKind::Nil);
+
+    pub fn bool(b: bool) -> Self {
+        Self(ValueKind::Bool(b))
+    }
+
+    pub fn number(n: f64) -> Self {
+        Self(ValueKind::Number(n))
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        Self(ValueKind::Obj(obj))
+    }
+
+    #[inline]
+    pub fn kind(self) -> ValueKind {
+        self.0
+    }
+}
+
+/// Doubles are stored as they are. The rest are quiet NaNs with bits no
+/// NaN coming out of arithmetic has set (`QNAN`), telling them apart by the
+/// sign bit and a tag in the lowest bits of the payload:
+///
+/// ```text
+/// nil     0 11111111111 11 0...0 01
+/// false   0 11111111111 11 0...0 10
+/// true    0 11111111111 11 0...0 11
+/// object  1 11111111111 11 0...0 handle:u32
+/// ```
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Value(u64);
+
+#[cfg(feature = "nan-boxing")]
+pub(crate) mod nan_boxing {
+    pub const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    pub const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    pub const TAG_NIL: u64 = 1;
+    pub const TAG_FALSE: u64 = 2;
+    pub const TAG_TRUE: u64 = 3;
+}
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub const NIL: Self = Self(nan_boxing::QNAN | nan_boxing::TAG_NIL);
+
+    pub fn bool(b: bool) -> Self {
+        let tag = if b {
+            nan_boxing::TAG_TRUE
+        } else {
+            nan_boxing::TAG_FALSE
+        };
+        Self(nan_boxing::QNAN | tag)
+    }
+
+    pub fn number(n: f64) -> Self {
+        // A NaN carrying a payload could pass for one of the other values,
+        // all NaNs behave the same anyway.
+        if n.is_nan() {
+            Self(f64::NAN.to_bits())
+        } else {
+            Self(n.to_bits())
+        }
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        Self(nan_boxing::SIGN_BIT | nan_boxing::QNAN | obj.0 as u64)
+    }
+
+    /// The bits of the value, for the machine code handling them directly.
+    pub fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    pub fn kind(self) -> ValueKind {
+        use nan_boxing::*;
+
+        if self.0 & QNAN != QNAN {
+            ValueKind::Number(f64::from_bits(self.0))
+        } else if self.0 & SIGN_BIT != 0 {
+            ValueKind::Obj(ObjRef(self.0 as u32))
+        } else {
+            match self.0 & !QNAN {
+                TAG_NIL => ValueKind::Nil,
+                TAG_FALSE => ValueKind::Bool(false),
+                TAG_TRUE => ValueKind::Bool(true),
+                _ => unreachable!("not a value: {:#x}", self.0),
+            }
+        }
+    }
+}
+
+impl Value {
+    pub fn is_falsey(self) -> bool {
+        matches!(self.kind(), ValueKind::Nil | ValueKind::Bool(false))
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        self.kind().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::ObjRef;
+    use crate::value::{Value, ValueKind};
+
+    fn number(value: Value) -> f64 {
+        match value.kind() {
+            ValueKind::Number(n) => n,
+            kind => panic!("not a number: {kind:?}"),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(Value::NIL.kind(), ValueKind::Nil);
+        assert_eq!(Value::bool(true).kind(), ValueKind::Bool(true));
+        assert_eq!(Value::bool(false).kind(), ValueKind::Bool(false));
+
+        for handle in [0, 1, 0xffff, u32::MAX] {
+            let obj = ObjRef(handle);
+            assert_eq!(Value::obj(obj).kind(), ValueKind::Obj(obj));
+        }
+
+        let numbers = [
+            0.0,
+            1.0,
+            -1.5,
+            f64::MAX,
+            f64::MIN,
+            f64::MIN_POSITIVE,
+            f64::EPSILON,
+            5e-324,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for n in numbers {
+            assert_eq!(number(Value::number(n)).to_bits(), n.to_bits(), "{n}");
+        }
+    }
+
+    #[test]
+    fn test_negative_zero() {
+        let zero = number(Value::number(-0.0));
+        assert!(zero == 0.0 && zero.is_sign_negative());
+        assert!(number(Value::number(0.0)).is_sign_positive());
+    }
+
+    #[test]
+    fn test_nan() {
+        let nans = [
+            f64::NAN,
+            -f64::NAN,
+            (-1.0f64).sqrt(),
+            f64::INFINITY - f64::INFINITY,
+            // Payloads that would look like nil, true and an object if
+            // stored as they are.
+            f64::from_bits(0x7ffc_0000_0000_0001),
+            f64::from_bits(0x7ffc_0000_0000_0003),
+            f64::from_bits(0xfffc_0000_0000_0000),
+            f64::from_bits(0x7ff0_0000_0000_0001),
+        ];
+        for nan in nans {
+            assert!(number(Value::number(nan)).is_nan(), "{:#x}", nan.to_bits());
+        }
+    }
+
+    #[test]
+    fn test_falsey() {
+        assert!(Value::NIL.is_falsey());
+        assert!(Value::bool(false).is_falsey());
+        assert!(!Value::bool(true).is_falsey());
+        assert!(!Value::number(0.0).is_falsey());
+        assert!(!Value::number(f64::NAN).is_falsey());
+        assert!(!Value::obj(ObjRef(0)).is_falsey());
+    }
+
+    #[test]
+    #[cfg(feature = "nan-boxing")]
+    fn test_size() {
+        assert_eq!(std::mem::size_of::<Value>(), 8);
+    }
+}
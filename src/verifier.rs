@@ -0,0 +1,754 @@
+//! Checks bytecode from untrusted sources before the VM runs it.
+//!
+//! Everything the VM takes for granted about the code it's given is checked
+//! here: instructions decode, operands point at what they should and the
+//! stack never underflows. What depends on the values themselves, like
+//! calling a number, is left to the VM, which reports it as a runtime error
+//! anyway.
+
+use crate::chunk::{Constant, Function, OpCode};
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyErrorKind {
+    InvalidOpcode(u8),
+    /// The operands of the last instruction are cut off.
+    TruncatedInstruction,
+    /// The code and its line table have different lengths.
+    MissingLines,
+    ConstantOutOfRange(u8),
+    /// A name operand pointing at a constant that isn't a string.
+    NameNotString(u8),
+    LocalOutOfRange(u8),
+    UpvalueOutOfRange(u8),
+    /// A closure made of a constant that isn't a function.
+    NotAFunction(u8),
+    /// The top level code has nothing to capture variables from.
+    ScriptWithUpvalues,
+    /// The top level code is called without arguments.
+    ScriptWithParameters,
+    /// A jump landing outside the code or in the middle of an instruction.
+    InvalidJumpTarget(isize),
+    /// The instruction pops more values than the stack holds.
+    StackUnderflow,
+    /// The instruction pops a local a closure captured, which only
+    /// `CLOSE_UPVALUE` may do.
+    PopsCapturedLocal(usize),
+    /// Two paths reach the instruction with different stack depths.
+    InconsistentStack {
+        expected: usize,
+        found: usize,
+    },
+    /// Execution can run past the last instruction.
+    FallsOffEnd,
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::InvalidOpcode(byte) => write!(f, "invalid opcode {byte}"),
+            Self::TruncatedInstruction => write!(f, "truncated instruction"),
+            Self::MissingLines => write!(f, "line table doesn't match the code"),
+            Self::ConstantOutOfRange(index) => write!(f, "constant {index} out of range"),
+            Self::NameNotString(index) => write!(f, "name constant {index} is not a string"),
+            Self::LocalOutOfRange(slot) => write!(f, "local slot {slot} out of range"),
+            Self::UpvalueOutOfRange(index) => write!(f, "upvalue {index} out of range"),
+            Self::NotAFunction(index) => write!(f, "constant {index} is not a function"),
+            Self::ScriptWithUpvalues => write!(f, "the script captures variables"),
+            Self::ScriptWithParameters => write!(f, "the script takes parameters"),
+            Self::InvalidJumpTarget(target) => write!(f, "invalid jump target {target}"),
+            Self::StackUnderflow => write!(f, "stack underflow"),
+            Self::PopsCapturedLocal(slot) => write!(f, "pops captured local slot {slot}"),
+            Self::InconsistentStack { expected, found } => write!(
+                f,
+                "stack depth {found} doesn't match {expected} from another path"
+            ),
+            Self::FallsOffEnd => write!(f, "execution falls off the end of the code"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    /// The function holding the bad instruction, as it prints.
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Invalid bytecode in {} at offset {}: {}.",
+            self.function, self.offset, self.kind
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Verifies a script and every function defined in it.
+pub fn verify(script: &Function) -> Result<(), VerifyError> {
+    if script.upvalue_count != 0 {
+        let verifier = Verifier::new(script);
+        return Err(verifier.error(0, VerifyErrorKind::ScriptWithUpvalues));
+    }
+    if script.arity != 0 {
+        let verifier = Verifier::new(script);
+        return Err(verifier.error(0, VerifyErrorKind::ScriptWithParameters));
+    }
+    verify_function(script)
+}
+
+fn verify_function(function: &Function) -> Result<(), VerifyError> {
+    Verifier::new(function).run()?;
+
+    for constant in &function.chunk.constants {
+        if let Constant::Function(function) = constant {
+            verify_function(function)?;
+        }
+    }
+
+    Ok(())
+}
+
+struct Verifier<'a> {
+    function: &'a Function,
+    /// The opcode at every offset an instruction starts.
+    instructions: Vec<Option<OpCode>>,
+    /// Stack depth at the start of every instruction, counting slot zero,
+    /// once a path to it has been found.
+    depths: Vec<Option<usize>>,
+    /// The locals captured by closures at the start of every instruction,
+    /// on any of the paths to it.
+    captured: Vec<BTreeSet<usize>>,
+}
+
+/// What's known of the stack at an instruction.
+struct State {
+    depth: usize,
+    /// The locals whose upvalues are still open. Popping them would leave
+    /// the upvalues pointing past the stack.
+    captured: BTreeSet<usize>,
+}
+
+impl<'a> Verifier<'a> {
+    fn new(function: &'a Function) -> Self {
+        let len = function.chunk.code.len();
+        Self {
+            function,
+            instructions: vec![None; len],
+            depths: vec![None; len],
+            captured: vec![BTreeSet::new(); len],
+        }
+    }
+
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.function.to_string(),
+            offset,
+            kind,
+        }
+    }
+
+    fn run(mut self) -> Result<(), VerifyError> {
+        let chunk = &self.function.chunk;
+        if chunk.lines.len() != chunk.code.len() {
+            return Err(self.error(0, VerifyErrorKind::MissingLines));
+        }
+
+        self.decode()?;
+
+        // The arguments sit right above the callee.
+        let start = State {
+            depth: self.function.arity + 1,
+            captured: BTreeSet::new(),
+        };
+        let mut pending = vec![(0, start)];
+        while let Some((offset, state)) = pending.pop() {
+            if offset == chunk.code.len() {
+                return Err(self.error(offset, VerifyErrorKind::FallsOffEnd));
+            }
+
+            match self.depths[offset] {
+                Some(expected) if expected != state.depth => {
+                    let kind = VerifyErrorKind::InconsistentStack {
+                        expected,
+                        found: state.depth,
+                    };
+                    return Err(self.error(offset, kind));
+                }
+                // Paths can capture different locals, a loop body only
+                // after the first iteration, so the instruction is checked
+                // again whenever another path adds some.
+                Some(_) if state.captured.is_subset(&self.captured[offset]) => continue,
+                _ => {
+                    self.depths[offset] = Some(state.depth);
+                    self.captured[offset].extend(state.captured);
+                }
+            }
+
+            let state = State {
+                depth: state.depth,
+                captured: self.captured[offset].clone(),
+            };
+            pending.extend(self.step(offset, state)?);
+        }
+
+        Ok(())
+    }
+
+    /// Splits the code into instructions, checking they're complete.
+    fn decode(&mut self) -> Result<(), VerifyError> {
+        let code = &self.function.chunk.code;
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let op = OpCode::try_from(code[offset])
+                .map_err(|byte| self.error(offset, VerifyErrorKind::InvalidOpcode(byte)))?;
+
+            let mut next = offset + 1 + op.operand_bytes();
+            if next > code.len() {
+                return Err(self.error(offset, VerifyErrorKind::TruncatedInstruction));
+            }
+            if op == OpCode::Closure {
+                next += 2 * self.closure_function(offset)?.upvalue_count;
+                if next > code.len() {
+                    return Err(self.error(offset, VerifyErrorKind::TruncatedInstruction));
+                }
+            }
+
+            self.instructions[offset] = Some(op);
+            offset = next;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the instruction at `offset`, returning where execution can go
+    /// next along with the stack depth there.
+    fn step(&self, offset: usize, state: State) -> Result<Vec<(usize, State)>, VerifyError> {
+        let State {
+            depth,
+            mut captured,
+        } = state;
+        let code = &self.function.chunk.code;
+        let op = self.instructions[offset].expect("offset is an instruction boundary");
+        let operand = code.get(offset + 1).copied().unwrap_or(0);
+        let next = offset + 1 + op.operand_bytes();
+
+        let (pops, pushes) = match op {
+            OpCode::Constant => {
+                self.constant(offset, operand)?;
+                (0, 1)
+            }
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1),
+            OpCode::Pop | OpCode::Print => (1, 0),
+            OpCode::GetLocal | OpCode::SetLocal => {
+                if operand as usize >= depth {
+                    return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(operand)));
+                }
+                if op == OpCode::GetLocal {
+                    (0, 1)
+                } else {
+                    (1, 1)
+                }
+            }
+            OpCode::AddLocalConstant
+            | OpCode::SubtractLocalConstant
+            | OpCode::LessLocalConstant => {
+                if operand as usize >= depth {
+                    return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(operand)));
+                }
+                self.constant(offset, code[offset + 2])?;
+                (0, 1)
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                self.upvalue(offset, operand)?;
+                if op == OpCode::GetUpvalue {
+                    (0, 1)
+                } else {
+                    (1, 1)
+                }
+            }
+            OpCode::GetGlobal | OpCode::Class => {
+                self.name(offset, operand)?;
+                (0, 1)
+            }
+            OpCode::DefineGlobal => {
+                self.name(offset, operand)?;
+                (1, 0)
+            }
+            OpCode::SetGlobal | OpCode::GetProperty => {
+                self.name(offset, operand)?;
+                (1, 1)
+            }
+            OpCode::SetProperty | OpCode::GetSuper | OpCode::Method => {
+                self.name(offset, operand)?;
+                (2, 1)
+            }
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide => (2, 1),
+            OpCode::Not | OpCode::Negate => (1, 1),
+            OpCode::CloseUpvalue => {
+                self.check_depth(offset, depth, 1)?;
+                captured.remove(&(depth - 1));
+                let state = State {
+                    depth: depth - 1,
+                    captured,
+                };
+                return Ok(vec![(next, state)]);
+            }
+            OpCode::Inherit => (2, 1),
+            OpCode::Call => (operand as usize + 1, 1),
+            OpCode::Invoke => {
+                self.name(offset, operand)?;
+                (code[offset + 2] as usize + 1, 1)
+            }
+            OpCode::SuperInvoke => {
+                self.name(offset, operand)?;
+                // The superclass sits on top of the arguments.
+                (code[offset + 2] as usize + 2, 1)
+            }
+            OpCode::Closure => {
+                let upvalues = self.closure_function(offset)?.upvalue_count;
+                for i in 0..upvalues {
+                    let is_local = code[offset + 2 + 2 * i] != 0;
+                    let index = code[offset + 3 + 2 * i];
+                    if is_local && index as usize >= depth {
+                        return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(index)));
+                    }
+                    if is_local {
+                        captured.insert(index as usize);
+                    } else {
+                        self.upvalue(offset, index)?;
+                    }
+                }
+                let state = State {
+                    depth: depth + 1,
+                    captured,
+                };
+                return Ok(vec![(offset + 2 + 2 * upvalues, state)]);
+            }
+            OpCode::Jump | OpCode::Loop => {
+                self.check_depth(offset, depth, 0)?;
+                let target = self.jump_target(offset, op)?;
+                return Ok(vec![(target, State { depth, captured })]);
+            }
+            OpCode::JumpIfFalse => {
+                self.check_depth(offset, depth, 1)?;
+                let target = self.jump_target(offset, op)?;
+                let branch = State {
+                    depth,
+                    captured: captured.clone(),
+                };
+                return Ok(vec![(next, State { depth, captured }), (target, branch)]);
+            }
+            // The upvalues of the returning function are closed once the
+            // result and, for natives, the callee and arguments are popped.
+            OpCode::Return => {
+                self.check_depth(offset, depth, 1)?;
+                self.check_captured(offset, &captured, depth - 1)?;
+                return Ok(vec![]);
+            }
+            OpCode::TailCall => {
+                let pops = operand as usize + 1;
+                self.check_depth(offset, depth, pops)?;
+                self.check_captured(offset, &captured, depth - pops)?;
+                return Ok(vec![]);
+            }
+        };
+
+        self.check_depth(offset, depth, pops)?;
+        let depth = depth - pops + pushes;
+        self.check_captured(offset, &captured, depth)?;
+        Ok(vec![(next, State { depth, captured })])
+    }
+
+    /// Slot zero isn't a value the code can pop.
+    fn check_depth(&self, offset: usize, depth: usize, pops: usize) -> Result<(), VerifyError> {
+        if pops >= depth {
+            return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+        }
+        Ok(())
+    }
+
+    /// Captured locals have to stay on the stack until they're closed.
+    fn check_captured(
+        &self,
+        offset: usize,
+        captured: &BTreeSet<usize>,
+        depth: usize,
+    ) -> Result<(), VerifyError> {
+        match captured.range(depth..).next() {
+            Some(&slot) => Err(self.error(offset, VerifyErrorKind::PopsCapturedLocal(slot))),
+            None => Ok(()),
+        }
+    }
+
+    fn constant(&self, offset: usize, index: u8) -> Result<&Constant, VerifyError> {
+        self.function
+            .chunk
+            .constants
+            .get(index as usize)
+            .ok_or_else(|| self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)))
+    }
+
+    fn name(&self, offset: usize, index: u8) -> Result<(), VerifyError> {
+        match self.constant(offset, index)? {
+            Constant::String(_) => Ok(()),
+            _ => Err(self.error(offset, VerifyErrorKind::NameNotString(index))),
+        }
+    }
+
+    fn upvalue(&self, offset: usize, index: u8) -> Result<(), VerifyError> {
+        if index as usize >= self.function.upvalue_count {
+            return Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)));
+        }
+        Ok(())
+    }
+
+    /// The function the `CLOSURE` at `offset` wraps.
+    fn closure_function(&self, offset: usize) -> Result<&Function, VerifyError> {
+        let index = self.function.chunk.code[offset + 1];
+        match self.constant(offset, index)? {
+            Constant::Function(function) => Ok(function),
+            _ => Err(self.error(offset, VerifyErrorKind::NotAFunction(index))),
+        }
+    }
+
+    fn jump_target(&self, offset: usize, op: OpCode) -> Result<usize, VerifyError> {
+        let code = &self.function.chunk.code;
+        let jump = u16::from_be_bytes([code[offset + 1], code[offset + 2]]) as isize;
+        let sign = if op == OpCode::Loop { -1 } else { 1 };
+        let target = offset as isize + 3 + sign * jump;
+
+        match usize::try_from(target) {
+            Ok(target) if self.instructions.get(target).is_some_and(Option::is_some) => Ok(target),
+            _ => Err(self.error(offset, VerifyErrorKind::InvalidJumpTarget(target))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, Constant, Function, OpCode};
+    use crate::compiler::Compiler;
+    use crate::fuzz::Rng;
+    use crate::loxc;
+    use crate::parser::Parser;
+    use crate::programs::PROGRAMS;
+    use crate::scanner::Scanner;
+    use crate::verifier::{verify, VerifyErrorKind};
+    use crate::vm::Vm;
+    use std::io;
+
+    fn script(code: Vec<u8>, constants: Vec<Constant>) -> Function {
+        Function {
+            name: None,
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk {
+                lines: vec![1; code.len()],
+                code,
+                constants,
+            },
+        }
+    }
+
+    fn error(code: &[u8], constants: Vec<Constant>) -> (usize, VerifyErrorKind) {
+        let err = verify(&script(code.to_vec(), constants)).unwrap_err();
+        (err.offset, err.kind)
+    }
+
+    #[test]
+    fn test_compiled_programs_verify() {
+        for program in PROGRAMS {
+            let tokens = Scanner::new(program.source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            if let Ok(script) = Compiler::new().compile(&statements) {
+                assert_eq!(verify(&script), Ok(()), "{}", program.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejected() {
+        const NIL: u8 = OpCode::Nil as u8;
+        const POP: u8 = OpCode::Pop as u8;
+        const RETURN: u8 = OpCode::Return as u8;
+        const JUMP: u8 = OpCode::Jump as u8;
+        const JUMP_IF_FALSE: u8 = OpCode::JumpIfFalse as u8;
+        const LOOP: u8 = OpCode::Loop as u8;
+        const GET_LOCAL: u8 = OpCode::GetLocal as u8;
+        const GET_GLOBAL: u8 = OpCode::GetGlobal as u8;
+        const CONSTANT: u8 = OpCode::Constant as u8;
+        const GET_UPVALUE: u8 = OpCode::GetUpvalue as u8;
+        const CLOSURE: u8 = OpCode::Closure as u8;
+
+        assert_eq!(
+            error(&[255], vec![]),
+            (0, VerifyErrorKind::InvalidOpcode(255))
+        );
+        assert_eq!(
+            error(&[NIL, CONSTANT], vec![]),
+            (1, VerifyErrorKind::TruncatedInstruction)
+        );
+        assert_eq!(
+            error(&[CONSTANT, 0, RETURN], vec![]),
+            (0, VerifyErrorKind::ConstantOutOfRange(0))
+        );
+        assert_eq!(
+            error(&[GET_GLOBAL, 0, RETURN], vec![Constant::Number(1.0)]),
+            (0, VerifyErrorKind::NameNotString(0))
+        );
+        assert_eq!(
+            error(&[GET_LOCAL, 1, RETURN], vec![]),
+            (0, VerifyErrorKind::LocalOutOfRange(1))
+        );
+        assert_eq!(
+            error(&[GET_UPVALUE, 0, RETURN], vec![]),
+            (0, VerifyErrorKind::UpvalueOutOfRange(0))
+        );
+        assert_eq!(
+            error(&[CLOSURE, 0, RETURN], vec![Constant::Number(1.0)]),
+            (0, VerifyErrorKind::NotAFunction(0))
+        );
+        let capturing = Function {
+            upvalue_count: 1,
+            ..script(vec![NIL, RETURN], vec![])
+        };
+        assert_eq!(
+            error(
+                &[CLOSURE, 0, 1, 3, RETURN],
+                vec![Constant::Function(capturing.into())]
+            ),
+            (0, VerifyErrorKind::LocalOutOfRange(3))
+        );
+        assert_eq!(
+            verify(&Function {
+                upvalue_count: 1,
+                ..script(vec![NIL, RETURN], vec![])
+            })
+            .unwrap_err()
+            .kind,
+            VerifyErrorKind::ScriptWithUpvalues
+        );
+        assert_eq!(
+            error(&[POP, NIL, RETURN], vec![]),
+            (0, VerifyErrorKind::StackUnderflow)
+        );
+        assert_eq!(
+            error(&[NIL, POP], vec![]),
+            (2, VerifyErrorKind::FallsOffEnd)
+        );
+        // Into the middle of the constant instruction.
+        assert_eq!(
+            error(
+                &[JUMP, 0, 1, CONSTANT, 0, RETURN],
+                vec![Constant::Number(1.0)]
+            ),
+            (0, VerifyErrorKind::InvalidJumpTarget(4))
+        );
+        assert_eq!(
+            error(&[LOOP, 0, 4, NIL, RETURN], vec![]),
+            (0, VerifyErrorKind::InvalidJumpTarget(-1))
+        );
+        // Pushing nil on only one of the branches.
+        assert_eq!(
+            error(&[NIL, JUMP_IF_FALSE, 0, 1, NIL, NIL, RETURN], vec![]),
+            (
+                5,
+                VerifyErrorKind::InconsistentStack {
+                    expected: 2,
+                    found: 3
+                }
+            )
+        );
+
+        assert_eq!(
+            verify(&Function {
+                arity: 1,
+                ..script(vec![NIL, RETURN], vec![])
+            })
+            .unwrap_err()
+            .kind,
+            VerifyErrorKind::ScriptWithParameters
+        );
+        // Capturing slot 1 and popping it without closing its upvalue.
+        let capturing = Function {
+            upvalue_count: 1,
+            ..script(vec![NIL, RETURN], vec![])
+        };
+        assert_eq!(
+            error(
+                &[NIL, CLOSURE, 0, 1, 1, POP, POP, NIL, RETURN],
+                vec![Constant::Function(capturing.into())]
+            ),
+            (6, VerifyErrorKind::PopsCapturedLocal(1))
+        );
+        assert_eq!(
+            error(&[NIL, CLOSURE, 0, 1, 1, POP, RETURN], vec![]),
+            (1, VerifyErrorKind::ConstantOutOfRange(0))
+        );
+
+        let mut missing_lines = script(vec![NIL, RETURN], vec![]);
+        missing_lines.chunk.lines.pop();
+        assert_eq!(
+            verify(&missing_lines).unwrap_err().kind,
+            VerifyErrorKind::MissingLines
+        );
+    }
+
+    #[test]
+    fn test_nested_functions() {
+        let inner = script(vec![OpCode::Pop as u8], vec![]);
+        let outer = script(
+            vec![OpCode::Nil as u8, OpCode::Return as u8],
+            vec![Constant::Function(inner.into())],
+        );
+        let err = verify(&outer).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid bytecode in <script> at offset 0: stack underflow."
+        );
+    }
+
+    #[test]
+    fn test_closed_upvalues_verify() {
+        const NIL: u8 = OpCode::Nil as u8;
+        const POP: u8 = OpCode::Pop as u8;
+        const RETURN: u8 = OpCode::Return as u8;
+        const CLOSURE: u8 = OpCode::Closure as u8;
+        const CLOSE_UPVALUE: u8 = OpCode::CloseUpvalue as u8;
+
+        let capturing = Function {
+            upvalue_count: 1,
+            ..script(vec![NIL, RETURN], vec![])
+        };
+        let code = vec![NIL, CLOSURE, 0, 1, 1, POP, CLOSE_UPVALUE, NIL, RETURN];
+        let constants = vec![Constant::Function(capturing.into())];
+        assert_eq!(verify(&script(code, constants)), Ok(()));
+    }
+
+    #[test]
+    fn test_dead_code_operands_run() {
+        const NIL: u8 = OpCode::Nil as u8;
+        const RETURN: u8 = OpCode::Return as u8;
+        const CONSTANT: u8 = OpCode::Constant as u8;
+        const GET_GLOBAL: u8 = OpCode::GetGlobal as u8;
+        const LOOP: u8 = OpCode::Loop as u8;
+        const JUMP: u8 = OpCode::Jump as u8;
+
+        // Nothing runs past the return, so its operands aren't checked.
+        let code = vec![
+            NIL, RETURN, CONSTANT, 36, GET_GLOBAL, 7, LOOP, 0, 200, JUMP, 1, 0, RETURN,
+        ];
+        let script = std::rc::Rc::new(script(code, vec![]));
+        assert_eq!(verify(&script), Ok(()));
+
+        let mut vm = Vm::with_output(Box::new(io::sink()));
+        #[cfg(feature = "jit")]
+        vm.set_jit_threshold(0);
+        assert!(vm.interpret(script).is_ok());
+    }
+
+    /// The calls in `function` and the functions in its constants, or
+    /// `None` if it can loop or recurse without bound.
+    fn calls(function: &Function) -> Option<usize> {
+        let code = &function.chunk.code;
+        let mut count = 0;
+        let mut offset = 0;
+        while offset < code.len() {
+            let op = OpCode::try_from(code[offset]).unwrap();
+            match op {
+                OpCode::Loop | OpCode::TailCall => return None,
+                OpCode::Call | OpCode::Invoke | OpCode::SuperInvoke => count += 1,
+                _ => {}
+            }
+            offset += 1 + op.operand_bytes();
+            if op == OpCode::Closure {
+                let Constant::Function(closure) =
+                    &function.chunk.constants[code[offset - 1] as usize]
+                else {
+                    unreachable!("verified");
+                };
+                offset += 2 * closure.upvalue_count;
+            }
+        }
+
+        for constant in &function.chunk.constants {
+            if let Constant::Function(function) = constant {
+                // More than one call in a function could recurse
+                // exponentially, up to the frame limit.
+                if calls(function)? > 1 {
+                    return None;
+                }
+            }
+        }
+        Some(count)
+    }
+
+    #[test]
+    fn test_verified_mutants_run() {
+        let source = "
+        var greeting = \"hi\";
+        fun makeCounter() {
+          var count = 0;
+          fun counter() { count = count + 1; return count; }
+          return counter;
+        }
+        class Animal {
+          init(name) { this.name = name; }
+          speak() { return this.name + \" makes a sound\"; }
+        }
+        class Dog < Animal {
+          speak() { return super.speak() + \" woof\"; }
+        }
+        var counter = makeCounter();
+        print counter();
+        var dog = Dog(\"rex\");
+        print dog.speak();
+        {
+          var a = 1;
+          var b = 2;
+          fun sum() { return a + b; }
+          if (a < b) print sum(); else print greeting;
+          print a and b or nil;
+        }
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let bytes = loxc::write(&Compiler::new().compile(&statements).unwrap());
+
+        let mut rng = Rng(0);
+        let mut ran = 0;
+        for _ in 0..5000 {
+            let mut mutant = bytes.clone();
+            for _ in 0..1 + rng.below(3) {
+                let i = rng.below(mutant.len());
+                mutant[i] = rng.next() as u8;
+            }
+
+            let Ok(script) = loxc::read(&mutant) else {
+                continue;
+            };
+            if verify(&script).is_err() || calls(&script).is_none() {
+                continue;
+            }
+            // Runtime errors are fine, panics aren't.
+            let _ = Vm::with_output(Box::new(io::sink())).interpret(script);
+            ran += 1;
+        }
+        assert!(ran > 100, "only {ran} mutants verified");
+    }
+}
@@ -0,0 +1,1238 @@
+#[cfg(not(feature = "threaded-dispatch"))]
+use crate::chunk::OpCode;
+use crate::chunk::{Constant, Function};
+use crate::debug;
+use crate::memory::{GcStats, Heap};
+use crate::object::{
+    NativeFunction, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjRef,
+    ObjUpvalue,
+};
+use crate::shape::{CacheTarget, InlineCache, Shapes};
+use crate::value::{Value, ValueKind};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "jit")]
+pub(crate) mod jit;
+#[cfg(feature = "threaded-dispatch")]
+pub(crate) mod threaded;
+
+/// How deep calls can nest before the VM gives up with a stack overflow.
+pub(crate) const FRAMES_MAX: usize = 1024;
+
+pub(crate) fn clock(_args: &[Value]) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is set before the unix epoch");
+    Value::number(now.as_secs_f64())
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}\n[line {}]", self.message, self.line)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+struct CallFrame {
+    closure: ObjRef,
+    /// The function of `closure`, and the compiled code behind it, kept
+    /// around to not go through the heap for every instruction.
+    function: ObjRef,
+    code: Rc<Function>,
+    #[cfg(feature = "threaded-dispatch")]
+    threaded: Rc<[threaded::Threaded]>,
+    #[cfg(feature = "jit")]
+    jit: Option<Rc<jit::Code>>,
+    ip: usize,
+    /// Where the slot zero of the function is in the stack.
+    slots: usize,
+}
+
+/// Stack based virtual machine running the bytecode of the [`Compiler`].
+///
+/// [`Compiler`]: crate::compiler::Compiler
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    globals: HashMap<ObjRef, Value>,
+    /// The interned name of initializers.
+    init_string: ObjRef,
+    shapes: Shapes,
+    /// Upvalues still pointing into the stack, ordered by their slot, so
+    /// closures capturing the same variable share them.
+    open_upvalues: Vec<ObjRef>,
+    /// Set while a tail call pushes the frame of its callee, which replaces
+    /// the frame of the caller right after, so it doesn't count towards the
+    /// limit.
+    tail_calling: bool,
+    out: Box<dyn Write>,
+    /// Whether to print the stack and every instruction as they run.
+    trace: bool,
+    /// How hot functions get before they're compiled to machine code.
+    #[cfg(feature = "jit")]
+    jit_threshold: u32,
+    /// How many functions were compiled to machine code.
+    #[cfg(feature = "jit")]
+    jit_compiled: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Sends what `print` writes to `out` instead of the standard output.
+    pub fn with_output(out: Box<dyn Write>) -> Self {
+        let mut heap = Heap::default();
+        let init_string = heap.alloc(Obj::String("init".into()));
+
+        let mut vm = Self {
+            heap,
+            stack: vec![],
+            frames: vec![],
+            globals: HashMap::new(),
+            init_string,
+            shapes: Shapes::default(),
+            open_upvalues: vec![],
+            tail_calling: false,
+            out,
+            trace: false,
+            #[cfg(feature = "jit")]
+            jit_threshold: jit::THRESHOLD,
+            #[cfg(feature = "jit")]
+            jit_compiled: 0,
+        };
+
+        vm.define_native(NativeFunction {
+            name: "clock",
+            arity: 0,
+            function: clock,
+        });
+
+        vm
+    }
+
+    /// Writes the stack and the instruction about to run, for every
+    /// instruction, along with the output of the program.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Collects garbage before every allocation, which makes any object the
+    /// VM forgets to mark get freed right away.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    /// Compiles functions to machine code once they were called or looped
+    /// `threshold` times, zero compiling them before they first run.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, threshold: u32) {
+        self.jit_threshold = threshold;
+    }
+
+    /// How many functions were compiled to machine code.
+    #[cfg(feature = "jit")]
+    pub fn jit_compiled(&self) -> usize {
+        self.jit_compiled
+    }
+
+    fn define_native(&mut self, native: NativeFunction) {
+        let name = self.intern(native.name);
+        self.push(Value::obj(name));
+        let function = self.alloc(Obj::Native(native));
+        self.pop();
+        self.globals.insert(name, Value::obj(function));
+    }
+
+    /// Runs a script, keeping its globals around for the next one.
+    pub fn interpret(&mut self, script: Rc<Function>) -> Result<(), RuntimeError> {
+        let function = self.load(script);
+        self.stack.push(Value::obj(function));
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: vec![],
+        }));
+        self.pop();
+        self.stack.push(Value::obj(closure));
+
+        let result = self.call(closure, 0).and_then(|()| self.run());
+        if result.is_err() {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+        }
+        result
+    }
+
+    /// Moves a compiled function into the heap, along with the functions in
+    /// its constants.
+    fn load(&mut self, function: Rc<Function>) -> ObjRef {
+        // The constants wait on the stack, where the collector sees them.
+        let base = self.stack.len();
+        for constant in &function.chunk.constants {
+            let value = match constant {
+                Constant::Number(n) => Value::number(*n),
+                Constant::String(s) => Value::obj(self.intern(s)),
+                Constant::Function(f) => Value::obj(self.load(f.clone())),
+            };
+            self.stack.push(value);
+        }
+
+        let constants = self.stack.split_off(base);
+        let caches = vec![InlineCache::default(); function.chunk.code.len()];
+        self.alloc(Obj::Function(ObjFunction {
+            #[cfg(feature = "threaded-dispatch")]
+            threaded: threaded::decode(&function, &constants),
+            function,
+            constants,
+            caches,
+            #[cfg(feature = "jit")]
+            hotness: 0,
+            #[cfg(feature = "jit")]
+            jit: None,
+        }))
+    }
+
+    /// Allocates an object, collecting garbage first if it's time to.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.heap.mark_references(&obj);
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    /// The string object with these contents, made if there's none yet.
+    fn intern(&mut self, s: &str) -> ObjRef {
+        match self.heap.find_string(s) {
+            Some(string) => string,
+            None => self.alloc(Obj::String(s.into())),
+        }
+    }
+
+    fn collect_garbage(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_object(*upvalue);
+        }
+        for (name, value) in &self.globals {
+            self.heap.mark_object(*name);
+            self.heap.mark_value(*value);
+        }
+        self.heap.mark_object(self.init_string);
+        for name in self.shapes.names() {
+            self.heap.mark_object(name);
+        }
+
+        self.heap.collect();
+        self.shapes.collect(&mut self.heap);
+    }
+
+    /// Dispatches on the opcode of every instruction as it runs, unless the
+    /// `threaded-dispatch` feature replaces it.
+    #[cfg(not(feature = "threaded-dispatch"))]
+    fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            if self.trace {
+                self.trace_instruction();
+            } else {
+                #[cfg(feature = "jit")]
+                self.run_jit();
+            }
+
+            let byte = self.read_byte();
+            let op = OpCode::try_from(byte).unwrap_or_else(|byte| panic!("unknown opcode {byte}"));
+
+            match op {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.push(constant);
+                }
+                OpCode::Nil => self.push(Value::NIL),
+                OpCode::True => self.push(Value::bool(true)),
+                OpCode::False => self.push(Value::bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte();
+                    self.get_local(slot);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte();
+                    self.set_local(slot);
+                }
+                OpCode::GetGlobal => {
+                    let name = self.read_string();
+                    self.get_global(name)?;
+                }
+                OpCode::DefineGlobal => {
+                    let name = self.read_string();
+                    self.define_global(name);
+                }
+                OpCode::SetGlobal => {
+                    let name = self.read_string();
+                    self.set_global(name)?;
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte();
+                    self.get_upvalue(index);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte();
+                    self.set_upvalue(index);
+                }
+                OpCode::GetProperty => {
+                    let name = self.read_string();
+                    self.get_property(name)?;
+                }
+                OpCode::SetProperty => {
+                    let name = self.read_string();
+                    self.set_property(name)?;
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let superclass = self.superclass()?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::Equal => self.equal(),
+                OpCode::Greater => self.binary_op(|a, b| Value::bool(a > b))?,
+                OpCode::GreaterEqual => self.binary_op(|a, b| Value::bool(a >= b))?,
+                OpCode::Less => self.binary_op(|a, b| Value::bool(a < b))?,
+                OpCode::LessEqual => self.binary_op(|a, b| Value::bool(a <= b))?,
+                OpCode::Add => self.add()?,
+                OpCode::Subtract => self.binary_op(|a, b| Value::number(a - b))?,
+                OpCode::Multiply => self.binary_op(|a, b| Value::number(a * b))?,
+                OpCode::Divide => self.binary_op(|a, b| Value::number(a / b))?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::bool(value.is_falsey()));
+                }
+                OpCode::Negate => self.negate()?,
+                OpCode::Print => self.print(),
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                    #[cfg(feature = "jit")]
+                    self.heat();
+                }
+                OpCode::Call => {
+                    let argc = self.read_byte() as usize;
+                    self.call_value(self.peek(argc), argc)?;
+                }
+                OpCode::TailCall => {
+                    let argc = self.read_byte() as usize;
+                    if self.tail_call(argc)? {
+                        return Ok(());
+                    }
+                }
+                OpCode::Invoke => {
+                    let name = self.read_string();
+                    let argc = self.read_byte() as usize;
+                    self.invoke(name, argc)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let argc = self.read_byte() as usize;
+                    let superclass = self.superclass()?;
+                    self.invoke_from_class(superclass, name, argc)?;
+                }
+                OpCode::Closure => {
+                    let function = match self.read_constant().kind() {
+                        ValueKind::Obj(obj) => obj,
+                        _ => unreachable!("closures are made of function constants"),
+                    };
+                    self.closure(function);
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    if self.return_from_call() {
+                        return Ok(());
+                    }
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    self.class(name);
+                }
+                OpCode::Inherit => self.inherit()?,
+                OpCode::Method => {
+                    let name = self.read_string();
+                    self.method(name)?;
+                }
+                OpCode::AddLocalConstant => {
+                    let slot = self.read_byte();
+                    self.get_local(slot);
+                    let constant = self.read_constant();
+                    self.push(constant);
+                    self.add()?;
+                }
+                OpCode::SubtractLocalConstant => {
+                    let slot = self.read_byte();
+                    self.get_local(slot);
+                    let constant = self.read_constant();
+                    self.push(constant);
+                    self.binary_op(|a, b| Value::number(a - b))?;
+                }
+                OpCode::LessLocalConstant => {
+                    let slot = self.read_byte();
+                    self.get_local(slot);
+                    let constant = self.read_constant();
+                    self.push(constant);
+                    self.binary_op(|a, b| Value::bool(a < b))?;
+                }
+            }
+        }
+    }
+
+    // What the instructions do, once their operands are decoded.
+
+    fn get_local(&mut self, slot: u8) {
+        let slot = self.frame().slots + slot as usize;
+        self.push(self.stack[slot]);
+    }
+
+    fn set_local(&mut self, slot: u8) {
+        let slot = self.frame().slots + slot as usize;
+        self.stack[slot] = self.peek(0);
+    }
+
+    fn get_global(&mut self, name: ObjRef) -> Result<(), RuntimeError> {
+        match self.globals.get(&name) {
+            Some(value) => {
+                self.push(*value);
+                Ok(())
+            }
+            None => Err(self.undefined_variable(name)),
+        }
+    }
+
+    fn define_global(&mut self, name: ObjRef) {
+        let value = self.pop();
+        self.globals.insert(name, value);
+    }
+
+    fn set_global(&mut self, name: ObjRef) -> Result<(), RuntimeError> {
+        let value = self.peek(0);
+        match self.globals.get_mut(&name) {
+            Some(global) => {
+                *global = value;
+                Ok(())
+            }
+            None => Err(self.undefined_variable(name)),
+        }
+    }
+
+    fn get_upvalue(&mut self, index: u8) {
+        let upvalue = self.heap.closure(self.frame().closure).upvalues[index as usize];
+        let value = match self.heap.upvalue(upvalue) {
+            ObjUpvalue::Open(slot) => self.stack[*slot],
+            ObjUpvalue::Closed(value) => *value,
+        };
+        self.push(value);
+    }
+
+    fn set_upvalue(&mut self, index: u8) {
+        let upvalue = self.heap.closure(self.frame().closure).upvalues[index as usize];
+        let value = self.peek(0);
+        match self.heap.upvalue_mut(upvalue) {
+            ObjUpvalue::Open(slot) => self.stack[*slot] = value,
+            ObjUpvalue::Closed(closed) => *closed = value,
+        }
+    }
+
+    fn get_property(&mut self, name: ObjRef) -> Result<(), RuntimeError> {
+        let site = self.frame().ip - 2;
+        let Some(instance) = self.as_instance(self.peek(0)) else {
+            return Err(self.error("Only instances have properties."));
+        };
+
+        match self.find_property(site, instance, name)? {
+            CacheTarget::Field(slot) => {
+                let value = self.heap.instance(instance).fields[slot];
+                self.pop();
+                self.push(value);
+            }
+            CacheTarget::Method(method) => self.bind(method),
+            CacheTarget::Transition(_) => unreachable!("only setters transition"),
+        }
+        Ok(())
+    }
+
+    fn set_property(&mut self, name: ObjRef) -> Result<(), RuntimeError> {
+        let site = self.frame().ip - 2;
+        let Some(instance) = self.as_instance(self.peek(1)) else {
+            return Err(self.error("Only instances have fields."));
+        };
+
+        let value = self.pop();
+        let shape = self.heap.instance(instance).shape;
+        let function = self.frame().function;
+        let target = match self.heap.function(function).caches[site].lookup(shape) {
+            Some(target) => target,
+            None => {
+                let target = match self.shapes.slot(shape, name) {
+                    Some(slot) => CacheTarget::Field(slot),
+                    None => CacheTarget::Transition(self.shapes.transition(shape, name)),
+                };
+                self.heap.function_mut(function).caches[site].insert(shape, target);
+                target
+            }
+        };
+
+        match target {
+            CacheTarget::Field(slot) => self.heap.instance_mut(instance).fields[slot] = value,
+            CacheTarget::Transition(next) => {
+                let object = self.heap.instance_mut(instance);
+                object.fields.push(value);
+                object.shape = next;
+                // The fields may have outgrown what the instance was counted at.
+                self.heap.resized(instance);
+            }
+            CacheTarget::Method(_) => unreachable!("setters don't cache methods"),
+        }
+        self.pop();
+        self.push(value);
+        Ok(())
+    }
+
+    fn equal(&mut self) {
+        let b = self.pop();
+        let a = self.pop();
+        self.push(Value::bool(self.heap.values_equal(a, b)));
+    }
+
+    fn negate(&mut self) -> Result<(), RuntimeError> {
+        match self.peek(0).kind() {
+            ValueKind::Number(n) => {
+                self.pop();
+                self.push(Value::number(-n));
+                Ok(())
+            }
+            _ => Err(self.error("Operand must be a number.")),
+        }
+    }
+
+    fn print(&mut self) {
+        let value = self.pop();
+        writeln!(self.out, "{}", self.heap.display(value)).expect("failed to print");
+    }
+
+    /// Wraps `function` in a closure, reading what it captures from the
+    /// code.
+    fn closure(&mut self, function: ObjRef) {
+        let upvalue_count = self.heap.function(function).function.upvalue_count;
+
+        // On the stack before capturing anything, as that can collect
+        // garbage.
+        let closure = self.alloc(Obj::Closure(ObjClosure {
+            function,
+            upvalues: Vec::with_capacity(upvalue_count),
+        }));
+        self.push(Value::obj(closure));
+
+        for _ in 0..upvalue_count {
+            let is_local = self.read_byte() != 0;
+            let index = self.read_byte() as usize;
+            let upvalue = if is_local {
+                self.capture_upvalue(self.frame().slots + index)
+            } else {
+                self.heap.closure(self.frame().closure).upvalues[index]
+            };
+            self.heap.closure_mut(closure).upvalues.push(upvalue);
+        }
+    }
+
+    /// Returns from the running function, returning whether it was the
+    /// script, which ends the run.
+    fn return_from_call(&mut self) -> bool {
+        let result = self.pop();
+        let frame = self.frames.pop().expect("no frame to return from");
+        self.close_upvalues(frame.slots);
+        self.stack.truncate(frame.slots);
+
+        if self.frames.is_empty() {
+            return true;
+        }
+        self.push(result);
+        false
+    }
+
+    fn class(&mut self, name: ObjRef) {
+        let shape = self.shapes.root();
+        let class = self.alloc(Obj::Class(ObjClass {
+            name: self.heap.string(name).clone(),
+            methods: HashMap::new(),
+            shape,
+        }));
+        self.push(Value::obj(class));
+    }
+
+    fn inherit(&mut self) -> Result<(), RuntimeError> {
+        let Some(superclass) = self.as_class(self.peek(1)) else {
+            return Err(self.error("Superclass must be a class."));
+        };
+        // Unlike the superclass, these are up to the compiler, so only
+        // malformed bytecode gets them wrong.
+        let Some(subclass) = self.as_class(self.peek(0)) else {
+            return Err(self.error("Invalid bytecode: only classes inherit."));
+        };
+
+        // Copied down, so method lookups never walk the chain.
+        let methods = self.heap.class(superclass).methods.clone();
+        self.heap.class_mut(subclass).methods.extend(methods);
+        self.heap.resized(subclass);
+        self.pop();
+        Ok(())
+    }
+
+    fn method(&mut self, name: ObjRef) -> Result<(), RuntimeError> {
+        let (Some(method), Some(class)) =
+            (self.as_closure(self.peek(0)), self.as_class(self.peek(1)))
+        else {
+            return Err(self.error("Invalid bytecode: methods are functions defined on classes."));
+        };
+        self.heap.class_mut(class).methods.insert(name, method);
+        self.heap.resized(class);
+        self.pop();
+        Ok(())
+    }
+
+    fn trace_instruction(&mut self) {
+        let mut stack = String::from("          ");
+        for value in &self.stack {
+            stack.push_str(&format!("[ {} ]", self.heap.display(*value)));
+        }
+
+        let frame = self.frame();
+        let (instruction, _) = debug::disassemble_instruction(&frame.code.chunk, frame.ip);
+        writeln!(self.out, "{stack}\n{instruction}").expect("failed to trace");
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no frame running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no frame running")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.code.chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    #[cfg(not(feature = "threaded-dispatch"))]
+    fn read_short(&mut self) -> u16 {
+        let hi = self.read_byte();
+        let lo = self.read_byte();
+        u16::from_be_bytes([hi, lo])
+    }
+
+    #[cfg(not(feature = "threaded-dispatch"))]
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_byte() as usize;
+        self.heap.function(self.frame().function).constants[index]
+    }
+
+    /// Reads a name, which is an interned string.
+    #[cfg(not(feature = "threaded-dispatch"))]
+    fn read_string(&mut self) -> ObjRef {
+        match self.read_constant().kind() {
+            ValueKind::Obj(obj) => obj,
+            _ => unreachable!("names are string constants"),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn add(&mut self) -> Result<(), RuntimeError> {
+        match (self.peek(1).kind(), self.peek(0).kind()) {
+            (ValueKind::Number(a), ValueKind::Number(b)) => {
+                self.pop();
+                self.pop();
+                self.push(Value::number(a + b));
+            }
+            (ValueKind::Obj(a), ValueKind::Obj(b))
+                if matches!(self.heap.get(a), Obj::String(_))
+                    && matches!(self.heap.get(b), Obj::String(_)) =>
+            {
+                let concatenated = format!("{}{}", self.heap.string(a), self.heap.string(b));
+                let string = self.intern(&concatenated);
+                self.pop();
+                self.pop();
+                self.push(Value::obj(string));
+            }
+            _ => return Err(self.error("Operands must be two numbers or two strings.")),
+        }
+        Ok(())
+    }
+
+    fn binary_op(&mut self, op: impl Fn(f64, f64) -> Value) -> Result<(), RuntimeError> {
+        match (self.peek(1).kind(), self.peek(0).kind()) {
+            (ValueKind::Number(a), ValueKind::Number(b)) => {
+                self.pop();
+                self.pop();
+                self.push(op(a, b));
+                Ok(())
+            }
+            _ => Err(self.error("Operands must be numbers.")),
+        }
+    }
+
+    fn as_instance(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Instance(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn as_class(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Class(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn as_closure(&self, value: Value) -> Option<ObjRef> {
+        match value.kind() {
+            ValueKind::Obj(obj) if matches!(self.heap.get(obj), Obj::Closure(_)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    /// Pops the class `super` refers to.
+    fn superclass(&mut self) -> Result<ObjRef, RuntimeError> {
+        let value = self.pop();
+        self.as_class(value)
+            .ok_or_else(|| self.error("Invalid bytecode: 'super' is not a class."))
+    }
+
+    /// The upvalue for the variable at `slot` in the stack, shared with the
+    /// closures that already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self
+            .open_upvalues
+            .partition_point(|&upvalue| self.open_slot(upvalue) < slot);
+        if let Some(&upvalue) = self.open_upvalues.get(position) {
+            if self.open_slot(upvalue) == slot {
+                return upvalue;
+            }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    fn open_slot(&self, upvalue: ObjRef) -> usize {
+        match self.heap.upvalue(upvalue) {
+            ObjUpvalue::Open(slot) => *slot,
+            ObjUpvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+        }
+    }
+
+    /// Moves the variables from `last` up in the stack into their upvalues,
+    /// as they're about to be popped.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = self.open_slot(upvalue);
+            if slot < last {
+                break;
+            }
+
+            *self.heap.upvalue_mut(upvalue) = ObjUpvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, argc: usize) -> Result<(), RuntimeError> {
+        let ValueKind::Obj(obj) = callee.kind() else {
+            return Err(self.error("Can only call functions and classes."));
+        };
+
+        let receiver = self.stack.len() - argc - 1;
+        match self.heap.get(obj) {
+            Obj::Closure(_) => self.call(obj, argc),
+            Obj::BoundMethod(ObjBoundMethod {
+                receiver: this,
+                method,
+            }) => {
+                let method = *method;
+                self.stack[receiver] = *this;
+                self.call(method, argc)
+            }
+            Obj::Class(class) => {
+                let init = class.methods.get(&self.init_string).copied();
+                let instance = self.alloc(Obj::Instance(ObjInstance {
+                    class: obj,
+                    shape: class.shape,
+                    fields: vec![],
+                }));
+                self.stack[receiver] = Value::obj(instance);
+
+                match init {
+                    Some(init) => self.call(init, argc),
+                    None if argc != 0 => {
+                        Err(self.error(format!("Expected 0 arguments but got {argc}.")))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Obj::Native(native) => {
+                if argc != native.arity {
+                    let message = format!("Expected {} arguments but got {argc}.", native.arity);
+                    return Err(self.error(message));
+                }
+
+                let result = (native.function)(&self.stack[receiver + 1..]);
+                self.stack.truncate(receiver);
+                self.push(result);
+                Ok(())
+            }
+            _ => Err(self.error("Can only call functions and classes.")),
+        }
+    }
+
+    /// Calls the callee `argc` values down the stack and returns its result
+    /// from the running function, the callee taking over the frame if it
+    /// runs on one. Returns whether it returned from the script.
+    fn tail_call(&mut self, argc: usize) -> Result<bool, RuntimeError> {
+        let depth = self.frames.len();
+        self.tail_calling = true;
+        let called = self.call_value(self.peek(argc), argc);
+        self.tail_calling = false;
+        called?;
+        if self.frames.len() == depth {
+            // Natives and classes without initializers are done already.
+            return Ok(self.return_from_call());
+        }
+
+        // The locals of the caller are about to be overwritten, so the
+        // closures capturing them get their own copies first.
+        let mut callee = self.frames.pop().expect("called a function");
+        let caller = self.frames.pop().expect("tail call from a function");
+        self.close_upvalues(caller.slots);
+        self.stack.drain(caller.slots..callee.slots);
+        callee.slots = caller.slots;
+        self.frames.push(callee);
+        Ok(false)
+    }
+
+    fn call(&mut self, closure: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        let function = self.heap.closure(closure).function;
+        let code = self.heap.function(function).function.clone();
+        if argc != code.arity {
+            return Err(self.error(format!("Expected {} arguments but got {argc}.", code.arity)));
+        }
+
+        if self.frames.len() == FRAMES_MAX + usize::from(self.tail_calling) {
+            return Err(self.error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            code,
+            #[cfg(feature = "threaded-dispatch")]
+            threaded: self.heap.function(function).threaded.clone(),
+            #[cfg(feature = "jit")]
+            jit: self.heap.function(function).jit.clone(),
+            ip: 0,
+            slots: self.stack.len() - argc - 1,
+        });
+        #[cfg(feature = "jit")]
+        self.heat();
+        Ok(())
+    }
+
+    /// Counts a call or loop of the running function, compiling it to
+    /// machine code once it got hot.
+    #[cfg(feature = "jit")]
+    fn heat(&mut self) {
+        let function = self.frame().function;
+        let obj = self.heap.function_mut(function);
+        if obj.jit.is_some() {
+            return;
+        }
+        obj.hotness += 1;
+        if obj.hotness < self.jit_threshold {
+            return;
+        }
+
+        let code = Rc::new(jit::compile(&obj.function, &obj.constants));
+        obj.jit = Some(code.clone());
+        self.jit_compiled += 1;
+        self.frame_mut().jit = Some(code);
+    }
+
+    /// Runs the machine code of the running function from the instruction
+    /// about to run, if it has any, up to the first one it leaves to the
+    /// interpreter.
+    #[cfg(feature = "jit")]
+    fn run_jit(&mut self) {
+        let frame = self.frames.last_mut().expect("a function is running");
+        let Some(code) = &frame.jit else {
+            return;
+        };
+        if code.has_entry(frame.ip) {
+            // Every instruction pushes a value at most.
+            self.stack.reserve(frame.code.chunk.code.len());
+            // SAFETY: the stack holds the frame as the bytecode expects, with
+            // room for what the code can push.
+            frame.ip = unsafe { code.run(&mut self.stack, frame.slots, frame.ip) };
+        }
+    }
+
+    /// Calls a method straight from the receiver on the stack, unless it's
+    /// shadowed by a field.
+    fn invoke(&mut self, name: ObjRef, argc: usize) -> Result<(), RuntimeError> {
+        let site = self.frame().ip - 3;
+        let Some(instance) = self.as_instance(self.peek(argc)) else {
+            return Err(self.error("Only instances have properties."));
+        };
+
+        match self.find_property(site, instance, name)? {
+            CacheTarget::Field(slot) => {
+                let field = self.heap.instance(instance).fields[slot];
+                let receiver = self.stack.len() - argc - 1;
+                self.stack[receiver] = field;
+                self.call_value(field, argc)
+            }
+            CacheTarget::Method(method) => self.call(method, argc),
+            CacheTarget::Transition(_) => unreachable!("only setters transition"),
+        }
+    }
+
+    /// Finds the property `name` of `instance`, a field or else a method,
+    /// going through the inline cache of the instruction at `site`.
+    fn find_property(
+        &mut self,
+        site: usize,
+        instance: ObjRef,
+        name: ObjRef,
+    ) -> Result<CacheTarget, RuntimeError> {
+        let ObjInstance { class, shape, .. } = *self.heap.instance(instance);
+        let function = self.frame().function;
+        if let Some(target) = self.heap.function(function).caches[site].lookup(shape) {
+            return Ok(target);
+        }
+
+        let target = match self.shapes.slot(shape, name) {
+            Some(slot) => CacheTarget::Field(slot),
+            None => match self.heap.class(class).methods.get(&name) {
+                Some(&method) => CacheTarget::Method(method),
+                None => return Err(self.undefined_property(name)),
+            },
+        };
+        self.heap.function_mut(function).caches[site].insert(shape, target);
+        Ok(target)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        argc: usize,
+    ) -> Result<(), RuntimeError> {
+        match self.heap.class(class).methods.get(&name).copied() {
+            Some(method) => self.call(method, argc),
+            None => Err(self.undefined_property(name)),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let Some(method) = self.heap.class(class).methods.get(&name).copied() else {
+            return Err(self.undefined_property(name));
+        };
+        self.bind(method);
+        Ok(())
+    }
+
+    /// Replaces the instance on top of the stack with `method` bound to it.
+    fn bind(&mut self, method: ObjRef) {
+        let receiver = self.pop();
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method }));
+        self.push(Value::obj(bound));
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> RuntimeError {
+        self.error(format!("Undefined variable '{}'.", self.heap.string(name)))
+    }
+
+    fn undefined_property(&self, name: ObjRef) -> RuntimeError {
+        self.error(format!("Undefined property '{}'.", self.heap.string(name)))
+    }
+
+    /// Builds an error pointing at the instruction being run, or at line 0
+    /// when calling the script fails before it runs any.
+    fn error(&self, message: impl Into<String>) -> RuntimeError {
+        let line = self
+            .frames
+            .last()
+            .map_or(0, |frame| frame.code.chunk.lines[frame.ip - 1]);
+        RuntimeError {
+            message: message.into(),
+            line,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::programs::{self, Output};
+    use crate::scanner::Scanner;
+    use crate::vm::Vm;
+    use std::io::Write;
+
+    #[test]
+    fn test_programs() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let script = Compiler::new().compile(&statements).unwrap();
+
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            if let Err(err) = vm.interpret(script) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_programs_unoptimized() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let mut compiler = Compiler::new();
+            compiler.set_optimize(false);
+            let script = compiler.compile(&statements).unwrap();
+
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            if let Err(err) = vm.interpret(script) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_programs_under_gc_stress() {
+        programs::check(|source, mut output| {
+            let tokens = Scanner::new(source).scan_tokens().unwrap();
+            let statements = Parser::new(&tokens).parse().unwrap();
+            let script = Compiler::new().compile(&statements).unwrap();
+
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            vm.set_gc_stress(true);
+            if let Err(err) = vm.interpret(script) {
+                writeln!(output, "{err}").unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_gc_frees_cycles() {
+        let source = "
+        class Node {}
+        for (var i = 0; i < 100000; i = i + 1) {
+          var node = Node();
+          node.self = node;
+          node.other = Node();
+          node.other.node = node;
+        }
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.interpret(script).unwrap();
+
+        let stats = vm.gc_stats();
+        assert!(stats.collections > 0);
+        // Only the last nodes since the last collection are still around.
+        let live = stats.bytes_allocated - stats.bytes_freed;
+        assert!(live < 2 * 1024 * 1024, "{stats:?}");
+        assert!(stats.bytes_allocated > 10 * 1024 * 1024, "{stats:?}");
+    }
+
+    #[test]
+    fn test_gc_frees_shapes() {
+        let source = "
+        for (var i = 0; i < 100000; i = i + 1) {
+          class A { m() {} }
+          var a = A();
+          a.x = 1;
+          a.y = 2;
+        }
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let mut vm = Vm::with_output(Box::new(std::io::sink()));
+        vm.interpret(script).unwrap();
+
+        let stats = vm.gc_stats();
+        assert!(stats.collections > 0);
+        let live = stats.bytes_allocated - stats.bytes_freed;
+        assert!(live < 2 * 1024 * 1024, "{stats:?}");
+        // Rather than the 300000 of every class declared.
+        assert!(vm.shapes.len() < 30_000, "{} shapes", vm.shapes.len());
+    }
+
+    #[test]
+    fn test_tail_calls_reuse_frames() {
+        let source = "
+        fun count(n, total) {
+          if (n == 0) return total;
+          return count(n - 1, total + 1);
+        }
+        print count(100000, 0);
+
+        fun even(n) {
+          if (n == 0) return true;
+          return odd(n - 1);
+        }
+        fun odd(n) {
+          if (n == 0) return false;
+          return even(n - 1);
+        }
+        print even(100001);
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.interpret(script).unwrap();
+        assert_eq!(output.contents(), "100000\nfalse\n");
+    }
+
+    #[test]
+    fn test_trace() {
+        let tokens = Scanner::new("print 1 + 2;").scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_trace(true);
+        vm.interpret(script).unwrap();
+
+        let expected = "          [ <script> ]
+0000    1 OP_CONSTANT         0 '1'
+          [ <script> ][ 1 ]
+0002    | OP_CONSTANT         1 '2'
+          [ <script> ][ 1 ][ 2 ]
+0004    | OP_ADD
+          [ <script> ][ 3 ]
+0005    | OP_PRINT
+3
+          [ <script> ]
+0006    | OP_NIL
+          [ <script> ][ nil ]
+0007    | OP_RETURN
+";
+        assert_eq!(output.contents(), expected);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let tokens = Scanner::new("fun f() { f(); }\nf();")
+            .scan_tokens()
+            .unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let err = Vm::with_output(Box::new(std::io::sink()))
+            .interpret(script)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Stack overflow.\n[line 1]");
+    }
+
+    #[test]
+    fn test_tail_call_at_the_frame_limit() {
+        // The script and the nested calls fill every frame, the last one
+        // tail calling `loop`, which takes it over. One more nested call
+        // overflows.
+        let source = "
+        fun loop(n) {
+          if (n == 0) return \"done\";
+          return loop(n - 1);
+        }
+        fun nest(n) {
+          if (n == 0) return loop(10);
+          var result = nest(n - 1);
+          return result;
+        }
+        print nest(1022);
+        print nest(1023);
+        ";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        let err = vm.interpret(script).unwrap_err();
+        assert_eq!(output.contents(), "done\n");
+        assert_eq!(err.message, "Stack overflow.");
+    }
+}
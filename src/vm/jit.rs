@@ -0,0 +1,672 @@
+//! A baseline JIT for Linux on x86-64, picked with the `jit` feature.
+//!
+//! Once a function gets hot, counting its calls and the loops it runs, every
+//! instruction of its bytecode is translated into a template of machine code
+//! doing the same to the VM stack in memory. The stack is left exactly as
+//! the interpreter would leave it, so the machine code can hand over to the
+//! interpreter anywhere, and be entered again at any instruction.
+//!
+//! Only what keeps to locals and numbers is translated: constants, locals,
+//! arithmetic, comparisons and jumps. The rest, calls and returns included,
+//! exits to the interpreter, which runs that one instruction and enters the
+//! machine code again after it. So does arithmetic on anything but numbers,
+//! which is guarded for, the interpreter then adding strings or reporting
+//! the error.
+//!
+//! Values are NaN-boxed, so the feature turns `nan-boxing` on as well.
+//!
+//! On the benches, the nested loops over locals run more than ten times
+//! faster, staying in machine code throughout. Everything else gains a
+//! quarter to a half, leaving the interpreter for every call, global and
+//! property.
+//!
+//! The generated code has the signature of [`Entry`]: `rdi` points at slot
+//! zero of the frame and `rsi` at the top of the stack, where the next value
+//! goes. It returns in `rax` the offset of the instruction the interpreter
+//! has to go on with, and in `rdx` the top of the stack. Besides those,
+//! templates use `rax`, `rcx` and `rdx` as scratch, `r8` for the bits telling
+//! numbers apart, and `xmm0` and `xmm1` for arithmetic, which are all free to
+//! clobber in the System V ABI.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature only supports Linux on x86-64");
+
+use crate::chunk::{Constant, Function, OpCode};
+use crate::value::{nan_boxing, Value};
+use std::ffi::c_void;
+use std::ptr;
+
+/// How hot a function gets, in calls and loop iterations, before it's
+/// compiled.
+pub const THRESHOLD: u32 = 1000;
+
+#[repr(C)]
+struct Exit {
+    ip: usize,
+    top: *mut Value,
+}
+
+type Entry = unsafe extern "sysv64" fn(slots: *mut Value, top: *mut Value, entry: usize) -> Exit;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// The machine code of a function, in executable pages of its own.
+pub struct Code {
+    memory: *mut c_void,
+    len: usize,
+    /// Where the template of every instruction starts, by bytecode offset,
+    /// for the instructions that got one.
+    entries: Vec<Option<u32>>,
+}
+
+impl Code {
+    /// Copies the code into pages that are made executable, and no longer
+    /// writable, once it's there.
+    fn new(code: &[u8], entries: Vec<Option<u32>>) -> Self {
+        let len = code.len();
+        // SAFETY: a fresh anonymous mapping, only written within its length.
+        unsafe {
+            let memory = mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert!(memory != MAP_FAILED, "failed to map memory for the JIT");
+            ptr::copy_nonoverlapping(code.as_ptr(), memory.cast(), len);
+            let protected = mprotect(memory, len, PROT_READ | PROT_EXEC);
+            assert!(protected == 0, "failed to make JIT code executable");
+            Self {
+                memory,
+                len,
+                entries,
+            }
+        }
+    }
+
+    /// Whether there's machine code for the instruction at `ip`.
+    pub fn has_entry(&self, ip: usize) -> bool {
+        self.entries[ip].is_some()
+    }
+
+    /// Runs the machine code from the instruction at `ip` until it exits,
+    /// returning the instruction to go on with.
+    ///
+    /// # Safety
+    ///
+    /// There has to be an entry at `ip`. `stack` has to hold the frame with
+    /// its slot zero at `slots`, laid out as the instruction at `ip` expects,
+    /// and have capacity for as many more values as the function has bytes
+    /// of bytecode.
+    pub unsafe fn run(&self, stack: &mut Vec<Value>, slots: usize, ip: usize) -> usize {
+        let entry = self.entries[ip].expect("no machine code for the instruction");
+        let function: Entry = std::mem::transmute(self.memory);
+        let base = stack.as_mut_ptr();
+
+        let exit = function(
+            base.add(slots),
+            base.add(stack.len()),
+            self.memory as usize + entry as usize,
+        );
+        stack.set_len(exit.top.offset_from(base) as usize);
+        exit.ip
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        // SAFETY: the mapping made in `new`, which nothing runs anymore.
+        unsafe {
+            munmap(self.memory, self.len);
+        }
+    }
+}
+
+/// Translates a function the VM loaded, with `constants` the values of its
+/// constants.
+pub fn compile(function: &Function, constants: &[Value]) -> Code {
+    let code = &function.chunk.code;
+    let mut asm = Assembler::default();
+    let mut labels = vec![None; code.len()];
+    let mut entries = vec![None; code.len()];
+    // Jumps from and to bytecode offsets, and guards exiting at them.
+    let mut jumps = vec![];
+    let mut guards = vec![];
+
+    // The prologue, jumping to the entry the caller asked for.
+    asm.mov_imm(R8, nan_boxing::QNAN);
+    asm.bytes(&[0xff, 0xe2]); // jmp rdx
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::try_from(code[offset]).expect("loaded code decodes");
+        let operand = |i: usize| code[offset + i];
+        let short = || u16::from_be_bytes([operand(1), operand(2)]) as usize;
+        let next = offset + 1 + op.operand_bytes();
+        // The verifier only checks operands on the paths that run, so dead
+        // code can point past the constants or the code.
+        let constant = |i: usize| {
+            constants
+                .get(operand(i) as usize)
+                .copied()
+                .unwrap_or(Value::NIL)
+        };
+        labels[offset] = Some(asm.len());
+
+        let mut template = Template {
+            asm: &mut asm,
+            guards: &mut guards,
+            offset,
+        };
+        match op {
+            OpCode::Constant => template.push_value(constant(1)),
+            OpCode::Nil => template.push_value(Value::NIL),
+            OpCode::True => template.push_value(Value::bool(true)),
+            OpCode::False => template.push_value(Value::bool(false)),
+            OpCode::Pop => template.asm.sub_top(),
+            OpCode::GetLocal => {
+                template.asm.load(RAX, RDI, slot(operand(1)));
+                template.push(RAX);
+            }
+            OpCode::SetLocal => {
+                template.asm.load(RAX, RSI, -8);
+                template.asm.store(RDI, slot(operand(1)), RAX);
+            }
+            OpCode::Add => template.arithmetic(Operands::Stack, 0x58),
+            OpCode::Subtract => template.arithmetic(Operands::Stack, 0x5c),
+            OpCode::Multiply => template.arithmetic(Operands::Stack, 0x59),
+            OpCode::Divide => template.arithmetic(Operands::Stack, 0x5e),
+            OpCode::Greater => template.comparison(Operands::Stack, Comparison::Greater),
+            OpCode::GreaterEqual => template.comparison(Operands::Stack, Comparison::GreaterEqual),
+            OpCode::Less => template.comparison(Operands::Stack, Comparison::Less),
+            OpCode::LessEqual => template.comparison(Operands::Stack, Comparison::LessEqual),
+            OpCode::Equal => template.comparison(Operands::Stack, Comparison::Equal),
+            OpCode::Not => template.not(),
+            OpCode::Negate => template.negate(),
+            OpCode::Jump => jumps.push((template.asm.jmp(), offset, next + short())),
+            OpCode::Loop => {
+                let target = next.checked_sub(short()).unwrap_or(usize::MAX);
+                jumps.push((template.asm.jmp(), offset, target));
+            }
+            OpCode::JumpIfFalse => {
+                // Leaves the condition on the stack, like the instruction.
+                template.asm.load(RAX, RSI, -8);
+                for falsey in [Value::NIL, Value::bool(false)] {
+                    template.asm.mov_imm(RCX, falsey.to_bits());
+                    template.asm.alu(CMP, RAX, RCX);
+                    jumps.push((template.asm.jcc(JE), offset, next + short()));
+                }
+            }
+            OpCode::AddLocalConstant
+            | OpCode::SubtractLocalConstant
+            | OpCode::LessLocalConstant => {
+                let operands = Operands::LocalConstant(operand(1), constant(2));
+                match op {
+                    OpCode::AddLocalConstant => template.arithmetic(operands, 0x58),
+                    OpCode::SubtractLocalConstant => template.arithmetic(operands, 0x5c),
+                    _ => template.comparison(operands, Comparison::Less),
+                }
+            }
+            _ => {
+                template.asm.exit(offset);
+                offset = skip(function, op, next);
+                continue;
+            }
+        }
+
+        entries[offset] = labels[offset].map(|label| label as u32);
+        offset = next;
+    }
+
+    for (at, offset, target) in jumps {
+        match labels.get(target).copied().flatten() {
+            Some(label) => asm.patch(at, label),
+            // Only dead code jumps off the instructions, and leaving it to
+            // the interpreter keeps it from ever running.
+            None => guards.push((at, offset)),
+        }
+    }
+
+    // The guards exit right at their instruction, which hasn't touched the
+    // stack yet.
+    guards.sort_by_key(|&(_, offset)| offset);
+    let mut stub = None;
+    for (at, offset) in guards {
+        let label = match stub {
+            Some((stub_offset, label)) if stub_offset == offset => label,
+            _ => {
+                let label = asm.len();
+                asm.exit(offset);
+                stub = Some((offset, label));
+                label
+            }
+        };
+        asm.patch(at, label);
+    }
+
+    Code::new(&asm.code, entries)
+}
+
+/// The offset of the instruction after one the JIT doesn't translate, past
+/// the upvalues of closures.
+fn skip(function: &Function, op: OpCode, next: usize) -> usize {
+    if op != OpCode::Closure {
+        return next;
+    }
+    let index = function.chunk.code[next - 1] as usize;
+    let Constant::Function(captured) = &function.chunk.constants[index] else {
+        unreachable!("closures are made of function constants");
+    };
+    next + 2 * captured.upvalue_count
+}
+
+fn slot(slot: u8) -> i32 {
+    slot as i32 * std::mem::size_of::<Value>() as i32
+}
+
+/// Where the operands of an arithmetic or comparison come from.
+enum Operands {
+    /// The two values on top of the stack, replaced by the result.
+    Stack,
+    /// A local and a constant, the result being pushed.
+    LocalConstant(u8, Value),
+}
+
+enum Comparison {
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Equal,
+}
+
+/// Emits the machine code of the instruction at `offset`.
+struct Template<'a> {
+    asm: &'a mut Assembler,
+    /// Where guards exit, patched once the stubs are emitted.
+    guards: &'a mut Vec<(usize, usize)>,
+    offset: usize,
+}
+
+impl Template<'_> {
+    fn push(&mut self, reg: u8) {
+        self.asm.store(RSI, 0, reg);
+        self.asm.add_top();
+    }
+
+    fn push_value(&mut self, value: Value) {
+        self.asm.mov_imm(RAX, value.to_bits());
+        self.push(RAX);
+    }
+
+    /// Loads the operands into `rax` and `rcx`, exiting unless both are
+    /// numbers.
+    fn load_numbers(&mut self, operands: &Operands) {
+        match *operands {
+            Operands::Stack => {
+                self.asm.load(RAX, RSI, -16);
+                self.asm.load(RCX, RSI, -8);
+            }
+            Operands::LocalConstant(local, constant) => {
+                self.asm.load(RAX, RDI, slot(local));
+                self.asm.mov_imm(RCX, constant.to_bits());
+            }
+        }
+
+        for reg in [RAX, RCX] {
+            // Numbers are whatever doesn't have all the bits of `QNAN` set.
+            self.asm.alu(MOV, RDX, reg);
+            self.asm.alu(AND, RDX, R8);
+            self.asm.alu(CMP, RDX, R8);
+            let at = self.asm.jcc(JE);
+            self.guards.push((at, self.offset));
+        }
+
+        self.asm.movq_to_xmm(0, RAX);
+        self.asm.movq_to_xmm(1, RCX);
+    }
+
+    fn store_result(&mut self, operands: &Operands) {
+        match operands {
+            Operands::Stack => {
+                self.asm.store(RSI, -16, RAX);
+                self.asm.sub_top();
+            }
+            Operands::LocalConstant(..) => self.push(RAX),
+        }
+    }
+
+    /// `opcode` is the one of the scalar double instruction, `addsd` and the
+    /// like.
+    fn arithmetic(&mut self, operands: Operands, opcode: u8) {
+        self.load_numbers(&operands);
+        self.asm.bytes(&[0xf2, 0x0f, opcode, 0xc1]); // xmm0 op= xmm1
+        self.asm.movq_from_xmm(RAX, 0);
+        self.store_result(&operands);
+    }
+
+    fn comparison(&mut self, operands: Operands, comparison: Comparison) {
+        self.load_numbers(&operands);
+
+        // Unordered comparisons, with NaNs, set the carry and zero flags,
+        // which makes them all false but for `!=`.
+        let (swapped, setcc) = match comparison {
+            Comparison::Greater => (false, SETA),
+            Comparison::GreaterEqual => (false, SETAE),
+            Comparison::Less => (true, SETA),
+            Comparison::LessEqual => (true, SETAE),
+            Comparison::Equal => (false, SETE),
+        };
+        let ucomisd = if swapped { 0xc8 } else { 0xc1 };
+        self.asm.bytes(&[0x66, 0x0f, 0x2e, ucomisd]);
+        self.asm.bytes(&[0x0f, setcc, 0xc0]); // setcc al
+        if let Comparison::Equal = comparison {
+            self.asm.bytes(&[0x0f, SETNP, 0xc1]); // setnp cl
+            self.asm.bytes(&[0x20, 0xc8]); // and al, cl
+        }
+
+        self.bool_from_al();
+        self.store_result(&operands);
+    }
+
+    /// Turns `al`, zero or one, into the bits of `false` or `true` in `rax`.
+    fn bool_from_al(&mut self) {
+        debug_assert_eq!(Value::bool(false).to_bits(), nan_boxing::QNAN | 2);
+        debug_assert_eq!(Value::bool(true).to_bits(), nan_boxing::QNAN | 3);
+        self.asm.bytes(&[0x0f, 0xb6, 0xc0]); // movzx eax, al
+        self.asm.bytes(&[0x83, 0xc0, 0x02]); // add eax, 2
+        self.asm.alu(OR, RAX, R8);
+    }
+
+    fn not(&mut self) {
+        self.asm.load(RAX, RSI, -8);
+        self.asm.mov_imm(RCX, Value::NIL.to_bits());
+        self.asm.alu(CMP, RAX, RCX);
+        self.asm.bytes(&[0x0f, SETE, 0xc2]); // sete dl
+        self.asm.mov_imm(RCX, Value::bool(false).to_bits());
+        self.asm.alu(CMP, RAX, RCX);
+        self.asm.bytes(&[0x0f, SETE, 0xc0]); // sete al
+        self.asm.bytes(&[0x08, 0xd0]); // or al, dl
+        self.bool_from_al();
+        self.asm.store(RSI, -8, RAX);
+    }
+
+    fn negate(&mut self) {
+        self.asm.load(RAX, RSI, -8);
+        self.asm.alu(MOV, RDX, RAX);
+        self.asm.alu(AND, RDX, R8);
+        self.asm.alu(CMP, RDX, R8);
+        let at = self.asm.jcc(JE);
+        self.guards.push((at, self.offset));
+
+        self.asm.mov_imm(RCX, nan_boxing::SIGN_BIT);
+        self.asm.alu(XOR, RAX, RCX);
+        self.asm.store(RSI, -8, RAX);
+    }
+}
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R8: u8 = 8;
+
+// Opcodes of `op r/m64, r64`.
+const MOV: u8 = 0x89;
+const AND: u8 = 0x21;
+const OR: u8 = 0x09;
+const XOR: u8 = 0x31;
+const CMP: u8 = 0x39;
+
+const JE: u8 = 0x84;
+
+const SETA: u8 = 0x97;
+const SETAE: u8 = 0x93;
+const SETE: u8 = 0x94;
+const SETNP: u8 = 0x9b;
+
+/// Encodes the few x86-64 instructions the templates are made of.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// The REX prefix of a 64 bit instruction on `reg` and `rm`.
+    fn rex(&mut self, reg: u8, rm: u8) {
+        self.code.push(0x48 | (reg >> 3) << 2 | rm >> 3);
+    }
+
+    fn modrm(&mut self, mode: u8, reg: u8, rm: u8) {
+        self.code.push(mode << 6 | (reg & 7) << 3 | rm & 7);
+    }
+
+    /// `op rm, reg`
+    fn alu(&mut self, op: u8, rm: u8, reg: u8) {
+        self.rex(reg, rm);
+        self.code.push(op);
+        self.modrm(0b11, reg, rm);
+    }
+
+    /// `mov dst, imm64`
+    fn mov_imm(&mut self, dst: u8, imm: u64) {
+        self.rex(0, dst);
+        self.code.push(0xb8 + (dst & 7));
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `mov dst, [base + disp]`, with `base` neither `rsp` nor `r12`.
+    fn load(&mut self, dst: u8, base: u8, disp: i32) {
+        self.rex(dst, base);
+        self.code.push(0x8b);
+        self.modrm(0b10, dst, base);
+        self.bytes(&disp.to_le_bytes());
+    }
+
+    /// `mov [base + disp], src`, with `base` neither `rsp` nor `r12`.
+    fn store(&mut self, base: u8, disp: i32, src: u8) {
+        self.rex(src, base);
+        self.code.push(0x89);
+        self.modrm(0b10, src, base);
+        self.bytes(&disp.to_le_bytes());
+    }
+
+    /// `add rsi, 8`
+    fn add_top(&mut self) {
+        self.bytes(&[0x48, 0x83, 0xc6, 0x08]);
+    }
+
+    /// `sub rsi, 8`
+    fn sub_top(&mut self) {
+        self.bytes(&[0x48, 0x83, 0xee, 0x08]);
+    }
+
+    /// `movq xmm, src`
+    fn movq_to_xmm(&mut self, xmm: u8, src: u8) {
+        self.code.push(0x66);
+        self.rex(xmm, src);
+        self.bytes(&[0x0f, 0x6e]);
+        self.modrm(0b11, xmm, src);
+    }
+
+    /// `movq dst, xmm`
+    fn movq_from_xmm(&mut self, dst: u8, xmm: u8) {
+        self.code.push(0x66);
+        self.rex(xmm, dst);
+        self.bytes(&[0x0f, 0x7e]);
+        self.modrm(0b11, xmm, dst);
+    }
+
+    /// `jmp rel32`, returning where to patch the target.
+    fn jmp(&mut self) -> usize {
+        self.code.push(0xe9);
+        self.bytes(&[0; 4]);
+        self.len() - 4
+    }
+
+    /// `jcc rel32`, returning where to patch the target.
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.bytes(&[0x0f, cc]);
+        self.bytes(&[0; 4]);
+        self.len() - 4
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        let rel = target as i64 - (at as i64 + 4);
+        let rel = i32::try_from(rel).expect("JIT code too large");
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    /// Returns to the interpreter at the instruction at `offset`.
+    fn exit(&mut self, offset: usize) {
+        let offset = u32::try_from(offset).expect("bytecode too large");
+        self.code.push(0xb8); // mov eax, imm32
+        self.bytes(&offset.to_le_bytes());
+        self.alu(MOV, RDX, RSI);
+        self.code.push(0xc3); // ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Compiler;
+    use crate::interpreter::Interpreter;
+    use crate::parser::Parser;
+    use crate::programs::{self, Output};
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+    use crate::vm::Vm;
+    use std::io::Write;
+
+    fn run_jit(source: &str, output: Output) -> usize {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let script = Compiler::new().compile(&statements).unwrap();
+
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        vm.set_jit_threshold(0);
+        if let Err(err) = vm.interpret(script) {
+            writeln!(output.clone(), "{err}").unwrap();
+        }
+        vm.jit_compiled()
+    }
+
+    fn run_interpreter(source: &str, mut output: Output) {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let statements = Parser::new(&tokens).parse().unwrap();
+        let locals = Resolver::new().resolve(&statements).unwrap();
+
+        let mut interpreter = Interpreter::with_output(Box::new(output.clone()));
+        interpreter.resolve(locals);
+        if let Err(err) = interpreter.interpret(&statements) {
+            writeln!(output, "{err}").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_programs() {
+        programs::check(|source, output| {
+            run_jit(source, output);
+        });
+    }
+
+    #[test]
+    fn test_same_results_as_the_interpreter() {
+        let sources = [
+            "
+            fun fib(n) {
+              if (n < 2) return n;
+              return fib(n - 1) + fib(n - 2);
+            }
+            print fib(20);
+            ",
+            "
+            fun loops() {
+              var sum = 0;
+              for (var i = 0; i < 100; i = i + 1) {
+                for (var j = 100; j >= 0; j = j - 3) {
+                  sum = sum + i * j / 7 - -i;
+                }
+              }
+              return sum;
+            }
+            print loops();
+            ",
+            "
+            fun compare(a, b) {
+              print a < b;
+              print a <= b;
+              print a > b;
+              print a >= b;
+              print a == b;
+              print !(a == b);
+              print !a;
+              print -a;
+            }
+            compare(1, 2);
+            compare(2, 2);
+            compare(0, -0);
+            compare(0 / 0, 1);
+            compare(0 / 0, 0 / 0);
+            compare(1 / 0, -1 / 0);
+            ",
+            "
+            fun mixed(a, b) {
+              if (a) print a + b;
+              else print a == b;
+            }
+            mixed(1, 2);
+            mixed(\"a\", \"b\");
+            mixed(nil, nil);
+            mixed(false, 1);
+            mixed(true, 1);
+            ",
+            "
+            fun broken(a) {
+              var b = a * 2;
+              return -b;
+            }
+            print broken(2);
+            print broken(\"x\");
+            ",
+        ];
+
+        for source in sources {
+            let (expected, actual) = (Output::default(), Output::default());
+            run_interpreter(source, expected.clone());
+            let compiled = run_jit(source, actual.clone());
+
+            assert!(compiled > 0);
+            assert_eq!(actual.contents(), expected.contents(), "{source}");
+        }
+    }
+}
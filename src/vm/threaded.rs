@@ -0,0 +1,376 @@
+//! Threaded dispatch, picked with the `threaded-dispatch` feature.
+//!
+//! Functions are decoded once, as the VM loads them, into the handler of
+//! every instruction along with its operands, the constants they refer to
+//! already turned into values. Running an instruction is then a call through
+//! a function pointer, without matching on the opcode or reading operand
+//! bytes.
+//!
+//! Rust has no guaranteed tail calls, so handlers return to a small loop
+//! instead of jumping straight to the next one. Decoded instructions sit at
+//! the offset of their opcode, which keeps the instruction pointer a byte
+//! offset, as inline caches, the line table and traces expect.
+//!
+//! It's no faster than the `match` loop on the benches, which the compiler
+//! already turns into a jump table, and slower in tight loops over locals,
+//! where the inlined arms keep the stack in registers across instructions.
+
+use super::{RuntimeError, Vm};
+use crate::chunk::{Constant, Function, OpCode};
+use crate::object::ObjRef;
+use crate::value::{Value, ValueKind};
+use std::ops::ControlFlow;
+use std::rc::Rc;
+
+/// Whether to go on with the next instruction, or the script returned.
+///
+/// Errors are boxed to keep what every handler returns in two registers.
+type Flow = Result<ControlFlow<()>, Box<RuntimeError>>;
+
+type Handler = fn(&mut Vm, Threaded) -> Flow;
+
+const NEXT: Flow = Ok(ControlFlow::Continue(()));
+
+/// What the offsets in the middle of instructions hold, which verified code
+/// never jumps to.
+const INVALID: Threaded = Threaded {
+    handler: |_, _| unreachable!("jumped into the middle of an instruction"),
+    constant: Value::NIL,
+    operand: 0,
+    argc: 0,
+    next: 0,
+};
+
+#[derive(Clone, Copy)]
+pub struct Threaded {
+    handler: Handler,
+    /// The value of the constant operand, names included.
+    constant: Value,
+    /// A slot, upvalue index, argument count or jump offset.
+    operand: u16,
+    /// The argument count of invokes.
+    argc: u8,
+    /// Where the instruction pointer is while the handler runs: past the
+    /// operands, but before the upvalues of closures.
+    next: u32,
+}
+
+impl Threaded {
+    /// The object the constant operand is, a name, function or class.
+    fn object(self) -> ObjRef {
+        match self.constant.kind() {
+            ValueKind::Obj(obj) => obj,
+            _ => unreachable!("operand is not an object"),
+        }
+    }
+}
+
+/// Decodes a function the VM loaded, with `constants` the values of its
+/// constants.
+pub fn decode(function: &Function, constants: &[Value]) -> Rc<[Threaded]> {
+    let code = &function.chunk.code;
+    let mut threaded = vec![INVALID; code.len()];
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::try_from(code[offset]).expect("loaded code decodes");
+        let operand = |i: usize| code[offset + i];
+        // The verifier only checks operands on the paths that run, so dead
+        // code can point past the constants.
+        let constant = |i: usize| {
+            constants
+                .get(operand(i) as usize)
+                .copied()
+                .unwrap_or(Value::NIL)
+        };
+        let mut instruction = Threaded {
+            handler: handler(op),
+            next: (offset + 1 + op.operand_bytes()) as u32,
+            ..INVALID
+        };
+
+        match op {
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::Method => instruction.constant = constant(1),
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::TailCall => instruction.operand = operand(1) as u16,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                instruction.operand = u16::from_be_bytes([operand(1), operand(2)]);
+            }
+            OpCode::Invoke | OpCode::SuperInvoke => {
+                instruction.constant = constant(1);
+                instruction.argc = operand(2);
+            }
+            OpCode::AddLocalConstant
+            | OpCode::SubtractLocalConstant
+            | OpCode::LessLocalConstant => {
+                instruction.operand = operand(1) as u16;
+                instruction.constant = constant(2);
+            }
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Pop
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Print
+            | OpCode::CloseUpvalue
+            | OpCode::Return
+            | OpCode::Inherit => {}
+        }
+
+        let mut len = 1 + op.operand_bytes();
+        if op == OpCode::Closure {
+            let Constant::Function(captured) = &function.chunk.constants[operand(1) as usize]
+            else {
+                unreachable!("closures are made of function constants");
+            };
+            len += 2 * captured.upvalue_count;
+        }
+
+        threaded[offset] = instruction;
+        offset += len;
+    }
+
+    threaded.into()
+}
+
+fn handler(op: OpCode) -> Handler {
+    match op {
+        OpCode::Constant => |vm, i| {
+            vm.push(i.constant);
+            NEXT
+        },
+        OpCode::Nil => |vm, _| {
+            vm.push(Value::NIL);
+            NEXT
+        },
+        OpCode::True => |vm, _| {
+            vm.push(Value::bool(true));
+            NEXT
+        },
+        OpCode::False => |vm, _| {
+            vm.push(Value::bool(false));
+            NEXT
+        },
+        OpCode::Pop => |vm, _| {
+            vm.pop();
+            NEXT
+        },
+        OpCode::GetLocal => |vm, i| {
+            vm.get_local(i.operand as u8);
+            NEXT
+        },
+        OpCode::SetLocal => |vm, i| {
+            vm.set_local(i.operand as u8);
+            NEXT
+        },
+        OpCode::GetGlobal => |vm, i| {
+            vm.get_global(i.object())?;
+            NEXT
+        },
+        OpCode::DefineGlobal => |vm, i| {
+            vm.define_global(i.object());
+            NEXT
+        },
+        OpCode::SetGlobal => |vm, i| {
+            vm.set_global(i.object())?;
+            NEXT
+        },
+        OpCode::GetUpvalue => |vm, i| {
+            vm.get_upvalue(i.operand as u8);
+            NEXT
+        },
+        OpCode::SetUpvalue => |vm, i| {
+            vm.set_upvalue(i.operand as u8);
+            NEXT
+        },
+        OpCode::GetProperty => |vm, i| {
+            vm.get_property(i.object())?;
+            NEXT
+        },
+        OpCode::SetProperty => |vm, i| {
+            vm.set_property(i.object())?;
+            NEXT
+        },
+        OpCode::GetSuper => |vm, i| {
+            let superclass = vm.superclass()?;
+            vm.bind_method(superclass, i.object())?;
+            NEXT
+        },
+        OpCode::Equal => |vm, _| {
+            vm.equal();
+            NEXT
+        },
+        OpCode::Greater => |vm, _| {
+            vm.binary_op(|a, b| Value::bool(a > b))?;
+            NEXT
+        },
+        OpCode::GreaterEqual => |vm, _| {
+            vm.binary_op(|a, b| Value::bool(a >= b))?;
+            NEXT
+        },
+        OpCode::Less => |vm, _| {
+            vm.binary_op(|a, b| Value::bool(a < b))?;
+            NEXT
+        },
+        OpCode::LessEqual => |vm, _| {
+            vm.binary_op(|a, b| Value::bool(a <= b))?;
+            NEXT
+        },
+        OpCode::Add => |vm, _| {
+            vm.add()?;
+            NEXT
+        },
+        OpCode::Subtract => |vm, _| {
+            vm.binary_op(|a, b| Value::number(a - b))?;
+            NEXT
+        },
+        OpCode::Multiply => |vm, _| {
+            vm.binary_op(|a, b| Value::number(a * b))?;
+            NEXT
+        },
+        OpCode::Divide => |vm, _| {
+            vm.binary_op(|a, b| Value::number(a / b))?;
+            NEXT
+        },
+        OpCode::Not => |vm, _| {
+            let value = vm.pop();
+            vm.push(Value::bool(value.is_falsey()));
+            NEXT
+        },
+        OpCode::Negate => |vm, _| {
+            vm.negate()?;
+            NEXT
+        },
+        OpCode::Print => |vm, _| {
+            vm.print();
+            NEXT
+        },
+        OpCode::Jump => |vm, i| {
+            vm.frame_mut().ip += i.operand as usize;
+            NEXT
+        },
+        OpCode::JumpIfFalse => |vm, i| {
+            if vm.peek(0).is_falsey() {
+                vm.frame_mut().ip += i.operand as usize;
+            }
+            NEXT
+        },
+        OpCode::Loop => |vm, i| {
+            vm.frame_mut().ip -= i.operand as usize;
+            #[cfg(feature = "jit")]
+            vm.heat();
+            NEXT
+        },
+        OpCode::Call => |vm, i| {
+            let argc = i.operand as usize;
+            vm.call_value(vm.peek(argc), argc)?;
+            NEXT
+        },
+        OpCode::TailCall => |vm, i| {
+            if vm.tail_call(i.operand as usize)? {
+                return Ok(ControlFlow::Break(()));
+            }
+            NEXT
+        },
+        OpCode::Invoke => |vm, i| {
+            vm.invoke(i.object(), i.argc as usize)?;
+            NEXT
+        },
+        OpCode::SuperInvoke => |vm, i| {
+            let superclass = vm.superclass()?;
+            vm.invoke_from_class(superclass, i.object(), i.argc as usize)?;
+            NEXT
+        },
+        OpCode::Closure => |vm, i| {
+            vm.closure(i.object());
+            NEXT
+        },
+        OpCode::CloseUpvalue => |vm, _| {
+            vm.close_upvalues(vm.stack.len() - 1);
+            vm.pop();
+            NEXT
+        },
+        OpCode::Return => |vm, _| {
+            if vm.return_from_call() {
+                return Ok(ControlFlow::Break(()));
+            }
+            NEXT
+        },
+        OpCode::Class => |vm, i| {
+            vm.class(i.object());
+            NEXT
+        },
+        OpCode::Inherit => |vm, _| {
+            vm.inherit()?;
+            NEXT
+        },
+        OpCode::Method => |vm, i| {
+            vm.method(i.object())?;
+            NEXT
+        },
+        OpCode::AddLocalConstant => |vm, i| {
+            vm.get_local(i.operand as u8);
+            vm.push(i.constant);
+            vm.add()?;
+            NEXT
+        },
+        OpCode::SubtractLocalConstant => |vm, i| {
+            vm.get_local(i.operand as u8);
+            vm.push(i.constant);
+            vm.binary_op(|a, b| Value::number(a - b))?;
+            NEXT
+        },
+        OpCode::LessLocalConstant => |vm, i| {
+            vm.get_local(i.operand as u8);
+            vm.push(i.constant);
+            vm.binary_op(|a, b| Value::bool(a < b))?;
+            NEXT
+        },
+    }
+}
+
+impl Vm {
+    pub(super) fn run(&mut self) -> Result<(), RuntimeError> {
+        loop {
+            if self.trace {
+                self.trace_instruction();
+            } else {
+                #[cfg(feature = "jit")]
+                self.run_jit();
+            }
+
+            let frame = self.frame_mut();
+            let instruction = frame.threaded[frame.ip];
+            frame.ip = instruction.next as usize;
+            if (instruction.handler)(self, instruction)
+                .map_err(|err| *err)?
+                .is_break()
+            {
+                return Ok(());
+            }
+        }
+    }
+}
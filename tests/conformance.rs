@@ -0,0 +1,286 @@
+//! Runs the programs under `tests/lox` through the CLI, on every backend,
+//! checking what they print, the errors they report and their exit status
+//! against the annotations in their comments, the way the upstream Lox test
+//! suite does:
+//!
+//! - `// expect: value` is a line the program prints.
+//! - `// expect runtime error: message` is the runtime error the program
+//!   ends with, on the line of the comment, exiting with 70.
+//! - `// [line N] Error at 'x': message` is a compile error reported on line
+//!   `N`, or on the line of the comment without `[line N]`, exiting with 65.
+//!
+//! The programs are copied from that suite, under the license in
+//! `tests/lox/LICENSE`, keeping its directories. Left out of it are:
+//!
+//! - `benchmark/`, timed rather than checked, whose classic programs are
+//!   under `benches/lox` instead.
+//! - `expressions/` and the token dumps of `scanning/`, which check the
+//!   book's early chapters rather than a whole interpreter. Here `scanning/`
+//!   holds the suite's top-level `unexpected_character.lox` instead.
+//! - Most files of the remaining directories: the corpus is a selection of
+//!   each, not the whole suite.
+//!
+//! A backend known to fall short of a program is listed in [`SKIPPED`], with
+//! why. Skipped programs still run, and one that passes fails the run until
+//! it's taken off the list.
+//!
+//! Arguments only keep the programs whose path contains one of them, like
+//! `cargo test --test conformance -- closure`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+
+const BACKENDS: &[&str] = &["interpreter", "closure", "vm", "register"];
+
+/// The programs some backends are known to fail: the path, the backends and
+/// why.
+const SKIPPED: &[(&str, &[&str], &str)] = &[
+    (
+        "limit/loop_too_large.lox",
+        &["interpreter", "closure", "register"],
+        "no limit on how far a loop jumps back",
+    ),
+    (
+        "limit/loop_too_large.lox",
+        &["vm"],
+        "reported on the line of `while`, with no `}` to report it at, and \
+         followed by the exit jump's error",
+    ),
+    (
+        "limit/too_many_constants.lox",
+        &["interpreter", "closure"],
+        "no constant table to fill",
+    ),
+    (
+        "limit/too_many_constants.lox",
+        &["vm", "register"],
+        "the optimizer drops the literal statements filling the table",
+    ),
+    (
+        "limit/too_many_locals.lox",
+        &["interpreter", "closure"],
+        "no limit on local variables",
+    ),
+    (
+        "limit/too_many_locals.lox",
+        &["register"],
+        "locals share the registers with temporaries, running out of those",
+    ),
+    (
+        "limit/too_many_upvalues.lox",
+        &["interpreter", "closure"],
+        "no limit on closure variables",
+    ),
+];
+
+/// Why `backend` is known to fail `name`, if it is.
+fn skipped(name: &str, backend: &str) -> Option<&'static str> {
+    SKIPPED
+        .iter()
+        .find(|(path, backends, _)| *path == name && backends.contains(&backend))
+        .map(|(_, _, reason)| *reason)
+}
+
+/// What a program is expected to do.
+#[derive(Debug, Default, PartialEq)]
+struct Expectations {
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+    status: i32,
+}
+
+impl Expectations {
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut expected = Self::default();
+        let mut compile_errors = false;
+        let mut runtime_error = false;
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            if let Some((_, value)) = line.split_once("// expect: ") {
+                expected.stdout.push(value.into());
+            } else if let Some((_, message)) = line.split_once("// expect runtime error: ") {
+                if runtime_error {
+                    return Err(format!("line {line_number}: a second runtime error"));
+                }
+                runtime_error = true;
+                expected.stderr.push(message.into());
+                expected.stderr.push(format!("[line {line_number}]"));
+            } else if let Some((_, error)) = line.split_once("// [line ") {
+                let (n, message) = error
+                    .split_once("] ")
+                    .filter(|(n, message)| {
+                        n.parse::<usize>().is_ok() && message.starts_with("Error")
+                    })
+                    .ok_or_else(|| format!("line {line_number}: a malformed error"))?;
+                compile_errors = true;
+                expected.stderr.push(format!("[line {n}] {message}"));
+            } else if let Some((_, error)) = line.split_once("// Error") {
+                compile_errors = true;
+                expected
+                    .stderr
+                    .push(format!("[line {line_number}] Error{error}"));
+            }
+        }
+
+        expected.status = match (compile_errors, runtime_error) {
+            (true, true) => return Err("both compile and runtime errors".into()),
+            (true, false) => 65,
+            (false, true) => 70,
+            (false, false) => 0,
+        };
+        Ok(expected)
+    }
+}
+
+/// Runs `path` on `backend`, describing how it went wrong, if it did.
+fn run(path: &Path, backend: &str, expected: &Expectations) -> Result<(), String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_lox0"))
+        .args(["--backend", backend])
+        .arg(path)
+        .output()
+        .map_err(|err| format!("couldn't run the CLI: {err}"))?;
+    let actual = Expectations {
+        stdout: lines(&output.stdout),
+        stderr: lines(&output.stderr),
+        status: output.status.code().unwrap_or(-1),
+    };
+    if actual == *expected {
+        return Ok(());
+    }
+
+    let mut report = String::new();
+    let mut compare = |name, expected: &[String], actual: &[String]| {
+        if expected != actual {
+            writeln!(report, "    expected {name}:").unwrap();
+            for line in expected {
+                writeln!(report, "      {line}").unwrap();
+            }
+            writeln!(report, "    got:").unwrap();
+            for line in actual {
+                writeln!(report, "      {line}").unwrap();
+            }
+        }
+    };
+    compare("stdout", &expected.stdout, &actual.stdout);
+    compare("stderr", &expected.stderr, &actual.stderr);
+    if expected.status != actual.status {
+        writeln!(
+            report,
+            "    expected exit status {}, got {}",
+            expected.status, actual.status
+        )
+        .unwrap();
+    }
+    Err(report)
+}
+
+fn lines(output: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .map(Into::into)
+        .collect()
+}
+
+/// The `.lox` files under `dir`, sorted.
+fn discover(dir: &Path, files: &mut Vec<PathBuf>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("couldn't read {}: {err}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            discover(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            files.push(path);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+fn main() -> ExitCode {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let filters: Vec<_> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+
+    let mut files = vec![];
+    discover(&root, &mut files);
+
+    let mut summaries: BTreeMap<String, Summary> = BTreeMap::new();
+    for path in files {
+        let name = path.strip_prefix(&root).unwrap().display().to_string();
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter)) {
+            continue;
+        }
+        let dir = match name.rsplit_once('/') {
+            Some((dir, _)) => dir.to_string(),
+            None => ".".into(),
+        };
+        let summary = summaries.entry(dir).or_default();
+
+        let source = fs::read_to_string(&path).unwrap();
+        let expected = match Expectations::parse(&source) {
+            Ok(expected) => expected,
+            Err(err) => {
+                println!("FAIL {name}: {err}");
+                summary.failed += 1;
+                continue;
+            }
+        };
+        for backend in BACKENDS {
+            match (run(&path, backend, &expected), skipped(&name, backend)) {
+                (Ok(()), None) => summary.passed += 1,
+                (Err(report), None) => {
+                    println!("FAIL {name} ({backend})\n{report}");
+                    summary.failed += 1;
+                }
+                (Ok(()), Some(_)) => {
+                    println!("FAIL {name} ({backend}): passes, but is skipped\n");
+                    summary.failed += 1;
+                }
+                (Err(_), Some(reason)) => {
+                    println!("SKIP {name} ({backend}): {reason}");
+                    summary.skipped += 1;
+                }
+            }
+        }
+    }
+
+    let mut total = Summary::default();
+    for (dir, summary) in &summaries {
+        let status = if summary.failed == 0 { "ok" } else { "FAILED" };
+        println!(
+            "{dir:<24} {status:<6} {} passed, {} failed, {} skipped",
+            summary.passed, summary.failed, summary.skipped
+        );
+        total.passed += summary.passed;
+        total.failed += summary.failed;
+        total.skipped += summary.skipped;
+    }
+    println!(
+        "\n{} passed, {} failed, {} skipped, on {} backends",
+        total.passed,
+        total.failed,
+        total.skipped,
+        BACKENDS.len()
+    );
+
+    if total.failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}